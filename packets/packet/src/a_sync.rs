@@ -0,0 +1,467 @@
+use crate::content::{check_limit, read_timestamp, TIMESTAMP_EXT};
+use crate::limits;
+use crate::PacketReadError;
+use async_trait::async_trait;
+use bytes::Bytes;
+use rmp::decode::ValueReadError;
+use rmp::tokio::decode;
+use rmp::Marker;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use uuid::Uuid;
+
+/// Anything AsyncPacketContent can be read from. `tokio::io::BufReader` around a socket for example
+pub trait AsyncReader: AsyncBufRead + Unpin + Send {}
+
+impl<T: AsyncBufRead + Unpin + Send + ?Sized> AsyncReader for T {}
+
+/// A [`PacketContent`](crate::PacketContent) that is decoded straight from an AsyncRead.
+/// Nothing has to be buffered first, so a field can be larger than memory allows. See [`StreamedBin`].
+/// The wire format is the same as PacketContent. Writing still goes through PacketContent
+///
+/// Derive it with `#[derive(AsyncPacketContent)]`
+#[async_trait]
+pub trait AsyncPacketContent: Sized + Send {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError>;
+
+    /// See [`PacketContent::read_vec`](crate::PacketContent::read_vec)
+    async fn read_vec_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Vec<Self>, PacketReadError> {
+        let len = read_array_len_limited(reader).await?;
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(Self::read_async(reader).await?);
+        }
+        Ok(vec)
+    }
+}
+
+async fn read_marker<Reader: AsyncRead + Unpin>(reader: &mut Reader) -> Result<Marker, PacketReadError> {
+    decode::read_marker(reader)
+        .await
+        .map_err(|error| PacketReadError::IOError(error.0))
+}
+
+fn type_mismatch(marker: Marker) -> PacketReadError {
+    ValueReadError::<std::io::Error>::TypeMismatch(marker).into()
+}
+
+/// The length in front of every frame. A msgpack unsigned int.
+/// Only reads the int, so the reader does not have to be buffered
+pub async fn read_frame_len<Reader: AsyncRead + Unpin>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = match read_marker(reader).await? {
+        Marker::FixPos(len) => len as usize,
+        Marker::U8 => reader.read_u8().await? as usize,
+        Marker::U16 => reader.read_u16().await? as usize,
+        Marker::U32 => reader.read_u32().await? as usize,
+        Marker::U64 => reader.read_u64().await? as usize,
+        marker => return Err(type_mismatch(marker)),
+    };
+    Ok(len)
+}
+
+async fn read_bin_len<Reader: AsyncReader>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = match read_marker(reader).await? {
+        Marker::Bin8 => reader.read_u8().await? as usize,
+        Marker::Bin16 => reader.read_u16().await? as usize,
+        Marker::Bin32 => reader.read_u32().await? as usize,
+        marker => return Err(type_mismatch(marker)),
+    };
+    Ok(len)
+}
+
+async fn read_str_len_limited<Reader: AsyncReader>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = match read_marker(reader).await? {
+        Marker::FixStr(len) => len as usize,
+        Marker::Str8 => reader.read_u8().await? as usize,
+        Marker::Str16 => reader.read_u16().await? as usize,
+        Marker::Str32 => reader.read_u32().await? as usize,
+        marker => return Err(type_mismatch(marker)),
+    };
+    check_limit(len, limits::take_next_async(limits::current_async().max_bytes), "String length")
+}
+
+async fn read_array_len<Reader: AsyncReader>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = match read_marker(reader).await? {
+        Marker::FixArray(len) => len as usize,
+        Marker::Array16 => reader.read_u16().await? as usize,
+        Marker::Array32 => reader.read_u32().await? as usize,
+        marker => return Err(type_mismatch(marker)),
+    };
+    Ok(len)
+}
+
+async fn read_array_len_limited<Reader: AsyncReader>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = read_array_len(reader).await?;
+    check_limit(len, limits::take_next_async(limits::current_async().max_elements), "Array length")
+}
+
+/// Reads an array header and makes sure it has `expected` elements
+async fn read_array_len_exact<Reader: AsyncReader>(reader: &mut Reader, expected: usize) -> Result<(), PacketReadError> {
+    let len = read_array_len(reader).await?;
+    if len != expected {
+        return Err(PacketReadError::ContentError(format!("Expected {} elements got {}", expected, len).into()));
+    }
+    Ok(())
+}
+
+async fn read_map_len_limited<Reader: AsyncReader>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = match read_marker(reader).await? {
+        Marker::FixMap(len) => len as usize,
+        Marker::Map16 => reader.read_u16().await? as usize,
+        Marker::Map32 => reader.read_u32().await? as usize,
+        marker => return Err(type_mismatch(marker)),
+    };
+    check_limit(len, limits::take_next_async(limits::current_async().max_elements), "Map length")
+}
+
+/// Reads a bin of at most [`Limits::max_bytes`](crate::limits::Limits::max_bytes) into memory
+async fn read_bin<Reader: AsyncReader>(reader: &mut Reader) -> Result<Vec<u8>, PacketReadError> {
+    let max = limits::take_next_async(limits::current_async().max_bytes);
+    let len = check_limit(read_bin_len(reader).await?, max, "Bin length")?;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Only the header of a bin. The `len` bytes after it are left on the reader, so the last field of a packet
+/// can be streamed somewhere else. `AsyncReadExt::take(len)` for example.
+/// Not limited by [`Limits::max_bytes`](crate::limits::Limits::max_bytes)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamedBin {
+    pub len: usize,
+}
+
+#[async_trait]
+impl AsyncPacketContent for StreamedBin {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        Ok(StreamedBin { len: read_bin_len(reader).await? })
+    }
+}
+
+/// Fixed width msgpack integers. Same as the PacketContent ones
+macro_rules! fixed_int {
+    ($($ty:ty => $read:ident),*) => {
+        $(
+            #[async_trait]
+            impl AsyncPacketContent for $ty {
+                async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+                    decode::$read(reader).await.map_err(PacketReadError::from)
+                }
+            }
+        )*
+    };
+}
+
+fixed_int!(u16 => read_u16, u32 => read_u32, u64 => read_u64);
+fixed_int!(i8 => read_i8, i16 => read_i16, i32 => read_i32, i64 => read_i64);
+
+#[async_trait]
+impl AsyncPacketContent for u8 {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        decode::read_u8(reader).await.map_err(PacketReadError::from)
+    }
+
+    /// Still a bin
+    async fn read_vec_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Vec<Self>, PacketReadError> {
+        read_bin(reader).await
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for f32 {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        match read_marker(reader).await? {
+            Marker::F32 => Ok(reader.read_f32().await?),
+            marker => Err(type_mismatch(marker)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for f64 {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        match read_marker(reader).await? {
+            Marker::F64 => Ok(reader.read_f64().await?),
+            marker => Err(type_mismatch(marker)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for bool {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        match read_marker(reader).await? {
+            Marker::True => Ok(true),
+            Marker::False => Ok(false),
+            marker => Err(type_mismatch(marker)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for () {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        match read_marker(reader).await? {
+            Marker::FixArray(0) => Ok(()),
+            marker => Err(type_mismatch(marker)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for Uuid {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let most = u64::read_async(reader).await?;
+        let least = u64::read_async(reader).await?;
+        Ok(Uuid::from_u64_pair(most, least))
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for Bytes {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        read_bin(reader).await.map(Bytes::from)
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for String {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let len = read_str_len_limited(reader).await?;
+        let mut vec = vec![0u8; len];
+        reader.read_exact(&mut vec).await?;
+        String::from_utf8(vec).map_err(|error| PacketReadError::ContentError(Box::new(error)))
+    }
+}
+
+/// Peeks at the marker the same way the PacketContent one does
+#[async_trait]
+impl<T: AsyncPacketContent> AsyncPacketContent for Option<T> {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let is_null = match reader.fill_buf().await?.first() {
+            Some(marker) => Marker::from_u8(*marker) == Marker::Null,
+            None => return Err(PacketReadError::IOError(std::io::ErrorKind::UnexpectedEof.into())),
+        };
+        if is_null {
+            reader.consume(1);
+            Ok(None)
+        } else {
+            T::read_async(reader).await.map(Some)
+        }
+    }
+}
+
+#[async_trait]
+impl<T: AsyncPacketContent> AsyncPacketContent for Vec<T> {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        T::read_vec_async(reader).await
+    }
+}
+
+#[async_trait]
+impl<T: AsyncPacketContent> AsyncPacketContent for Box<T> {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        T::read_async(reader).await.map(Box::new)
+    }
+}
+
+#[async_trait]
+impl<T: AsyncPacketContent + Clone + Sync> AsyncPacketContent for Cow<'_, T> {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        T::read_async(reader).await.map(Cow::Owned)
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for Cow<'_, str> {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        String::read_async(reader).await.map(Cow::Owned)
+    }
+}
+
+/// A bin that has to be exactly `N` bytes long
+#[async_trait]
+impl<const N: usize> AsyncPacketContent for [u8; N] {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let len = read_bin_len(reader).await?;
+        if len != N {
+            return Err(PacketReadError::ContentError(format!("Expected {} bytes got {}", N, len).into()));
+        }
+        let mut bytes = [0u8; N];
+        reader.read_exact(&mut bytes).await?;
+        Ok(bytes)
+    }
+}
+
+/// A 16 byte big endian bin. Same as the PacketContent ones
+macro_rules! wide_int {
+    ($($ty:ty),*) => {
+        $(
+            #[async_trait]
+            impl AsyncPacketContent for $ty {
+                async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+                    Ok(<$ty>::from_be_bytes(<[u8; 16]>::read_async(reader).await?))
+                }
+            }
+        )*
+    };
+}
+
+wide_int!(u128, i128);
+
+#[async_trait]
+impl AsyncPacketContent for char {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let value = u32::read_async(reader).await?;
+        char::from_u32(value).ok_or_else(|| PacketReadError::ContentError(format!("{:#x} is not a char", value).into()))
+    }
+}
+
+/// Tuples are msgpack arrays
+macro_rules! tuple {
+    ($len:literal => $($name:ident),+) => {
+        #[async_trait]
+        impl<$($name: AsyncPacketContent),+> AsyncPacketContent for ($($name,)+) {
+            async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+                read_array_len_exact(reader, $len).await?;
+                Ok(($($name::read_async(reader).await?,)+))
+            }
+        }
+    };
+}
+
+tuple!(1 => A);
+tuple!(2 => A, B);
+tuple!(3 => A, B, C);
+tuple!(4 => A, B, C, D);
+tuple!(5 => A, B, C, D, E);
+tuple!(6 => A, B, C, D, E, F);
+tuple!(7 => A, B, C, D, E, F, G);
+tuple!(8 => A, B, C, D, E, F, G, H);
+
+#[async_trait]
+impl AsyncPacketContent for Duration {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let (secs, nanos) = <(u64, u32)>::read_async(reader).await?;
+        if nanos >= 1_000_000_000 {
+            return Err(PacketReadError::ContentError(format!("{} nanoseconds is more than a second", nanos).into()));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for SystemTime {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let size = match read_marker(reader).await? {
+            Marker::FixExt4 => 4,
+            Marker::FixExt8 => 8,
+            Marker::Ext8 => reader.read_u8().await? as usize,
+            marker => return Err(type_mismatch(marker)),
+        };
+        let typeid = reader.read_i8().await?;
+        if typeid != TIMESTAMP_EXT {
+            return Err(PacketReadError::ContentError(format!("Extension {} is not a timestamp", typeid).into()));
+        }
+        if !matches!(size, 4 | 8 | 12) {
+            return Err(PacketReadError::ContentError(format!("A timestamp can not be {} bytes", size).into()));
+        }
+        let mut data = [0u8; 12];
+        reader.read_exact(&mut data[..size]).await?;
+        read_timestamp(&data[..size])
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for Ipv4Addr {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        <[u8; 4]>::read_async(reader).await.map(Ipv4Addr::from)
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for Ipv6Addr {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        <[u8; 16]>::read_async(reader).await.map(Ipv6Addr::from)
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for IpAddr {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        match read_bin_len(reader).await? {
+            4 => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets).await?;
+                Ok(IpAddr::from(octets))
+            }
+            16 => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets).await?;
+                Ok(IpAddr::from(octets))
+            }
+            len => Err(PacketReadError::ContentError(format!("An address can not be {} bytes", len).into())),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncPacketContent for SocketAddr {
+    async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+        let (ip, port) = <(IpAddr, u16)>::read_async(reader).await?;
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+/// Sets are msgpack arrays
+macro_rules! set {
+    ($($set:ident: $($bound:path),+;)*) => {
+        $(
+            #[async_trait]
+            impl<T: AsyncPacketContent $(+ $bound)+> AsyncPacketContent for $set<T> {
+                async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+                    let len = read_array_len_limited(reader).await?;
+                    let mut set = $set::new();
+                    for _ in 0..len {
+                        set.insert(T::read_async(reader).await?);
+                    }
+                    Ok(set)
+                }
+            }
+        )*
+    };
+}
+
+set! {
+    HashSet: Eq, Hash;
+    BTreeSet: Ord;
+}
+
+/// Maps are msgpack maps
+macro_rules! map {
+    ($($map:ident: $($bound:path),+;)*) => {
+        $(
+            #[async_trait]
+            impl<K: AsyncPacketContent $(+ $bound)+, V: AsyncPacketContent> AsyncPacketContent for $map<K, V> {
+                async fn read_async<Reader: AsyncReader>(reader: &mut Reader) -> Result<Self, PacketReadError> {
+                    let len = read_map_len_limited(reader).await?;
+                    let mut map = $map::new();
+                    for _ in 0..len {
+                        let key = K::read_async(reader).await?;
+                        map.insert(key, V::read_async(reader).await?);
+                    }
+                    Ok(map)
+                }
+            }
+        )*
+    };
+}
+
+map! {
+    HashMap: Eq, Hash;
+    BTreeMap: Ord;
+}
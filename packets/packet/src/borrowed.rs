@@ -0,0 +1,355 @@
+use crate::content::{
+    read_array_len, read_array_len_limited, read_bin_len_limited, read_map_len_limited, read_str_len_limited,
+    write_array_len, write_map_len,
+};
+use crate::{PacketContent, PacketReadError, PacketWriteError};
+use bytes::Bytes;
+use rmp::Marker;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::io::{BufRead, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Reads out of a whole frame that is already in memory.
+/// Bins and strings can be taken as slices of the frame instead of being copied out of it
+#[derive(Debug, Clone)]
+pub struct FrameReader<'a> {
+    frame: &'a Bytes,
+    position: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    pub fn new(frame: &'a Bytes) -> Self {
+        FrameReader { frame, position: 0 }
+    }
+
+    /// How many bytes have been read
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Everything that has not been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        let frame: &'a [u8] = self.frame;
+        &frame[self.position..]
+    }
+
+    /// Takes the next `len` bytes without copying them
+    pub fn take_slice(&mut self, len: usize) -> Result<&'a [u8], PacketReadError> {
+        let remaining = self.remaining();
+        if remaining.len() < len {
+            return Err(PacketReadError::IOError(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.position += len;
+        Ok(&remaining[..len])
+    }
+
+    /// Same as [`FrameReader::take_slice`]. The returned Bytes shares the memory of the frame
+    pub fn take_bytes(&mut self, len: usize) -> Result<Bytes, PacketReadError> {
+        let start = self.position;
+        self.take_slice(len)?;
+        Ok(self.frame.slice(start..start + len))
+    }
+}
+
+impl Read for FrameReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.remaining().read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl BufRead for FrameReader<'_> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        Ok(self.remaining())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.frame.len());
+    }
+}
+
+/// A [`PacketContent`] that can borrow from the frame it was read from.
+/// `Bytes` fields become slices of the frame and `&'a str` or `&'a [u8]` fields point into it.
+/// The wire format is the same as PacketContent
+///
+/// Derive it with `#[derive(PacketContentRef)]`. Read it with [`read_from_bytes`]
+pub trait PacketContentRef<'a>: Sized {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError>;
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError>;
+
+    /// See [`PacketContent::read_vec`]
+    fn read_vec_ref(reader: &mut FrameReader<'a>) -> Result<Vec<Self>, PacketReadError> {
+        let len = read_array_len_limited(reader)?;
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(Self::read_ref(reader)?);
+        }
+        Ok(vec)
+    }
+
+    fn write_slice_ref<Writer: Write>(slice: &[Self], writer: &mut Writer) -> Result<(), PacketWriteError> {
+        write_array_len(writer, slice.len())?;
+        for value in slice {
+            value.write_ref(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads `T` out of the frame. Anything `T` borrows lives as long as the frame
+pub fn read_from_bytes<'a, T: PacketContentRef<'a>>(frame: &'a Bytes) -> Result<T, PacketReadError> {
+    T::read_ref(&mut FrameReader::new(frame))
+}
+
+/// Types that own everything anyway. They are read like a PacketContent
+macro_rules! owned {
+    ($($ty:ty),*) => {
+        $(
+            impl<'a> PacketContentRef<'a> for $ty {
+                fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+                    <$ty as PacketContent>::read(reader)
+                }
+
+                fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+                    PacketContent::write(self, writer)
+                }
+            }
+        )*
+    };
+}
+
+owned!(u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64, bool, char, ());
+owned!(String, Uuid, Duration, SystemTime, Ipv4Addr, Ipv6Addr, IpAddr, SocketAddr);
+
+impl<'a> PacketContentRef<'a> for u8 {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        u8::read(reader)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        self.write(writer)
+    }
+
+    /// Still a bin. Use `&'a [u8]` or `Bytes` to not copy it
+    fn read_vec_ref(reader: &mut FrameReader<'a>) -> Result<Vec<Self>, PacketReadError> {
+        u8::read_vec(reader)
+    }
+
+    fn write_slice_ref<Writer: Write>(slice: &[Self], writer: &mut Writer) -> Result<(), PacketWriteError> {
+        u8::write_slice(slice, writer)
+    }
+}
+
+impl<'a> PacketContentRef<'a> for Bytes {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        let len = read_bin_len_limited(reader)?;
+        reader.take_bytes(len)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        self.write(writer)
+    }
+}
+
+impl<'a> PacketContentRef<'a> for &'a [u8] {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        let len = read_bin_len_limited(reader)?;
+        reader.take_slice(len)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        rmp::encode::write_bin(writer, self).map_err(PacketWriteError::from)
+    }
+}
+
+impl<'a> PacketContentRef<'a> for &'a str {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        let len = read_str_len_limited(reader)?;
+        let slice = reader.take_slice(len)?;
+        std::str::from_utf8(slice).map_err(|error| PacketReadError::ContentError(Box::new(error)))
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        rmp::encode::write_str(writer, self).map_err(PacketWriteError::from)
+    }
+}
+
+impl<'a> PacketContentRef<'a> for Cow<'a, str> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        <&'a str>::read_ref(reader).map(Cow::Borrowed)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        rmp::encode::write_str(writer, self).map_err(PacketWriteError::from)
+    }
+}
+
+impl<'a, T: PacketContentRef<'a>> PacketContentRef<'a> for Option<T> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        match reader.remaining().first() {
+            Some(marker) if Marker::from_u8(*marker) == Marker::Null => {
+                reader.consume(1);
+                Ok(None)
+            }
+            _ => T::read_ref(reader).map(Some),
+        }
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        match self {
+            Some(value) => value.write_ref(writer),
+            None => {
+                rmp::encode::write_nil(writer).map_err(PacketWriteError::from)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a, T: PacketContentRef<'a>> PacketContentRef<'a> for Vec<T> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        T::read_vec_ref(reader)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        T::write_slice_ref(self, writer)
+    }
+}
+
+/// A bin that has to be exactly `N` bytes long. Copied, it is owned
+impl<'a, const N: usize> PacketContentRef<'a> for [u8; N] {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        <[u8; N] as PacketContent>::read(reader)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        PacketContent::write(self, writer)
+    }
+}
+
+impl<'a> PacketContentRef<'a> for Cow<'a, [u8]> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        <&'a [u8]>::read_ref(reader).map(Cow::Borrowed)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        rmp::encode::write_bin(writer, self).map_err(PacketWriteError::from)
+    }
+}
+
+impl<'a, T: PacketContentRef<'a> + Clone> PacketContentRef<'a> for Cow<'a, T> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        T::read_ref(reader).map(Cow::Owned)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        T::write_ref(self.as_ref(), writer)
+    }
+}
+
+impl<'a, T: PacketContentRef<'a>> PacketContentRef<'a> for Box<T> {
+    fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+        T::read_ref(reader).map(Box::new)
+    }
+
+    fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        T::write_ref(self.as_ref(), writer)
+    }
+}
+
+/// Tuples are msgpack arrays
+macro_rules! tuple {
+    ($len:literal => $($name:ident $index:tt),+) => {
+        impl<'a, $($name: PacketContentRef<'a>),+> PacketContentRef<'a> for ($($name,)+) {
+            fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+                read_array_len(reader, $len)?;
+                Ok(($($name::read_ref(reader)?,)+))
+            }
+
+            fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+                rmp::encode::write_array_len(writer, $len).map_err(PacketWriteError::from)?;
+                $(self.$index.write_ref(writer)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+tuple!(1 => A 0);
+tuple!(2 => A 0, B 1);
+tuple!(3 => A 0, B 1, C 2);
+tuple!(4 => A 0, B 1, C 2, D 3);
+tuple!(5 => A 0, B 1, C 2, D 3, E 4);
+tuple!(6 => A 0, B 1, C 2, D 3, E 4, F 5);
+tuple!(7 => A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple!(8 => A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// Sets are msgpack arrays
+macro_rules! set {
+    ($($set:ident: $($bound:path),+;)*) => {
+        $(
+            impl<'a, T: PacketContentRef<'a> $(+ $bound)+> PacketContentRef<'a> for $set<T> {
+                fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+                    let len = read_array_len_limited(reader)?;
+                    let mut set = $set::new();
+                    for _ in 0..len {
+                        set.insert(T::read_ref(reader)?);
+                    }
+                    Ok(set)
+                }
+
+                fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+                    write_array_len(writer, self.len())?;
+                    for value in self {
+                        value.write_ref(writer)?;
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+set! {
+    HashSet: Eq, Hash;
+    BTreeSet: Ord;
+}
+
+/// Maps are msgpack maps
+macro_rules! map {
+    ($($map:ident: $($bound:path),+;)*) => {
+        $(
+            impl<'a, K: PacketContentRef<'a> $(+ $bound)+, V: PacketContentRef<'a>> PacketContentRef<'a> for $map<K, V> {
+                fn read_ref(reader: &mut FrameReader<'a>) -> Result<Self, PacketReadError> {
+                    let len = read_map_len_limited(reader)?;
+                    let mut map = $map::new();
+                    for _ in 0..len {
+                        let key = K::read_ref(reader)?;
+                        map.insert(key, V::read_ref(reader)?);
+                    }
+                    Ok(map)
+                }
+
+                fn write_ref<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> {
+                    write_map_len(writer, self.len())?;
+                    for (key, value) in self {
+                        key.write_ref(writer)?;
+                        value.write_ref(writer)?;
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+map! {
+    HashMap: Eq, Hash;
+    BTreeMap: Ord;
+}
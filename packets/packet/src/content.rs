@@ -1,7 +1,12 @@
 use std::borrow::Cow;
-use crate::{PacketReadError, PacketWriteError};
-use std::io::{BufRead, Read, Write};
-use bytes::{Bytes, BytesMut};
+use crate::limits;
+use crate::{FrameReader, PacketReadError, PacketWriteError};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
 use rmp::Marker;
 use uuid::Uuid;
 
@@ -16,6 +21,90 @@ pub trait PacketContent {
     fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError>
         where
             Self: Sized;
+
+    /// Reads a `Vec<Self>`. A msgpack array unless the type has a better encoding. `u8` reads a bin
+    fn read_vec<Reader: BufRead>(reader: &mut Reader) -> Result<Vec<Self>, PacketReadError>
+        where
+            Self: Sized,
+    {
+        let len = read_array_len_limited(reader)?;
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(Self::read(reader)?);
+        }
+        Ok(vec)
+    }
+    /// Writes a slice of Self. See [`PacketContent::read_vec`]
+    fn write_slice<Writer: Write>(slice: &[Self], writer: &mut Writer) -> Result<(), PacketWriteError>
+        where
+            Self: Sized,
+    {
+        write_array_len(writer, slice.len())?;
+        for value in slice {
+            value.write(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Reads out of a frame that is already in memory. A `Bytes` inside of Self becomes a slice of the frame instead of a copy.
+    /// Same as [`PacketContent::read`] unless the type has a bin to share
+    fn read_frame(reader: &mut FrameReader<'_>) -> Result<Self, PacketReadError>
+        where
+            Self: Sized,
+    {
+        Self::read(reader)
+    }
+    /// See [`PacketContent::read_vec`] and [`PacketContent::read_frame`]
+    fn read_vec_frame(reader: &mut FrameReader<'_>) -> Result<Vec<Self>, PacketReadError>
+        where
+            Self: Sized,
+    {
+        let len = read_array_len_limited(reader)?;
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(Self::read_frame(reader)?);
+        }
+        Ok(vec)
+    }
+}
+
+pub(crate) fn check_limit(len: usize, max: usize, what: &str) -> Result<usize, PacketReadError> {
+    if len > max {
+        return Err(PacketReadError::ContentError(format!("{} {} is over the limit of {}", what, len, max).into()));
+    }
+    Ok(len)
+}
+
+pub(crate) fn read_array_len_limited<Reader: BufRead>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = rmp::decode::read_array_len(reader).map_err(PacketReadError::from)?;
+    check_limit(len as usize, limits::take_next(limits::current().max_elements), "Array length")
+}
+
+pub(crate) fn read_map_len_limited<Reader: BufRead>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = rmp::decode::read_map_len(reader).map_err(PacketReadError::from)?;
+    check_limit(len as usize, limits::take_next(limits::current().max_elements), "Map length")
+}
+
+pub(crate) fn read_bin_len_limited<Reader: BufRead>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = rmp::decode::read_bin_len(reader).map_err(PacketReadError::from)?;
+    check_limit(len as usize, limits::take_next(limits::current().max_bytes), "Bin length")
+}
+
+pub(crate) fn read_str_len_limited<Reader: BufRead>(reader: &mut Reader) -> Result<usize, PacketReadError> {
+    let len = rmp::decode::read_str_len(reader).map_err(PacketReadError::from)?;
+    check_limit(len as usize, limits::take_next(limits::current().max_bytes), "String length")
+}
+
+pub(crate) fn write_array_len<Writer: Write>(writer: &mut Writer, len: usize) -> Result<(), PacketWriteError> {
+    let len = u32::try_from(len).map_err(|error| PacketWriteError::ContentError(Box::new(error)))?;
+    rmp::encode::write_array_len(writer, len).map_err(PacketWriteError::from)?;
+    Ok(())
+}
+
+pub(crate) fn write_map_len<Writer: Write>(writer: &mut Writer, len: usize) -> Result<(), PacketWriteError> {
+    let len = u32::try_from(len).map_err(|error| PacketWriteError::ContentError(Box::new(error)))?;
+    rmp::encode::write_map_len(writer, len).map_err(PacketWriteError::from)?;
+    Ok(())
 }
 
 impl PacketContent for u8 {
@@ -32,6 +121,32 @@ impl PacketContent for u8 {
     {
         rmp::encode::write_u8(writer, *self).map_err(PacketWriteError::from)
     }
+
+    /// Byte vectors are a bin instead of an array of u8
+    fn read_vec<Reader: BufRead>(reader: &mut Reader) -> Result<Vec<Self>, PacketReadError>
+        where
+            Self: Sized,
+    {
+        let len = read_bin_len_limited(reader)?;
+        let mut vec = vec![0u8; len];
+        reader.read_exact(&mut vec).map_err(PacketReadError::from)?;
+        Ok(vec)
+    }
+
+    fn write_slice<Writer: Write>(slice: &[Self], writer: &mut Writer) -> Result<(), PacketWriteError>
+        where
+            Self: Sized,
+    {
+        rmp::encode::write_bin(writer, slice).map_err(PacketWriteError::from)
+    }
+
+    /// Still copied. A `Vec` owns its memory
+    fn read_vec_frame(reader: &mut FrameReader<'_>) -> Result<Vec<Self>, PacketReadError>
+        where
+            Self: Sized,
+    {
+        Self::read_vec(reader)
+    }
 }
 
 impl PacketContent for u32 {
@@ -81,31 +196,23 @@ impl PacketContent for Uuid {
     }
 }
 
-impl PacketContent for Vec<u8> {
+impl PacketContent for Bytes {
     fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        let len: usize = rmp::decode::read_int(reader).map_err(PacketReadError::from)?;
-        let mut vec = Vec::with_capacity(len);
-        reader.read_to_end(&mut vec).map_err(PacketReadError::from)?;
-        Ok(vec)
+        let len = read_bin_len_limited(reader)?;
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes).map_err(PacketReadError::from)?;
+        Ok(Bytes::from(bytes))
     }
 
     fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
         rmp::encode::write_bin(writer, self.as_ref()).map_err(PacketWriteError::from)?;
         Ok(())
     }
-}
 
-impl PacketContent for Bytes {
-    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        let len: usize = rmp::decode::read_int(reader).map_err(PacketReadError::from)?;
-        let mut bytes = BytesMut::with_capacity(len);
-        reader.take(len as u64).read_exact(&mut bytes).map_err(PacketReadError::from)?;
-        Ok(bytes.freeze())
-    }
-
-    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
-        rmp::encode::write_bin(writer, self.as_ref()).map_err(PacketWriteError::from)?;
-        Ok(())
+    /// A slice of the frame
+    fn read_frame(reader: &mut FrameReader<'_>) -> Result<Self, PacketReadError> where Self: Sized {
+        let len = read_bin_len_limited(reader)?;
+        reader.take_bytes(len)
     }
 }
 
@@ -122,8 +229,8 @@ impl PacketContent for bool {
 
 impl PacketContent for String {
     fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        let len: u32 = rmp::decode::read_str_len(reader).map_err(PacketReadError::from)?;
-        let mut vec = Vec::with_capacity(len as usize);
+        let len = read_str_len_limited(reader)?;
+        let mut vec = vec![0u8; len];
         reader.read_exact(&mut vec).map_err(PacketReadError::from)?;
         Ok(String::from_utf8(vec).map_err(|e| PacketReadError::ContentError(Box::new(e)))?)
     }
@@ -148,6 +255,15 @@ impl<T> PacketContent for Option<T> where T: PacketContent {
             Ok(Some(content))
         }
     }
+    fn read_frame(reader: &mut FrameReader<'_>) -> Result<Self, PacketReadError> where Self: Sized {
+        match reader.remaining().first() {
+            Some(marker) if Marker::from_u8(*marker) == Marker::Null => {
+                reader.consume(1);
+                Ok(None)
+            }
+            _ => T::read_frame(reader).map(Some),
+        }
+    }
     fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
         if let Some(value) = self {
             T::write(value, writer)?;
@@ -167,8 +283,8 @@ impl< T> PacketContent for Cow<'_, T>  where T: PacketContent + Clone {
 }
 impl PacketContent for Cow<'_, str> {
     fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        let len: u32 = rmp::decode::read_str_len(reader).map_err(PacketReadError::from)?;
-        let mut vec = Vec::with_capacity(len as usize);
+        let len = read_str_len_limited(reader)?;
+        let mut vec = vec![0u8; len];
         reader.read_exact(&mut vec).map_err(PacketReadError::from)?;
         Ok(Cow::Owned(String::from_utf8(vec).map_err(|e| PacketReadError::ContentError(Box::new(e)))?))
     }
@@ -176,4 +292,362 @@ impl PacketContent for Cow<'_, str> {
         rmp::encode::write_str(writer, self.as_ref()).map_err(PacketWriteError::from)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+/// Fixed width msgpack integers. `read` expects the same width that `write` produced
+macro_rules! fixed_int {
+    ($($ty:ty => $read:ident, $write:ident;)*) => {
+        $(
+            impl PacketContent for $ty {
+                fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+                    rmp::decode::$read(reader).map_err(PacketReadError::from)
+                }
+
+                fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+                    rmp::encode::$write(writer, *self).map_err(PacketWriteError::from)
+                }
+            }
+        )*
+    };
+}
+
+fixed_int! {
+    u16 => read_u16, write_u16;
+    i8 => read_i8, write_i8;
+    i16 => read_i16, write_i16;
+    i32 => read_i32, write_i32;
+    i64 => read_i64, write_i64;
+    f32 => read_f32, write_f32;
+    f64 => read_f64, write_f64;
+}
+
+/// msgpack has nothing wider than 64 bits. Written as a 16 byte big endian bin
+macro_rules! wide_int {
+    ($($ty:ty),*) => {
+        $(
+            impl PacketContent for $ty {
+                fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+                    Ok(<$ty>::from_be_bytes(<[u8; 16]>::read(reader)?))
+                }
+
+                fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+                    self.to_be_bytes().write(writer)
+                }
+            }
+        )*
+    };
+}
+
+wide_int!(u128, i128);
+
+impl PacketContent for char {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let value = u32::read(reader)?;
+        char::from_u32(value).ok_or_else(|| PacketReadError::ContentError(format!("{:#x} is not a char", value).into()))
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        (*self as u32).write(writer)
+    }
+}
+
+/// An empty array. Nil is [`Option::None`], so `Some(())` would come back as None
+impl PacketContent for () {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        match rmp::decode::read_array_len(reader).map_err(PacketReadError::from)? {
+            0 => Ok(()),
+            len => Err(PacketReadError::ContentError(format!("Expected an empty array got {} elements", len).into())),
+        }
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        rmp::encode::write_array_len(writer, 0).map_err(PacketWriteError::from)?;
+        Ok(())
+    }
+}
+
+/// A bin that has to be exactly `N` bytes long
+impl<const N: usize> PacketContent for [u8; N] {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let len = rmp::decode::read_bin_len(reader).map_err(PacketReadError::from)? as usize;
+        if len != N {
+            return Err(PacketReadError::ContentError(format!("Expected {} bytes got {}", N, len).into()));
+        }
+        let mut bytes = [0u8; N];
+        reader.read_exact(&mut bytes).map_err(PacketReadError::from)?;
+        Ok(bytes)
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        rmp::encode::write_bin(writer, self).map_err(PacketWriteError::from)
+    }
+}
+
+/// Reads an array header and makes sure it has `expected` elements
+pub(crate) fn read_array_len<Reader: BufRead>(reader: &mut Reader, expected: u32) -> Result<(), PacketReadError> {
+    let len = rmp::decode::read_array_len(reader).map_err(PacketReadError::from)?;
+    if len != expected {
+        return Err(PacketReadError::ContentError(format!("Expected {} elements got {}", expected, len).into()));
+    }
+    Ok(())
+}
+
+/// Tuples are msgpack arrays
+macro_rules! tuple {
+    ($len:literal => $($name:ident $index:tt),+) => {
+        impl<$($name: PacketContent),+> PacketContent for ($($name,)+) {
+            fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+                read_array_len(reader, $len)?;
+                Ok(($($name::read(reader)?,)+))
+            }
+
+            fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+                rmp::encode::write_array_len(writer, $len).map_err(PacketWriteError::from)?;
+                $(self.$index.write(writer)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+tuple!(1 => A 0);
+tuple!(2 => A 0, B 1);
+tuple!(3 => A 0, B 1, C 2);
+tuple!(4 => A 0, B 1, C 2, D 3);
+tuple!(5 => A 0, B 1, C 2, D 3, E 4);
+tuple!(6 => A 0, B 1, C 2, D 3, E 4, F 5);
+tuple!(7 => A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple!(8 => A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+/// An array of the seconds and the nanoseconds
+impl PacketContent for Duration {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let (secs, nanos) = <(u64, u32)>::read(reader)?;
+        if nanos >= 1_000_000_000 {
+            return Err(PacketReadError::ContentError(format!("{} nanoseconds is more than a second", nanos).into()));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        (self.as_secs(), self.subsec_nanos()).write(writer)
+    }
+}
+
+/// The msgpack timestamp extension type
+pub(crate) const TIMESTAMP_EXT: i8 = -1;
+
+/// The data of a timestamp extension. The 32, 64 or 96 bit form
+pub(crate) fn read_timestamp(data: &[u8]) -> Result<SystemTime, PacketReadError> {
+    let (secs, nanos) = match data.len() {
+        4 => (u32::from_be_bytes(data.try_into().unwrap()) as i64, 0),
+        8 => {
+            let value = u64::from_be_bytes(data.try_into().unwrap());
+            ((value & 0x0000_0003_ffff_ffff) as i64, (value >> 34) as u32)
+        }
+        12 => (
+            i64::from_be_bytes(data[4..].try_into().unwrap()),
+            u32::from_be_bytes(data[..4].try_into().unwrap()),
+        ),
+        size => {
+            return Err(PacketReadError::ContentError(format!("A timestamp can not be {} bytes", size).into()));
+        }
+    };
+    if nanos >= 1_000_000_000 {
+        return Err(PacketReadError::ContentError(format!("{} nanoseconds is more than a second", nanos).into()));
+    }
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(secs as u64, nanos))
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(Duration::from_nanos(nanos as u64)))
+    };
+    time.ok_or_else(|| PacketReadError::ContentError("The timestamp is out of range".into()))
+}
+
+/// The msgpack timestamp extension. Always written as the 96 bit form. Every form can be read
+impl PacketContent for SystemTime {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let meta = rmp::decode::read_ext_meta(reader).map_err(PacketReadError::from)?;
+        if meta.typeid != TIMESTAMP_EXT {
+            return Err(PacketReadError::ContentError(format!("Extension {} is not a timestamp", meta.typeid).into()));
+        }
+        if !matches!(meta.size, 4 | 8 | 12) {
+            return Err(PacketReadError::ContentError(format!("A timestamp can not be {} bytes", meta.size).into()));
+        }
+        let mut data = [0u8; 12];
+        let data = &mut data[..meta.size as usize];
+        reader.read_exact(data)?;
+        read_timestamp(data)
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        let (secs, nanos) = match self.duration_since(UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+            Err(error) => {
+                // Before the epoch. The nanoseconds are always counted forward
+                let before = error.duration();
+                if before.subsec_nanos() == 0 {
+                    (-(before.as_secs() as i64), 0)
+                } else {
+                    (-(before.as_secs() as i64) - 1, 1_000_000_000 - before.subsec_nanos())
+                }
+            }
+        };
+        rmp::encode::write_ext_meta(writer, 12, TIMESTAMP_EXT).map_err(PacketWriteError::from)?;
+        writer.write_all(&nanos.to_be_bytes())?;
+        writer.write_all(&secs.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+impl PacketContent for Ipv4Addr {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        Ok(Ipv4Addr::from(<[u8; 4]>::read(reader)?))
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        self.octets().write(writer)
+    }
+}
+
+impl PacketContent for Ipv6Addr {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        Ok(Ipv6Addr::from(<[u8; 16]>::read(reader)?))
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        self.octets().write(writer)
+    }
+}
+
+/// A bin of 4 or 16 bytes. The length tells the versions apart
+impl PacketContent for IpAddr {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let len = rmp::decode::read_bin_len(reader).map_err(PacketReadError::from)?;
+        match len {
+            4 => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets)?;
+                Ok(IpAddr::from(octets))
+            }
+            16 => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets)?;
+                Ok(IpAddr::from(octets))
+            }
+            len => Err(PacketReadError::ContentError(format!("An address can not be {} bytes", len).into())),
+        }
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        match self {
+            IpAddr::V4(address) => address.write(writer),
+            IpAddr::V6(address) => address.write(writer),
+        }
+    }
+}
+
+/// An array of the address and the port
+impl PacketContent for SocketAddr {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        let (ip, port) = <(IpAddr, u16)>::read(reader)?;
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        (self.ip(), self.port()).write(writer)
+    }
+}
+
+impl<T: PacketContent> PacketContent for Vec<T> {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        T::read_vec(reader)
+    }
+
+    fn read_frame(reader: &mut FrameReader<'_>) -> Result<Self, PacketReadError> where Self: Sized {
+        T::read_vec_frame(reader)
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        T::write_slice(self, writer)
+    }
+}
+
+impl<T: PacketContent> PacketContent for Box<T> {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        T::read(reader).map(Box::new)
+    }
+
+    fn read_frame(reader: &mut FrameReader<'_>) -> Result<Self, PacketReadError> where Self: Sized {
+        T::read_frame(reader).map(Box::new)
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        T::write(self.as_ref(), writer)
+    }
+}
+
+/// Sets are msgpack arrays
+macro_rules! set {
+    ($($set:ident: $($bound:path),+;)*) => {
+        $(
+            impl<T: PacketContent $(+ $bound)+> PacketContent for $set<T> {
+                fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+                    let len = read_array_len_limited(reader)?;
+                    let mut set = $set::new();
+                    for _ in 0..len {
+                        set.insert(T::read(reader)?);
+                    }
+                    Ok(set)
+                }
+
+                fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+                    write_array_len(writer, self.len())?;
+                    for value in self {
+                        value.write(writer)?;
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+set! {
+    HashSet: Eq, Hash;
+    BTreeSet: Ord;
+}
+
+/// Maps are msgpack maps
+macro_rules! map {
+    ($($map:ident: $($bound:path),+;)*) => {
+        $(
+            impl<K: PacketContent $(+ $bound)+, V: PacketContent> PacketContent for $map<K, V> {
+                fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+                    let len = read_map_len_limited(reader)?;
+                    let mut map = $map::new();
+                    for _ in 0..len {
+                        let key = K::read(reader)?;
+                        map.insert(key, V::read(reader)?);
+                    }
+                    Ok(map)
+                }
+
+                fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+                    write_map_len(writer, self.len())?;
+                    for (key, value) in self {
+                        key.write(writer)?;
+                        value.write(writer)?;
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+map! {
+    HashMap: Eq, Hash;
+    BTreeMap: Ord;
+}
@@ -4,14 +4,37 @@ use rmp::decode::{NumValueReadError, ValueReadError};
 use rmp::encode::ValueWriteError;
 use std::io::{Read, Write};
 
+/// Decoding straight from a tokio AsyncRead
+#[cfg(feature = "tokio")]
+mod a_sync;
+/// Decoding that borrows from the frame
+mod borrowed;
 mod content;
+/// Per decode limits on length prefixes
+pub mod limits;
 pub mod packet;
 pub mod protocol;
+/// Descriptions of the wire format generated by the derives
+pub mod schema;
+/// The protocol version used by `#[packet(since = N)]` fields
+pub mod version;
 
-pub use packet_derive::{Packet, Protocol, PacketContent};
+pub use packet_derive::{Packet, Protocol, PacketContent, PacketContentRef};
 use crate::packet::Packet;
 use crate::protocol::Protocol;
 pub use content::PacketContent;
+pub use limits::{with_limits, Limits, DEFAULT_MAX_BYTES, DEFAULT_MAX_ELEMENTS};
+#[cfg(feature = "tokio")]
+pub use limits::with_limits_async;
+pub use borrowed::{read_from_bytes, FrameReader, PacketContentRef};
+#[cfg(feature = "tokio")]
+pub use a_sync::{read_frame_len, AsyncPacketContent, AsyncReader, StreamedBin};
+#[cfg(feature = "tokio")]
+pub use packet_derive::AsyncPacketContent;
+/// Used by `#[derive(AsyncPacketContent)]`
+#[cfg(feature = "tokio")]
+#[doc(hidden)]
+pub use async_trait::async_trait;
 
 /// A Write Error for a Packet
 #[derive(Debug, thiserror::Error)]
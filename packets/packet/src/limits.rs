@@ -0,0 +1,107 @@
+use std::cell::Cell;
+
+pub const DEFAULT_MAX_ELEMENTS: usize = 65_536;
+pub const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// How large a decoded value may get.
+/// Length prefixes are checked against these before anything is allocated. So a prefix can not make the reader allocate more than the packet could hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most elements an array or map can have
+    pub max_elements: usize,
+    /// The longest bin or str. Used for `Bytes`, `Vec<u8>` and strings
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_elements: DEFAULT_MAX_ELEMENTS,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<Option<Limits>> = const { Cell::new(None) };
+    static NEXT_LEN: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+#[cfg(feature = "tokio")]
+tokio::task_local! {
+    static ASYNC_LIMITS: Limits;
+    static ASYNC_NEXT_LEN: Cell<Option<usize>>;
+}
+
+/// Puts the previous limits back. Even if `f` panics
+struct Restore(Option<Limits>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        LIMITS.with(|limits| limits.set(self.0));
+    }
+}
+
+/// Decodes everything inside of `f` with `limits`. Decodes outside of `f` keep their own limits
+pub fn with_limits<R>(limits: Limits, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(LIMITS.with(|current| current.replace(Some(limits))));
+    f()
+}
+
+/// The async version of [`with_limits`]. The limits are kept by the task, so they hold across `.await`
+#[cfg(feature = "tokio")]
+pub async fn with_limits_async<F: std::future::Future>(limits: Limits, future: F) -> F::Output {
+    ASYNC_LIMITS.scope(limits, future).await
+}
+
+/// The limits of the decode that is running. [`Limits::default`] outside of [`with_limits`]
+pub fn current() -> Limits {
+    LIMITS.with(Cell::get).unwrap_or_default()
+}
+
+/// The limits of the async decode that is running. [`Limits::default`] outside of [`with_limits_async`]
+#[cfg(feature = "tokio")]
+pub(crate) fn current_async() -> Limits {
+    ASYNC_LIMITS.try_with(|limits| *limits).unwrap_or_default()
+}
+
+/// Clears the limit of [`limit_next`] when the field was read. Even if it had no length prefix
+#[doc(hidden)]
+pub struct NextLen(Option<usize>);
+
+impl Drop for NextLen {
+    fn drop(&mut self) {
+        NEXT_LEN.with(|next| next.set(self.0));
+    }
+}
+
+/// Limits the next length prefix that is read to `max_len`. Used by `#[packet(max_len = N)]`.
+/// Only the first prefix. So the elements of a limited `Vec` are not limited by it
+#[doc(hidden)]
+pub fn limit_next(max_len: usize) -> NextLen {
+    NextLen(NEXT_LEN.with(|next| next.replace(Some(max_len))))
+}
+
+/// The async version of [`limit_next`]. Only for the prefixes read by `future`
+#[doc(hidden)]
+#[cfg(feature = "tokio")]
+pub async fn limit_next_async<F: std::future::Future>(max_len: usize, future: F) -> F::Output {
+    ASYNC_NEXT_LEN.scope(Cell::new(Some(max_len)), future).await
+}
+
+/// `max` lowered to the limit of [`limit_next`] if there is one. Uses it up
+pub(crate) fn take_next(max: usize) -> usize {
+    match NEXT_LEN.with(Cell::take) {
+        Some(next) => next.min(max),
+        None => max,
+    }
+}
+
+/// The async version of [`take_next`]
+#[cfg(feature = "tokio")]
+pub(crate) fn take_next_async(max: usize) -> usize {
+    match ASYNC_NEXT_LEN.try_with(Cell::take).ok().flatten() {
+        Some(next) => next.min(max),
+        None => max,
+    }
+}
@@ -1,3 +1,4 @@
+use crate::FrameReader;
 use std::error::Error;
 use std::io::{BufRead, Write};
 
@@ -22,4 +23,16 @@ pub trait Packet {
     ) -> Option<Result<Self, Self::ReadError>>
         where
             Self: Sized;
+
+    /// Same as [`build_or_none`](Packet::build_or_none) out of a frame that is already in memory.
+    /// The derive reads the fields with [`PacketContent::read_frame`](crate::PacketContent::read_frame), so a `Bytes` field is not copied
+    fn build_or_none_frame(
+        id: u8,
+        reader: &mut FrameReader<'_>,
+    ) -> Option<Result<Self, Self::ReadError>>
+        where
+            Self: Sized,
+    {
+        Self::build_or_none(id, reader)
+    }
 }
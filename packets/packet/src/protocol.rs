@@ -1,3 +1,4 @@
+use crate::FrameReader;
 use std::error::Error;
 use std::io::BufRead;
 use std::io::Write;
@@ -24,4 +25,17 @@ pub trait Protocol {
     ) -> Option<Result<Self, Self::ReadError>>
         where
             Self: Sized;
+
+    /// Same as [`build_if_supported`](Protocol::build_if_supported) out of a frame that is already in memory.
+    /// Please Refer to [`build_or_none_frame`](crate::packet::Packet::build_or_none_frame)
+    fn build_if_supported_frame(
+        protocol_id: u8,
+        packet_id: u8,
+        reader: &mut FrameReader<'_>,
+    ) -> Option<Result<Self, Self::ReadError>>
+        where
+            Self: Sized,
+    {
+        Self::build_if_supported(protocol_id, packet_id, reader)
+    }
 }
@@ -0,0 +1,317 @@
+use std::any::type_name;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use bytes::Bytes;
+#[cfg(feature = "schema")]
+use serde::Serialize;
+use uuid::Uuid;
+use crate::content::TIMESTAMP_EXT;
+
+/// Describes a type the way it is on the wire.
+/// Generated by the `Packet`, `Protocol` and `PacketContent` derives
+pub trait PacketSchema {
+    fn schema() -> TypeSchema;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize))]
+pub struct TypeSchema {
+    pub name: &'static str,
+    /// The doc comment of the type. Empty if it has none
+    pub docs: &'static str,
+    #[cfg_attr(feature = "schema", serde(flatten))]
+    pub kind: SchemaKind,
+}
+
+#[cfg(feature = "schema")]
+impl TypeSchema {
+    /// Pretty printed JSON. `kind` is one of `struct`, `enum`, `packets` or `protocols`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("A schema only has strings, numbers and lists")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize), serde(tag = "kind", rename_all = "snake_case"))]
+pub enum SchemaKind {
+    /// The fields in order
+    Struct { fields: Vec<FieldSchema> },
+    /// The tag of the variant as a u8 followed by its fields
+    Enum { variants: Vec<VariantSchema> },
+    /// The packet id followed by the fields of the packet
+    Packets { packets: Vec<VariantSchema> },
+    /// The protocol id followed by one of its packets
+    Protocols { protocols: Vec<ProtocolSchema> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize))]
+pub struct VariantSchema {
+    pub name: &'static str,
+    pub docs: &'static str,
+    /// The tag or packet id. `None` for the `#[packet(default)]` packet, it gets every unknown id
+    pub id: Option<u8>,
+    pub fields: Vec<FieldSchema>,
+}
+
+/// A field that is on the wire. Skipped fields are left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize))]
+pub struct FieldSchema {
+    /// The index for tuple fields
+    pub name: &'static str,
+    /// The Rust type as written in the source
+    #[cfg_attr(feature = "schema", serde(rename = "type"))]
+    pub ty: &'static str,
+    pub docs: &'static str,
+    /// The module of `#[packet(with = "...")]`. The type is not written the usual way
+    pub with: Option<&'static str>,
+    pub max_len: Option<usize>,
+    /// Only on the wire from this protocol version
+    pub since: Option<u8>,
+    /// How the value is encoded
+    pub wire: WireType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize))]
+pub struct ProtocolSchema {
+    pub name: &'static str,
+    pub docs: &'static str,
+    pub id: u8,
+    pub packets: TypeSchema,
+}
+
+/// The msgpack encoding of a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(Serialize), serde(tag = "type", rename_all = "snake_case"))]
+pub enum WireType {
+    Bool,
+    /// Always written with the marker of that width
+    Uint { bits: u8 },
+    Int { bits: u8 },
+    Float { bits: u8 },
+    Str,
+    /// `len` when it is always that many bytes
+    Bin { len: Option<usize> },
+    /// An array of any length
+    Array { items: Box<WireType> },
+    /// An array with exactly these elements
+    Tuple { items: Vec<WireType> },
+    Map { key: Box<WireType>, value: Box<WireType> },
+    /// Nil or the value
+    Optional { value: Box<WireType> },
+    /// Values written one after another without an array header
+    Sequence { items: Vec<WireType> },
+    /// A msgpack extension
+    Ext { typeid: i8 },
+    /// A type with its own PacketSchema
+    Schema { schema: Box<TypeSchema> },
+    /// A type that is already being described further up. Ends recursive types
+    Ref { rust: &'static str },
+    /// Written by a `with` module, a type parameter or a PacketContent impl without a [`Wire`] impl
+    Opaque,
+}
+
+/// The encoding of a PacketContent. Derived types get one that nests their schema
+pub trait Wire {
+    fn wire() -> WireType;
+
+    /// The encoding of a `Vec<Self>`. An array unless the type has a better one. `u8` is a bin
+    fn vec_wire() -> WireType {
+        WireType::Array { items: Box::new(Self::wire()) }
+    }
+}
+
+thread_local! {
+    /// The types that are being described on this thread
+    static DESCRIBING: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Takes the type off [`DESCRIBING`] even if its schema panics
+struct Describing;
+
+impl Drop for Describing {
+    fn drop(&mut self) {
+        DESCRIBING.with(|describing| describing.borrow_mut().pop());
+    }
+}
+
+/// The schema of `T` as a [`WireType::Schema`]. A [`WireType::Ref`] if `T` is already being described
+pub fn nested<T: PacketSchema + ?Sized>() -> WireType {
+    let rust = type_name::<T>();
+    if DESCRIBING.with(|describing| describing.borrow().contains(&rust)) {
+        return WireType::Ref { rust };
+    }
+    WireType::Schema { schema: Box::new(T::schema()) }
+}
+
+/// Builds the schema of `T` with `T` marked as being described. Used by the derived `schema()`
+#[doc(hidden)]
+pub fn describe<T: ?Sized>(build: impl FnOnce() -> TypeSchema) -> TypeSchema {
+    DESCRIBING.with(|describing| describing.borrow_mut().push(type_name::<T>()));
+    let _describing = Describing;
+    build()
+}
+
+/// Used by the derives. A field type without a [`Wire`] impl is [`WireType::Opaque`]
+#[doc(hidden)]
+pub mod __private {
+    use super::*;
+
+    pub struct Probe<T: ?Sized>(pub PhantomData<T>);
+
+    /// Picked first through autoref when `T: Wire`
+    pub trait ViaWire {
+        fn wire(&self) -> WireType;
+    }
+
+    impl<T: Wire + ?Sized> ViaWire for &Probe<T> {
+        fn wire(&self) -> WireType {
+            T::wire()
+        }
+    }
+
+    pub trait ViaOpaque {
+        fn wire(&self) -> WireType;
+    }
+
+    impl<T: ?Sized> ViaOpaque for &&Probe<T> {
+        fn wire(&self) -> WireType {
+            WireType::Opaque
+        }
+    }
+}
+
+macro_rules! wire {
+    ($($ty:ty => $wire:expr;)*) => {
+        $(
+            impl Wire for $ty {
+                fn wire() -> WireType {
+                    $wire
+                }
+            }
+        )*
+    };
+}
+
+wire! {
+    bool => WireType::Bool;
+    u16 => WireType::Uint { bits: 16 };
+    u32 => WireType::Uint { bits: 32 };
+    u64 => WireType::Uint { bits: 64 };
+    i8 => WireType::Int { bits: 8 };
+    i16 => WireType::Int { bits: 16 };
+    i32 => WireType::Int { bits: 32 };
+    i64 => WireType::Int { bits: 64 };
+    f32 => WireType::Float { bits: 32 };
+    f64 => WireType::Float { bits: 64 };
+    u128 => WireType::Bin { len: Some(16) };
+    i128 => WireType::Bin { len: Some(16) };
+    char => WireType::Uint { bits: 32 };
+    () => WireType::Tuple { items: Vec::new() };
+    String => WireType::Str;
+    str => WireType::Str;
+    Bytes => WireType::Bin { len: None };
+    [u8] => WireType::Bin { len: None };
+    Uuid => WireType::Sequence { items: vec![WireType::Uint { bits: 64 }, WireType::Uint { bits: 64 }] };
+    Duration => WireType::Tuple { items: vec![WireType::Uint { bits: 64 }, WireType::Uint { bits: 32 }] };
+    SystemTime => WireType::Ext { typeid: TIMESTAMP_EXT };
+    Ipv4Addr => WireType::Bin { len: Some(4) };
+    Ipv6Addr => WireType::Bin { len: Some(16) };
+    IpAddr => WireType::Bin { len: None };
+    SocketAddr => WireType::Tuple { items: vec![IpAddr::wire(), u16::wire()] };
+}
+
+impl Wire for u8 {
+    fn wire() -> WireType {
+        WireType::Uint { bits: 8 }
+    }
+
+    fn vec_wire() -> WireType {
+        WireType::Bin { len: None }
+    }
+}
+
+impl<const N: usize> Wire for [u8; N] {
+    fn wire() -> WireType {
+        WireType::Bin { len: Some(N) }
+    }
+}
+
+impl<T: Wire> Wire for Vec<T> {
+    fn wire() -> WireType {
+        T::vec_wire()
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn wire() -> WireType {
+        WireType::Optional { value: Box::new(T::wire()) }
+    }
+}
+
+impl<T: Wire + ?Sized> Wire for Box<T> {
+    fn wire() -> WireType {
+        T::wire()
+    }
+}
+
+impl<T: Wire + ?Sized> Wire for &T {
+    fn wire() -> WireType {
+        T::wire()
+    }
+}
+
+impl<T: Wire + ToOwned + ?Sized> Wire for Cow<'_, T> {
+    fn wire() -> WireType {
+        T::wire()
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Wire),+> Wire for ($($name,)+) {
+            fn wire() -> WireType {
+                WireType::Tuple { items: vec![$($name::wire()),+] }
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+tuple!(A, B, C, D, E);
+tuple!(A, B, C, D, E, F);
+tuple!(A, B, C, D, E, F, G);
+tuple!(A, B, C, D, E, F, G, H);
+
+impl<T: Wire> Wire for HashSet<T> {
+    fn wire() -> WireType {
+        WireType::Array { items: Box::new(T::wire()) }
+    }
+}
+
+impl<T: Wire> Wire for BTreeSet<T> {
+    fn wire() -> WireType {
+        WireType::Array { items: Box::new(T::wire()) }
+    }
+}
+
+impl<K: Wire, V: Wire> Wire for HashMap<K, V> {
+    fn wire() -> WireType {
+        WireType::Map { key: Box::new(K::wire()), value: Box::new(V::wire()) }
+    }
+}
+
+impl<K: Wire, V: Wire> Wire for BTreeMap<K, V> {
+    fn wire() -> WireType {
+        WireType::Map { key: Box::new(K::wire()), value: Box::new(V::wire()) }
+    }
+}
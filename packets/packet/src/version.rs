@@ -0,0 +1,33 @@
+use std::cell::Cell;
+
+thread_local! {
+    static VERSION: Cell<Option<u8>> = const { Cell::new(None) };
+}
+
+/// Puts the previous version back. Even if `f` panics
+struct Restore(Option<u8>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        VERSION.with(|version| version.set(self.0));
+    }
+}
+
+/// Reads or writes everything inside of `f` as `version`.
+/// Fields with `#[packet(since = N)]` are only on the wire if `version` is at least N
+///
+/// Packets are read and written synchronously. So wrap the call that reads or writes the packet with the negotiated version
+pub fn with_version<R>(version: u8, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(VERSION.with(|current| current.replace(Some(version))));
+    f()
+}
+
+/// None outside of [`with_version`]
+pub fn current() -> Option<u8> {
+    VERSION.with(Cell::get)
+}
+
+/// Outside of [`with_version`] every field is present
+pub fn is_present(since: u8) -> bool {
+    !matches!(current(), Some(version) if version < since)
+}
@@ -0,0 +1,168 @@
+use bytes::Bytes;
+use packet::{read_frame_len, AsyncPacketContent, PacketContent, StreamedBin};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, PartialEq, PacketContent, AsyncPacketContent)]
+pub struct Chat {
+    #[packet(max_len = 32)]
+    pub sender: String,
+    pub message: Option<String>,
+    pub attachments: Vec<Bytes>,
+    pub flags: Vec<u8>,
+    #[packet(skip, default = 7)]
+    pub local: u32,
+}
+
+#[derive(Debug, PartialEq, PacketContent, AsyncPacketContent)]
+pub enum Event {
+    #[content(tag = 0)]
+    Joined { name: String },
+    #[content(tag = 1)]
+    Left(u64, bool),
+    #[content(tag = 2)]
+    Closed,
+}
+
+/// The header of an upload. The file itself is left on the reader
+#[derive(Debug, PartialEq, PacketContent, AsyncPacketContent)]
+pub struct Upload {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, AsyncPacketContent)]
+pub struct UploadHeader {
+    pub name: String,
+    pub data: StreamedBin,
+}
+
+async fn roundtrip<T: PacketContent + AsyncPacketContent + PartialEq + Debug>(value: T) {
+    let mut buffer = Vec::new();
+    value.write(&mut buffer).unwrap();
+    let mut reader = buffer.as_slice();
+    assert_eq!(T::read_async(&mut reader).await.unwrap(), value);
+    assert!(reader.is_empty());
+}
+
+#[tokio::test]
+async fn async_content() {
+    roundtrip(Chat {
+        sender: "kinggoesgaming".to_string(),
+        message: Some("Hello".to_string()),
+        attachments: vec![Bytes::from_static(b"one"), Bytes::from_static(b"two")],
+        flags: vec![1, 2, 3],
+        local: 7,
+    })
+    .await;
+    roundtrip(Chat {
+        sender: String::new(),
+        message: None,
+        attachments: Vec::new(),
+        flags: Vec::new(),
+        local: 7,
+    })
+    .await;
+    roundtrip(Event::Joined { name: "wyatt".to_string() }).await;
+    roundtrip(Event::Left(u64::MAX, true)).await;
+    roundtrip(Event::Closed).await;
+    roundtrip(Some(())).await;
+
+    let mut buffer = Vec::new();
+    9u8.write(&mut buffer).unwrap();
+    let error = Event::read_async(&mut buffer.as_slice()).await.unwrap_err();
+    assert!(error.to_string().contains("Unknown tag 9"));
+}
+
+#[tokio::test]
+async fn async_primitives() {
+    roundtrip(u128::MAX).await;
+    roundtrip(i128::MIN).await;
+    roundtrip('ß').await;
+    roundtrip([1u8, 2, 3, 4]).await;
+    roundtrip((1u8, String::from("two"), Some(3u64))).await;
+    roundtrip(Duration::new(5, 999_999_999)).await;
+    roundtrip(UNIX_EPOCH + Duration::new(1_700_000_000, 42)).await;
+    roundtrip(UNIX_EPOCH - Duration::new(10, 1)).await;
+    roundtrip(SystemTime::now()).await;
+    roundtrip(Ipv4Addr::LOCALHOST).await;
+    roundtrip(Ipv6Addr::LOCALHOST).await;
+    roundtrip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).await;
+    roundtrip(IpAddr::V6(Ipv6Addr::UNSPECIFIED)).await;
+    roundtrip(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)).await;
+    roundtrip(HashMap::from([(1u8, String::from("one")), (2, String::from("two"))])).await;
+    roundtrip(BTreeMap::from([(String::from("a"), vec![1u16, 2])])).await;
+    roundtrip(HashSet::from([1u32, 2, 3])).await;
+    roundtrip(BTreeSet::from([String::from("x")])).await;
+    roundtrip(Cow::<str>::Owned(String::from("cow"))).await;
+    roundtrip(Cow::<u64>::Owned(7)).await;
+
+    // The same checks as PacketContent
+    let mut buffer = Vec::new();
+    [1u8, 2, 3].write(&mut buffer).unwrap();
+    assert!(<[u8; 4]>::read_async(&mut buffer.as_slice()).await.is_err());
+    let mut buffer = Vec::new();
+    (1u8, 2u8).write(&mut buffer).unwrap();
+    assert!(<(u8, u8, u8)>::read_async(&mut buffer.as_slice()).await.is_err());
+    let mut buffer = Vec::new();
+    0xd800u32.write(&mut buffer).unwrap();
+    assert!(char::read_async(&mut buffer.as_slice()).await.is_err());
+}
+
+#[tokio::test]
+async fn streamed_bin() {
+    let upload = Upload {
+        name: "large.bin".to_string(),
+        data: vec![42u8; 1024],
+    };
+    let mut buffer = Vec::new();
+    upload.write(&mut buffer).unwrap();
+
+    let mut reader = buffer.as_slice();
+    let header = UploadHeader::read_async(&mut reader).await.unwrap();
+    assert_eq!(header.name, "large.bin");
+    assert_eq!(header.data, StreamedBin { len: 1024 });
+    let mut data = Vec::new();
+    (&mut reader).take(header.data.len as u64).read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, upload.data);
+}
+
+#[tokio::test]
+async fn frame_len() {
+    let lens: [(&[u8], usize); 4] = [
+        (&[0x05], 5),
+        (&[0xcc, 200], 200),
+        (&[0xcd, 0x01, 0x2c], 300),
+        (&[0xce, 0x00, 0x01, 0x00, 0x00], 65536),
+    ];
+    for (mut reader, len) in lens {
+        assert_eq!(read_frame_len(&mut reader).await.unwrap(), len);
+        assert!(reader.is_empty());
+    }
+    // A length can not be negative
+    assert!(read_frame_len(&mut &[0xff][..]).await.is_err());
+}
+
+#[tokio::test]
+async fn async_limits() {
+    let mut buffer = Vec::new();
+    Bytes::from_static(&[1; 16]).write(&mut buffer).unwrap();
+    let limits = packet::Limits { max_bytes: 8, ..Default::default() };
+    let result = packet::with_limits_async(limits, async {
+        tokio::task::yield_now().await;
+        Bytes::read_async(&mut buffer.as_slice()).await
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(Bytes::read_async(&mut buffer.as_slice()).await.unwrap(), Bytes::from_static(&[1; 16]));
+
+    // max_len is checked against the prefix. The string itself is never read
+    let mut buffer = Vec::new();
+    rmp::encode::write_str_len(&mut buffer, 1_000_000).unwrap();
+    let error = Chat::read_async(&mut buffer.as_slice()).await.unwrap_err();
+    assert!(error.to_string().contains("over the limit of 32"), "{}", error);
+}
@@ -1,6 +1,12 @@
-use packet::{ PacketReadError, PacketWriteError};
-use packet_derive::{Packet, Protocol, PacketContent};
-use std::io::{Read, Write};
+use bytes::Bytes;
+use packet::schema::{FieldSchema, PacketSchema, SchemaKind, VariantSchema, WireType};
+use packet::{read_from_bytes, PacketContent, PacketContentRef, PacketReadError, PacketWriteError};
+use packet_derive::{Packet, Protocol};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Protocol)]
 pub enum Protocols {
@@ -25,5 +31,711 @@ pub struct ComplexPacket {
 }
 #[derive(Debug, PacketContent)]
 pub struct InnerPacket(pub u8, pub u8, pub u8);
+
+#[derive(Debug, PartialEq, PacketContent, PacketContentRef)]
+pub struct Marker;
+
+/// Writes the u32 as a str
+mod as_string {
+    use packet::{PacketContent, PacketReadError, PacketWriteError};
+    use std::io::{BufRead, Write};
+
+    pub fn read<Reader: BufRead>(reader: &mut Reader) -> Result<u32, PacketReadError> {
+        String::read(reader)?
+            .parse()
+            .map_err(|error| PacketReadError::ContentError(Box::new(error)))
+    }
+
+    pub fn write<Writer: Write>(value: &u32, writer: &mut Writer) -> Result<(), PacketWriteError> {
+        value.to_string().write(writer)
+    }
+}
+
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct Evolving {
+    pub id: u8,
+    #[packet(skip, default = 7)]
+    pub cached: u8,
+    /// Sent as a str
+    #[packet(with = "as_string")]
+    pub count: u32,
+    #[packet(max_len = 4)]
+    pub name: String,
+    #[packet(since = 2, default = String::from("none"))]
+    pub added: String,
+}
+
+/// A packet family defined once and used with different bodies
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct Envelope<T> {
+    pub request_id: u32,
+    pub body: T,
+}
+
+#[derive(Debug, PartialEq, PacketContent)]
+pub enum Reply<T, E = String>
+    where
+        E: Clone,
+{
+    #[content(tag = 0)]
+    Ok(T),
+    #[content(tag = 1)]
+    Err(E),
+}
+
+#[derive(Debug, PartialEq, Packet)]
+pub enum RpcPackets<T> {
+    #[packet(packet_id = 0)]
+    Request(Envelope<T>),
+    #[packet(packet_id = 1)]
+    Reply { request_id: u32, reply: Reply<T> },
+}
+
+#[derive(Debug, PartialEq, Protocol)]
+pub enum RpcProtocols<T> {
+    #[protocol(protocol_id = 5)]
+    Rpc(RpcPackets<T>),
+}
+
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct Message<'a> {
+    pub text: std::borrow::Cow<'a, str>,
+}
+
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct OwnedUpload {
+    pub name: String,
+    pub kind: Option<String>,
+    pub tags: Vec<String>,
+    pub checksum: Bytes,
+    pub body: Bytes,
+}
+
+/// Built out of a FrameReader with `build_if_supported_frame`
+#[derive(Debug, PartialEq, Packet)]
+pub enum ProxyPackets {
+    #[packet(packet_id = 0)]
+    Proxy(uuid::Uuid, Bytes),
+    #[packet(packet_id = 1)]
+    Batch {
+        chunks: Vec<Bytes>,
+        last: Option<Bytes>,
+        upload: Box<OwnedUpload>,
+    },
+}
+
+#[derive(Debug, PartialEq, Protocol)]
+pub enum ProxyProtocols {
+    #[protocol(protocol_id = 3)]
+    Proxy(ProxyPackets),
+}
+
+#[derive(Debug, PartialEq, PacketContentRef)]
+pub struct Upload<'a> {
+    pub name: &'a str,
+    pub kind: Option<&'a str>,
+    pub tags: Vec<&'a str>,
+    pub checksum: &'a [u8],
+    pub body: Bytes,
+}
+
+/// Containers of borrowed fields
+#[derive(Debug, PartialEq, PacketContentRef)]
+pub struct Index<'a> {
+    pub hash: [u8; 4],
+    pub entry: (&'a str, u16),
+    pub parent: Box<&'a str>,
+    pub offsets: Vec<u32>,
+    pub names: BTreeSet<&'a str>,
+    pub sizes: HashMap<&'a str, u64>,
+    pub labels: HashSet<String>,
+    pub blob: Cow<'a, [u8]>,
+    pub version: Cow<'a, u32>,
+}
+
+/// The same wire format as [`Index`]
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct OwnedIndex {
+    pub hash: [u8; 4],
+    pub entry: (String, u16),
+    pub parent: Box<String>,
+    pub offsets: Vec<u32>,
+    pub names: BTreeSet<String>,
+    pub sizes: HashMap<String, u64>,
+    pub labels: HashSet<String>,
+    pub blob: Vec<u8>,
+    pub version: u32,
+}
+
+#[derive(Debug, PartialEq, PacketContentRef)]
+pub enum Chunk<'a> {
+    #[content(tag = 0)]
+    Data(Upload<'a>),
+    #[content(tag = 1)]
+    End { count: u32 },
+}
+
+#[derive(Debug, PartialEq, Packet)]
+pub enum ForwardedPackets {
+    #[packet(packet_id = 0)]
+    Ping(u8),
+    #[packet(default)]
+    Unknown { id: u8, body: bytes::Bytes },
+}
+
+#[derive(Debug, PartialEq, Packet)]
+pub enum EvolvingPackets {
+    #[packet(packet_id = 0)]
+    Login {
+        #[packet(max_len = 2)]
+        token: Vec<u8>,
+        #[packet(since = 2)]
+        expires_at: u64,
+    },
+}
+
+/// Refers to itself through `children`
+#[derive(Debug, PartialEq, PacketContent)]
+pub struct Node {
+    pub id: uuid::Uuid,
+    pub size: u128,
+    pub seen: SystemTime,
+    pub details: Option<Details>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq, PacketContent)]
+pub enum Details {
+    #[content(tag = 0)]
+    None,
+    #[content(tag = 1)]
+    Id(u8, Vec<u8>),
+    #[content(tag = 7)]
+    Named { id: u16, name: String },
+}
 #[test]
 pub fn test() {}
+
+fn roundtrip<T: PacketContent + PartialEq + Debug>(value: T) {
+    let mut buffer = Vec::new();
+    value.write(&mut buffer).unwrap();
+    let mut reader = buffer.as_slice();
+    assert_eq!(T::read(&mut reader).unwrap(), value);
+    assert!(reader.is_empty(), "{:?} left bytes behind", value);
+}
+
+#[test]
+pub fn primitives() {
+    roundtrip(u16::MAX);
+    roundtrip(u128::MAX);
+    roundtrip(i8::MIN);
+    roundtrip(i16::MIN);
+    roundtrip(-1i32);
+    roundtrip(i64::MIN);
+    roundtrip(i128::MIN);
+    roundtrip(1.5f32);
+    roundtrip(-2.25f64);
+    roundtrip('\u{1F980}');
+    roundtrip(());
+    // Nil is taken by None
+    roundtrip(Some(()));
+    roundtrip(None::<()>);
+    roundtrip(vec![Some(()), None]);
+    roundtrip([1u8, 2, 3, 4]);
+    roundtrip((1u8, String::from("two"), (3u16, true)));
+    roundtrip(Duration::new(5, 999_999_999));
+    roundtrip(UNIX_EPOCH + Duration::new(1_654_000_000, 123));
+    roundtrip(UNIX_EPOCH - Duration::new(10, 1));
+    roundtrip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    roundtrip(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    roundtrip(SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8080));
+}
+
+#[test]
+pub fn invalid_primitives() {
+    let mut buffer = Vec::new();
+    0xD800u32.write(&mut buffer).unwrap();
+    assert!(char::read(&mut buffer.as_slice()).is_err());
+
+    let mut buffer = Vec::new();
+    [1u8, 2, 3].write(&mut buffer).unwrap();
+    assert!(<[u8; 4]>::read(&mut buffer.as_slice()).is_err());
+    assert!(IpAddr::read(&mut buffer.as_slice()).is_err());
+
+    let mut buffer = Vec::new();
+    (1u8, 2u8).write(&mut buffer).unwrap();
+    assert!(<(u8, u8, u8)>::read(&mut buffer.as_slice()).is_err());
+
+    // The 32 bit timestamp form written by other msgpack libraries
+    let time = SystemTime::read(&mut [0xd6, 0xff, 0x00, 0x00, 0x00, 0x10].as_slice()).unwrap();
+    assert_eq!(time, UNIX_EPOCH + Duration::from_secs(16));
+}
+
+#[test]
+pub fn collections() {
+    roundtrip(vec![1u16, 2, 3]);
+    roundtrip(vec![String::from("a"), String::from("b")]);
+    roundtrip(vec![vec![1u8, 2], vec![]]);
+    roundtrip(Box::new(7u32));
+    roundtrip(HashSet::from([1u32, 2, 3]));
+    roundtrip(BTreeSet::from([1u32, 2, 3]));
+    roundtrip(HashMap::from([(String::from("a"), 1u8), (String::from("b"), 2u8)]));
+    roundtrip(BTreeMap::from([(1u8, vec![1u8]), (2u8, vec![2u8, 3])]));
+
+    // Byte vectors are a bin, everything else is an array
+    let mut buffer = Vec::new();
+    vec![1u8, 2, 3].write(&mut buffer).unwrap();
+    assert_eq!(buffer, [0xc4, 3, 1, 2, 3]);
+    let mut buffer = Vec::new();
+    vec![1u16, 2, 3].write(&mut buffer).unwrap();
+    assert_eq!(buffer, [0x93, 0xcd, 0, 1, 0xcd, 0, 2, 0xcd, 0, 3]);
+
+    // A byte vector does not take the bytes after it
+    let mut buffer = Vec::new();
+    vec![1u8, 2].write(&mut buffer).unwrap();
+    5u8.write(&mut buffer).unwrap();
+    let mut reader = buffer.as_slice();
+    assert_eq!(Vec::<u8>::read(&mut reader).unwrap(), vec![1, 2]);
+    assert_eq!(u8::read(&mut reader).unwrap(), 5);
+}
+
+#[test]
+pub fn collection_limits() {
+    // The length is checked before anything is allocated
+    let mut buffer = Vec::new();
+    rmp::encode::write_array_len(&mut buffer, packet::DEFAULT_MAX_ELEMENTS as u32 + 1).unwrap();
+    assert!(matches!(Vec::<u16>::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+    let mut buffer = Vec::new();
+    rmp::encode::write_map_len(&mut buffer, u32::MAX).unwrap();
+    assert!(matches!(HashMap::<u8, u8>::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+    let mut buffer = Vec::new();
+    rmp::encode::write_bin_len(&mut buffer, packet::DEFAULT_MAX_BYTES as u32 + 1).unwrap();
+    assert!(matches!(Vec::<u8>::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+
+    // Raised for this decode only
+    let mut buffer = Vec::new();
+    rmp::encode::write_array_len(&mut buffer, packet::DEFAULT_MAX_ELEMENTS as u32 + 1).unwrap();
+    let limits = packet::Limits { max_elements: packet::DEFAULT_MAX_ELEMENTS * 2, ..Default::default() };
+    let result = packet::with_limits(limits, || {
+        assert_eq!(packet::limits::current(), limits);
+        Vec::<u16>::read(&mut buffer.as_slice())
+    });
+    assert!(matches!(result, Err(PacketReadError::IOError(_))));
+    assert_eq!(packet::limits::current(), packet::Limits::default());
+    assert!(matches!(Vec::<u16>::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+}
+
+#[test]
+pub fn derived_enums() {
+    roundtrip(Marker);
+    roundtrip(Details::None);
+    roundtrip(Details::Id(3, vec![1, 2]));
+    roundtrip(Details::Named {
+        id: 5,
+        name: String::from("name"),
+    });
+
+    // The tag comes first
+    let mut buffer = Vec::new();
+    Details::Id(3, vec![]).write(&mut buffer).unwrap();
+    assert_eq!(buffer, [0xcc, 1, 0xcc, 3, 0xc4, 0]);
+    let mut buffer = Vec::new();
+    Marker.write(&mut buffer).unwrap();
+    assert!(buffer.is_empty());
+
+    assert!(matches!(Details::read(&mut [0xcc, 2].as_slice()), Err(PacketReadError::ContentError(_))));
+}
+
+#[test]
+pub fn field_attributes() {
+    let value = Evolving {
+        id: 1,
+        cached: 7,
+        count: 42,
+        name: String::from("abc"),
+        added: String::from("new"),
+    };
+    roundtrip(Evolving {
+        id: 1,
+        cached: 7,
+        count: 42,
+        name: String::from("abc"),
+        added: String::from("new"),
+    });
+
+    let mut buffer = Vec::new();
+    value.write(&mut buffer).unwrap();
+    let mut expected = Vec::new();
+    1u8.write(&mut expected).unwrap();
+    String::from("42").write(&mut expected).unwrap();
+    String::from("abc").write(&mut expected).unwrap();
+    String::from("new").write(&mut expected).unwrap();
+    assert_eq!(buffer, expected);
+
+    // Version 1 does not have `added`
+    let mut buffer = Vec::new();
+    packet::version::with_version(1, || value.write(&mut buffer)).unwrap();
+    assert_eq!(buffer.len(), expected.len() - 4);
+    let read = packet::version::with_version(1, || Evolving::read(&mut buffer.as_slice())).unwrap();
+    assert_eq!(read.added, "none");
+    assert_eq!(packet::version::current(), None);
+
+    let too_long = Evolving {
+        name: String::from("abcde"),
+        ..value
+    };
+    assert!(matches!(too_long.write(&mut Vec::new()), Err(PacketWriteError::ContentError(_))));
+    let mut buffer = Vec::new();
+    1u8.write(&mut buffer).unwrap();
+    String::from("42").write(&mut buffer).unwrap();
+    String::from("abcde").write(&mut buffer).unwrap();
+    assert!(matches!(Evolving::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+    // The prefix is refused before the string is read. Only `name` is limited
+    let mut buffer = Vec::new();
+    1u8.write(&mut buffer).unwrap();
+    String::from("42").write(&mut buffer).unwrap();
+    rmp::encode::write_str_len(&mut buffer, 1_000_000).unwrap();
+    assert!(matches!(Evolving::read(&mut buffer.as_slice()), Err(PacketReadError::ContentError(_))));
+    roundtrip(Evolving {
+        id: 1,
+        cached: 7,
+        count: 42,
+        name: String::from("abc"),
+        added: String::from("longer than four"),
+    });
+
+    use packet::packet::Packet;
+    let login = EvolvingPackets::Login {
+        token: vec![1, 2],
+        expires_at: 9,
+    };
+    let mut buffer = Vec::new();
+    packet::version::with_version(1, || login.write_payload(&mut buffer)).unwrap();
+    let mut reader = buffer.as_slice();
+    let packet_id = u8::read(&mut reader).unwrap();
+    let read = packet::version::with_version(1, || EvolvingPackets::build_or_none(packet_id, &mut reader))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        read,
+        EvolvingPackets::Login {
+            token: vec![1, 2],
+            expires_at: 0
+        }
+    );
+    assert!(reader.is_empty());
+}
+
+#[test]
+pub fn default_packet() {
+    use packet::packet::Packet;
+    // A packet this side does not know about
+    let mut buffer = Vec::new();
+    EvolvingPackets::Login {
+        token: vec![1],
+        expires_at: 5,
+    }
+    .write_payload(&mut buffer)
+    .unwrap();
+    buffer[1] = 9;
+
+    let mut reader = buffer.as_slice();
+    let packet_id = u8::read(&mut reader).unwrap();
+    let packet = ForwardedPackets::build_or_none(packet_id, &mut reader).unwrap().unwrap();
+    assert_eq!(packet.get_packet_id(), 9);
+    assert!(matches!(&packet, ForwardedPackets::Unknown { id: 9, body } if body.as_ref() == &buffer[2..]));
+
+    // Forwarded verbatim
+    let mut forwarded = Vec::new();
+    packet.write_payload(&mut forwarded).unwrap();
+    assert_eq!(forwarded, buffer);
+
+    // Out of a frame the body is not copied
+    let frame = Bytes::from(buffer.clone());
+    let mut reader = packet::FrameReader::new(&frame);
+    let packet_id = u8::read(&mut reader).unwrap();
+    match ForwardedPackets::build_or_none_frame(packet_id, &mut reader).unwrap().unwrap() {
+        ForwardedPackets::Unknown { id: 9, body } => {
+            assert_eq!(body.as_ref(), &buffer[2..]);
+            assert_eq!(body.as_ptr(), frame[2..].as_ptr());
+        }
+        packet => panic!("Expected the default packet, got {:?}", packet),
+    }
+
+    let mut buffer = Vec::new();
+    ForwardedPackets::Ping(3).write_payload(&mut buffer).unwrap();
+    let mut reader = buffer.as_slice();
+    let packet_id = u8::read(&mut reader).unwrap();
+    assert_eq!(
+        ForwardedPackets::build_or_none(packet_id, &mut reader).unwrap().unwrap(),
+        ForwardedPackets::Ping(3)
+    );
+}
+
+#[test]
+pub fn generics() {
+    use packet::protocol::Protocol;
+    roundtrip(Envelope {
+        request_id: 1,
+        body: vec![1u16, 2],
+    });
+    roundtrip(Reply::<u8>::Ok(1));
+    roundtrip(Reply::<u8>::Err(String::from("failed")));
+    roundtrip(Message {
+        text: std::borrow::Cow::Borrowed("text"),
+    });
+
+    let protocol = RpcProtocols::from(RpcPackets::Reply {
+        request_id: 3,
+        reply: Reply::Ok(String::from("done")),
+    });
+    let mut buffer = Vec::new();
+    protocol.write_payload(&mut buffer).unwrap();
+    let mut reader = buffer.as_slice();
+    let protocol_id = u8::read(&mut reader).unwrap();
+    let packet_id = u8::read(&mut reader).unwrap();
+    let read = RpcProtocols::<String>::build_if_supported(protocol_id, packet_id, &mut reader)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        read,
+        RpcProtocols::Rpc(RpcPackets::Reply {
+            request_id: 3,
+            reply: Reply::Ok(String::from("done")),
+        })
+    );
+    assert!(reader.is_empty());
+}
+
+#[test]
+pub fn zero_copy() {
+    let owned = OwnedUpload {
+        name: String::from("file"),
+        kind: None,
+        tags: vec![String::from("a"), String::from("b")],
+        checksum: Bytes::from_static(&[1, 2, 3]),
+        body: Bytes::from(vec![7u8; 1024]),
+    };
+    let mut buffer = Vec::new();
+    owned.write(&mut buffer).unwrap();
+    let frame = Bytes::from(buffer);
+
+    // Same wire format as PacketContent
+    let upload: Upload = read_from_bytes(&frame).unwrap();
+    assert_eq!(upload.name, "file");
+    assert_eq!(upload.kind, None);
+    assert_eq!(upload.tags, ["a", "b"]);
+    assert_eq!(upload.checksum, [1, 2, 3]);
+    assert_eq!(upload.body.len(), 1024);
+
+    // Nothing was copied out of the frame
+    let frame_range = frame.as_ptr_range();
+    assert!(frame_range.contains(&upload.name.as_ptr()));
+    assert!(frame_range.contains(&upload.checksum.as_ptr()));
+    assert!(frame_range.contains(&upload.body.as_ptr()));
+
+    let mut written = Vec::new();
+    upload.write_ref(&mut written).unwrap();
+    assert_eq!(written, frame);
+
+    assert_eq!(read_from_bytes::<Marker>(&Bytes::new()).unwrap(), Marker);
+
+    let chunk = Chunk::End { count: 3 };
+    let mut buffer = Vec::new();
+    chunk.write_ref(&mut buffer).unwrap();
+    let frame = Bytes::from(buffer);
+    assert_eq!(read_from_bytes::<Chunk>(&frame).unwrap(), chunk);
+
+    let frame = Bytes::from_static(&[0xa4, b'a']);
+    assert!(matches!(read_from_bytes::<&str>(&frame), Err(PacketReadError::IOError(_))));
+
+    // Packets and protocols built out of the frame share it as well
+    use packet::protocol::Protocol;
+    use packet::FrameReader;
+    let proxy = ProxyProtocols::Proxy(ProxyPackets::Proxy(uuid::Uuid::from_u128(9), Bytes::from(vec![5u8; 512])));
+    let mut buffer = Vec::new();
+    proxy.write_payload(&mut buffer).unwrap();
+    let frame = Bytes::from(buffer);
+    let mut reader = FrameReader::new(&frame);
+    let (protocol_id, packet_id) = (u8::read(&mut reader).unwrap(), u8::read(&mut reader).unwrap());
+    let read = ProxyProtocols::build_if_supported_frame(protocol_id, packet_id, &mut reader).unwrap().unwrap();
+    let (device, payload) = match &read {
+        ProxyProtocols::Proxy(ProxyPackets::Proxy(device, payload)) => (device, payload),
+        other => panic!("Expected a proxy packet, got {:?}", other),
+    };
+    assert_eq!(*device, uuid::Uuid::from_u128(9));
+    assert_eq!(payload.len(), 512);
+    assert!(frame.as_ptr_range().contains(&payload.as_ptr()));
+
+    let mut buffer = Vec::new();
+    ProxyProtocols::Proxy(ProxyPackets::Batch {
+        chunks: vec![Bytes::from_static(b"one"), Bytes::from_static(b"two")],
+        last: Some(Bytes::from_static(b"three")),
+        upload: Box::new(owned),
+    })
+    .write_payload(&mut buffer)
+    .unwrap();
+    let frame = Bytes::from(buffer);
+    let mut reader = FrameReader::new(&frame);
+    let (protocol_id, packet_id) = (u8::read(&mut reader).unwrap(), u8::read(&mut reader).unwrap());
+    let read = ProxyProtocols::build_if_supported_frame(protocol_id, packet_id, &mut reader).unwrap().unwrap();
+    let (chunks, last, upload) = match &read {
+        ProxyProtocols::Proxy(ProxyPackets::Batch { chunks, last, upload }) => (chunks, last, upload),
+        other => panic!("Expected a batch, got {:?}", other),
+    };
+    assert_eq!(chunks, &[Bytes::from_static(b"one"), Bytes::from_static(b"two")]);
+    assert_eq!(last.as_deref(), Some(&b"three"[..]));
+    assert_eq!(upload.tags, ["a", "b"]);
+    let frame_range = frame.as_ptr_range();
+    assert!(chunks.iter().all(|chunk| frame_range.contains(&chunk.as_ptr())));
+    assert!(frame_range.contains(&last.as_ref().unwrap().as_ptr()));
+    assert!(frame_range.contains(&upload.body.as_ptr()));
+    assert!(ProxyProtocols::build_if_supported_frame(4, 0, &mut FrameReader::new(&frame)).is_none());
+}
+
+#[test]
+pub fn zero_copy_containers() {
+    let owned = OwnedIndex {
+        hash: [1, 2, 3, 4],
+        entry: (String::from("main"), 80),
+        parent: Box::new(String::from("root")),
+        offsets: vec![0, 512, 4096],
+        names: BTreeSet::from([String::from("a"), String::from("b")]),
+        sizes: HashMap::from([(String::from("a"), 10)]),
+        labels: HashSet::from([String::from("cached")]),
+        blob: vec![9u8; 64],
+        version: 3,
+    };
+    let mut buffer = Vec::new();
+    owned.write(&mut buffer).unwrap();
+    let frame = Bytes::from(buffer);
+
+    let index: Index = read_from_bytes(&frame).unwrap();
+    assert_eq!(index.hash, [1, 2, 3, 4]);
+    assert_eq!(index.entry, ("main", 80));
+    assert_eq!(*index.parent, "root");
+    assert_eq!(index.offsets, [0, 512, 4096]);
+    assert_eq!(index.names, BTreeSet::from(["a", "b"]));
+    assert_eq!(index.sizes, HashMap::from([("a", 10)]));
+    assert_eq!(index.labels, owned.labels);
+    assert_eq!(*index.version, 3);
+
+    // Nothing was copied out of the frame
+    let frame_range = frame.as_ptr_range();
+    assert!(frame_range.contains(&index.entry.0.as_ptr()));
+    assert!(frame_range.contains(&index.parent.as_ptr()));
+    assert!(index.names.iter().all(|name| frame_range.contains(&name.as_ptr())));
+    assert!(matches!(&index.blob, Cow::Borrowed(blob) if frame_range.contains(&blob.as_ptr())));
+
+    let mut written = Vec::new();
+    index.write_ref(&mut written).unwrap();
+    assert_eq!(OwnedIndex::read(&mut written.as_slice()).unwrap(), owned);
+}
+
+#[test]
+pub fn schema() {
+    let evolving = Evolving::schema();
+    assert_eq!(evolving.name, "Evolving");
+    let fields = match evolving.kind {
+        SchemaKind::Struct { fields } => fields,
+        kind => panic!("Expected a struct, got {:?}", kind),
+    };
+    // `cached` is skipped and not on the wire
+    let names: Vec<_> = fields.iter().map(|field| field.name).collect();
+    assert_eq!(names, ["id", "count", "name", "added"]);
+    assert_eq!(
+        fields[1],
+        FieldSchema {
+            name: "count",
+            ty: "u32",
+            docs: "Sent as a str",
+            with: Some("as_string"),
+            max_len: None,
+            since: None,
+            wire: WireType::Opaque,
+        }
+    );
+    assert_eq!(fields[0].wire, WireType::Uint { bits: 8 });
+    assert_eq!(fields[2].max_len, Some(4));
+    assert_eq!(fields[3].since, Some(2));
+
+    match Details::schema().kind {
+        SchemaKind::Enum { variants } => {
+            let tags: Vec<_> = variants.iter().map(|variant| (variant.name, variant.id)).collect();
+            assert_eq!(tags, [("None", Some(0)), ("Id", Some(1)), ("Named", Some(7))]);
+            assert_eq!(variants[1].fields[1].ty, "Vec<u8>");
+            assert_eq!(variants[1].fields[1].name, "1");
+            assert_eq!(variants[1].fields[1].wire, WireType::Bin { len: None });
+        }
+        kind => panic!("Expected an enum, got {:?}", kind),
+    }
+
+    match ForwardedPackets::schema().kind {
+        SchemaKind::Packets { packets } => assert_eq!(
+            packets[1],
+            VariantSchema {
+                name: "Unknown",
+                docs: "",
+                id: None,
+                fields: vec![
+                    FieldSchema {
+                        name: "id",
+                        ty: "u8",
+                        docs: "",
+                        with: None,
+                        max_len: None,
+                        since: None,
+                        wire: WireType::Uint { bits: 8 },
+                    },
+                    FieldSchema {
+                        name: "body",
+                        ty: "bytes::Bytes",
+                        docs: "",
+                        with: None,
+                        max_len: None,
+                        since: None,
+                        wire: WireType::Bin { len: None },
+                    },
+                ],
+            }
+        ),
+        kind => panic!("Expected packets, got {:?}", kind),
+    }
+
+    let fields = match Node::schema().kind {
+        SchemaKind::Struct { fields } => fields,
+        kind => panic!("Expected a struct, got {:?}", kind),
+    };
+    let wires: Vec<_> = fields.iter().map(|field| field.wire.clone()).collect();
+    assert_eq!(wires[0], WireType::Sequence { items: vec![WireType::Uint { bits: 64 }, WireType::Uint { bits: 64 }] });
+    assert_eq!(wires[1], WireType::Bin { len: Some(16) });
+    assert_eq!(wires[2], WireType::Ext { typeid: -1 });
+    match &wires[3] {
+        WireType::Optional { value } => assert_eq!(**value, WireType::Schema { schema: Box::new(Details::schema()) }),
+        wire => panic!("Expected an optional, got {:?}", wire),
+    }
+    // Node is already being described
+    assert_eq!(wires[4], WireType::Array { items: Box::new(WireType::Ref { rust: std::any::type_name::<Node>() }) });
+}
+
+#[test]
+#[cfg(feature = "schema")]
+pub fn schema_json() {
+    let json: serde_json::Value = serde_json::from_str(&RpcProtocols::<u8>::schema().to_json()).unwrap();
+    assert_eq!(json["kind"], "protocols");
+    let rpc = &json["protocols"][0];
+    assert_eq!(rpc["name"], "Rpc");
+    assert_eq!(rpc["id"], 5);
+    assert_eq!(rpc["packets"]["name"], "RpcPackets");
+    assert_eq!(rpc["packets"]["kind"], "packets");
+    let reply = &rpc["packets"]["packets"][1];
+    assert_eq!(reply["name"], "Reply");
+    assert_eq!(reply["id"], 1);
+    assert_eq!(reply["fields"][1]["type"], "Reply<T>");
+    assert_eq!(reply["fields"][0]["wire"]["type"], "uint");
+    assert_eq!(reply["fields"][0]["wire"]["bits"], 32);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&Envelope::<u8>::schema().to_json()).unwrap()["docs"],
+        "A packet family defined once and used with different bodies"
+    );
+}
@@ -0,0 +1,10 @@
+use packet_derive::AsyncPacketContent;
+
+#[derive(AsyncPacketContent)]
+pub struct Chat {
+    pub sender: String,
+    #[packet(since = 2)]
+    pub edited: bool,
+}
+
+fn main() {}
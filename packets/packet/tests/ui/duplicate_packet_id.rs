@@ -0,0 +1,11 @@
+use packet::Packet;
+
+#[derive(Packet)]
+pub enum Packets {
+    #[packet(packet_id = 0)]
+    Ping,
+    #[packet(packet_id = 0)]
+    Pong,
+}
+
+fn main() {}
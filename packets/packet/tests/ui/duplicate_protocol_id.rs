@@ -0,0 +1,23 @@
+use packet::{Packet, Protocol};
+
+#[derive(Packet)]
+pub enum First {
+    #[packet(packet_id = 0)]
+    Ping,
+}
+
+#[derive(Packet)]
+pub enum Second {
+    #[packet(packet_id = 0)]
+    Ping,
+}
+
+#[derive(Protocol)]
+pub enum Protocols {
+    #[protocol(protocol_id = 1)]
+    First(First),
+    #[protocol(protocol_id = 1)]
+    Second(Second),
+}
+
+fn main() {}
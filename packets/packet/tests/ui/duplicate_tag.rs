@@ -0,0 +1,11 @@
+use packet::PacketContent;
+
+#[derive(PacketContent)]
+pub enum Details {
+    #[content(tag = 0)]
+    None,
+    #[content(tag = 0)]
+    Other(u8),
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use packet::Packet;
+
+#[derive(Packet)]
+pub enum Packets {
+    #[packet(packet_id = 0)]
+    Ping,
+    Pong,
+}
+
+fn main() {}
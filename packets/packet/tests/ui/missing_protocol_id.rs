@@ -0,0 +1,14 @@
+use packet::{Packet, Protocol};
+
+#[derive(Packet)]
+pub enum First {
+    #[packet(packet_id = 0)]
+    Ping,
+}
+
+#[derive(Protocol)]
+pub enum Protocols {
+    First(First),
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use packet::PacketContent;
+
+#[derive(PacketContent)]
+pub enum Details {
+    #[content(tag = 0)]
+    None,
+    Other(u8),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use packet::Packet;
+
+#[derive(Packet)]
+pub enum Packets {
+    #[packet(packet_id = 300)]
+    Ping,
+}
+
+fn main() {}
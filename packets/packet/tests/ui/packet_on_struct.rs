@@ -0,0 +1,8 @@
+use packet::Packet;
+
+#[derive(Packet)]
+pub struct Ping {
+    pub id: u8,
+}
+
+fn main() {}
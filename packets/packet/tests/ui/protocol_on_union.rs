@@ -0,0 +1,8 @@
+use packet::Protocol;
+
+#[derive(Protocol)]
+pub union Protocols {
+    pub id: u8,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use packet::PacketContent;
+
+#[derive(PacketContent)]
+pub struct Details {
+    #[packet(skip, since = 2)]
+    pub added: u8,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use packet::PacketContent;
+
+#[derive(PacketContent)]
+pub enum Details {
+    #[content(tag = 256)]
+    None,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use packet::Packet;
+
+#[derive(Packet)]
+pub enum Packets {
+    #[packet(default)]
+    Unknown(u8, Vec<u8>),
+    #[packet(default)]
+    Other(u8, Vec<u8>),
+}
+
+fn main() {}
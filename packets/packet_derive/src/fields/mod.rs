@@ -0,0 +1,274 @@
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{Expr, Field, LitInt, LitStr, Path, Result, Type};
+
+mod field_attrs {
+    syn::custom_keyword!(skip);
+    syn::custom_keyword!(default);
+    syn::custom_keyword!(with);
+    syn::custom_keyword!(max_len);
+    syn::custom_keyword!(since);
+}
+
+enum FieldAttr {
+    Skip,
+    Default(Expr),
+    With(Path),
+    MaxLen(LitInt),
+    Since(LitInt),
+}
+
+impl Parse for FieldAttr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(field_attrs::skip) {
+            input.parse::<field_attrs::skip>()?;
+            Ok(FieldAttr::Skip)
+        } else if lookahead.peek(field_attrs::default) {
+            input.parse::<field_attrs::default>()?;
+            input.parse::<syn::Token![=]>()?;
+            Ok(FieldAttr::Default(input.parse()?))
+        } else if lookahead.peek(field_attrs::with) {
+            input.parse::<field_attrs::with>()?;
+            input.parse::<syn::Token![=]>()?;
+            let module: LitStr = input.parse()?;
+            Ok(FieldAttr::With(module.parse()?))
+        } else if lookahead.peek(field_attrs::max_len) {
+            input.parse::<field_attrs::max_len>()?;
+            input.parse::<syn::Token![=]>()?;
+            Ok(FieldAttr::MaxLen(input.parse()?))
+        } else if lookahead.peek(field_attrs::since) {
+            input.parse::<field_attrs::since>()?;
+            input.parse::<syn::Token![=]>()?;
+            Ok(FieldAttr::Since(input.parse()?))
+        } else {
+            Err(lookahead.error())
+        }
+    }
+}
+
+/// Which trait the field is read and written with
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Codec {
+    /// PacketContent
+    Owned,
+    /// PacketContent read with `read_frame`. Bins are slices of the frame. `with` modules use their `read`
+    Frame,
+    /// PacketContentRef. `with` modules need `read_ref` and `write_ref`
+    Borrowed,
+    /// AsyncPacketContent. Only reads, `with` modules need an async `read_async`
+    Async,
+}
+
+/// The `#[packet(...)]` attributes of a single field
+///
+/// * `skip` never on the wire. Read as `default`
+/// * `default = expr` used for `skip` and for `since` when the field is not present. `Default::default()` otherwise
+/// * `with = "module"` uses `module::read(reader)` and `module::write(&value, writer)` instead of PacketContent
+/// * `max_len = N` fails reading or writing if `value.len()` is over N. The length prefix is checked before the value is read
+/// * `since = N` only on the wire from protocol version N. See `packet::version`. Not for AsyncPacketContent
+pub(crate) struct FieldOptions {
+    name: String,
+    docs: String,
+    ty: Type,
+    skip: bool,
+    default: Option<Expr>,
+    with: Option<Path>,
+    max_len: Option<usize>,
+    since: Option<u8>,
+    /// Where `since` was set. For errors
+    since_span: Option<proc_macro2::Span>,
+}
+
+impl FieldOptions {
+    /// `name` is used inside of error messages
+    pub(crate) fn from_field(field: &Field, name: String) -> Result<Self> {
+        let mut options = FieldOptions {
+            name,
+            docs: crate::schema::docs(&field.attrs),
+            ty: field.ty.clone(),
+            skip: false,
+            default: None,
+            with: None,
+            max_len: None,
+            since: None,
+            since_span: None,
+        };
+        for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("packet")) {
+            let attrs = attr.parse_args_with(Punctuated::<FieldAttr, syn::Token![,]>::parse_terminated)?;
+            for field_attr in attrs {
+                match field_attr {
+                    FieldAttr::Skip => options.skip = true,
+                    FieldAttr::Default(expr) => options.default = Some(expr),
+                    FieldAttr::With(module) => options.with = Some(module),
+                    FieldAttr::MaxLen(value) => options.max_len = Some(value.base10_parse()?),
+                    FieldAttr::Since(value) => {
+                        options.since = Some(value.base10_parse()?);
+                        options.since_span = Some(value.span());
+                    }
+                }
+            }
+        }
+        if options.skip && (options.with.is_some() || options.max_len.is_some() || options.since.is_some()) {
+            return Err(syn::Error::new(
+                field.span(),
+                "A skipped field can not have `with`, `max_len` or `since`",
+            ));
+        }
+        if options.default.is_some() && !options.skip && options.since.is_none() {
+            return Err(syn::Error::new(
+                field.span(),
+                "`default` is only used with `skip` or `since`",
+            ));
+        }
+        Ok(options)
+    }
+
+    /// The version is thread local and an async read can move between threads on every `.await`.
+    /// So AsyncPacketContent can not have a field that depends on it
+    pub(crate) fn check_codec(&self, codec: Codec) -> Result<()> {
+        match (self.since_span, codec) {
+            (Some(span), Codec::Async) => Err(syn::Error::new(
+                span,
+                "`since` is not supported by AsyncPacketContent. Read the type with PacketContent instead",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn default_value(&self) -> TokenStream {
+        match &self.default {
+            Some(expr) => expr.to_token_stream(),
+            None => quote!(::std::default::Default::default()),
+        }
+    }
+
+    /// An expression that reads the field. Must be used inside of a function returning `Result<_, PacketReadError>`
+    pub(crate) fn read(&self, codec: Codec) -> TokenStream {
+        if self.skip {
+            return self.default_value();
+        }
+        let ty = &self.ty;
+        let mut read = match (&self.with, codec) {
+            (Some(module), Codec::Owned | Codec::Frame) => quote!(#module::read(reader)?),
+            (Some(module), Codec::Borrowed) => quote!(#module::read_ref(reader)?),
+            (None, Codec::Owned) => quote!(<#ty as ::packet::PacketContent>::read(reader)?),
+            (None, Codec::Borrowed) => quote!(<#ty as ::packet::PacketContentRef<'_>>::read_ref(reader)?),
+            (None, Codec::Frame) => quote!(<#ty as ::packet::PacketContent>::read_frame(reader)?),
+            (Some(module), Codec::Async) => quote!(#module::read_async(reader)),
+            (None, Codec::Async) => quote!(<#ty as ::packet::AsyncPacketContent>::read_async(reader)),
+        };
+        if let Some(max_len) = self.max_len {
+            // The length prefix is checked against max_len before anything is allocated.
+            // And the length again after. In case the prefix does not count what `len` does
+            let limited = match codec {
+                Codec::Async => quote!(::packet::limits::limit_next_async(#max_len, #read).await?),
+                Codec::Owned | Codec::Borrowed | Codec::Frame => quote! {
+                    {
+                        let _limit = ::packet::limits::limit_next(#max_len);
+                        #read
+                    }
+                },
+            };
+            let message = format!("`{}` is longer than {}", self.name, max_len);
+            read = quote! {
+                {
+                    let value: #ty = #limited;
+                    if value.len() > #max_len {
+                        return Err(::packet::PacketReadError::ContentError(#message.into()));
+                    }
+                    value
+                }
+            };
+        } else if codec == Codec::Async {
+            read = quote!(#read.await?);
+        }
+        if let Some(since) = self.since {
+            let default = self.default_value();
+            read = quote! {
+                if ::packet::version::is_present(#since) { #read } else { #default }
+            };
+        }
+        read
+    }
+
+    /// Statements that write the field. `value` is a reference to it.
+    /// AsyncPacketContent has no write of its own, so it writes the same as PacketContent
+    pub(crate) fn write(&self, value: TokenStream, codec: Codec) -> TokenStream {
+        if self.skip {
+            return quote! {
+                let _ = #value;
+            };
+        }
+        let mut write = match (&self.with, codec) {
+            (Some(module), Codec::Owned | Codec::Frame | Codec::Async) => quote!(#module::write(#value, writer)?;),
+            (Some(module), Codec::Borrowed) => quote!(#module::write_ref(#value, writer)?;),
+            (None, Codec::Owned | Codec::Frame | Codec::Async) => quote!(::packet::PacketContent::write(#value, writer)?;),
+            (None, Codec::Borrowed) => quote!(::packet::PacketContentRef::write_ref(#value, writer)?;),
+        };
+        if let Some(max_len) = self.max_len {
+            let message = format!("`{}` is longer than {}", self.name, max_len);
+            write = quote! {
+                if (#value).len() > #max_len {
+                    return Err(::packet::PacketWriteError::ContentError(#message.into()));
+                }
+                #write
+            };
+        }
+        if let Some(since) = self.since {
+            write = quote! {
+                if ::packet::version::is_present(#since) { #write }
+            };
+        }
+        write
+    }
+
+    /// A FieldSchema expression. None if the field is skipped
+    pub(crate) fn schema(&self, name: &str) -> Option<TokenStream> {
+        if self.skip {
+            return None;
+        }
+        let ty = crate::schema::source(&self.ty);
+        let docs = &self.docs;
+        let with = match &self.with {
+            Some(module) => {
+                let module = crate::schema::source(module);
+                quote!(Some(#module))
+            }
+            None => quote!(None),
+        };
+        let max_len = match self.max_len {
+            Some(max_len) => quote!(Some(#max_len)),
+            None => quote!(None),
+        };
+        let since = match self.since {
+            Some(since) => quote!(Some(#since)),
+            None => quote!(None),
+        };
+        let wire = match &self.with {
+            Some(_) => quote!(::packet::schema::WireType::Opaque),
+            None => {
+                let field_ty = &self.ty;
+                quote! {{
+                    #[allow(unused_imports)]
+                    use ::packet::schema::__private::{ViaOpaque, ViaWire};
+                    (&&::packet::schema::__private::Probe::<#field_ty>(::core::marker::PhantomData)).wire()
+                }}
+            }
+        };
+        Some(quote! {
+            ::packet::schema::FieldSchema {
+                name: #name,
+                ty: #ty,
+                docs: #docs,
+                with: #with,
+                max_len: #max_len,
+                since: #since,
+                wire: #wire,
+            }
+        })
+    }
+}
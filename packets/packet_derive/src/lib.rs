@@ -1,39 +1,94 @@
+mod fields;
 mod packet;
 mod protocol;
 mod packet_content;
+mod schema;
 
+use fields::Codec;
 use proc_macro::TokenStream;
-use syn::{parse_macro_input, Data, DeriveInput};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, GenericParam, Generics, LitInt};
 
+/// Parses a packet id, protocol id or tag. `what` is the name of the attribute for the error
+pub(crate) fn parse_id(value: &LitInt, what: &str) -> syn::Result<u8> {
+    value
+        .base10_parse::<u8>()
+        .map_err(|_| syn::Error::new(value.span(), format!("{} must be between 0 and 255", what)))
+}
+
+/// Adds `bound` to every type parameter. Lifetimes and const parameters are left alone
+pub(crate) fn add_bound(mut generics: Generics, bound: proc_macro2::TokenStream) -> Generics {
+    for param in generics.params.iter_mut() {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!(#bound));
+        }
+    }
+    generics
+}
+
+/// Fields can have `#[packet(skip, default = ..., with = "module", max_len = N, since = N)]`.
+/// Also implements `packet::schema::PacketSchema`
 #[proc_macro_derive(Packet, attributes(packet))]
 pub fn packet(stream: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(stream as DeriveInput);
+    let docs = schema::docs(&input.attrs);
     match input.data {
-        Data::Enum(en) => packet::parse_enum(input.ident, en)
+        Data::Enum(en) => packet::parse_enum(input.ident, input.generics, docs, en)
             .unwrap_or_else(|e| e.to_compile_error())
             .into(),
-        _ => panic!("Packet can only be derived from an enum"),
+        _ => syn::Error::new(input.ident.span(), "Packet can only be derived from an enum")
+            .to_compile_error()
+            .into(),
     }
 }
 
+/// Also implements `packet::schema::PacketSchema`. The packets need it as well
 #[proc_macro_derive(Protocol, attributes(protocol))]
 pub fn protocol(stream: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(stream as DeriveInput);
+    let docs = schema::docs(&input.attrs);
     match input.data {
-        Data::Enum(en) => protocol::parse_enum(input.ident, en)
+        Data::Enum(en) => protocol::parse_enum(input.ident, input.generics, docs, en)
             .unwrap_or_else(|e| e.to_compile_error())
             .into(),
-        _ => panic!("Protocol can only be derived from an enum"),
+        _ => syn::Error::new(input.ident.span(), "Protocol can only be derived from an enum")
+            .to_compile_error()
+            .into(),
     }
 }
 
-#[proc_macro_derive(PacketContent, )]
+/// Enums need a `#[content(tag = N)]` on every variant. Fields take the same `#[packet(...)]` attributes as [`Packet`].
+/// Also implements `packet::schema::PacketSchema`
+#[proc_macro_derive(PacketContent, attributes(content, packet))]
 pub fn packet_content(stream: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(stream as DeriveInput);
-    match input.data {
-        Data::Struct(en) => packet_content::parse_struct(input.ident, en)
-            .unwrap_or_else(|e| e.to_compile_error())
-            .into(),
-        _ => panic!("PacketContent can only be derived from an struct"),
-    }
+    content(input, Codec::Owned)
+}
+
+/// Same as [`PacketContent`]. `Bytes`, `&'a str` and `&'a [u8]` fields borrow from the frame.
+/// `'a` is the only lifetime of the type
+#[proc_macro_derive(PacketContentRef, attributes(content, packet))]
+pub fn packet_content_ref(stream: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(stream as DeriveInput);
+    content(input, Codec::Borrowed)
+}
+
+/// Same as [`PacketContent`] but read from a tokio AsyncRead. Needs the `tokio` feature of packet.
+/// Only reads, derive PacketContent as well to write it
+#[proc_macro_derive(AsyncPacketContent, attributes(content, packet))]
+pub fn async_packet_content(stream: TokenStream) -> TokenStream {
+    let input: DeriveInput = parse_macro_input!(stream as DeriveInput);
+    content(input, Codec::Async)
+}
+
+fn content(input: DeriveInput, codec: Codec) -> TokenStream {
+    let docs = schema::docs(&input.attrs);
+    let result = match input.data {
+        Data::Struct(data) => packet_content::parse_struct(input.ident, input.generics, docs, data, codec),
+        Data::Enum(data) => packet_content::parse_enum(input.ident, input.generics, docs, data, codec),
+        Data::Union(_) => Err(syn::Error::new(
+            input.ident.span(),
+            "PacketContent can not be derived for a union",
+        )),
+    };
+    result.unwrap_or_else(|e| e.to_compile_error()).into()
 }
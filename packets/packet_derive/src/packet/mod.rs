@@ -1,9 +1,11 @@
+use crate::fields::{Codec, FieldOptions};
+use crate::schema;
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
 use std::collections::HashMap;
 use syn::parse::{Parse, ParseStream};
 use syn::spanned::Spanned;
-use syn::{DataEnum, LitInt, Variant};
+use syn::{DataEnum, Generics, LitInt, Variant};
 use syn::{Fields, FieldsNamed, FieldsUnnamed, Result};
 
 mod packet_attrs {
@@ -30,6 +32,7 @@ impl Parse for PacketAttrs {
                 value: input.parse()?,
             })
         } else if lookahead.peek(packet_attrs::default) {
+            input.parse::<packet_attrs::default>()?;
             Ok(PacketAttrs::Default)
         } else {
             Err(lookahead.error())
@@ -37,11 +40,18 @@ impl Parse for PacketAttrs {
     }
 }
 
-pub(crate) fn parse_enum(packet_ident: syn::Ident, data: DataEnum) -> Result<TokenStream> {
-    let mut packet_types = HashMap::new();
+pub(crate) fn parse_enum(
+    packet_ident: syn::Ident,
+    generics: Generics,
+    docs: String,
+    data: DataEnum,
+) -> Result<TokenStream> {
+    let mut packet_types: HashMap<u8, Variant> = HashMap::new();
     let mut packet_handlers = Vec::new();
     let mut get_packet_id_arms = Vec::new();
     let mut write_arms = Vec::new();
+    let mut default_arm: Option<(syn::Ident, TokenStream, TokenStream)> = None;
+    let mut packet_schemas = Vec::new();
 
     for packet_variant in data.variants {
         let packet_id = packet_variant
@@ -58,7 +68,13 @@ pub(crate) fn parse_enum(packet_ident: syn::Ident, data: DataEnum) -> Result<Tok
         let value = packet_id.parse_args::<PacketAttrs>()?;
         match value {
             PacketAttrs::PacketId { value, .. } => {
-                let packet_id_id = value.base10_parse::<u8>()?;
+                let packet_id_id = crate::parse_id(&value, "packet_id")?;
+                if let Some(existing) = packet_types.get(&packet_id_id) {
+                    return Err(syn::Error::new(
+                        value.span(),
+                        format!("Duplicate packet_id {}, already used by `{}`", packet_id_id, existing.ident),
+                    ));
+                }
                 let variant_name = packet_variant.ident.clone();
                 let packet_id_arm = match packet_variant.fields {
                     Fields::Named(_) => {
@@ -84,70 +100,99 @@ pub(crate) fn parse_enum(packet_ident: syn::Ident, data: DataEnum) -> Result<Tok
                     }
                 };
                 get_packet_id_arms.push(packet_id_arm);
-                let mod_name = format_ident!("{}_handler", variant_name);
+                let read_name = format_ident!("__read_{}", variant_name);
+                let write_name = format_ident!("__write_{}", variant_name);
 
-                let read_method = match &packet_variant.fields {
-                    Fields::Named(named) => named_parser(&packet_variant, &packet_ident, named),
+                let frame_read_name = format_ident!("__read_frame_{}", variant_name);
+                let parser = |read_name: &syn::Ident, codec: Codec| match &packet_variant.fields {
+                    Fields::Named(named) => named_parser(&packet_variant, read_name, named, codec),
                     Fields::Unnamed(unamed) => {
-                        unamed_parser(&packet_variant, &packet_ident, unamed)
+                        unamed_parser(&packet_variant, read_name, unamed, codec)
                     }
-                    Fields::Unit => unit_parser(&packet_variant, &packet_ident),
-                }?;
+                    Fields::Unit => unit_parser(&packet_variant, read_name, codec),
+                };
+                let read_method = parser(&read_name, Codec::Owned)?;
+                let frame_read_method = parser(&frame_read_name, Codec::Frame)?;
                 let (write_method, arm) = match &packet_variant.fields {
                     Fields::Named(named) => named_writer(
                         &packet_variant,
                         packet_id_id,
                         &packet_ident,
-                        &mod_name,
+                        &write_name,
                         named,
                     ),
                     Fields::Unnamed(unamed) => unamed_writer(
                         &packet_variant,
                         packet_id_id,
                         &packet_ident,
-                        &mod_name,
+                        &write_name,
                         unamed,
                     ),
                     Fields::Unit => {
-                        unit_writer(&packet_variant, packet_id_id, &packet_ident, &mod_name)
+                        unit_writer(&packet_variant, packet_id_id, &packet_ident, &write_name)
                     }
                 }?;
                 write_arms.push(arm);
+                packet_schemas.push(schema::variant(&packet_variant, Some(packet_id_id))?);
                 let handler = quote! {
-                    #[allow(non_snake_case)]
-                    mod #mod_name {
-                        use super::*;
-                        use packet::PacketContent;
-                        use packet::packet::Packet;
-                        use packet::protocol::Protocol;
-
-                        #read_method
-                        #write_method
-                    }
+                    #read_method
+                    #frame_read_method
+                    #write_method
                 };
                 packet_types.insert(packet_id_id, packet_variant);
                 packet_handlers.push(handler);
             }
             PacketAttrs::Default => {
-                //TODO crate a default handler
+                if let Some((existing, _, _)) = &default_arm {
+                    return Err(syn::Error::new(
+                        packet_variant.ident.span(),
+                        format!("Only one default packet is allowed, `{}` is already the default", existing),
+                    ));
+                }
+                let (get_packet_id_arm, write_arm, read_arm, frame_read_arm) =
+                    default_packet(&packet_variant, &packet_ident)?;
+                get_packet_id_arms.push(get_packet_id_arm);
+                write_arms.push(write_arm);
+                packet_schemas.push(schema::variant(&packet_variant, None)?);
+                default_arm = Some((packet_variant.ident.clone(), read_arm, frame_read_arm));
             }
         }
     }
     let mut read_arms = Vec::new();
+    let mut frame_read_arms = Vec::new();
     for (packet_id, variant) in packet_types.iter() {
-        let variant_name = &variant.ident;
-        let mod_name = format_ident!("{}_handler", variant_name);
+        let read_name = format_ident!("__read_{}", variant.ident);
+        let frame_read_name = format_ident!("__read_frame_{}", variant.ident);
 
         read_arms.push(quote! {
             #packet_id => {
-                let packet = #mod_name::read(reader);
+                let packet = Self::#read_name(reader);
                 Some(packet)
             }
         });
+        frame_read_arms.push(quote! {
+            #packet_id => Some(Self::#frame_read_name(reader)),
+        });
     }
+    let (default_arm, frame_default_arm) = match default_arm {
+        Some((_, read_arm, frame_read_arm)) => (read_arm, frame_read_arm),
+        None => (quote!(_ => None), quote!(_ => None)),
+    };
+    let schema_impl = schema::schema_impl(
+        &packet_ident,
+        &generics,
+        &docs,
+        quote!(::packet::schema::SchemaKind::Packets { packets: vec![#(#packet_schemas),*] }),
+    );
+    let generics = crate::add_bound(generics, quote!(::packet::PacketContent));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     Ok(quote! {
-        #(#packet_handlers)*
-        impl ::packet::packet::Packet for #packet_ident{
+        #schema_impl
+        #[allow(non_snake_case)]
+        impl #impl_generics #packet_ident #ty_generics #where_clause {
+            #(#packet_handlers)*
+        }
+        impl #impl_generics ::packet::packet::Packet for #packet_ident #ty_generics #where_clause {
             type ReadError = ::packet::PacketReadError;
             type WriteError = ::packet::PacketWriteError;
             fn get_packet_id(&self) -> u8 {
@@ -164,7 +209,13 @@ pub(crate) fn parse_enum(packet_ident: syn::Ident, data: DataEnum) -> Result<Tok
             fn build_or_none<Reader: ::std::io::BufRead>(id: u8, reader: &mut Reader) -> Option<Result<Self, Self::ReadError>> where Self: ::std::marker::Sized{
                 match id {
                     #(#read_arms)*
-                    _ => None
+                    #default_arm
+                }
+            }
+            fn build_or_none_frame(id: u8, reader: &mut ::packet::FrameReader<'_>) -> Option<Result<Self, Self::ReadError>> where Self: ::std::marker::Sized{
+                match id {
+                    #(#frame_read_arms)*
+                    #frame_default_arm
                 }
             }
 
@@ -172,26 +223,103 @@ pub(crate) fn parse_enum(packet_ident: syn::Ident, data: DataEnum) -> Result<Tok
     })
 }
 
+/// The variant marked with `#[packet(default)]`. Gets every unknown packet id and the rest of the frame.
+/// Either `{ id: u8, body: Bytes }` or `(u8, Bytes)`. The body can be anything that is `From<Vec<u8>>`, `From<Bytes>` and `AsRef<[u8]>`
+///
+/// Returns the get_packet_id arm, the write arm, the read arm and the read arm of [`Codec::Frame`]
+fn default_packet(
+    variant: &Variant,
+    type_ident: &syn::Ident,
+) -> Result<(TokenStream, TokenStream, TokenStream, TokenStream)> {
+    let variant_name = &variant.ident;
+    let field_names: Vec<String> = variant
+        .fields
+        .iter()
+        .filter_map(|field| field.ident.as_ref().map(ToString::to_string))
+        .collect();
+    let (pattern, constructor) = match &variant.fields {
+        Fields::Named(_) if field_names == ["id", "body"] || field_names == ["body", "id"] => {
+            (
+                quote!(#type_ident::#variant_name { id, body }),
+                quote!(#type_ident::#variant_name { id, body: body.into() }),
+            )
+        }
+        Fields::Unnamed(fields) if fields.unnamed.len() == 2 => (
+            quote!(#type_ident::#variant_name(id, body)),
+            quote!(#type_ident::#variant_name(id, body.into())),
+        ),
+        _ => {
+            return Err(syn::Error::new(
+                variant.span(),
+                "The default packet must be `{ id: u8, body: Bytes }` or `(u8, Bytes)`",
+            ))
+        }
+    };
+    let get_packet_id_arm = quote! {
+        #pattern => {
+            let _ = body;
+            return *id;
+        }
+    };
+    let write_arm = quote! {
+        #pattern => {
+            ::packet::PacketContent::write(&id, writer)?;
+            ::std::io::Write::write_all(writer, ::std::convert::AsRef::<[u8]>::as_ref(&body))?;
+        }
+    };
+    let read_arm = quote! {
+        id => {
+            let mut body = ::std::vec::Vec::new();
+            if let Err(error) = ::std::io::Read::read_to_end(reader, &mut body) {
+                return Some(Err(::packet::PacketReadError::IOError(error)));
+            }
+            Some(Ok(#constructor))
+        }
+    };
+    // The rest of the frame is sliced out of it instead of copied
+    let frame_read_arm = quote! {
+        id => {
+            let body = match reader.take_bytes(reader.remaining().len()) {
+                Ok(body) => body,
+                Err(error) => return Some(Err(error)),
+            };
+            Some(Ok(#constructor))
+        }
+    };
+    Ok((get_packet_id_arm, write_arm, read_arm, frame_read_arm))
+}
+
+/// The generics and arguments of a read method. [`Codec::Frame`] reads out of a FrameReader
+fn read_signature(codec: Codec) -> TokenStream {
+    match codec {
+        Codec::Frame => quote!((reader: &mut ::packet::FrameReader<'_>)),
+        _ => quote!(<Reader: ::std::io::BufRead>(reader: &mut Reader)),
+    }
+}
+
 /// Returns a A method name. That takes a reader and Token Stream for the method
 fn unamed_parser(
     variant: &Variant,
-    value: &syn::Ident,
+    read_name: &syn::Ident,
     fields: &FieldsUnnamed,
+    codec: Codec,
 ) -> Result<TokenStream> {
     let variant_name = &variant.ident;
     let mut fields_parsers = Vec::new();
     let mut field_names = Vec::new();
     for (key, field) in fields.unnamed.iter().enumerate() {
         let field_name = format_ident!("field_{}", key);
+        let read = FieldOptions::from_field(field, format!("{}.{}", variant_name, key))?.read(codec);
         fields_parsers.push(quote! {
-            let #field_name: #field = PacketContent::read(reader)?;
+            let #field_name = #read;
         });
         field_names.push(field_name);
     }
+    let signature = read_signature(codec);
     let token_stream = quote! {
-        pub fn read<Reader: ::std::io::BufRead>(reader: &mut Reader) -> Result<#value, ::packet::PacketReadError>{
+        fn #read_name #signature -> Result<Self, ::packet::PacketReadError>{
             #(#fields_parsers)*
-            Ok(#value::#variant_name(#(#field_names),*))
+            Ok(Self::#variant_name(#(#field_names),*))
         }
     };
     Ok(token_stream)
@@ -199,24 +327,26 @@ fn unamed_parser(
 
 fn named_parser(
     variant: &Variant,
-    value: &syn::Ident,
+    read_name: &syn::Ident,
     fields: &FieldsNamed,
+    codec: Codec,
 ) -> Result<TokenStream> {
     let variant_name = &variant.ident;
     let mut fields_parsers = Vec::new();
-    for (_key, field) in fields.named.iter().enumerate() {
+    for field in fields.named.iter() {
         let field_name = field
             .ident
             .as_ref()
             .ok_or_else(|| syn::Error::new(field.span(), "Field must have a name"))?;
-        let _type_name = &field.ty;
+        let read = FieldOptions::from_field(field, format!("{}.{}", variant_name, field_name))?.read(codec);
         fields_parsers.push(quote! {
-            #field_name:  PacketContent::read(reader)?
+            #field_name: #read
         });
     }
+    let signature = read_signature(codec);
     let token_stream = quote! {
-        pub fn read<Reader: ::std::io::BufRead>(reader: &mut Reader) -> Result<#value, ::packet::PacketReadError>{
-            Ok(#value::#variant_name{
+        fn #read_name #signature -> Result<Self, ::packet::PacketReadError>{
+            Ok(Self::#variant_name{
              #(#fields_parsers),*
             })
         }
@@ -224,11 +354,15 @@ fn named_parser(
     Ok(token_stream)
 }
 
-fn unit_parser(variant: &Variant, value: &syn::Ident) -> Result<TokenStream> {
+fn unit_parser(variant: &Variant, read_name: &syn::Ident, codec: Codec) -> Result<TokenStream> {
     let variant_name = &variant.ident;
+    let reader = match codec {
+        Codec::Frame => quote!((_reader: &mut ::packet::FrameReader<'_>)),
+        _ => quote!(<Reader: ::std::io::BufRead>(_reader: &mut Reader)),
+    };
     let token_stream = quote! {
-        pub fn read<Reader: ::std::io::BufRead>(reader: &mut Reader) -> Result<#value, ::packet::PacketReadError>{
-            Ok(#value::#variant_name)
+        fn #read_name #reader -> Result<Self, ::packet::PacketReadError>{
+            Ok(Self::#variant_name)
         }
     };
     Ok(token_stream)
@@ -238,7 +372,7 @@ fn unamed_writer(
     variant: &Variant,
     packet_id: u8,
     type_ident: &syn::Ident,
-    mod_name: &syn::Ident,
+    write_name: &syn::Ident,
     fields: &FieldsUnnamed,
 ) -> Result<(TokenStream, TokenStream)> {
     let variant_name = &variant.ident;
@@ -249,19 +383,18 @@ fn unamed_writer(
     for (key, field) in fields.unnamed.iter().enumerate() {
         let field_name = format_ident!("field_{}", key);
         field_types.push(&field.ty);
-        field_writers.push(quote! {
-                #field_name.write(writer)?;
-        });
+        let options = FieldOptions::from_field(field, format!("{}.{}", variant_name, key))?;
+        field_writers.push(options.write(quote!(&#field_name), Codec::Owned));
         field_names.push(field_name);
     }
     let arm = quote! {
             #type_ident::#variant_name(#(#field_names),*) => {
-                #mod_name::write(writer,(#(#field_names),*))?;
+                Self::#write_name(writer,(#(#field_names),*))?;
             }
     };
     let token_stream = quote! {
-        pub fn write<Writer: ::std::io::Write>(writer: &mut Writer,(#(#field_names),*):(#(#field_types),*)) -> Result<(), ::packet::PacketWriteError>{
-            PacketContent::write(&#packet_id,writer)?;
+        fn #write_name<Writer: ::std::io::Write>(writer: &mut Writer,(#(#field_names),*):(#(#field_types),*)) -> Result<(), ::packet::PacketWriteError>{
+            ::packet::PacketContent::write(&#packet_id,writer)?;
             #(#field_writers)*
             Ok(())
         }
@@ -274,19 +407,19 @@ fn unit_writer(
     variant: &Variant,
     packet_id: u8,
     type_ident: &syn::Ident,
-    mod_name: &syn::Ident,
+    write_name: &syn::Ident,
 ) -> Result<(TokenStream, TokenStream)> {
     let variant_name = &variant.ident;
 
     let token_stream = quote! {
-    pub fn write<Writer: ::std::io::Write>(writer: &mut Writer) -> Result<(), ::packet::PacketWriteError> {
-        PacketContent::write(&#packet_id,writer)?;
+    fn #write_name<Writer: ::std::io::Write>(writer: &mut Writer) -> Result<(), ::packet::PacketWriteError> {
+        ::packet::PacketContent::write(&#packet_id,writer)?;
         Ok(())
     }
     };
     let arm = quote! {
         #type_ident::#variant_name => {
-            #mod_name::write(writer)?;
+            Self::#write_name(writer)?;
         }
     };
     Ok((token_stream, arm))
@@ -296,7 +429,7 @@ fn named_writer(
     variant: &Variant,
     packet_id: u8,
     type_ident: &syn::Ident,
-    mod_name: &syn::Ident,
+    write_name: &syn::Ident,
     fields: &FieldsNamed,
 ) -> Result<(TokenStream, TokenStream)> {
     let variant_name = &variant.ident;
@@ -309,9 +442,8 @@ fn named_writer(
             .ident
             .as_ref()
             .ok_or_else(|| syn::Error::new(field.span(), "Field must have a name"))?;
-        field_writers.push(quote! {
-            #field_name.write(writer)?;
-        });
+        let options = FieldOptions::from_field(field, format!("{}.{}", variant_name, field_name))?;
+        field_writers.push(options.write(quote!(&#field_name), Codec::Owned));
         field_names.push(field_name);
         field_types.push(&field.ty);
     }
@@ -319,13 +451,13 @@ fn named_writer(
         #type_ident::#variant_name{
             #(#field_names),*
         } => {
-            #mod_name::write(writer,(#(#field_names),*))?;
+            Self::#write_name(writer,(#(#field_names),*))?;
         }
     };
 
     let token_stream = quote! {
-             pub fn write<Writer: ::std::io::Write>(writer: &mut Writer,(#(#field_names),*):(#(#field_types),*)) -> Result<(), ::packet::PacketWriteError>{
-        PacketContent::write(&#packet_id,writer)?;
+             fn #write_name<Writer: ::std::io::Write>(writer: &mut Writer,(#(#field_names),*):(#(#field_types),*)) -> Result<(), ::packet::PacketWriteError>{
+        ::packet::PacketContent::write(&#packet_id,writer)?;
         #(#field_writers)*
         Ok(())
     }
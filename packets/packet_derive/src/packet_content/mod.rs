@@ -1,81 +1,344 @@
+use crate::fields::{Codec, FieldOptions};
+use crate::schema;
 use proc_macro2::TokenStream;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, ToTokens};
+use std::collections::HashMap;
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{parse_quote, DataEnum, DataStruct, Generics, Lifetime, LitInt, Result};
+use syn::{Fields, Ident, Index};
 
-use syn::{DataStruct, Result};
-use syn::{Fields, Ident};
+mod content_attrs {
+    syn::custom_keyword!(tag);
+}
+
+enum ContentAttrs {
+    Tag { value: LitInt },
+}
+
+impl Parse for ContentAttrs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(content_attrs::tag) {
+            input.parse::<content_attrs::tag>()?;
+            input.parse::<syn::Token![=]>()?;
+            Ok(ContentAttrs::Tag {
+                value: input.parse()?,
+            })
+        } else {
+            Err(lookahead.error())
+        }
+    }
+}
+
+/// The options of every field. `prefix` goes in front of the field names inside of error messages
+fn field_options(prefix: &str, fields: &Fields, codec: Codec) -> Result<Vec<FieldOptions>> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(key, field)| {
+            let name = match &field.ident {
+                Some(ident) => format!("{}{}", prefix, ident),
+                None => format!("{}{}", prefix, key),
+            };
+            let options = FieldOptions::from_field(field, name)?;
+            options.check_codec(codec)?;
+            Ok(options)
+        })
+        .collect()
+}
 
-pub(crate) fn parse_struct(type_ident: Ident, data: DataStruct) -> Result<TokenStream> {
-    let (write_func, read_func) = match data.fields {
+/// Reads every field in order. `constructor` is the path of the struct or variant
+fn read_fields(constructor: TokenStream, fields: &Fields, options: &[FieldOptions], codec: Codec) -> TokenStream {
+    match fields {
         Fields::Named(fields) => {
-            let mut field_writes = Vec::new();
-            let mut field_reads = Vec::new();
-            for field in fields.named.iter() {
+            let field_reads = fields.named.iter().zip(options).map(|(field, options)| {
                 let field_name = &field.ident;
-                let field_type = &field.ty;
-                field_writes.push(quote! {
-                    self.#field_name.write(writer)?;
-                });
-                field_reads.push(quote! {
-                    #field_name: #field_type::read(reader)?,
-                });
+                let read = options.read(codec);
+                quote! {
+                    #field_name: #read
+                }
+            });
+            quote! {
+                #constructor { #(#field_reads),* }
             }
-            let read_func = quote! {
-                let value = Self {
-                     #(#field_reads)*
-                };
-                Ok(value)
-            };
-            let write_func = quote! {
-                #(#field_writes)*
-                Ok(())
-            };
-            (write_func, read_func)
         }
-        Fields::Unnamed(fields) => {
-            let mut field_writes = Vec::new();
-            let mut field_reads = Vec::new();
-            let mut values = Vec::new();
-            for (key, field) in fields.unnamed.iter().enumerate() {
-                let field_type = &field.ty;
-                let value = format_ident!("field_{}", key);
-                field_writes.push(quote! {
-                    self.#key.write(writer)?;
-                });
-                field_reads.push(quote! {
-                    let #value = #field_type::read(reader)?;
-                });
-                values.push(value);
+        Fields::Unnamed(_) => {
+            let field_reads = options.iter().map(|options| options.read(codec));
+            quote! {
+                #constructor(#(#field_reads),*)
             }
-            let read_func = quote! {
-                #(#field_reads)*
-                Ok(Self(#(#values),*))
-            };
-            let write_func = quote! {
-                #(#field_writes)*
-                Ok(())
+        }
+        Fields::Unit => constructor,
+    }
+}
+
+/// Returns the pattern that binds every field and the writes for them
+fn write_fields(
+    constructor: TokenStream,
+    fields: &Fields,
+    options: &[FieldOptions],
+    codec: Codec,
+) -> (TokenStream, TokenStream) {
+    match fields {
+        Fields::Named(fields) => {
+            let field_names: Vec<_> = fields.named.iter().map(|field| &field.ident).collect();
+            let pattern = quote! {
+                #constructor { #(#field_names),* }
             };
-            (write_func, read_func)
+            let writes = field_names
+                .iter()
+                .zip(options)
+                .map(|(field_name, options)| options.write(quote!(#field_name), codec));
+            (pattern, quote!(#(#writes)*))
         }
-        Fields::Unit => {
-            return Err(syn::Error::new(
-                type_ident.span(),
-                "Unit Structs are not supported",
-            ));
+        Fields::Unnamed(fields) => {
+            let field_names: Vec<_> = (0..fields.unnamed.len())
+                .map(|key| format_ident!("field_{}", key))
+                .collect();
+            let pattern = quote! {
+                #constructor(#(#field_names),*)
+            };
+            let writes = field_names
+                .iter()
+                .zip(options)
+                .map(|(field_name, options)| options.write(quote!(#field_name), codec));
+            (pattern, quote!(#(#writes)*))
         }
-    };
+        Fields::Unit => (constructor, quote! {}),
+    }
+}
 
+/// `frame_read_func` is the read of [`Codec::Frame`]. Only used by PacketContent
+fn content_impl(
+    type_ident: &Ident,
+    generics: Generics,
+    codec: Codec,
+    read_func: TokenStream,
+    frame_read_func: TokenStream,
+    write_func: TokenStream,
+) -> Result<TokenStream> {
+    match codec {
+        Codec::Owned | Codec::Frame => {}
+        Codec::Borrowed => return ref_impl(type_ident, generics, read_func, write_func),
+        Codec::Async => return Ok(async_impl(type_ident, generics, read_func)),
+    }
+    let generics = crate::add_bound(generics, quote!(::packet::PacketContent));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     Ok(quote! {
-        impl ::packet::PacketContent for #type_ident {
-            fn read<Reader: ::std::io::BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError>
+        impl #impl_generics ::packet::PacketContent for #type_ident #ty_generics #where_clause {
+            fn read<Reader: ::std::io::BufRead>(reader: &mut Reader) -> Result<Self, ::packet::PacketReadError>
                 where Self: Sized,
             {
                 #read_func
             }
-            fn write<Writer: ::std::io::Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError>
+            fn write<Writer: ::std::io::Write>(&self, writer: &mut Writer) -> Result<(), ::packet::PacketWriteError>
             where Self: Sized,
             {
                 #write_func
             }
+            fn read_frame(reader: &mut ::packet::FrameReader<'_>) -> Result<Self, ::packet::PacketReadError>
+                where Self: Sized,
+            {
+                #frame_read_func
+            }
         }
     })
 }
+
+/// PacketContentRef borrows for the only lifetime of the type. A type without one gets its own
+fn ref_impl(type_ident: &Ident, generics: Generics, read_func: TokenStream, write_func: TokenStream) -> Result<TokenStream> {
+    let lifetimes: Vec<Lifetime> = generics.lifetimes().map(|param| param.lifetime.clone()).collect();
+    let (ty_generics, where_clause) = {
+        let (_, ty_generics, where_clause) = generics.split_for_impl();
+        (ty_generics.to_token_stream(), where_clause.to_token_stream())
+    };
+    let (generics, frame) = match lifetimes.as_slice() {
+        [] => {
+            let frame: Lifetime = parse_quote!('__frame);
+            let mut generics = generics;
+            generics.params.insert(0, parse_quote!(#frame));
+            (generics, frame)
+        }
+        [lifetime] => (generics.clone(), lifetime.clone()),
+        [_, second, ..] => {
+            return Err(syn::Error::new(
+                second.span(),
+                "PacketContentRef can only borrow for a single lifetime",
+            ))
+        }
+    };
+    let generics = crate::add_bound(generics, quote!(::packet::PacketContentRef<#frame>));
+    let (impl_generics, _, _) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::packet::PacketContentRef<#frame> for #type_ident #ty_generics #where_clause {
+            fn read_ref(reader: &mut ::packet::FrameReader<#frame>) -> Result<Self, ::packet::PacketReadError> {
+                #read_func
+            }
+            fn write_ref<Writer: ::std::io::Write>(&self, writer: &mut Writer) -> Result<(), ::packet::PacketWriteError> {
+                #write_func
+            }
+        }
+    })
+}
+
+/// AsyncPacketContent only reads. The write of the type comes from PacketContent
+fn async_impl(type_ident: &Ident, generics: Generics, read_func: TokenStream) -> TokenStream {
+    let generics = crate::add_bound(generics, quote!(::packet::AsyncPacketContent));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        #[::packet::async_trait]
+        impl #impl_generics ::packet::AsyncPacketContent for #type_ident #ty_generics #where_clause {
+            async fn read_async<Reader: ::packet::AsyncReader>(reader: &mut Reader) -> Result<Self, ::packet::PacketReadError> {
+                #read_func
+            }
+        }
+    }
+}
+
+/// Reads the tag of an enum
+fn read_tag(codec: Codec) -> TokenStream {
+    match codec {
+        Codec::Owned | Codec::Borrowed | Codec::Frame => quote!(<u8 as ::packet::PacketContent>::read(reader)?),
+        Codec::Async => quote!(<u8 as ::packet::AsyncPacketContent>::read_async(reader).await?),
+    }
+}
+
+/// Fields are written in order without any framing. A unit struct writes nothing
+pub(crate) fn parse_struct(
+    type_ident: Ident,
+    generics: Generics,
+    docs: String,
+    data: DataStruct,
+    codec: Codec,
+) -> Result<TokenStream> {
+    let options = field_options("", &data.fields, codec)?;
+    let read_func = |codec| {
+        let read_func = read_fields(quote!(Self), &data.fields, &options, codec);
+        quote! {
+            Ok(#read_func)
+        }
+    };
+    let writes = data.fields.iter().zip(&options).enumerate().map(|(key, (field, options))| {
+        match &field.ident {
+            Some(field_name) => options.write(quote!(&self.#field_name), codec),
+            None => {
+                let key = Index::from(key);
+                options.write(quote!(&self.#key), codec)
+            }
+        }
+    });
+    let write_func = quote! {
+        #(#writes)*
+        Ok(())
+    };
+    let mut tokens = content_impl(
+        &type_ident,
+        generics.clone(),
+        codec,
+        read_func(codec),
+        read_func(Codec::Frame),
+        write_func,
+    )?;
+    // Only once. A type can derive PacketContentRef or AsyncPacketContent next to PacketContent
+    if codec == Codec::Owned {
+        let fields = schema::fields(&data.fields)?;
+        tokens.extend(schema::schema_impl(
+            &type_ident,
+            &generics,
+            &docs,
+            quote!(::packet::schema::SchemaKind::Struct { fields: #fields }),
+        ));
+    }
+    Ok(tokens)
+}
+
+/// The tag of the variant as a u8 followed by its fields
+pub(crate) fn parse_enum(
+    type_ident: Ident,
+    generics: Generics,
+    docs: String,
+    data: DataEnum,
+    codec: Codec,
+) -> Result<TokenStream> {
+    let mut tags: HashMap<u8, Ident> = HashMap::new();
+    let mut read_arms = Vec::new();
+    let mut frame_read_arms = Vec::new();
+    let mut write_arms = Vec::new();
+    let mut variant_schemas = Vec::new();
+
+    for variant in data.variants.iter() {
+        let attr = variant
+            .attrs
+            .iter()
+            .find(|attr| attr.path.is_ident("content"))
+            .ok_or_else(|| syn::Error::new(variant.span(), "Variant must have a #[content(tag = N)] attribute"))?;
+        let tag = match attr.parse_args::<ContentAttrs>()? {
+            ContentAttrs::Tag { value } => {
+                let tag = crate::parse_id(&value, "tag")?;
+                if let Some(existing) = tags.get(&tag) {
+                    return Err(syn::Error::new(
+                        value.span(),
+                        format!("Duplicate tag {}, already used by `{}`", tag, existing),
+                    ));
+                }
+                tags.insert(tag, variant.ident.clone());
+                tag
+            }
+        };
+        variant_schemas.push(schema::variant(variant, Some(tag))?);
+        let variant_name = &variant.ident;
+
+        let options = field_options(&format!("{}.", variant_name), &variant.fields, codec)?;
+        let read = read_fields(quote!(Self::#variant_name), &variant.fields, &options, codec);
+        read_arms.push(quote! {
+            #tag => Ok(#read),
+        });
+        let read = read_fields(quote!(Self::#variant_name), &variant.fields, &options, Codec::Frame);
+        frame_read_arms.push(quote! {
+            #tag => Ok(#read),
+        });
+        let (pattern, writes) = write_fields(quote!(Self::#variant_name), &variant.fields, &options, codec);
+        write_arms.push(quote! {
+            #pattern => {
+                ::packet::PacketContent::write(&#tag, writer)?;
+                #writes
+            }
+        });
+    }
+
+    let read_func = |codec, read_arms: &[TokenStream]| {
+        let read_tag = read_tag(codec);
+        quote! {
+            match #read_tag {
+                #(#read_arms)*
+                tag => Err(::packet::PacketReadError::ContentError(
+                    format!("Unknown tag {} for {}", tag, stringify!(#type_ident)).into(),
+                )),
+            }
+        }
+    };
+    let write_func = quote! {
+        match self {
+            #(#write_arms)*
+        }
+        Ok(())
+    };
+    let mut tokens = content_impl(
+        &type_ident,
+        generics.clone(),
+        codec,
+        read_func(codec, &read_arms),
+        read_func(Codec::Frame, &frame_read_arms),
+        write_func,
+    )?;
+    if codec == Codec::Owned {
+        tokens.extend(schema::schema_impl(
+            &type_ident,
+            &generics,
+            &docs,
+            quote!(::packet::schema::SchemaKind::Enum { variants: vec![#(#variant_schemas),*] }),
+        ));
+    }
+    Ok(tokens)
+}
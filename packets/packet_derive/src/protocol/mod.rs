@@ -1,9 +1,11 @@
+use crate::schema;
 use proc_macro2::TokenStream;
 use quote::{format_ident, quote};
+use std::collections::HashMap;
 use syn::parse::{Parse, ParseStream};
 use syn::spanned::Spanned;
-use syn::Result;
-use syn::{DataEnum, Field, Fields, Ident, LitInt, Variant};
+use syn::{parse_quote, Result};
+use syn::{DataEnum, Field, Fields, Generics, Ident, LitInt, Variant};
 
 mod packet_attrs {
     syn::custom_keyword!(protocol_id);
@@ -32,13 +34,17 @@ impl Parse for ProtocolAttrs {
     }
 }
 
-pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStream> {
+pub(crate) fn parse_enum(type_ident: Ident, generics: Generics, docs: String, data: DataEnum) -> Result<TokenStream> {
     let mut handlers = Vec::new();
     let mut protocol_ids = Vec::new();
     let mut get_id_arms = Vec::new();
     let mut write_data = Vec::new();
     let mut read_data = Vec::new();
+    let mut frame_read_data = Vec::new();
     let mut from_packet_impls = Vec::new();
+    let mut used_ids: HashMap<u8, Ident> = HashMap::new();
+    let mut packet_types = Vec::new();
+    let mut protocol_schemas = Vec::new();
     for protocol_variant in data.variants {
         let protocol_id = protocol_variant
             .attrs
@@ -46,25 +52,37 @@ pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStrea
             .find(|attr| attr.path.is_ident("protocol"));
         if protocol_id.is_none() {
             return Err(syn::Error::new(
-                protocol_id.span(),
-                "Packet must have a protocol_id attribute",
+                protocol_variant.span(),
+                "Protocol must have a protocol_id attribute",
             ));
         }
         let protocol_id = protocol_id.unwrap();
         let value = protocol_id.parse_args::<ProtocolAttrs>()?;
         match value {
             ProtocolAttrs::ProtocolId { value, .. } => {
-                let protocol_id = value.base10_parse::<u8>()?;
+                let protocol_id = crate::parse_id(&value, "protocol_id")?;
+                if let Some(existing) = used_ids.get(&protocol_id) {
+                    return Err(syn::Error::new(
+                        value.span(),
+                        format!("Duplicate protocol_id {}, already used by `{}`", protocol_id, existing),
+                    ));
+                }
+                used_ids.insert(protocol_id, protocol_variant.ident.clone());
                 let variant_name = &protocol_variant.ident;
                 get_id_arms.push(quote! {
                     #type_ident::#variant_name(..) => { #protocol_id},
                 });
-                let mod_name = format_ident!("{}_handler", protocol_variant.ident);
+                let read_name = format_ident!("__read_{}", protocol_variant.ident);
+                let write_name = format_ident!("__write_{}", protocol_variant.ident);
                 write_data.push(quote! {
-                    #type_ident::#variant_name(data) => { #mod_name::write(data, writer)?; },
+                    #type_ident::#variant_name(data) => { Self::#write_name(data, writer)?; },
                 });
                 read_data.push(quote! {
-                    #protocol_id => { return #mod_name::read(packet_id, reader); },
+                    #protocol_id => { return Self::#read_name(packet_id, reader); },
+                });
+                let frame_read_name = format_ident!("__read_frame_{}", protocol_variant.ident);
+                frame_read_data.push(quote! {
+                    #protocol_id => Self::#frame_read_name(packet_id, reader),
                 });
                 let value = match &protocol_variant.fields {
                     Fields::Unnamed(value) => {
@@ -85,29 +103,53 @@ pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStrea
                     }
                 };
 
-                let read_method = create_reader(&value, &protocol_variant, &type_ident)?;
-                let write_method = create_writer(&value, protocol_id)?;
+                let read_method = create_reader(&value, &protocol_variant, &read_name)?;
+                let frame_read_method = create_frame_reader(&value, &protocol_variant, &frame_read_name);
+                let write_method = create_writer(&value, protocol_id, &write_name)?;
                 let handler = quote! {
-                    #[allow(non_snake_case)]
-                    mod #mod_name {
-                        use super::*;
-                        use packet::PacketContent;
-                        use packet::packet::Packet;
-                        #read_method
-                        #write_method
-                    }
+                    #read_method
+                    #frame_read_method
+                    #write_method
                 };
                 handlers.push(handler);
                 protocol_ids.push(protocol_id);
-                from_packet_impls.push(from_packet(&value, &protocol_variant, &type_ident)?);
+                packet_types.push(value.ty.clone());
+                protocol_schemas.push(protocol_schema(&value, &protocol_variant, protocol_id));
+                from_packet_impls.push(from_packet(&value, &protocol_variant, &type_ident, &generics)?);
             }
         }
     }
 
+    // Type parameters are usually used inside of the packets. So the packets are bound instead of the parameters
+    let mut schema_generics = generics.clone();
+    let mut generics = generics;
+    if !generics.params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        let schema_where_clause = schema_generics.make_where_clause();
+        for packet_type in packet_types {
+            where_clause.predicates.push(parse_quote! {
+                #packet_type: ::packet::packet::Packet<ReadError = ::packet::PacketReadError, WriteError = ::packet::PacketWriteError>
+            });
+            schema_where_clause.predicates.push(parse_quote! {
+                #packet_type: ::packet::schema::PacketSchema
+            });
+        }
+    }
+    let schema_impl = schema::schema_impl(
+        &type_ident,
+        &schema_generics,
+        &docs,
+        quote!(::packet::schema::SchemaKind::Protocols { protocols: vec![#(#protocol_schemas),*] }),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     Ok(quote! {
-        #(#handlers)*
+        #[allow(non_snake_case)]
+        impl #impl_generics #type_ident #ty_generics #where_clause {
+            #(#handlers)*
+        }
         #(#from_packet_impls)*
-        impl ::packet::protocol::Protocol for #type_ident {
+        #schema_impl
+        impl #impl_generics ::packet::protocol::Protocol for #type_ident #ty_generics #where_clause {
             type ReadError = ::packet::PacketReadError;
             type WriteError = ::packet::PacketWriteError;
             fn get_protocol_id(&self) -> u8{
@@ -125,7 +167,7 @@ pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStrea
             }
 
             fn supports_protocol_id(id: u8) -> bool{
-                let mut ids = [#(#protocol_ids),*];
+                let ids = [#(#protocol_ids),*];
                 ids.contains(&id)
             }
 
@@ -135,6 +177,13 @@ pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStrea
                     _ => None
                 }
             }
+
+            fn build_if_supported_frame(protocol_id: u8, packet_id: u8, reader: &mut ::packet::FrameReader<'_>) -> Option<Result<Self, Self::ReadError>> where Self: Sized{
+                match protocol_id {
+                    #(#frame_read_data)*
+                    _ => None
+                }
+            }
         }
     })
 }
@@ -143,17 +192,18 @@ pub(crate) fn parse_enum(type_ident: Ident, data: DataEnum) -> Result<TokenStrea
 fn create_reader(
     packet_type: &Field,
     variant: &Variant,
-    value: &syn::Ident,
+    read_name: &syn::Ident,
 ) -> Result<TokenStream> {
     let variant_ident = &variant.ident;
+    let packet_type = &packet_type.ty;
     let read_method = quote! {
-        pub fn read<Reader: ::std::io::BufRead>(packet_id: u8, reader: &mut Reader) -> Option<Result<#value, ::packet::PacketReadError>>{
+        fn #read_name<Reader: ::std::io::BufRead>(packet_id: u8, reader: &mut Reader) -> Option<Result<Self, ::packet::PacketReadError>>{
            let packet = <#packet_type as ::packet::packet::Packet>::build_or_none(packet_id, reader);
             if let Some(packet) = packet {
                 if let Err(error) = packet {
                     return Some(Err(error));
                 }else if let Ok(packet) = packet {
-                    return Some(Ok(#value::#variant_ident(packet)));
+                    return Some(Ok(Self::#variant_ident(packet)));
                 }else{
                     return None;
                 }
@@ -165,22 +215,51 @@ fn create_reader(
     Ok(read_method)
 }
 
+/// Same as [`create_reader`] out of a FrameReader. The packet is built with `build_or_none_frame`
+fn create_frame_reader(packet_type: &Field, variant: &Variant, read_name: &syn::Ident) -> TokenStream {
+    let variant_ident = &variant.ident;
+    let packet_type = &packet_type.ty;
+    quote! {
+        fn #read_name(packet_id: u8, reader: &mut ::packet::FrameReader<'_>) -> Option<Result<Self, ::packet::PacketReadError>>{
+            <#packet_type as ::packet::packet::Packet>::build_or_none_frame(packet_id, reader)
+                .map(|packet| packet.map(Self::#variant_ident))
+        }
+    }
+}
+
 /// Returns a A method name. That takes a reader and Token Stream for the method
-fn create_writer(packet_type: &Field, protocol_id: u8) -> Result<TokenStream> {
+fn create_writer(packet_type: &Field, protocol_id: u8, write_name: &syn::Ident) -> Result<TokenStream> {
+    let packet_type = &packet_type.ty;
     let write_method = quote! {
-        pub fn write<Writer: ::std::io::Write>(data: #packet_type, writer: &mut Writer) -> Result<(), ::packet::PacketWriteError>{
+        fn #write_name<Writer: ::std::io::Write>(data: #packet_type, writer: &mut Writer) -> Result<(), ::packet::PacketWriteError>{
             ::packet::PacketContent::write(&#protocol_id, writer)?;
-            data.write_payload(writer)?;
+            ::packet::packet::Packet::write_payload(data, writer)?;
             Ok(())
         }
     };
     Ok(write_method)
 }
-fn from_packet(packet_type: &Field, variant: &Variant, value: &syn::Ident) -> Result<TokenStream> {
+/// The schema of the packets comes from their own PacketSchema
+fn protocol_schema(packet_type: &Field, variant: &Variant, protocol_id: u8) -> TokenStream {
+    let name = variant.ident.to_string();
+    let docs = schema::docs(&variant.attrs);
+    let packet_type = &packet_type.ty;
+    quote! {
+        ::packet::schema::ProtocolSchema {
+            name: #name,
+            docs: #docs,
+            id: #protocol_id,
+            packets: <#packet_type as ::packet::schema::PacketSchema>::schema(),
+        }
+    }
+}
+
+fn from_packet(packet_type: &Field, variant: &Variant, value: &syn::Ident, generics: &Generics) -> Result<TokenStream> {
     let variant_ident = &variant.ident;
     let packet_ty = &packet_type.ty;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
     let from_packet = quote! {
-        impl From<#packet_ty> for #value {
+        impl #impl_generics From<#packet_ty> for #value #ty_generics #where_clause {
             fn from(packet: #packet_ty) -> Self {
                 #value::#variant_ident(packet)
             }
@@ -0,0 +1,82 @@
+use crate::fields::FieldOptions;
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::{Attribute, Fields, Generics, Ident, Lit, Meta, MetaNameValue, Result, Variant};
+
+/// The doc comment of an item without the leading space of every line
+pub(crate) fn docs(attrs: &[Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(MetaNameValue { lit: Lit::Str(doc), .. })) => Some(doc.value()),
+            _ => None,
+        })
+        .map(|line| match line.strip_prefix(' ') {
+            Some(line) => line.to_string(),
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Tokens as they would be written by hand. `Vec < u8 >` becomes `Vec<u8>`
+pub(crate) fn source(tokens: &impl ToTokens) -> String {
+    let mut source = tokens.to_token_stream().to_string();
+    for (from, to) in [(" <", "<"), ("< ", "<"), (" >", ">"), (" ,", ","), (" ;", ";"), (" :: ", "::"), (":: ", "::"), ("& ", "&")] {
+        source = source.replace(from, to);
+    }
+    source
+}
+
+/// A `Vec<FieldSchema>` expression of every field that is on the wire
+pub(crate) fn fields(fields: &Fields) -> Result<TokenStream> {
+    let mut schemas = Vec::new();
+    for (key, field) in fields.iter().enumerate() {
+        let name = match &field.ident {
+            Some(ident) => ident.to_string(),
+            None => key.to_string(),
+        };
+        if let Some(schema) = FieldOptions::from_field(field, name.clone())?.schema(&name) {
+            schemas.push(schema);
+        }
+    }
+    Ok(quote!(vec![#(#schemas),*]))
+}
+
+/// A VariantSchema expression. `id` is None for the default packet
+pub(crate) fn variant(variant: &Variant, id: Option<u8>) -> Result<TokenStream> {
+    let name = variant.ident.to_string();
+    let docs = docs(&variant.attrs);
+    let id = match id {
+        Some(id) => quote!(Some(#id)),
+        None => quote!(None),
+    };
+    let fields = fields(&variant.fields)?;
+    Ok(quote! {
+        ::packet::schema::VariantSchema { name: #name, docs: #docs, id: #id, fields: #fields }
+    })
+}
+
+/// The PacketSchema impl and a Wire impl that nests it. `kind` is a SchemaKind expression
+pub(crate) fn schema_impl(type_ident: &Ident, generics: &Generics, docs: &str, kind: TokenStream) -> TokenStream {
+    let name = type_ident.to_string();
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    quote! {
+        impl #impl_generics ::packet::schema::PacketSchema for #type_ident #ty_generics #where_clause {
+            fn schema() -> ::packet::schema::TypeSchema {
+                ::packet::schema::describe::<Self>(|| ::packet::schema::TypeSchema {
+                    name: #name,
+                    docs: #docs,
+                    kind: #kind,
+                })
+            }
+        }
+
+        impl #impl_generics ::packet::schema::Wire for #type_ident #ty_generics #where_clause {
+            fn wire() -> ::packet::schema::WireType {
+                ::packet::schema::nested::<Self>()
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use abst_rs::realm::limits::{LockoutSettings, RateLimit, RateLimits};
+use abst_rs::realm::login::{PasswordVerifier, SessionSettings};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The TOML config of the Realm
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen on
+    pub bind: SocketAddr,
+    /// The directory the device registry is stored in
+    pub storage: PathBuf,
+    /// How long to wait for connections to close on shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|error| format!("Unable to read {}: {}", path.display(), error))?;
+        toml::from_str(&content).map_err(|error| format!("Invalid config {}: {}", path.display(), error))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoginConfig {
+    /// Allow `LoginDetails::None`
+    #[serde(default)]
+    pub allow_anonymous: bool,
+    #[serde(default = "default_session_ttl")]
+    pub session_ttl_secs: u64,
+    /// Accept tokens and passwords on links that are not encrypted.
+    /// Only turn this on if the realm is behind an encrypted tunnel or only listens on loopback
+    #[serde(default)]
+    pub allow_unencrypted_secrets: bool,
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    /// Devices that log in by signing a challenge with their Themis key
+    #[serde(default)]
+    pub device_keys: Vec<DeviceKeyConfig>,
+}
+
+fn default_session_ttl() -> u64 {
+    60 * 60
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            allow_anonymous: false,
+            session_ttl_secs: default_session_ttl(),
+            allow_unencrypted_secrets: false,
+            tokens: Vec::new(),
+            accounts: Vec::new(),
+            device_keys: Vec::new(),
+        }
+    }
+}
+
+impl LoginConfig {
+    pub fn session(&self, scopes: Vec<String>) -> SessionSettings {
+        SessionSettings {
+            ttl: Duration::from_secs(self.session_ttl_secs),
+            scopes,
+        }
+    }
+}
+
+/// A pre-shared token for a single device
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub device_id: Uuid,
+    pub token: String,
+}
+
+/// The Themis public key of a device for the challenge-response login.
+/// Nothing secret is sent, so it works on links that are not encrypted
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceKeyConfig {
+    pub device_id: Uuid,
+    /// Hex encoded
+    pub public_key: String,
+}
+
+/// A username and password account. Generate the verifier with `ref-realm --hash-password`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub username: String,
+    /// Hex encoded
+    pub salt: String,
+    pub rounds: u32,
+    /// Hex encoded
+    pub hash: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// The device ids that can log in with this account
+    #[serde(default)]
+    pub devices: Vec<Uuid>,
+}
+
+impl AccountConfig {
+    pub fn verifier(&self) -> Result<PasswordVerifier, String> {
+        Ok(PasswordVerifier {
+            salt: hex_decode(&self.salt)?,
+            rounds: self.rounds,
+            hash: hex_decode(&self.hash)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    pub capacity: u32,
+    pub per_second: f64,
+}
+
+impl From<&LimitConfig> for RateLimit {
+    fn from(value: &LimitConfig) -> Self {
+        RateLimit::new(value.capacity, value.per_second)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub per_ip: Option<LimitConfig>,
+    pub per_device: Option<LimitConfig>,
+    pub per_target: Option<LimitConfig>,
+    pub failures_before_lockout: Option<u32>,
+    pub failures_before_ip_lockout: Option<u32>,
+    pub lockout_base_secs: Option<u64>,
+    pub lockout_max_secs: Option<u64>,
+    pub forget_failures_after_secs: Option<u64>,
+}
+
+impl LimitsConfig {
+    /// Anything not set uses the library defaults
+    pub fn rate_limits(&self) -> RateLimits {
+        let defaults = RateLimits::default();
+        let lockout = LockoutSettings::default();
+        RateLimits {
+            per_ip: self.per_ip.as_ref().map(RateLimit::from).or(defaults.per_ip),
+            per_device: self.per_device.as_ref().map(RateLimit::from).or(defaults.per_device),
+            per_target: self.per_target.as_ref().map(RateLimit::from).or(defaults.per_target),
+            lockout: LockoutSettings {
+                failures_before_lockout: self
+                    .failures_before_lockout
+                    .unwrap_or(lockout.failures_before_lockout),
+                failures_before_ip_lockout: self
+                    .failures_before_ip_lockout
+                    .unwrap_or(lockout.failures_before_ip_lockout),
+                base: self.lockout_base_secs.map(Duration::from_secs).unwrap_or(lockout.base),
+                max: self.lockout_max_secs.map(Duration::from_secs).unwrap_or(lockout.max),
+                forget_after: self
+                    .forget_failures_after_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or(lockout.forget_after),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Allow proxying between any two devices. Otherwise only devices of the same account can talk
+    #[serde(default = "default_true")]
+    pub allow_all: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig { allow_all: true }
+    }
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hex_decode(value: &str) -> Result<Bytes, String> {
+    if value.len() % 2 == 1 {
+        return Err(format!("Invalid hex value {}", value));
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&value[index..index + 2], 16)
+                .map_err(|_| format!("Invalid hex value {}", value))
+        })
+        .collect::<Result<Vec<u8>, String>>()
+        .map(Bytes::from)
+}
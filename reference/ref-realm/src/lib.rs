@@ -0,0 +1,6 @@
+/// The TOML config of the Realm
+pub mod config;
+/// The device registry and the login providers
+pub mod registry;
+/// Accepts device connections and proxies packets between them
+pub mod server;
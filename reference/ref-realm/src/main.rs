@@ -1,3 +1,85 @@
-fn main() {
-    println!("Hello, world!");
+use abst_rs::realm::login::PasswordVerifier;
+use log::{error, info};
+use ref_realm::config::{hex_encode, Config};
+use ref_realm::registry::FileRealm;
+use ref_realm::server;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "Usage: ref-realm <config.toml>\n       ref-realm --hash-password < password.txt";
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        // Read from stdin so the password does not end up in the shell history or the process list
+        [flag] if flag == "--hash-password" => match read_password() {
+            Ok(password) => print_account(&password),
+            Err(error) => {
+                eprintln!("Unable to read the password: {}", error);
+                exit(1);
+            }
+        },
+        [path] if !path.starts_with('-') => {
+            if let Err(message) = start(PathBuf::from(path)).await {
+                error!("event=fatal error={}", message);
+                exit(1);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
+
+async fn start(path: PathBuf) -> Result<(), String> {
+    let config = Config::load(&path)?;
+    let realm = FileRealm::open(&config)?;
+    info!("event=config_loaded path={}", path.display());
+    server::run(config, realm, shutdown_signal()).await
+}
+
+/// Completes on SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("event=signal signal=SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => info!("event=signal signal=SIGINT"),
+                }
+                return;
+            }
+            Err(error) => error!("event=signal_setup_failed error={}", error),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    info!("event=signal signal=SIGINT");
+}
+
+/// The first line of stdin without the line ending
+fn read_password() -> std::io::Result<String> {
+    eprintln!("Password:");
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The password is empty"));
+    }
+    Ok(password.to_string())
+}
+
+/// Prints an `[[login.accounts]]` entry for the config
+fn print_account(password: &str) {
+    let verifier = PasswordVerifier::new(password);
+    println!("[[login.accounts]]");
+    println!("username = \"<username>\"");
+    println!("salt = \"{}\"", hex_encode(&verifier.salt));
+    println!("rounds = {}", verifier.rounds);
+    println!("hash = \"{}\"", hex_encode(&verifier.hash));
 }
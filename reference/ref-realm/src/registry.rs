@@ -0,0 +1,380 @@
+use crate::config::{hex_decode, Config};
+use abst_rs::device_manager::{DeviceMetadata, PairedDevice};
+use abst_rs::encryption::{DynamicEncryptionManager, EncryptionError};
+use abst_rs::packets::realm::LoginDetails;
+use abst_rs::realm::acl::{AccessControl, AclAction};
+use abst_rs::realm::login::{
+    ChallengeResponseProvider, LoginError, LoginProvider, LoginProviders, LoginResult, LoginSession,
+    PasswordAccount, PasswordProvider, PreSharedTokenProvider, SessionSettings,
+    NO_DETAILS_PROVIDER_ID,
+};
+use abst_rs::realm::Realm;
+use themis::keys::EcdsaPublicKey;
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Bumped whenever the layout of the registry file changes
+const REGISTRY_VERSION: u32 = 1;
+const REGISTRY_FILE: &str = "devices.json";
+/// Sessions of password accounts get this scope followed by the username
+pub const ACCOUNT_SCOPE: &str = "account:";
+
+#[derive(Debug)]
+pub enum RealmError {
+    IO(std::io::Error),
+    Json(serde_json::Error),
+    Login(LoginError),
+    Encryption(EncryptionError),
+}
+
+impl Display for RealmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RealmError::IO(error) => write!(f, "IO error: {}", error),
+            RealmError::Json(error) => write!(f, "Invalid registry: {}", error),
+            RealmError::Login(error) => write!(f, "Login error: {:?}", error),
+            RealmError::Encryption(error) => write!(f, "Encryption error: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for RealmError {}
+
+impl From<std::io::Error> for RealmError {
+    fn from(value: std::io::Error) -> Self {
+        RealmError::IO(value)
+    }
+}
+
+impl From<serde_json::Error> for RealmError {
+    fn from(value: serde_json::Error) -> Self {
+        RealmError::Json(value)
+    }
+}
+
+impl From<LoginError> for RealmError {
+    fn from(value: LoginError) -> Self {
+        RealmError::Login(value)
+    }
+}
+
+impl From<EncryptionError> for RealmError {
+    fn from(value: EncryptionError) -> Self {
+        RealmError::Encryption(value)
+    }
+}
+
+/// The account a session was started for. Only password logins have one
+pub fn session_account(session: &LoginSession) -> Option<String> {
+    session
+        .scopes
+        .iter()
+        .find_map(|scope| scope.strip_prefix(ACCOUNT_SCOPE))
+        .map(|account| account.to_string())
+}
+
+/// A device known to the Realm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredDevice {
+    pub device_id: Uuid,
+    /// The account the device logged in with
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Seconds since the unix epoch
+    pub registered_at: u64,
+}
+
+impl PairedDevice<DynamicEncryptionManager> for RegisteredDevice {
+    fn get_device_id(&self) -> &Uuid {
+        &self.device_id
+    }
+
+    fn get_encryption_manager(&self) -> DynamicEncryptionManager {
+        // The realm key exchange is not implemented yet
+        DynamicEncryptionManager::None
+    }
+
+    fn get_metadata(&self) -> DeviceMetadata {
+        DeviceMetadata {
+            name: self.account.clone(),
+            paired_at: self.registered_at,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegistryFile {
+    version: u32,
+    realm_id: Uuid,
+    devices: Vec<RegisteredDevice>,
+}
+
+/// Allows `LoginDetails::None`
+struct AnonymousProvider {
+    session: SessionSettings,
+}
+
+impl LoginProvider for AnonymousProvider {
+    fn id(&self) -> u8 {
+        NO_DETAILS_PROVIDER_ID
+    }
+
+    fn login(&self, _device_id: &Uuid, _details: &Bytes) -> Result<LoginResult, LoginError> {
+        Ok(LoginResult::Accepted(self.session.start()))
+    }
+}
+
+/// A Realm that keeps its device registry inside of a JSON file
+pub struct FileRealm {
+    path: PathBuf,
+    realm_id: Uuid,
+    devices: RwLock<HashMap<Uuid, Arc<RegisteredDevice>>>,
+    providers: LoginProviders,
+    allow_all: bool,
+    /// Set when the registry changed since the last save
+    dirty: AtomicBool,
+    /// Only one save writes the temporary file at a time
+    save_lock: Mutex<()>,
+}
+
+impl FileRealm {
+    /// Loads the registry from the storage directory. Creates it if it does not exist
+    pub fn open(config: &Config) -> Result<FileRealm, String> {
+        std::fs::create_dir_all(&config.storage)
+            .map_err(|error| format!("Unable to create {}: {}", config.storage.display(), error))?;
+        let path = config.storage.join(REGISTRY_FILE);
+        let registry = if path.exists() {
+            let file = File::open(&path).map_err(|error| format!("Unable to open {}: {}", path.display(), error))?;
+            let registry: RegistryFile = serde_json::from_reader(file)
+                .map_err(|error| format!("Invalid registry {}: {}", path.display(), error))?;
+            if registry.version > REGISTRY_VERSION {
+                return Err(format!(
+                    "Registry version {} is newer than the supported version {}",
+                    registry.version, REGISTRY_VERSION
+                ));
+            }
+            registry
+        } else {
+            RegistryFile {
+                version: REGISTRY_VERSION,
+                realm_id: Uuid::new_v4(),
+                devices: Vec::new(),
+            }
+        };
+        let devices: HashMap<Uuid, Arc<RegisteredDevice>> = registry
+            .devices
+            .into_iter()
+            .map(|device| (device.device_id, Arc::new(device)))
+            .collect();
+        let providers = Self::providers(config)?;
+        let realm = FileRealm {
+            path,
+            realm_id: registry.realm_id,
+            devices: RwLock::new(devices),
+            providers,
+            allow_all: config.access.allow_all,
+            dirty: AtomicBool::new(false),
+            save_lock: Mutex::new(()),
+        };
+        realm.save().map_err(|error| format!("Unable to save the registry: {}", error))?;
+        Ok(realm)
+    }
+
+    fn providers(config: &Config) -> Result<LoginProviders, String> {
+        let login = &config.login;
+        let mut providers = LoginProviders::new();
+        if login.allow_anonymous {
+            providers.register(AnonymousProvider {
+                session: login.session(Vec::new()),
+            });
+        }
+        if !login.tokens.is_empty() {
+            let mut tokens = PreSharedTokenProvider::new(login.session(Vec::new()));
+            for token in &login.tokens {
+                tokens.add_token(token.device_id, Bytes::from(token.token.clone().into_bytes()));
+            }
+            providers.register(tokens);
+        }
+        if !login.accounts.is_empty() {
+            let mut accounts = PasswordProvider::new(login.session(Vec::new()));
+            for account in &login.accounts {
+                let mut scopes = account.scopes.clone();
+                scopes.push(format!("{}{}", ACCOUNT_SCOPE, account.username));
+                accounts.add_account(
+                    account.username.clone(),
+                    PasswordAccount {
+                        verifier: account.verifier()?,
+                        scopes,
+                        devices: account.devices.iter().copied().collect(),
+                    },
+                );
+            }
+            providers.register(accounts);
+        }
+        if !login.device_keys.is_empty() {
+            let mut challenge = ChallengeResponseProvider::new(login.session(Vec::new()));
+            for device in &login.device_keys {
+                let key = EcdsaPublicKey::try_from_slice(hex_decode(&device.public_key)?.as_ref())
+                    .map_err(|error| format!("Invalid public key for {}: {}", device.device_id, error))?;
+                challenge.add_device_key(device.device_id, key);
+            }
+            providers.register(challenge);
+        }
+        Ok(providers)
+    }
+
+    /// Writes the registry to a temporary file and moves it over the old one.
+    ///
+    /// This blocks on the disk. Call it from `spawn_blocking` inside of the server
+    pub fn save(&self) -> Result<(), RealmError> {
+        let _guard = self.save_lock.lock().unwrap_or_else(|error| error.into_inner());
+        self.dirty.store(false, Ordering::SeqCst);
+        let registry = {
+            let devices = self.devices.read().unwrap_or_else(|error| error.into_inner());
+            RegistryFile {
+                version: REGISTRY_VERSION,
+                realm_id: self.realm_id,
+                devices: devices.values().map(|device| device.as_ref().clone()).collect(),
+            }
+        };
+        let result = self.write(&registry);
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    fn write(&self, registry: &RegistryFile) -> Result<(), RealmError> {
+        let temp = self.path.with_extension("json.tmp");
+        let mut file = File::create(&temp)?;
+        serde_json::to_writer_pretty(&mut file, registry)?;
+        file.flush()?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rather or not the registry changed since the last save
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Adds the device to the registry or binds a known device to the account.
+    ///
+    /// Returns false if the device is already bound to an account and this login is not for that account.
+    /// A login without an account can not use a device that belongs to one.
+    /// The registry is only changed in memory. See [`FileRealm::save`]
+    pub fn register(&self, device_id: Uuid, account: Option<String>) -> bool {
+        let mut devices = self.devices.write().unwrap_or_else(|error| error.into_inner());
+        if let Some(device) = devices.get(&device_id) {
+            if device.account.is_some() && device.account != account {
+                return false;
+            }
+        }
+        let registered_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let device = devices.entry(device_id).or_insert_with(|| {
+            info!("event=device_registered device={}", device_id);
+            self.dirty.store(true, Ordering::SeqCst);
+            Arc::new(RegisteredDevice {
+                device_id,
+                account: None,
+                registered_at,
+            })
+        });
+        if account.is_some() && device.account != account {
+            Arc::make_mut(device).account = account;
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        true
+    }
+
+    /// The account the device is bound to
+    pub fn account(&self, device_id: &Uuid) -> Option<String> {
+        self.devices
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .get(device_id)
+            .and_then(|device| device.account.clone())
+    }
+
+    /// Builds the access control list from the accounts inside of the registry
+    pub fn access_control(&self) -> AccessControl {
+        let mut access_control = AccessControl::new(if self.allow_all {
+            AclAction::Allow
+        } else {
+            AclAction::Deny
+        });
+        let devices = self.devices.read().unwrap_or_else(|error| error.into_inner());
+        for device in devices.values() {
+            if let Some(account) = &device.account {
+                access_control.add_to_group(account.clone(), device.device_id);
+            }
+        }
+        access_control
+    }
+}
+
+impl Realm for FileRealm {
+    type Error = RealmError;
+    type EH = DynamicEncryptionManager;
+    type PD = RegisteredDevice;
+
+    fn login(&self, device_id: &Uuid, login: LoginDetails) -> Result<LoginResult, Self::Error> {
+        let result = self.providers.login(device_id, login)?;
+        if let LoginResult::Accepted(session) = &result {
+            let account = session_account(session);
+            if !self.register(*device_id, account.clone()) {
+                warn!(
+                    "event=login_refused device={} account={} reason=bound_to_account",
+                    device_id,
+                    account.unwrap_or_default()
+                );
+                return Ok(LoginResult::Rejected);
+            }
+        }
+        Ok(result)
+    }
+
+    fn is_paired(&self, uuid: &Uuid) -> bool {
+        self.devices
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .contains_key(uuid)
+    }
+
+    fn get_paired_device(&self, uuid: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        Ok(self
+            .devices
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .get(uuid)
+            .cloned())
+    }
+
+    fn get_realm_id(&self) -> Uuid {
+        self.realm_id
+    }
+
+    fn get_peer_realm(&self, _realm_id: &Uuid) -> Result<Option<Self::EH>, Self::Error> {
+        // This realm is not federated
+        Ok(None)
+    }
+}
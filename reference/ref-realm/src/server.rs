@@ -0,0 +1,362 @@
+use crate::config::Config;
+use crate::registry::{session_account, FileRealm};
+use abst_rs::a_sync::tokio_abst::{read_protocol, send_packet};
+use abst_rs::encryption::{EncryptionError, EncryptionManager};
+use abst_rs::packets::capabilities::DEVICE_TO_REALM;
+use abst_rs::packets::handlers::realm::{RealmConnectionContext, RealmHandler, RealmResponse};
+use abst_rs::packets::realm::{LoginDetails, RealmPacket};
+use abst_rs::packets::{ErrorPacket, Protocol};
+use abst_rs::realm::acl::{AccessControl, AclAction};
+use abst_rs::realm::limits::RateLimiter;
+use abst_rs::realm::login::{PASSWORD_PROVIDER_ID, PRE_SHARED_TOKEN_PROVIDER_ID};
+use abst_rs::Error;
+use bytes::Bytes;
+use log::{debug, error, info, warn};
+use packet::packet::Packet;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const OUTGOING_QUEUE: usize = 64;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Frames are sent as they are. The realm key exchange is not implemented yet, so every link uses this
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Plaintext;
+
+impl EncryptionManager for Plaintext {
+    type Error = EncryptionError;
+
+    fn decrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        Ok(message)
+    }
+
+    fn encrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        Ok(message)
+    }
+}
+
+/// Shared between every connection
+struct State {
+    realm: FileRealm,
+    limiter: RateLimiter,
+    allow_unencrypted_secrets: bool,
+    /// The outgoing queue of every logged in device
+    devices: Mutex<HashMap<Uuid, mpsc::Sender<Protocol>>>,
+    /// Rebuilt after every login. Connections copy it into their handler when it changed
+    access_control: RwLock<Arc<AccessControl>>,
+}
+
+impl State {
+    fn device(&self, device_id: &Uuid) -> Option<mpsc::Sender<Protocol>> {
+        self.devices
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .get(device_id)
+            .cloned()
+    }
+
+    fn access_control(&self) -> Arc<AccessControl> {
+        self.access_control.read().unwrap_or_else(|error| error.into_inner()).clone()
+    }
+
+    /// Picks up the accounts of devices that logged in
+    fn refresh_access_control(&self) {
+        let access_control = Arc::new(self.realm.access_control());
+        *self.access_control.write().unwrap_or_else(|error| error.into_inner()) = access_control;
+    }
+
+    /// Makes `sender` the outgoing queue of the device. Another connection that is still open only
+    /// gives up the device to a login that proves the account the device is bound to
+    fn connect(&self, context: &RealmConnectionContext, sender: &mpsc::Sender<Protocol>) -> bool {
+        let mut devices = self.devices.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some(current) = devices.get(&context.device_id) {
+            if !current.is_closed() && !current.same_channel(sender) {
+                let bound = self.realm.account(&context.device_id);
+                let proven = context.session.as_ref().and_then(session_account);
+                if bound.is_none() || bound != proven {
+                    return false;
+                }
+            }
+        }
+        devices.insert(context.device_id, sender.clone());
+        true
+    }
+
+    /// Writes the registry if a login changed it. The disk is only touched from a blocking thread
+    async fn save(self: &Arc<Self>) -> Result<(), String> {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || state.realm.save())
+            .await
+            .map_err(|error| format!("The save task failed: {}", error))?
+            .map_err(|error| format!("Unable to save the registry: {}", error))
+    }
+}
+
+/// Tokens and passwords are sent as they are inside of the login details
+fn sends_secret(details: &LoginDetails) -> bool {
+    matches!(
+        details,
+        LoginDetails::Other { id, .. } if *id == PASSWORD_PROVIDER_ID || *id == PRE_SHARED_TOKEN_PROVIDER_ID
+    )
+}
+
+/// Accepts connections until `shutdown` completes. Open connections are then given
+/// `shutdown_timeout_secs` to close before the registry is saved
+pub async fn run(config: Config, realm: FileRealm, shutdown: impl Future<Output = ()>) -> Result<(), String> {
+    let listener = TcpListener::bind(config.bind)
+        .await
+        .map_err(|error| format!("Unable to bind {}: {}", config.bind, error))?;
+    serve(listener, config, realm, shutdown).await
+}
+
+/// [`run`] with a listener that is already bound. `config.bind` is not used
+pub async fn serve(
+    listener: TcpListener,
+    config: Config,
+    realm: FileRealm,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), String> {
+    info!(
+        "event=listening bind={} realm_id={} registry={}",
+        listener.local_addr().map(|address| address.to_string()).unwrap_or_default(),
+        abst_rs::realm::Realm::get_realm_id(&realm),
+        realm.path().display()
+    );
+    let access_control = RwLock::new(Arc::new(realm.access_control()));
+    let state = Arc::new(State {
+        realm,
+        limiter: RateLimiter::new(config.limits.rate_limits()),
+        allow_unencrypted_secrets: config.login.allow_unencrypted_secrets,
+        devices: Mutex::new(HashMap::new()),
+        access_control,
+    });
+    if !state.allow_unencrypted_secrets && (!config.login.tokens.is_empty() || !config.login.accounts.is_empty()) {
+        warn!("event=secrets_refused reason=unencrypted_links");
+    }
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    // Every connection holds a clone. recv returns None once all of them are dropped
+    let (alive_sender, mut alive_receiver) = mpsc::channel::<()>(1);
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = prune.tick() => state.limiter.prune(),
+            accepted = listener.accept() => match accepted {
+                Ok((stream, address)) => {
+                    let state = state.clone();
+                    let shutdown = shutdown_receiver.clone();
+                    let alive = alive_sender.clone();
+                    tokio::spawn(async move {
+                        handle_connection(stream, address, state, shutdown).await;
+                        drop(alive);
+                    });
+                }
+                Err(error) => warn!("event=accept_failed error={}", error),
+            },
+        }
+    }
+    info!("event=shutdown_started");
+    drop(listener);
+    let _ = shutdown_sender.send(true);
+    drop(alive_sender);
+    let wait = Duration::from_secs(config.shutdown_timeout_secs);
+    if timeout(wait, alive_receiver.recv()).await.is_err() {
+        warn!("event=shutdown_timeout timeout_secs={}", config.shutdown_timeout_secs);
+    }
+    state.save().await?;
+    info!("event=shutdown_complete");
+    Ok(())
+}
+
+/// Handles a DeviceLogin on a blocking thread. A password login runs thousands of PBKDF2 rounds.
+/// Logins do not look at the access control list, so this handler gets an empty one
+async fn login(
+    state: &Arc<State>,
+    packet: Protocol,
+    source: IpAddr,
+    context: &mut Option<RealmConnectionContext>,
+) -> Result<RealmResponse, String> {
+    let state = state.clone();
+    let mut login_context = context.take();
+    let (response, login_context) = tokio::task::spawn_blocking(move || {
+        let mut handler = RealmHandler::new(&state.realm, AccessControl::new(AclAction::Deny))
+            .with_rate_limiter(&state.limiter);
+        let response = handler.handle_packet(packet, source, login_context.as_mut());
+        (response, login_context)
+    })
+    .await
+    .map_err(|error| format!("The login task failed: {}", error))?;
+    *context = login_context;
+    response.map_err(|error| error.to_string())
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    address: SocketAddr,
+    state: Arc<State>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    info!("event=connection_opened peer={}", address);
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::channel::<Protocol>(OUTGOING_QUEUE);
+    let encryption = Plaintext;
+    // What this connection negotiated. Forwarded packets are written as this device's version too
+    let (negotiated_sender, negotiated) = watch::channel(None);
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let negotiated = negotiated.borrow().clone();
+            if let Err(error) = send_packet(&mut writer, &encryption, negotiated.as_ref(), message).await {
+                warn!("event=write_failed peer={} error={:?}", address, error);
+                break;
+            }
+        }
+    });
+
+    let mut access_control = state.access_control();
+    let mut handler =
+        RealmHandler::new(&state.realm, access_control.as_ref().clone()).with_rate_limiter(&state.limiter);
+    let mut context: Option<RealmConnectionContext> = None;
+    let mut registered: Option<Uuid> = None;
+    loop {
+        let packet = tokio::select! {
+            _ = shutdown.changed() => break,
+            packet = read_protocol::<_, _, Protocol>(
+                &mut reader,
+                &encryption,
+                context.as_ref().and_then(|context| context.negotiated.as_ref()),
+            ) => packet,
+        };
+        let packet = match packet {
+            Ok(Some(packet)) => packet,
+            Ok(None) => {
+                debug!("event=unsupported_packet peer={}", address);
+                continue;
+            }
+            Err(Error::IO(error)) if error.kind() != ErrorKind::UnexpectedEof => {
+                warn!("event=read_failed peer={} error={}", address, error);
+                break;
+            }
+            // The device closed the connection or sent something that could not be read
+            Err(error) => {
+                debug!("event=read_ended peer={} error={:?}", address, error);
+                break;
+            }
+        };
+        // Errors sent from here are about the packet that was just read
+        let packet_id = match &packet {
+            Protocol::DeviceToRealm(realm_packet) => realm_packet.get_packet_id(),
+            Protocol::DeviceToDevice(packet) => packet.get_packet_id(),
+            Protocol::RealmToRealm(packet) => packet.get_packet_id(),
+        };
+        if let Protocol::DeviceToRealm(RealmPacket::DeviceLogin(details)) = &packet {
+            // Every link is plaintext
+            if !state.allow_unencrypted_secrets && sends_secret(details) {
+                warn!("event=login_refused peer={} reason=unencrypted_link", address);
+                let error = ErrorPacket::login_rejected(DEVICE_TO_REALM, packet_id);
+                if sender.send(RealmPacket::Error(error).into()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        }
+        // Devices of the same account can log in after this connection did
+        if let Protocol::DeviceToRealm(RealmPacket::DeviceProxy(..)) = &packet {
+            let current = state.access_control();
+            if !Arc::ptr_eq(&current, &access_control) {
+                *handler.access_control_mut() = current.as_ref().clone();
+                access_control = current;
+            }
+        }
+        let is_login = matches!(&packet, Protocol::DeviceToRealm(RealmPacket::DeviceLogin(_)));
+        let response = if is_login {
+            login(&state, packet, address.ip(), &mut context).await
+        } else {
+            handler
+                .handle_packet(packet, address.ip(), context.as_mut())
+                .map_err(|error| error.to_string())
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(message) => {
+                error!("event=realm_error peer={} error={}", address, message);
+                break;
+            }
+        };
+        // A login registers the device. It has to be on disk before the device is told
+        if state.realm.is_dirty() {
+            if let Err(message) = state.save().await {
+                error!("event=save_failed peer={} error={}", address, message);
+                break;
+            }
+        }
+        // Once logged in the device can receive proxied packets. It is reachable before LoginAccepted is sent
+        let mut response = response;
+        if let Some(context) = context.as_mut().filter(|context| context.is_logged_in()) {
+            if registered != Some(context.device_id) {
+                if state.connect(context, &sender) {
+                    info!("event=login peer={} device={}", address, context.device_id);
+                    state.refresh_access_control();
+                    registered = Some(context.device_id);
+                } else {
+                    warn!(
+                        "event=login_refused peer={} device={} reason=connected_elsewhere",
+                        address, context.device_id
+                    );
+                    context.session = None;
+                    let error = ErrorPacket::login_rejected(DEVICE_TO_REALM, packet_id);
+                    response = RealmResponse::Message(RealmPacket::Error(error).into());
+                }
+            }
+        }
+        let delivered = match response {
+            RealmResponse::NewContext(new_context) => {
+                info!("event=hello peer={} device={}", address, new_context.device_id);
+                let _ = negotiated_sender.send(new_context.negotiated.clone());
+                context = Some(*new_context);
+                true
+            }
+            RealmResponse::Message(message) => sender.send(message).await.is_ok(),
+            RealmResponse::Forward { target, message } => match state.device(&target) {
+                Some(target_sender) => {
+                    if target_sender.send(message).await.is_err() {
+                        debug!("event=forward_failed peer={} target={}", address, target);
+                    }
+                    true
+                }
+                None => {
+                    let error = ErrorPacket::unknown_device(DEVICE_TO_REALM, packet_id);
+                    sender.send(RealmPacket::Error(error).into()).await.is_ok()
+                }
+            },
+            RealmResponse::ForwardToRealm { realm_id, .. } => {
+                // Only reachable with a route table, which this server does not have
+                warn!("event=federation_unsupported peer={} realm_id={}", address, realm_id);
+                true
+            }
+            RealmResponse::Nothing => true,
+        };
+        if !delivered {
+            break;
+        }
+    }
+
+    if let Some(device_id) = registered {
+        let mut devices = state.devices.lock().unwrap_or_else(|error| error.into_inner());
+        // A newer connection of the same device could have replaced this one
+        if devices.get(&device_id).map(|value| value.same_channel(&sender)).unwrap_or(false) {
+            devices.remove(&device_id);
+        }
+    }
+    drop(sender);
+    let _ = writer_task.await;
+    info!("event=connection_closed peer={}", address);
+}
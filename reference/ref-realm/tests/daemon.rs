@@ -0,0 +1,303 @@
+use abst_rs::a_sync::tokio_abst::{read_protocol, send_packet};
+use abst_rs::packets::capabilities::{Capabilities, DEVICE_TO_DEVICE};
+use abst_rs::packets::realm::{LoginDetails, RealmPacket};
+use abst_rs::packets::{ErrorPacket, Protocol};
+use abst_rs::realm::login::{
+    PasswordCredentials, PasswordVerifier, CHALLENGE_RESPONSE_PROVIDER_ID, PASSWORD_PROVIDER_ID,
+    PRE_SHARED_TOKEN_PROVIDER_ID,
+};
+use abst_rs::test_util::{error_code, frame};
+use bytes::Bytes;
+use packet::PacketContent;
+use ref_realm::config::{hex_encode, Config};
+use ref_realm::registry::FileRealm;
+use ref_realm::server::{self, Plaintext};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use themis::keygen::gen_ec_key_pair;
+use themis::secure_message::SecureSign;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// A realm listening on loopback
+struct LocalRealm {
+    address: SocketAddr,
+    storage: PathBuf,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), String>>,
+}
+
+impl LocalRealm {
+    /// `login` is appended to the `[login]` table of the config
+    async fn start(login: &str) -> LocalRealm {
+        LocalRealm::start_with(login, "").await
+    }
+
+    /// `login` and `access` are appended to the `[login]` and `[access]` tables of the config
+    async fn start_with(login: &str, access: &str) -> LocalRealm {
+        let storage = std::env::temp_dir().join(format!("ref-realm-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&storage).unwrap();
+        let config_path = storage.join("realm.toml");
+        let config = format!(
+            "bind = \"127.0.0.1:0\"\nstorage = \"{}\"\nshutdown_timeout_secs = 1\n\n[login]\n{}\n\n[access]\n{}\n",
+            storage.join("data").display(),
+            login,
+            access
+        );
+        std::fs::write(&config_path, config).unwrap();
+        let config = Config::load(&config_path).unwrap();
+        let realm = FileRealm::open(&config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, receiver) = oneshot::channel();
+        let task = tokio::spawn(server::serve(listener, config, realm, async {
+            let _ = receiver.await;
+        }));
+        LocalRealm {
+            address,
+            storage,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Stops the realm and returns the saved registry
+    async fn stop(self) -> String {
+        self.shutdown.send(()).unwrap();
+        self.task.await.unwrap().unwrap();
+        let registry = std::fs::read_to_string(self.storage.join("data").join("devices.json")).unwrap();
+        std::fs::remove_dir_all(&self.storage).unwrap();
+        registry
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Connects and sends Hello
+    async fn connect(realm: &LocalRealm, device_id: Uuid) -> Client {
+        let mut client = Client {
+            stream: TcpStream::connect(realm.address).await.unwrap(),
+        };
+        client
+            .send(RealmPacket::Hello {
+                device_id,
+                public_key_hash: None,
+                capabilities: Capabilities::default(),
+            })
+            .await;
+        client
+    }
+
+    async fn send(&mut self, packet: RealmPacket) {
+        send_packet(&mut self.stream, &Plaintext, None, Protocol::from(packet))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(&mut self) -> RealmPacket {
+        match read_protocol::<_, _, Protocol>(&mut self.stream, &Plaintext, None).await {
+            Ok(Some(Protocol::DeviceToRealm(packet))) => packet,
+            _ => panic!("Expected a realm packet"),
+        }
+    }
+
+    /// Logs in and returns the error code if the login was not accepted
+    async fn login(&mut self, details: LoginDetails) -> Option<u8> {
+        self.send(RealmPacket::DeviceLogin(details)).await;
+        match self.receive().await {
+            RealmPacket::LoginAccepted { .. } => None,
+            RealmPacket::Error(error) => Some(error.error_code()),
+            _ => panic!("Expected the login to be answered"),
+        }
+    }
+}
+
+fn password(username: &str, password: &str) -> LoginDetails {
+    let mut details = Vec::new();
+    PasswordCredentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    }
+    .write(&mut details)
+    .unwrap();
+    LoginDetails::Other {
+        id: PASSWORD_PROVIDER_ID,
+        details: Bytes::from(details),
+    }
+}
+
+/// An `[[login.accounts]]` entry
+fn account(username: &str, password: &str, devices: &[Uuid]) -> String {
+    let verifier = PasswordVerifier::with_rounds(password, 1_000);
+    let devices: Vec<String> = devices.iter().map(|device| format!("\"{}\"", device)).collect();
+    format!(
+        "[[login.accounts]]\nusername = \"{}\"\nsalt = \"{}\"\nrounds = {}\nhash = \"{}\"\ndevices = [{}]\n",
+        username,
+        hex_encode(&verifier.salt),
+        verifier.rounds,
+        hex_encode(&verifier.hash),
+        devices.join(", ")
+    )
+}
+
+#[tokio::test]
+async fn proxies_between_devices_and_saves_on_shutdown() {
+    let realm = LocalRealm::start("allow_anonymous = true").await;
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut client_a = Client::connect(&realm, a).await;
+    let mut client_b = Client::connect(&realm, b).await;
+    assert_eq!(client_a.login(LoginDetails::None).await, None);
+    assert_eq!(client_b.login(LoginDetails::None).await, None);
+
+    client_a.send(RealmPacket::DeviceProxy(b, frame(DEVICE_TO_DEVICE))).await;
+    match client_b.receive().await {
+        RealmPacket::DeviceProxy(source, payload) => {
+            assert_eq!(source, a);
+            assert_eq!(payload, frame(DEVICE_TO_DEVICE));
+        }
+        _ => panic!("Expected the proxied frame"),
+    }
+    client_a.send(RealmPacket::DeviceProxy(Uuid::new_v4(), frame(DEVICE_TO_DEVICE))).await;
+    assert_eq!(error_code(client_a.receive().await), ErrorPacket::UNKNOWN_DEVICE);
+
+    drop((client_a, client_b));
+    let registry = realm.stop().await;
+    assert!(registry.contains(&a.to_string()));
+    assert!(registry.contains(&b.to_string()));
+}
+
+#[tokio::test]
+async fn secrets_are_refused_on_unencrypted_links() {
+    let device = Uuid::new_v4();
+    let login = format!(
+        "[[login.tokens]]\ndevice_id = \"{}\"\ntoken = \"secret\"\n{}",
+        device,
+        account("alice", "hunter2", &[device])
+    );
+    let realm = LocalRealm::start(&login).await;
+    let mut client = Client::connect(&realm, device).await;
+    let token = LoginDetails::Other {
+        id: PRE_SHARED_TOKEN_PROVIDER_ID,
+        details: Bytes::from_static(b"secret"),
+    };
+    assert_eq!(client.login(token).await, Some(ErrorPacket::LOGIN_REJECTED));
+    assert_eq!(client.login(password("alice", "hunter2")).await, Some(ErrorPacket::LOGIN_REJECTED));
+    drop(client);
+    realm.stop().await;
+}
+
+#[tokio::test]
+async fn device_is_bound_to_one_account() {
+    let device = Uuid::new_v4();
+    let login = format!(
+        "allow_unencrypted_secrets = true\n{}{}",
+        account("alice", "hunter2", &[device]),
+        account("bob", "swordfish", &[device])
+    );
+    let realm = LocalRealm::start(&login).await;
+
+    let mut client = Client::connect(&realm, device).await;
+    assert_eq!(client.login(password("alice", "hunter2")).await, None);
+    drop(client);
+    // bob lists the device as well, but it already belongs to alice
+    let mut client = Client::connect(&realm, device).await;
+    assert_eq!(client.login(password("bob", "swordfish")).await, Some(ErrorPacket::LOGIN_REJECTED));
+    assert_eq!(client.login(password("alice", "hunter2")).await, None);
+    drop(client);
+    realm.stop().await;
+}
+
+#[tokio::test]
+async fn connected_devices_can_not_be_taken_over() {
+    let device = Uuid::new_v4();
+    let login = format!(
+        "allow_anonymous = true\nallow_unencrypted_secrets = true\n{}",
+        account("alice", "hunter2", &[device])
+    );
+    let realm = LocalRealm::start(&login).await;
+    let mut owner = Client::connect(&realm, device).await;
+    assert_eq!(owner.login(password("alice", "hunter2")).await, None);
+    // The device belongs to alice. Without the password it can not be used
+    let mut other = Client::connect(&realm, device).await;
+    assert_eq!(other.login(LoginDetails::None).await, Some(ErrorPacket::LOGIN_REJECTED));
+    assert_eq!(other.login(password("alice", "hunter2")).await, None);
+
+    // A device without an account is not handed to a second connection while the first is open
+    let anonymous = Uuid::new_v4();
+    let mut first = Client::connect(&realm, anonymous).await;
+    assert_eq!(first.login(LoginDetails::None).await, None);
+    let mut second = Client::connect(&realm, anonymous).await;
+    assert_eq!(second.login(LoginDetails::None).await, Some(ErrorPacket::LOGIN_REJECTED));
+    other.send(RealmPacket::DeviceProxy(anonymous, frame(DEVICE_TO_DEVICE))).await;
+    match first.receive().await {
+        RealmPacket::DeviceProxy(source, _) => assert_eq!(source, device),
+        _ => panic!("Expected the proxied frame"),
+    }
+
+    drop((owner, other, first, second));
+    realm.stop().await;
+}
+
+#[tokio::test]
+async fn devices_of_one_account_can_talk_without_allow_all() {
+    let (phone, laptop, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let login = format!(
+        "allow_anonymous = true\nallow_unencrypted_secrets = true\n{}",
+        account("alice", "hunter2", &[phone, laptop])
+    );
+    let realm = LocalRealm::start_with(&login, "allow_all = false").await;
+    let mut phone_client = Client::connect(&realm, phone).await;
+    assert_eq!(phone_client.login(password("alice", "hunter2")).await, None);
+    // The laptop logs in after the phone did
+    let mut laptop_client = Client::connect(&realm, laptop).await;
+    assert_eq!(laptop_client.login(password("alice", "hunter2")).await, None);
+
+    phone_client.send(RealmPacket::DeviceProxy(laptop, frame(DEVICE_TO_DEVICE))).await;
+    match laptop_client.receive().await {
+        RealmPacket::DeviceProxy(source, _) => assert_eq!(source, phone),
+        _ => panic!("Expected the proxied frame"),
+    }
+    // A device without an account is not part of alice's group
+    let mut stranger_client = Client::connect(&realm, stranger).await;
+    assert_eq!(stranger_client.login(LoginDetails::None).await, None);
+    stranger_client.send(RealmPacket::DeviceProxy(phone, frame(DEVICE_TO_DEVICE))).await;
+    assert_eq!(error_code(stranger_client.receive().await), ErrorPacket::ACCESS_DENIED);
+
+    drop((phone_client, laptop_client, stranger_client));
+    realm.stop().await;
+}
+
+#[tokio::test]
+async fn devices_log_in_with_an_enrolled_key() {
+    let device = Uuid::new_v4();
+    let (private, public) = gen_ec_key_pair().split();
+    let login = format!(
+        "[[login.device_keys]]\ndevice_id = \"{}\"\npublic_key = \"{}\"\n",
+        device,
+        hex_encode(public.as_ref())
+    );
+    let realm = LocalRealm::start(&login).await;
+    let details = |details: Bytes| LoginDetails::Other {
+        id: CHALLENGE_RESPONSE_PROVIDER_ID,
+        details,
+    };
+    let mut client = Client::connect(&realm, device).await;
+    client.send(RealmPacket::DeviceLogin(details(Bytes::new()))).await;
+    let challenge = match client.receive().await {
+        RealmPacket::LoginChallenge { challenge, .. } => challenge,
+        _ => panic!("Expected a challenge"),
+    };
+    let signed = SecureSign::new(private).sign(&challenge).unwrap();
+    assert_eq!(client.login(details(Bytes::from(signed))).await, None);
+
+    // Only enrolled devices get a challenge
+    let mut other = Client::connect(&realm, Uuid::new_v4()).await;
+    assert_eq!(other.login(details(Bytes::new())).await, Some(ErrorPacket::LOGIN_REJECTED));
+    drop((client, other));
+    realm.stop().await;
+}
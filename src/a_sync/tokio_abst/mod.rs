@@ -1,30 +1,36 @@
 use crate::encryption::EncryptionManager;
 use crate::error::Error;
+use crate::packets::capabilities::{Negotiated, PROTOCOL_VERSION};
 use crate::protocol::{ConnectionType, DTDViaRealm, DirectConnection};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use packet::{read_packet_type, IntoPacket, PacketContent};
+use packet::protocol::Protocol;
+use packet::version::with_version;
+use packet::{read_packet_type, FrameReader, IntoPacket, PacketContent, PacketReadError};
+use packet::{read_frame_len, AsyncPacketContent, AsyncReader, StreamedBin};
 use rmp::decode::read_bin_len;
 use rmp::sync;
 use rmp::tokio::encode::write_uint;
-use std::io::Read;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use uuid::Uuid;
 
-pub mod tmp;
+/// The longest frame [`read_packet_raw`] reads. Anything longer is refused before it is allocated
+pub const MAX_FRAME_LEN: usize = packet::DEFAULT_MAX_BYTES;
 
 /// Reads the Packet and decrypts it from the
 pub async fn read_packet_raw<Reader: AsyncReadExt + Unpin>(
     reader: &mut Reader,
 ) -> Result<Bytes, Error> {
     // Binary Header
-    let result = tmp::read_binary_header(reader).await?;
-    let mut contents = BytesMut::with_capacity(result);
-    // Check for data that could need to be read
-    while contents.len() < result {
-        reader.read(&mut contents).await.map_err(Error::from)?;
+    let result = read_frame_len(reader).await?;
+    if result > MAX_FRAME_LEN {
+        return Err(Error::PacketRead(PacketReadError::ContentError(
+            format!("Frame length {} is over the limit of {}", result, MAX_FRAME_LEN).into(),
+        )));
     }
+    let mut contents = vec![0u8; result];
+    reader.read_exact(&mut contents).await.map_err(Error::from)?;
 
-    Ok(contents.freeze())
+    Ok(Bytes::from(contents))
 }
 
 pub async fn read_packet<Reader: AsyncReadExt + Unpin, EM: EncryptionManager>(
@@ -32,18 +38,65 @@ pub async fn read_packet<Reader: AsyncReadExt + Unpin, EM: EncryptionManager>(
     em: &EM,
 ) -> Result<(u8, u8, Bytes), Error>  where Error: From<EM::Error> {
     let  result = read_packet_raw(reader).await?;
-    let mut reader = em.decrypt_message(result)?.reader();
+    let frame = em.decrypt_message(result)?;
+    let mut reader = FrameReader::new(&frame);
     let (protocol, packet) = read_packet_type(&mut reader)?;
     let size = read_bin_len(&mut reader)? as usize;
-    let mut content = BytesMut::with_capacity(size);
-    reader
-        .take(size as u64)
-        .read_exact(&mut content)?;
-    Ok((protocol, packet, content.freeze()))
+    // A slice of the decrypted frame. Read it with `packet::read_from_bytes` to not copy the fields either
+    let content = reader.take_bytes(size)?;
+    Ok((protocol, packet, content))
+}
+
+/// Reads the Packet without buffering the frame. `Content` is decoded straight from the reader.
+/// Only for connections without encryption. An encrypted frame has to be decrypted as a whole
+///
+/// `Content` can not read past the payload of the packet. Anything it leaves unread, the body of a
+/// trailing [`StreamedBin`] for example, is skipped. So the next read starts at the next frame
+pub async fn read_packet_streaming<Reader: AsyncReader, Content: AsyncPacketContent>(
+    reader: &mut Reader,
+) -> Result<(u8, u8, Content), Error> {
+    // Binary Header
+    let frame_len = read_frame_len(reader).await?;
+    let mut frame = (&mut *reader).take(frame_len as u64);
+    let protocol = u8::read_async(&mut frame).await?;
+    let packet = u8::read_async(&mut frame).await?;
+    let payload = StreamedBin::read_async(&mut frame).await?;
+    let content = Content::read_async(&mut (&mut frame).take(payload.len as u64)).await?;
+    tokio::io::copy(&mut frame, &mut tokio::io::sink()).await?;
+    if frame.limit() > 0 {
+        return Err(Error::IO(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok((protocol, packet, content))
+}
+
+/// Reads the Packet, decrypts it and builds the Protocol from it.
+/// Read as the negotiated version. `None` before the Hello, which is read as [`PROTOCOL_VERSION`]
+///
+/// # Returns
+/// * `Ok(None)` if the Protocol ID or Packet ID is not supported
+pub async fn read_protocol<
+    Reader: AsyncReadExt + Unpin,
+    EM: EncryptionManager,
+    Pr: Protocol<ReadError = PacketReadError>,
+>(
+    reader: &mut Reader,
+    em: &EM,
+    negotiated: Option<&Negotiated>,
+) -> Result<Option<Pr>, Error> where Error: From<EM::Error> {
+    let result = read_packet_raw(reader).await?;
+    let frame = em.decrypt_message(result)?;
+    // Bytes inside of the packet are slices of the frame
+    let mut reader = FrameReader::new(&frame);
+    let (protocol, packet) = read_packet_type(&mut reader)?;
+    let version = negotiated.map_or(PROTOCOL_VERSION, |negotiated| negotiated.version);
+    match with_version(version, || Pr::build_if_supported_frame(protocol, packet, &mut reader)) {
+        Some(value) => Ok(Some(value?)),
+        None => Ok(None),
+    }
 }
 
 /// Writes a Packet to the given Writer
-/// Supports any Connection Type
+/// Supports any Connection Type. Written as the negotiated version. `None` before the Hello, which is written as [`PROTOCOL_VERSION`]
 pub async fn send_packet<
     Writer: AsyncWriteExt + Unpin,
     Content: IntoPacket,
@@ -51,10 +104,12 @@ pub async fn send_packet<
 >(
     writer: &mut Writer,
     em: &EM,
+    negotiated: Option<&Negotiated>,
     content: Content,
 ) -> Result<(), Error> where Error: From<EM::Error>  {
     let mut payload =BytesMut::new().writer();
-    content.into_packet(&mut payload)?;
+    let version = negotiated.map_or(PROTOCOL_VERSION, |negotiated| negotiated.version);
+    with_version(version, || content.into_packet(&mut payload))?;
     let payload = em.encrypt_message(payload.into_inner().freeze())?;
     write_uint(writer, payload.len() as u64).await?;
     writer.write_all(&payload).await?;
@@ -0,0 +1,254 @@
+use crate::device_manager::{derive_key, DeviceMetadata};
+use crate::encryption::{EncryptionError, ThemisEncryptionManager};
+use bytes::{BufMut, Bytes, BytesMut};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use themis::secure_cell::SecureCell;
+use uuid::Uuid;
+
+/// The first bytes of every sealed bundle
+pub const BUNDLE_MAGIC: &[u8; 8] = b"ABSTBNDL";
+/// Bumped whenever the sealed layout or the payload changes
+pub const BUNDLE_VERSION: u8 = 1;
+/// The PBKDF2 rounds of every version 1 bundle. [`PairingBundle::open`] refuses any other count
+pub const BUNDLE_KDF_ROUNDS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+/// Magic, version, rounds and salt
+const HEADER_LENGTH: usize = BUNDLE_MAGIC.len() + 1 + 4 + SALT_LENGTH;
+
+#[derive(Debug)]
+pub enum BundleError {
+    /// Not a bundle or cut short
+    Malformed,
+    /// The bundle was written by a newer version
+    UnsupportedVersion(u8),
+    /// The passphrase is wrong or the bundle was tampered with. Secure Cell can not tell the two apart
+    Rejected,
+    Json(serde_json::Error),
+    Encryption(EncryptionError),
+}
+
+impl Display for BundleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BundleError::Malformed => write!(f, "Not a pairing bundle"),
+            BundleError::UnsupportedVersion(version) => {
+                write!(f, "Bundle version {} is newer than {}", version, BUNDLE_VERSION)
+            }
+            BundleError::Rejected => write!(f, "Wrong passphrase or the bundle was modified"),
+            BundleError::Json(error) => write!(f, "Invalid bundle: {}", error),
+            BundleError::Encryption(error) => write!(f, "Encryption error: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for BundleError {}
+
+impl From<serde_json::Error> for BundleError {
+    fn from(value: serde_json::Error) -> Self {
+        BundleError::Json(value)
+    }
+}
+
+impl From<EncryptionError> for BundleError {
+    fn from(value: EncryptionError) -> Self {
+        BundleError::Encryption(value)
+    }
+}
+
+impl From<themis::Error> for BundleError {
+    fn from(value: themis::Error) -> Self {
+        BundleError::Encryption(EncryptionError::from(value))
+    }
+}
+
+/// A paired device inside of a [`PairingBundle`]
+#[derive(Clone)]
+pub struct BundledDevice {
+    pub device_id: Uuid,
+    pub encryption: ThemisEncryptionManager,
+    pub metadata: DeviceMetadata,
+}
+
+/// A realm inside of a [`PairingBundle`]. `encryption` is None if the keys were never exchanged
+#[derive(Clone)]
+pub struct BundledRealm {
+    pub ip: IpAddr,
+    pub realm_id: Option<Uuid>,
+    pub encryption: Option<ThemisEncryptionManager>,
+}
+
+/// Everything needed to move a device to another machine.
+///
+/// The device id is kept so peers do not have to pair again.
+/// See [`DeviceManager::export_bundle`](crate::device_manager::DeviceManager::export_bundle)
+#[derive(Clone)]
+pub struct PairingBundle {
+    pub device_id: Uuid,
+    pub device_name: String,
+    pub devices: Vec<BundledDevice>,
+    pub realms: Vec<BundledRealm>,
+}
+
+impl PairingBundle {
+    /// Encrypts the bundle with a key derived from the passphrase.
+    ///
+    /// The header is passed as the Secure Cell context so changing the salt or rounds is caught as well
+    pub fn seal(&self, passphrase: &str) -> Result<Bytes, BundleError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::thread_rng().fill(&mut salt);
+        let mut bundle = BytesMut::with_capacity(HEADER_LENGTH);
+        bundle.put_slice(BUNDLE_MAGIC);
+        bundle.put_u8(BUNDLE_VERSION);
+        bundle.put_u32(BUNDLE_KDF_ROUNDS);
+        bundle.put_slice(&salt);
+
+        let payload = serde_json::to_vec(&StoredBundle::from(self))?;
+        let key = derive_key(passphrase, &salt, BUNDLE_KDF_ROUNDS);
+        let sealed = SecureCell::with_key(&key)?
+            .seal()
+            .encrypt_with_context(&payload, &bundle)?;
+        bundle.put_slice(&sealed);
+        Ok(bundle.freeze())
+    }
+
+    /// Reverses [`PairingBundle::seal`]
+    pub fn open(bundle: &[u8], passphrase: &str) -> Result<Self, BundleError> {
+        if bundle.len() <= HEADER_LENGTH || !bundle.starts_with(BUNDLE_MAGIC) {
+            return Err(BundleError::Malformed);
+        }
+        let (header, sealed) = bundle.split_at(HEADER_LENGTH);
+        let version = header[BUNDLE_MAGIC.len()];
+        if version > BUNDLE_VERSION {
+            return Err(BundleError::UnsupportedVersion(version));
+        }
+        let rounds_start = BUNDLE_MAGIC.len() + 1;
+        let mut rounds = [0u8; 4];
+        rounds.copy_from_slice(&header[rounds_start..rounds_start + 4]);
+        let rounds = u32::from_be_bytes(rounds);
+        // Version 1 always uses the same rounds. Anything else would let a crafted bundle pick the PBKDF2 cost
+        if rounds != BUNDLE_KDF_ROUNDS {
+            return Err(BundleError::Malformed);
+        }
+        let salt = &header[rounds_start + 4..];
+
+        let key = derive_key(passphrase, salt, rounds);
+        let payload = SecureCell::with_key(&key)?
+            .seal()
+            .decrypt_with_context(sealed, header)
+            .map_err(|_| BundleError::Rejected)?;
+        let stored: StoredBundle = serde_json::from_slice(&payload)?;
+        stored.try_into()
+    }
+}
+
+/// The payload as it is written inside of the Secure Cell. Keys are hex encoded
+#[derive(Serialize, Deserialize)]
+struct StoredBundle {
+    device_id: Uuid,
+    device_name: String,
+    devices: Vec<StoredBundledDevice>,
+    realms: Vec<StoredBundledRealm>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKeys {
+    public_key: String,
+    private_key: String,
+    key_b: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredBundledDevice {
+    device_id: Uuid,
+    keys: StoredKeys,
+    metadata: DeviceMetadata,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredBundledRealm {
+    ip: IpAddr,
+    realm_id: Option<Uuid>,
+    keys: Option<StoredKeys>,
+}
+
+impl From<&ThemisEncryptionManager> for StoredKeys {
+    fn from(encryption: &ThemisEncryptionManager) -> Self {
+        StoredKeys {
+            public_key: hex::encode(&encryption.self_public_key),
+            private_key: hex::encode(&encryption.self_private_key),
+            key_b: hex::encode(&encryption.other_public_key),
+        }
+    }
+}
+
+impl TryFrom<StoredKeys> for ThemisEncryptionManager {
+    type Error = BundleError;
+
+    fn try_from(keys: StoredKeys) -> Result<Self, Self::Error> {
+        let decode = |value: String| hex::decode(value).map(Bytes::from).map_err(|_| BundleError::Malformed);
+        Ok(ThemisEncryptionManager {
+            self_private_key: decode(keys.private_key)?,
+            self_public_key: decode(keys.public_key)?,
+            other_public_key: decode(keys.key_b)?,
+        })
+    }
+}
+
+impl From<&PairingBundle> for StoredBundle {
+    fn from(bundle: &PairingBundle) -> Self {
+        StoredBundle {
+            device_id: bundle.device_id,
+            device_name: bundle.device_name.clone(),
+            devices: bundle
+                .devices
+                .iter()
+                .map(|device| StoredBundledDevice {
+                    device_id: device.device_id,
+                    keys: StoredKeys::from(&device.encryption),
+                    metadata: device.metadata.clone(),
+                })
+                .collect(),
+            realms: bundle
+                .realms
+                .iter()
+                .map(|realm| StoredBundledRealm {
+                    ip: realm.ip,
+                    realm_id: realm.realm_id,
+                    keys: realm.encryption.as_ref().map(StoredKeys::from),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<StoredBundle> for PairingBundle {
+    type Error = BundleError;
+
+    fn try_from(stored: StoredBundle) -> Result<Self, Self::Error> {
+        let mut devices = Vec::with_capacity(stored.devices.len());
+        for device in stored.devices {
+            devices.push(BundledDevice {
+                device_id: device.device_id,
+                encryption: device.keys.try_into()?,
+                metadata: device.metadata,
+            });
+        }
+        let mut realms = Vec::with_capacity(stored.realms.len());
+        for realm in stored.realms {
+            realms.push(BundledRealm {
+                ip: realm.ip,
+                realm_id: realm.realm_id,
+                encryption: realm.keys.map(TryInto::try_into).transpose()?,
+            });
+        }
+        Ok(PairingBundle {
+            device_id: stored.device_id,
+            device_name: stored.device_name,
+            devices,
+            realms,
+        })
+    }
+}
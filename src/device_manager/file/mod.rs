@@ -0,0 +1,686 @@
+use crate::device_manager::bundle::{BundledDevice, BundledRealm, PairingBundle};
+use crate::device_manager::{derive_key, DeviceManager, DeviceMetadata, PairedDevice};
+use crate::encryption::{DynamicEncryptionManager, EncryptionError, EncryptionSet, ThemisEncryptionManager};
+use crate::realm::DeviceRealmConnection;
+use bytes::Bytes;
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use themis::secure_cell::{SecureCell, SecureCellSeal};
+use uuid::Uuid;
+
+/// Bumped whenever the layout of the state file changes. Older files are migrated when opened
+pub const STATE_VERSION: u64 = 2;
+const STATE_FILE: &str = "state.json";
+/// The PBKDF2 rounds used for new state files
+pub const KDF_ROUNDS: u32 = 100_000;
+/// Sealed with the passphrase key. Used to tell a wrong passphrase apart from a corrupt file
+const KEY_CHECK: &[u8] = b"abst-rs key check";
+const KEY_CHECK_CONTEXT: &[u8] = b"key-check";
+
+/// Called when another device wants to pair. See [`DeviceManager::pair_request`]
+pub type PairRequestHandler = dyn Fn(&Uuid, &str, Cursor<Bytes>) -> (bool, Option<Bytes>) + Send + Sync;
+
+#[derive(Debug)]
+pub enum FileDeviceManagerError {
+    IO(std::io::Error),
+    Json(serde_json::Error),
+    Encryption(EncryptionError),
+    /// The passphrase does not match the one the state was created with
+    WrongPassphrase,
+    /// The state was written by a newer version
+    UnsupportedVersion(u64),
+    /// `create` was called on a directory that already has a state file
+    AlreadyExists,
+    Corrupt(String),
+}
+
+impl Display for FileDeviceManagerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileDeviceManagerError::IO(error) => write!(f, "IO error: {}", error),
+            FileDeviceManagerError::Json(error) => write!(f, "Invalid state file: {}", error),
+            FileDeviceManagerError::Encryption(error) => write!(f, "Encryption error: {:?}", error),
+            FileDeviceManagerError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            FileDeviceManagerError::UnsupportedVersion(version) => {
+                write!(f, "State version {} is newer than {}", version, STATE_VERSION)
+            }
+            FileDeviceManagerError::AlreadyExists => write!(f, "The state file already exists"),
+            FileDeviceManagerError::Corrupt(message) => write!(f, "Corrupt state file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FileDeviceManagerError {}
+
+impl From<std::io::Error> for FileDeviceManagerError {
+    fn from(value: std::io::Error) -> Self {
+        FileDeviceManagerError::IO(value)
+    }
+}
+
+impl From<serde_json::Error> for FileDeviceManagerError {
+    fn from(value: serde_json::Error) -> Self {
+        FileDeviceManagerError::Json(value)
+    }
+}
+
+impl From<EncryptionError> for FileDeviceManagerError {
+    fn from(value: EncryptionError) -> Self {
+        FileDeviceManagerError::Encryption(value)
+    }
+}
+
+impl From<themis::Error> for FileDeviceManagerError {
+    fn from(value: themis::Error) -> Self {
+        FileDeviceManagerError::Encryption(EncryptionError::from(value))
+    }
+}
+
+/// Keys as they are written to disk. Everything is hex encoded and the private key is sealed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKeys {
+    public_key: String,
+    private_key: String,
+    key_b: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredDevice {
+    device_id: Uuid,
+    keys: StoredKeys,
+    metadata: DeviceMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredRealm {
+    ip: IpAddr,
+    #[serde(default)]
+    realm_id: Option<Uuid>,
+    /// None until the keys have been exchanged with the realm
+    #[serde(default)]
+    keys: Option<StoredKeys>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredKdf {
+    salt: String,
+    rounds: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredState {
+    version: u64,
+    device_id: Uuid,
+    device_name: String,
+    kdf: StoredKdf,
+    key_check: String,
+    devices: Vec<StoredDevice>,
+    realms: Vec<StoredRealm>,
+}
+
+/// A device paired with this device
+pub struct FileDevice {
+    device_id: Uuid,
+    metadata: DeviceMetadata,
+    encryption: ThemisEncryptionManager,
+}
+
+impl PairedDevice<DynamicEncryptionManager> for FileDevice {
+    fn get_device_id(&self) -> &Uuid {
+        &self.device_id
+    }
+
+    fn get_encryption_manager(&self) -> DynamicEncryptionManager {
+        DynamicEncryptionManager::Themis(self.encryption.clone())
+    }
+
+    fn get_metadata(&self) -> DeviceMetadata {
+        self.metadata.clone()
+    }
+}
+
+/// A realm this device is paired with
+pub struct FileRealmConnection {
+    ip: IpAddr,
+    realm_id: Option<Uuid>,
+    encryption: Option<ThemisEncryptionManager>,
+}
+
+impl FileRealmConnection {
+    pub fn realm_id(&self) -> Option<&Uuid> {
+        self.realm_id.as_ref()
+    }
+}
+
+impl DeviceRealmConnection for FileRealmConnection {
+    type EH = DynamicEncryptionManager;
+
+    fn get_ip(&self) -> &IpAddr {
+        &self.ip
+    }
+
+    fn get_encryption_manager(&self) -> Self::EH {
+        match &self.encryption {
+            Some(encryption) => DynamicEncryptionManager::Themis(encryption.clone()),
+            None => DynamicEncryptionManager::None,
+        }
+    }
+}
+
+struct Inner {
+    stored: StoredState,
+    devices: HashMap<Uuid, Arc<FileDevice>>,
+    realms: HashMap<IpAddr, Arc<FileRealmConnection>>,
+}
+
+/// A [`DeviceManager`] that keeps the local device, paired devices and realms inside of a directory.
+///
+/// Everything is written to a single JSON file. Writes go to a temporary file that is moved over the old one.
+/// Private keys are sealed with a Themis Secure Cell under a key derived from the passphrase with PBKDF2.
+///
+/// `last_seen` is only kept in memory until the next write, [`flush`](Self::flush) or drop. So a Hello does not sync the file
+pub struct FileDeviceManager {
+    directory: PathBuf,
+    key: Bytes,
+    inner: RwLock<Inner>,
+    pair_request_handler: Option<Box<PairRequestHandler>>,
+    /// Set by `touch_last_seen`. Cleared by every save
+    unsaved: AtomicBool,
+}
+
+impl FileDeviceManager {
+    /// Creates a new device inside of the directory
+    pub fn create(
+        directory: impl Into<PathBuf>,
+        device_name: impl Into<String>,
+        passphrase: &str,
+    ) -> Result<Self, FileDeviceManagerError> {
+        let directory = directory.into();
+        if directory.join(STATE_FILE).exists() {
+            return Err(FileDeviceManagerError::AlreadyExists);
+        }
+        std::fs::create_dir_all(&directory)?;
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill(&mut salt);
+        let key = derive_key(passphrase, &salt, KDF_ROUNDS);
+        let key_check = seal(&key, KEY_CHECK, KEY_CHECK_CONTEXT)?;
+        let stored = StoredState {
+            version: STATE_VERSION,
+            device_id: Uuid::new_v4(),
+            device_name: device_name.into(),
+            kdf: StoredKdf {
+                salt: hex::encode(salt),
+                rounds: KDF_ROUNDS,
+            },
+            key_check: hex::encode(key_check),
+            devices: Vec::new(),
+            realms: Vec::new(),
+        };
+        let manager = FileDeviceManager {
+            directory,
+            key,
+            inner: RwLock::new(Inner {
+                stored,
+                devices: HashMap::new(),
+                realms: HashMap::new(),
+            }),
+            pair_request_handler: None,
+            unsaved: AtomicBool::new(false),
+        };
+        manager.save(&manager.read().stored)?;
+        info!("Created device {} in {}", manager.read().stored.device_id, manager.directory.display());
+        Ok(manager)
+    }
+
+    /// Opens the device inside of the directory
+    pub fn open(directory: impl Into<PathBuf>, passphrase: &str) -> Result<Self, FileDeviceManagerError> {
+        let directory = directory.into();
+        let file = File::open(directory.join(STATE_FILE))?;
+        let value: Value = serde_json::from_reader(file)?;
+        let stored = migrate(value)?;
+
+        let salt = decode_hex(&stored.kdf.salt)?;
+        let key = derive_key(passphrase, &salt, stored.kdf.rounds);
+        unseal(&key, &decode_hex(&stored.key_check)?, KEY_CHECK_CONTEXT)
+            .map_err(|_| FileDeviceManagerError::WrongPassphrase)?;
+
+        let mut devices = HashMap::new();
+        for device in &stored.devices {
+            let encryption = open_keys(&key, &device.keys, device.device_id.as_bytes())?;
+            devices.insert(
+                device.device_id,
+                Arc::new(FileDevice {
+                    device_id: device.device_id,
+                    metadata: device.metadata.clone(),
+                    encryption,
+                }),
+            );
+        }
+        let mut realms = HashMap::new();
+        for realm in &stored.realms {
+            let encryption = match &realm.keys {
+                Some(keys) => Some(open_keys(&key, keys, realm.ip.to_string().as_bytes())?),
+                None => None,
+            };
+            realms.insert(
+                realm.ip,
+                Arc::new(FileRealmConnection {
+                    ip: realm.ip,
+                    realm_id: realm.realm_id,
+                    encryption,
+                }),
+            );
+        }
+        Ok(FileDeviceManager {
+            directory,
+            key,
+            inner: RwLock::new(Inner {
+                stored,
+                devices,
+                realms,
+            }),
+            pair_request_handler: None,
+            unsaved: AtomicBool::new(false),
+        })
+    }
+
+    /// Sets the function that decides on pair requests. Without one every pair request is rejected
+    pub fn with_pair_request_handler(
+        mut self,
+        handler: impl Fn(&Uuid, &str, Cursor<Bytes>) -> (bool, Option<Bytes>) + Send + Sync + 'static,
+    ) -> Self {
+        self.pair_request_handler = Some(Box::new(handler));
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Writes the last seen times that are only in memory
+    pub fn flush(&self) -> Result<(), FileDeviceManagerError> {
+        let inner = self.write();
+        if self.unsaved.load(Ordering::Acquire) {
+            self.save(&inner.stored)?;
+        }
+        Ok(())
+    }
+
+    /// Renames the local device
+    pub fn set_device_name(&self, device_name: impl Into<String>) -> Result<(), FileDeviceManagerError> {
+        let mut inner = self.write();
+        inner.stored.device_name = device_name.into();
+        self.save(&inner.stored)
+    }
+
+    /// Adds or replaces a realm. `encryption` is None until the keys have been exchanged with the realm
+    pub fn add_realm(
+        &self,
+        ip: IpAddr,
+        realm_id: Option<Uuid>,
+        encryption: Option<EncryptionSet>,
+    ) -> Result<(), FileDeviceManagerError> {
+        let (keys, manager) = match encryption {
+            Some(encryption) => {
+                let manager = ThemisEncryptionManager::from(&encryption);
+                let keys = self.seal_keys(&manager, ip.to_string().as_bytes())?;
+                (Some(keys), Some(manager))
+            }
+            None => (None, None),
+        };
+        let mut inner = self.write();
+        inner.stored.realms.retain(|realm| realm.ip != ip);
+        inner.stored.realms.push(StoredRealm { ip, realm_id, keys });
+        inner.realms.insert(
+            ip,
+            Arc::new(FileRealmConnection {
+                ip,
+                realm_id,
+                encryption: manager,
+            }),
+        );
+        self.save(&inner.stored)
+    }
+
+    pub fn remove_realm(&self, ip: &IpAddr) -> Result<(), FileDeviceManagerError> {
+        let mut inner = self.write();
+        inner.stored.realms.retain(|realm| &realm.ip != ip);
+        inner.realms.remove(ip);
+        self.save(&inner.stored)
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn seal_keys(
+        &self,
+        encryption: &ThemisEncryptionManager,
+        context: &[u8],
+    ) -> Result<StoredKeys, FileDeviceManagerError> {
+        Ok(StoredKeys {
+            public_key: hex::encode(&encryption.self_public_key),
+            private_key: hex::encode(seal(&self.key, &encryption.self_private_key, context)?),
+            key_b: hex::encode(&encryption.other_public_key),
+        })
+    }
+
+    /// Writes the state to a temporary file and moves it over the old one
+    fn save(&self, stored: &StoredState) -> Result<(), FileDeviceManagerError> {
+        let path = self.directory.join(STATE_FILE);
+        let temp = self.directory.join(format!("{}.tmp", STATE_FILE));
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp)?;
+        serde_json::to_writer_pretty(&mut file, stored)?;
+        file.flush()?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp, &path)?;
+        // Make sure the rename itself is on disk
+        #[cfg(unix)]
+        File::open(&self.directory)?.sync_all()?;
+        self.unsaved.store(false, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Drop for FileDeviceManager {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            warn!("Could not save the last seen times: {}", error);
+        }
+    }
+}
+
+impl DeviceManager for FileDeviceManager {
+    type Error = FileDeviceManagerError;
+    type EH = DynamicEncryptionManager;
+    type PD = FileDevice;
+    type RealmConnection = FileRealmConnection;
+
+    fn get_device_id(&self) -> Uuid {
+        self.read().stored.device_id
+    }
+
+    fn get_device_name(&self) -> String {
+        self.read().stored.device_name.clone()
+    }
+
+    fn is_paired(&self, uuid: &Uuid) -> bool {
+        self.read().devices.contains_key(uuid)
+    }
+
+    fn get_paired_devices(&self) -> Result<Vec<Arc<Self::PD>>, Self::Error> {
+        Ok(self.read().devices.values().cloned().collect())
+    }
+
+    fn get_paired_device(&self, device_id: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        Ok(self.read().devices.get(device_id).cloned())
+    }
+
+    fn register_device(
+        &self,
+        device_id: &Uuid,
+        encryption: EncryptionSet,
+        metadata: DeviceMetadata,
+    ) -> Result<(), Self::Error> {
+        let manager = ThemisEncryptionManager::from(&encryption);
+        let keys = self.seal_keys(&manager, device_id.as_bytes())?;
+        let mut inner = self.write();
+        inner.stored.devices.retain(|device| &device.device_id != device_id);
+        inner.stored.devices.push(StoredDevice {
+            device_id: *device_id,
+            keys,
+            metadata: metadata.clone(),
+        });
+        inner.devices.insert(
+            *device_id,
+            Arc::new(FileDevice {
+                device_id: *device_id,
+                metadata,
+                encryption: manager,
+            }),
+        );
+        self.save(&inner.stored)
+    }
+
+    fn update_metadata(&self, device_id: &Uuid, metadata: DeviceMetadata) -> Result<(), Self::Error> {
+        let mut inner = self.write();
+        let device = match inner.devices.get(device_id) {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let device = Arc::new(FileDevice {
+            device_id: *device_id,
+            metadata: metadata.clone(),
+            encryption: device.encryption.clone(),
+        });
+        inner.devices.insert(*device_id, device);
+        if let Some(stored) = inner
+            .stored
+            .devices
+            .iter_mut()
+            .find(|device| &device.device_id == device_id)
+        {
+            stored.metadata = metadata;
+        }
+        self.save(&inner.stored)
+    }
+
+    fn touch_last_seen(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        let mut inner = self.write();
+        let device = match inner.devices.get(device_id) {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let metadata = device.metadata.clone().seen();
+        let last_seen = metadata.last_seen;
+        let device = Arc::new(FileDevice {
+            device_id: *device_id,
+            metadata,
+            encryption: device.encryption.clone(),
+        });
+        inner.devices.insert(*device_id, device);
+        if let Some(stored) = inner
+            .stored
+            .devices
+            .iter_mut()
+            .find(|device| &device.device_id == device_id)
+        {
+            stored.metadata.last_seen = last_seen;
+        }
+        self.unsaved.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn delete_device(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        let mut inner = self.write();
+        inner.stored.devices.retain(|device| &device.device_id != device_id);
+        inner.devices.remove(device_id);
+        self.save(&inner.stored)
+    }
+
+    fn export_bundle(&self) -> Result<PairingBundle, Self::Error> {
+        let inner = self.read();
+        Ok(PairingBundle {
+            device_id: inner.stored.device_id,
+            device_name: inner.stored.device_name.clone(),
+            devices: inner
+                .devices
+                .values()
+                .map(|device| BundledDevice {
+                    device_id: device.device_id,
+                    encryption: device.encryption.clone(),
+                    metadata: device.metadata.clone(),
+                })
+                .collect(),
+            realms: inner
+                .realms
+                .values()
+                .map(|realm| BundledRealm {
+                    ip: realm.ip,
+                    realm_id: realm.realm_id,
+                    encryption: realm.encryption.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    fn import_bundle(&self, bundle: PairingBundle) -> Result<(), Self::Error> {
+        // Seal everything under this directory's key before touching the state
+        let mut stored_devices = Vec::with_capacity(bundle.devices.len());
+        let mut devices = HashMap::new();
+        for device in bundle.devices {
+            stored_devices.push(StoredDevice {
+                device_id: device.device_id,
+                keys: self.seal_keys(&device.encryption, device.device_id.as_bytes())?,
+                metadata: device.metadata.clone(),
+            });
+            devices.insert(
+                device.device_id,
+                Arc::new(FileDevice {
+                    device_id: device.device_id,
+                    metadata: device.metadata,
+                    encryption: device.encryption,
+                }),
+            );
+        }
+        let mut stored_realms = Vec::with_capacity(bundle.realms.len());
+        let mut realms = HashMap::new();
+        for realm in bundle.realms {
+            let keys = match &realm.encryption {
+                Some(encryption) => Some(self.seal_keys(encryption, realm.ip.to_string().as_bytes())?),
+                None => None,
+            };
+            stored_realms.push(StoredRealm {
+                ip: realm.ip,
+                realm_id: realm.realm_id,
+                keys,
+            });
+            realms.insert(
+                realm.ip,
+                Arc::new(FileRealmConnection {
+                    ip: realm.ip,
+                    realm_id: realm.realm_id,
+                    encryption: realm.encryption,
+                }),
+            );
+        }
+
+        let mut inner = self.write();
+        let mut stored = inner.stored.clone();
+        stored.device_id = bundle.device_id;
+        stored.device_name = bundle.device_name;
+        stored.devices = stored_devices;
+        stored.realms = stored_realms;
+        self.save(&stored)?;
+        info!("Imported device {} into {}", stored.device_id, self.directory.display());
+        *inner = Inner {
+            stored,
+            devices,
+            realms,
+        };
+        Ok(())
+    }
+
+    fn pair_request(
+        &self,
+        device_id: &Uuid,
+        device_name: &str,
+        cursor: Cursor<Bytes>,
+    ) -> Result<(bool, Option<Bytes>), Self::Error> {
+        let handler = match &self.pair_request_handler {
+            Some(handler) => handler,
+            None => {
+                warn!("Rejected pair request from {}. No pair request handler is set", device_id);
+                return Ok((false, None));
+            }
+        };
+        Ok(handler(device_id, device_name, cursor))
+    }
+
+    fn get_connected_realms(&self) -> Result<Vec<Arc<Self::RealmConnection>>, Self::Error> {
+        Ok(self.read().realms.values().cloned().collect())
+    }
+
+    fn get_realm_by_ip(&self, realm: IpAddr) -> Result<Option<Arc<Self::RealmConnection>>, Self::Error> {
+        Ok(self.read().realms.get(&realm).cloned())
+    }
+}
+
+/// Upgrades the state file to [`STATE_VERSION`]
+fn migrate(mut value: Value) -> Result<StoredState, FileDeviceManagerError> {
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| FileDeviceManagerError::Corrupt("missing version".to_string()))?;
+    if version > STATE_VERSION {
+        return Err(FileDeviceManagerError::UnsupportedVersion(version));
+    }
+    // Each migration takes the value up a single version
+    if version < 2 {
+        migrate_v1(&mut value);
+    }
+    value["version"] = Value::from(STATE_VERSION);
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Version 1 kept the name and pairing time next to the keys. Version 2 moves them into the metadata
+fn migrate_v1(value: &mut Value) {
+    if let Some(devices) = value.get_mut("devices").and_then(Value::as_array_mut) {
+        for device in devices.iter_mut().filter_map(Value::as_object_mut) {
+            let mut metadata = serde_json::Map::new();
+            metadata.insert("name".to_string(), device.remove("name").unwrap_or(Value::Null));
+            metadata.insert(
+                "paired_at".to_string(),
+                device.remove("paired_at").unwrap_or_else(|| Value::from(0)),
+            );
+            device.insert("metadata".to_string(), Value::Object(metadata));
+        }
+    }
+}
+
+fn cell(key: &[u8]) -> Result<SecureCellSeal, FileDeviceManagerError> {
+    Ok(SecureCell::with_key(key)?.seal())
+}
+
+fn seal(key: &[u8], message: &[u8], context: &[u8]) -> Result<Vec<u8>, FileDeviceManagerError> {
+    Ok(cell(key)?.encrypt_with_context(message, context)?)
+}
+
+fn unseal(key: &[u8], message: &[u8], context: &[u8]) -> Result<Vec<u8>, FileDeviceManagerError> {
+    Ok(cell(key)?.decrypt_with_context(message, context)?)
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, FileDeviceManagerError> {
+    hex::decode(value).map_err(|error| FileDeviceManagerError::Corrupt(error.to_string()))
+}
+
+fn open_keys(key: &[u8], keys: &StoredKeys, context: &[u8]) -> Result<ThemisEncryptionManager, FileDeviceManagerError> {
+    let private_key = unseal(key, &decode_hex(&keys.private_key)?, context)?;
+    Ok(ThemisEncryptionManager {
+        self_private_key: Bytes::from(private_key),
+        self_public_key: Bytes::from(decode_hex(&keys.public_key)?),
+        other_public_key: Bytes::from(decode_hex(&keys.key_b)?),
+    })
+}
@@ -0,0 +1,310 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use hmac::Hmac;
+use sha2::Sha256;
+
+use crate::encryption::{EncryptionManager, EncryptionSet};
+use uuid::Uuid;
+use crate::realm::DeviceRealmConnection;
+
+/// Passphrase encrypted export of the local identity and paired devices. For moving a device to another machine
+pub mod bundle;
+/// A DeviceManager that keeps everything inside of a directory
+pub mod file;
+
+use bundle::PairingBundle;
+
+/// The Manger of Paired Devices
+///
+/// Lookups hand out owned [`Arc`] handles and every method takes `&self`.
+/// So the manager can be backed by a database or a locked map shared between connection tasks
+pub trait DeviceManager {
+    /// Error that can be returned
+    type Error;
+    ///  Encryption Manager
+    type EH: EncryptionManager;
+    /// The Paired Device Type
+    type PD: PairedDevice<Self::EH>;
+
+    /// The device side of a realm this device is paired with
+    type RealmConnection: DeviceRealmConnection<EH=Self::EH>;
+    /// Gets the Current Device ID
+    fn get_device_id(&self) -> Uuid;
+    /// Gets the current device name
+    fn get_device_name(&self) -> String;
+    /// Rather or not the uuid is paired
+    fn is_paired(&self, uuid: &Uuid) -> bool;
+    /// Gets the paired devices
+    fn get_paired_devices(&self) -> Result<Vec<Arc<Self::PD>>, Self::Error>;
+    /// Gets the paired device
+    ///
+    /// # Returns
+    /// None if the device is not paired
+    fn get_paired_device(&self, device_id: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error>;
+    /// Registers a device after successful pairing
+    fn register_device(
+        &self,
+        device_id: &Uuid,
+        encryption: EncryptionSet,
+        metadata: DeviceMetadata,
+    ) -> Result<(), Self::Error>;
+    /// Replaces the metadata of a paired device. Used to change the trust level
+    fn update_metadata(&self, device_id: &Uuid, metadata: DeviceMetadata) -> Result<(), Self::Error>;
+    /// Sets `last_seen` of a paired device to now. Called on every Hello.
+    ///
+    /// Nothing else in the metadata is touched. So a trust level change made at the same time is kept.
+    /// It does not have to be written to disk right away
+    fn touch_last_seen(&self, device_id: &Uuid) -> Result<(), Self::Error>;
+    /// Removes a device from the paired devices
+    fn delete_device(&self, device_id: &Uuid) -> Result<(), Self::Error>;
+    /// Exports the device id, name, paired devices and realms. Seal it with [`PairingBundle::seal`] before it leaves the process
+    fn export_bundle(&self) -> Result<PairingBundle, Self::Error>;
+    /// Replaces the local identity, paired devices and realms with the ones inside of the bundle
+    fn import_bundle(&self, bundle: PairingBundle) -> Result<(), Self::Error>;
+
+    /// This is called by the handler when a pair request happens. It is your job to ask the user if they want to pair.
+    ///
+    /// # Returns
+    /// Returns true if the user accepted the pairing request.
+    /// Returns false if the user rejected the pairing request.
+    /// Option<Bytes> is the Test value defined in send key.
+    fn pair_request(
+        &self,
+        device_id: &Uuid,
+        device_name: &str,
+        cursor: Cursor<Bytes>,
+    ) -> Result<(bool, Option<Bytes>), Self::Error>;
+
+    fn get_connected_realms(&self) -> Result<Vec<Arc<Self::RealmConnection>>, Self::Error>;
+    /// # Returns
+    /// None if there is no realm connection for the address
+    fn get_realm_by_ip(&self, realm: IpAddr) -> Result<Option<Arc<Self::RealmConnection>>, Self::Error>;
+}
+
+/// The async version of [`DeviceManager`]. For managers that talk to a database or wait on the user
+///
+/// Every [`DeviceManager`] that is `Send + Sync` implements this as well
+#[async_trait]
+pub trait AsyncDeviceManager: Send + Sync {
+    /// Error that can be returned
+    type Error: Send;
+    ///  Encryption Manager
+    type EH: EncryptionManager;
+    /// The Paired Device Type
+    type PD: PairedDevice<Self::EH> + Send + Sync;
+
+    /// The device side of a realm this device is paired with
+    type RealmConnection: DeviceRealmConnection<EH=Self::EH> + Send + Sync;
+    /// Gets the Current Device ID
+    async fn get_device_id(&self) -> Uuid;
+    /// Gets the current device name
+    async fn get_device_name(&self) -> String;
+    /// Rather or not the uuid is paired
+    async fn is_paired(&self, uuid: &Uuid) -> bool;
+    /// Gets the paired devices
+    async fn get_paired_devices(&self) -> Result<Vec<Arc<Self::PD>>, Self::Error>;
+    /// Gets the paired device
+    ///
+    /// # Returns
+    /// None if the device is not paired
+    async fn get_paired_device(&self, device_id: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error>;
+    /// Registers a device after successful pairing
+    async fn register_device(
+        &self,
+        device_id: &Uuid,
+        encryption: EncryptionSet,
+        metadata: DeviceMetadata,
+    ) -> Result<(), Self::Error>;
+    /// Replaces the metadata of a paired device
+    async fn update_metadata(&self, device_id: &Uuid, metadata: DeviceMetadata) -> Result<(), Self::Error>;
+    /// See [`DeviceManager::touch_last_seen`]
+    async fn touch_last_seen(&self, device_id: &Uuid) -> Result<(), Self::Error>;
+    /// Removes a device from the paired devices
+    async fn delete_device(&self, device_id: &Uuid) -> Result<(), Self::Error>;
+    /// See [`DeviceManager::export_bundle`]
+    async fn export_bundle(&self) -> Result<PairingBundle, Self::Error>;
+    /// See [`DeviceManager::import_bundle`]
+    async fn import_bundle(&self, bundle: PairingBundle) -> Result<(), Self::Error>;
+
+    /// See [`DeviceManager::pair_request`]. The handler awaits this while the user decides
+    async fn pair_request(
+        &self,
+        device_id: &Uuid,
+        device_name: &str,
+        cursor: Cursor<Bytes>,
+    ) -> Result<(bool, Option<Bytes>), Self::Error>;
+
+    async fn get_connected_realms(&self) -> Result<Vec<Arc<Self::RealmConnection>>, Self::Error>;
+    /// # Returns
+    /// None if there is no realm connection for the address
+    async fn get_realm_by_ip(&self, realm: IpAddr) -> Result<Option<Arc<Self::RealmConnection>>, Self::Error>;
+}
+
+#[async_trait]
+impl<T> AsyncDeviceManager for T
+    where
+        T: DeviceManager + Send + Sync,
+        T::Error: Send,
+        T::PD: Send + Sync,
+        T::RealmConnection: Send + Sync,
+{
+    type Error = T::Error;
+    type EH = T::EH;
+    type PD = T::PD;
+    type RealmConnection = T::RealmConnection;
+
+    async fn get_device_id(&self) -> Uuid {
+        DeviceManager::get_device_id(self)
+    }
+
+    async fn get_device_name(&self) -> String {
+        DeviceManager::get_device_name(self)
+    }
+
+    async fn is_paired(&self, uuid: &Uuid) -> bool {
+        DeviceManager::is_paired(self, uuid)
+    }
+
+    async fn get_paired_devices(&self) -> Result<Vec<Arc<Self::PD>>, Self::Error> {
+        DeviceManager::get_paired_devices(self)
+    }
+
+    async fn get_paired_device(&self, device_id: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        DeviceManager::get_paired_device(self, device_id)
+    }
+
+    async fn register_device(
+        &self,
+        device_id: &Uuid,
+        encryption: EncryptionSet,
+        metadata: DeviceMetadata,
+    ) -> Result<(), Self::Error> {
+        DeviceManager::register_device(self, device_id, encryption, metadata)
+    }
+
+    async fn update_metadata(&self, device_id: &Uuid, metadata: DeviceMetadata) -> Result<(), Self::Error> {
+        DeviceManager::update_metadata(self, device_id, metadata)
+    }
+
+    async fn touch_last_seen(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        DeviceManager::touch_last_seen(self, device_id)
+    }
+
+    async fn delete_device(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        DeviceManager::delete_device(self, device_id)
+    }
+
+    async fn export_bundle(&self) -> Result<PairingBundle, Self::Error> {
+        DeviceManager::export_bundle(self)
+    }
+
+    async fn import_bundle(&self, bundle: PairingBundle) -> Result<(), Self::Error> {
+        DeviceManager::import_bundle(self, bundle)
+    }
+
+    async fn pair_request(
+        &self,
+        device_id: &Uuid,
+        device_name: &str,
+        cursor: Cursor<Bytes>,
+    ) -> Result<(bool, Option<Bytes>), Self::Error> {
+        DeviceManager::pair_request(self, device_id, device_name, cursor)
+    }
+
+    async fn get_connected_realms(&self) -> Result<Vec<Arc<Self::RealmConnection>>, Self::Error> {
+        DeviceManager::get_connected_realms(self)
+    }
+
+    async fn get_realm_by_ip(&self, realm: IpAddr) -> Result<Option<Arc<Self::RealmConnection>>, Self::Error> {
+        DeviceManager::get_realm_by_ip(self, realm)
+    }
+}
+
+/// How much a paired device is trusted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrustLevel {
+    /// The user compared the short authentication string on both devices
+    Verified,
+    /// Paired but never verified. Every device starts here
+    #[default]
+    Unverified,
+    /// The handler refuses every Hello from the device
+    Blocked,
+}
+
+/// What is known about a paired device
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceMetadata {
+    /// The name sent inside of the PairRequest
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Seconds since the unix epoch
+    #[serde(default)]
+    pub paired_at: u64,
+    /// Seconds since the unix epoch. Updated on every Hello with [`DeviceManager::touch_last_seen`]
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    #[serde(default)]
+    pub device_type: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub trust_level: TrustLevel,
+}
+
+impl DeviceMetadata {
+    /// Metadata for a device that was just paired
+    pub fn new(name: Option<String>) -> Self {
+        let now = unix_now();
+        DeviceMetadata {
+            name,
+            paired_at: now,
+            last_seen: Some(now),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.trust_level == TrustLevel::Blocked
+    }
+
+    /// Sets `last_seen` to now
+    pub fn seen(mut self) -> Self {
+        self.last_seen = Some(unix_now());
+        self
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// PBKDF2-HMAC-SHA256. Used for the state file and bundles
+pub(crate) fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> Bytes {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    Bytes::copy_from_slice(&key)
+}
+
+/// The Paired Device
+pub trait PairedDevice<EH: EncryptionManager> {
+    /// The device UUID
+    fn get_device_id(&self) -> &Uuid;
+    /// An Encryption Manager.
+    /// This value is owned by the caller
+    fn get_encryption_manager(&self) -> EH;
+    /// The metadata recorded when the device was paired
+    fn get_metadata(&self) -> DeviceMetadata;
+
+    fn get_trust_level(&self) -> TrustLevel {
+        self.get_metadata().trust_level
+    }
+}
@@ -1,5 +1,6 @@
 use bytes::Bytes;
-use themis::keys::{EcdsaPrivateKey, EcdsaPublicKey};
+use themis::keys::{EcdsaKeyPair, EcdsaPrivateKey, EcdsaPublicKey};
+use themis::secure_message::SecureMessage;
 use uuid::Uuid;
 pub struct EncryptionSet {
     pub public_key: EcdsaPublicKey,
@@ -15,6 +16,7 @@ impl From<themis::Error> for EncryptionError {
         EncryptionError::ThemisError
     }
 }
+#[derive(Clone)]
 pub enum DynamicEncryptionManager {
     Themis(ThemisEncryptionManager),
     None,
@@ -22,12 +24,18 @@ pub enum DynamicEncryptionManager {
 impl EncryptionManager for DynamicEncryptionManager {
     type Error = EncryptionError;
 
-    fn decrypt_message(&self, _message: Bytes) -> Result<Bytes, Self::Error> {
-        todo!()
+    fn decrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        match self {
+            DynamicEncryptionManager::Themis(themis) => themis.decrypt_message(message),
+            DynamicEncryptionManager::None => todo!(),
+        }
     }
 
-    fn encrypt_message(&self, _message: Bytes) -> Result<Bytes, Self::Error> {
-        todo!()
+    fn encrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        match self {
+            DynamicEncryptionManager::Themis(themis) => themis.encrypt_message(message),
+            DynamicEncryptionManager::None => todo!(),
+        }
     }
 }
 
@@ -44,6 +52,38 @@ pub struct ThemisEncryptionManager {
     pub other_public_key: Bytes,
 }
 
+impl From<&EncryptionSet> for ThemisEncryptionManager {
+    fn from(encryption: &EncryptionSet) -> Self {
+        ThemisEncryptionManager {
+            self_private_key: Bytes::copy_from_slice(encryption.private_key.as_ref()),
+            self_public_key: Bytes::copy_from_slice(encryption.public_key.as_ref()),
+            other_public_key: Bytes::copy_from_slice(encryption.key_b.as_ref()),
+        }
+    }
+}
+
+impl ThemisEncryptionManager {
+    fn secure_message(&self) -> Result<SecureMessage, EncryptionError> {
+        let private_key = EcdsaPrivateKey::try_from_slice(self.self_private_key.as_ref())?;
+        let other_public_key = EcdsaPublicKey::try_from_slice(self.other_public_key.as_ref())?;
+        Ok(SecureMessage::new(EcdsaKeyPair::join(private_key, other_public_key)))
+    }
+}
+
+impl EncryptionManager for ThemisEncryptionManager {
+    type Error = EncryptionError;
+
+    fn decrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        let decrypted = self.secure_message()?.decrypt(message.as_ref())?;
+        Ok(Bytes::from(decrypted))
+    }
+
+    fn encrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        let encrypted = self.secure_message()?.encrypt(message.as_ref())?;
+        Ok(Bytes::from(encrypted))
+    }
+}
+
 pub struct ThemisEncryptionSession {
     server_id: Uuid,
 }
@@ -14,6 +14,9 @@ pub mod packets;
 /// Such as Device to Device. DTDViaRealm.
 pub mod protocol;
 pub mod realm;
+/// In-memory DeviceManager and Realm for tests. Every call is recorded so tests can check what the handlers asked for
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 use bytes::Bytes;
 pub use error::Error;
@@ -0,0 +1,144 @@
+use bytes::Bytes;
+use packet::{PacketContent, PacketReadError, PacketWriteError};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, Write};
+
+/// The wire format version this crate speaks
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The oldest version this crate still talks to
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Themis Secure Message with the keys exchanged while pairing
+pub const SUITE_THEMIS_SECURE_MESSAGE: u8 = 1;
+
+pub const DEVICE_TO_DEVICE: u8 = 0x00;
+pub const DEVICE_TO_REALM: u8 = 0x02;
+pub const REALM_TO_REALM: u8 = 0x03;
+
+/// What a device or Realm supports. Sent inside of Hello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The highest wire format version the sender speaks
+    pub version: u8,
+    /// Encryption suite ids in order of preference
+    pub encryption_suites: Vec<u8>,
+    /// Protocol ids the sender handles
+    pub protocols: Vec<u8>,
+}
+
+impl Default for Capabilities {
+    /// A device. Talks to other devices and to Realms
+    fn default() -> Self {
+        Capabilities::new(vec![DEVICE_TO_DEVICE, DEVICE_TO_REALM])
+    }
+}
+
+impl Capabilities {
+    /// The current version and every suite this crate implements
+    pub fn new(protocols: Vec<u8>) -> Self {
+        Capabilities {
+            version: PROTOCOL_VERSION,
+            encryption_suites: vec![SUITE_THEMIS_SECURE_MESSAGE],
+            protocols,
+        }
+    }
+
+    /// A Realm. Talks to devices and other Realms
+    pub fn realm() -> Self {
+        Capabilities::new(vec![DEVICE_TO_REALM, REALM_TO_REALM])
+    }
+
+    /// Picks what both sides support. Our order of preference wins for the encryption suite
+    pub fn negotiate(&self, other: &Capabilities) -> Result<Negotiated, Incompatible> {
+        let version = self.version.min(other.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(Incompatible::Version(other.version));
+        }
+        let encryption_suite = self
+            .encryption_suites
+            .iter()
+            .find(|suite| other.encryption_suites.contains(suite))
+            .copied()
+            .ok_or(Incompatible::NoCommonEncryptionSuite)?;
+        let protocols: Vec<u8> = self
+            .protocols
+            .iter()
+            .filter(|protocol| other.protocols.contains(protocol))
+            .copied()
+            .collect();
+        if protocols.is_empty() {
+            return Err(Incompatible::NoCommonProtocol);
+        }
+        Ok(Negotiated {
+            version,
+            encryption_suite,
+            protocols,
+        })
+    }
+}
+
+/// Read as two bins after the version. So a list of ids costs a byte per id
+impl PacketContent for Capabilities {
+    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
+        Ok(Capabilities {
+            version: u8::read(reader)?,
+            encryption_suites: Bytes::read(reader)?.to_vec(),
+            protocols: Bytes::read(reader)?.to_vec(),
+        })
+    }
+
+    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
+        self.version.write(writer)?;
+        rmp::encode::write_bin(writer, &self.encryption_suites)?;
+        rmp::encode::write_bin(writer, &self.protocols)?;
+        Ok(())
+    }
+}
+
+/// What both sides of a connection agreed on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u8,
+    pub encryption_suite: u8,
+    pub protocols: Vec<u8>,
+}
+
+impl Negotiated {
+    pub fn supports(&self, protocol: u8) -> bool {
+        self.protocols.contains(&protocol)
+    }
+
+    /// Sent back inside of Hello. The other side negotiating against this ends up with the same result
+    pub fn to_capabilities(&self) -> Capabilities {
+        Capabilities {
+            version: self.version,
+            encryption_suites: vec![self.encryption_suite],
+            protocols: self.protocols.clone(),
+        }
+    }
+}
+
+/// Why two sides can not talk to each other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatible {
+    /// The other side only speaks this version
+    Version(u8),
+    NoCommonEncryptionSuite,
+    NoCommonProtocol,
+}
+
+impl Display for Incompatible {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Incompatible::Version(version) => write!(
+                f,
+                "Version {} is older than {}",
+                version, MIN_PROTOCOL_VERSION
+            ),
+            Incompatible::NoCommonEncryptionSuite => write!(f, "No common encryption suite"),
+            Incompatible::NoCommonProtocol => write!(f, "No common protocol"),
+        }
+    }
+}
+
+impl std::error::Error for Incompatible {}
@@ -1,6 +1,7 @@
 use bytes::Bytes;
 use packet::Packet;
 use uuid::Uuid;
+use crate::packets::capabilities::Capabilities;
 use crate::packets::ErrorPacket;
 
 /// Device to Device Packets
@@ -18,6 +19,11 @@ pub enum DeviceToDevicePackets {
         device_id: Uuid,
         /// If the device is paired or not
         paired: bool,
+        /// The device you want to talk to. For when the other side hosts several devices.
+        /// None picks whatever device the other side uses by default
+        target: Option<Uuid>,
+        /// The version, encryption suites and protocols you support
+        capabilities: Capabilities,
     },
     /// Device ID and the Byte Array containing the Public Key
     #[packet(packet_id = 3)]
@@ -30,20 +36,22 @@ pub enum DeviceToDevicePackets {
     /// Send to the other device. They will use this key to send data to you.
     ///
     /// The Optional Test.
-    /// If you want to ensure that your key is the one sent to the other side.
-    /// Pass a String for the test. This must be encrypted with the same key.
-    /// On the other side they will need to know this string. They will use the public key provided to encrypt the known string
-    /// If they are test the encrypted test and the known string(encrypted) are the same. The key has not been compromised.
+    /// If you want to ensure that your key is the one the other side received.
+    /// The device that sent the PairRequest answers the first SendKey with its own key and the test encrypted with the shared keys.
+    /// The device that accepted the PairRequest must know the same string. It decrypts the test and compares the two.
+    /// The first SendKey never has a test. There is no key to encrypt it to yet.
     ///
-    /// Once the other side has a received the key they will use the public key to send you their key. And at this point both devices are paired
-    /// They can move onto the Key Check to mark the session as secure.
+    /// Once both keys have been sent the devices move onto the Key Check to mark the session as secure.
     #[packet(packet_id = 4)]
     SendKey {
         public_key: Bytes,
         test: Option<Bytes>,
     },
-    /// Send to the other device. Only encrypt the data inside the packets. leave the packet id and protocol id alone.
-    /// The other side will make sure they can decrypt the data. and do the same result back to you. At this point your session is secure.
+    /// Random bytes encrypted with the shared keys. Only encrypt the data inside the packets. leave the packet id and protocol id alone.
+    /// The other side decrypts them and sends them back inside of a KeyCheck. Then it is answered with a KeyCheckResponse.
+    ///
+    /// Both sides check each other. The device that answered first sends its own KeyCheck once it gets `KeyCheckResponse(true)`.
+    /// The session is secure once both checks passed
     #[packet(packet_id = 5)]
     KeyCheck(Bytes),
     #[packet(packet_id = 6)]
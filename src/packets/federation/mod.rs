@@ -0,0 +1,51 @@
+use bytes::Bytes;
+use packet::Packet;
+use uuid::Uuid;
+use crate::packets::ErrorPacket;
+
+/// Realm to Realm Packets. Used between realms that are federated with each other
+#[derive(Clone, Packet)]
+pub enum FederationPacket {
+    #[packet(packet_id = 0)]
+    Heartbeat,
+    #[packet(packet_id = 1)]
+    Error(ErrorPacket),
+    /// Sent by the realm opening the link
+    #[packet(packet_id = 2)]
+    Hello {
+        realm_id: Uuid,
+    },
+    /// Random bytes encrypted with the keys shared between the two realms.
+    /// The other side decrypts them and sends them back inside of a KeyCheckAnswer.
+    ///
+    /// Both realms send one. The link is connected once both have been answered
+    #[packet(packet_id = 3)]
+    KeyCheck(Bytes),
+    /// If the KeyCheckAnswer matched the bytes of the KeyCheck
+    #[packet(packet_id = 4)]
+    KeyCheckResponse(bool),
+    /// The device can be reached through the sending realm
+    #[packet(packet_id = 5)]
+    RouteAnnounce {
+        device_id: Uuid,
+        /// The number of realms between the sending realm and the device. 0 if the device is connected to the sending realm
+        hops: u8,
+    },
+    /// The device can no longer be reached through the sending realm
+    #[packet(packet_id = 6)]
+    RouteWithdraw {
+        device_id: Uuid,
+    },
+    /// A DeviceProxy that is being forwarded to the realm the target is connected to
+    #[packet(packet_id = 7)]
+    Forward {
+        source: Uuid,
+        target: Uuid,
+        /// The number of realms the frame has already passed through
+        hops: u8,
+        payload: Bytes,
+    },
+    /// The decrypted bytes of a KeyCheck encrypted again
+    #[packet(packet_id = 8)]
+    KeyCheckAnswer(Bytes),
+}
@@ -0,0 +1,42 @@
+use crate::packets::handlers::PairingAborted;
+use crate::packets::ErrorPacket;
+use crate::protocol::ConnectionStatusKind;
+use uuid::Uuid;
+
+/// Something that happened on a connection. `device_id` is always the other device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// `from` is None for a connection that was just created
+    StatusChanged {
+        device_id: Uuid,
+        from: Option<ConnectionStatusKind>,
+        to: ConnectionStatusKind,
+    },
+    /// Both key checks passed and the device was registered
+    Paired { device_id: Uuid },
+    PairingAborted(PairingAborted),
+    /// The key check of an already paired device failed
+    KeyCheckFailed { device_id: Uuid },
+    /// The other device sent an Error packet
+    ErrorReceived {
+        device_id: Option<Uuid>,
+        error: ErrorPacket,
+    },
+    Disconnected { device_id: Uuid },
+}
+
+/// Told about every [`ConnectionEvent`]. Called from inside of the handler so do not block
+///
+/// Implemented for closures. To get a channel pass `move |event| { sender.send(event).ok(); }`
+pub trait ConnectionObserver: Send + Sync {
+    fn on_event(&self, event: ConnectionEvent);
+}
+
+impl<F> ConnectionObserver for F
+    where
+        F: Fn(ConnectionEvent) + Send + Sync,
+{
+    fn on_event(&self, event: ConnectionEvent) {
+        self(event)
+    }
+}
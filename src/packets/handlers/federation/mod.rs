@@ -0,0 +1,399 @@
+use crate::encryption::EncryptionManager;
+use crate::packets::capabilities::REALM_TO_REALM;
+use crate::packets::federation::FederationPacket;
+use crate::packets::realm::RealmPacket;
+use crate::packets::{ErrorPacket, Protocol};
+use crate::realm::acl::{AccessControl, AclAction};
+use crate::realm::federation::{Route, RouteTable, MAX_HOPS};
+use crate::realm::limits::RateLimiter;
+use crate::realm::Realm;
+use bytes::Bytes;
+use log::{debug, warn};
+use packet::packet::Packet;
+use rand::Rng;
+use uuid::Uuid;
+
+/// Responses the Federation Handler can return
+pub enum FederationResponse {
+    /// Send these messages back over the link
+    Messages(Vec<Protocol>),
+    /// Send this message to a device connected to this Realm
+    Deliver {
+        target: Uuid,
+        message: Protocol,
+    },
+    /// Send this message to another federated Realm
+    SendToRealm {
+        realm_id: Uuid,
+        message: Protocol,
+    },
+    /// Send these messages to every connected federated Realm except `except`
+    Broadcast {
+        except: Option<Uuid>,
+        messages: Vec<Protocol>,
+    },
+    Nothing,
+}
+
+/// The state of a link to another Realm
+pub enum FederationStatus {
+    /// Hello has been sent or received
+    Entry,
+    /// Both Realms are checking that the other one has the shared keys
+    CheckingKeys {
+        /// Sent to the other Realm inside of a KeyCheck
+        random_bytes: Bytes,
+        /// The other Realm sent `random_bytes` back
+        verified: bool,
+        /// The KeyCheck of the other Realm has been answered
+        answered: bool,
+    },
+    Connected,
+}
+
+/// Context for a link between two Realms
+pub struct FederationContext {
+    /// The Realm on the other side of the link
+    pub peer_id: Uuid,
+    pub status: FederationStatus,
+}
+
+impl FederationContext {
+    /// The context for a link opened by this Realm. Send [`FederationHandler::hello`] after creating it
+    pub fn outgoing(peer_id: Uuid) -> Self {
+        FederationContext {
+            peer_id,
+            status: FederationStatus::Entry,
+        }
+    }
+    /// The context for a link opened by another Realm. The peer id is set once the Hello is received.
+    /// Nothing but Hello is accepted before that
+    pub fn incoming() -> Self {
+        FederationContext {
+            peer_id: Uuid::nil(),
+            status: FederationStatus::Entry,
+        }
+    }
+    pub fn is_connected(&self) -> bool {
+        matches!(self.status, FederationStatus::Connected)
+    }
+}
+
+/// The Protocol Handler for links between federated Realms.
+///
+/// Routes are learnt from RouteAnnounce packets and stored inside of the shared [`RouteTable`].
+/// The [`RealmHandler`](super::realm::RealmHandler) uses the same table to forward DeviceProxy packets.
+///
+/// Forwarded frames go through the same access control and rate limiter as DeviceProxy packets of local devices.
+pub struct FederationHandler<'realm, R: Realm> {
+    realm: &'realm R,
+    routes: &'realm RouteTable,
+    access_control: AccessControl,
+    rate_limiter: Option<&'realm RateLimiter>,
+}
+
+impl<'realm, R: Realm> FederationHandler<'realm, R>
+    where
+        R::Error: From<<R::EH as EncryptionManager>::Error>,
+{
+    pub fn new(realm: &'realm R, routes: &'realm RouteTable, access_control: AccessControl) -> Self {
+        FederationHandler {
+            realm,
+            routes,
+            access_control,
+            rate_limiter: None,
+        }
+    }
+
+    /// Forward packets will be checked against the rate limiter by their source and target
+    pub fn with_rate_limiter(mut self, rate_limiter: &'realm RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn access_control(&self) -> &AccessControl {
+        &self.access_control
+    }
+
+    pub fn access_control_mut(&mut self) -> &mut AccessControl {
+        &mut self.access_control
+    }
+
+    /// The first message of a link opened by this Realm
+    pub fn hello(&self) -> Protocol {
+        FederationPacket::Hello {
+            realm_id: self.realm.get_realm_id(),
+        }
+            .into()
+    }
+
+    /// Announces a device that connected to this Realm to every federated Realm.
+    ///
+    /// Devices connected before a link was established need to be announced over the new link as well
+    pub fn announce_local_device(&self, device_id: Uuid) -> FederationResponse {
+        FederationResponse::Broadcast {
+            except: None,
+            messages: vec![FederationPacket::RouteAnnounce { device_id, hops: 0 }.into()],
+        }
+    }
+
+    /// Withdraws a device that disconnected from this Realm
+    pub fn withdraw_local_device(&self, device_id: Uuid) -> FederationResponse {
+        FederationResponse::Broadcast {
+            except: None,
+            messages: vec![FederationPacket::RouteWithdraw { device_id }.into()],
+        }
+    }
+
+    /// Call this once the link is closed. Every route through the Realm is removed
+    pub fn link_closed(&self, context: &FederationContext) -> FederationResponse {
+        let removed = self.routes.remove_via(&context.peer_id);
+        if removed.is_empty() {
+            return FederationResponse::Nothing;
+        }
+        FederationResponse::Broadcast {
+            except: Some(context.peer_id),
+            messages: removed
+                .into_iter()
+                .map(|device_id| FederationPacket::RouteWithdraw { device_id }.into())
+                .collect(),
+        }
+    }
+
+    /// Handles a packet sent over a link between two Realms
+    pub fn handle_packet(
+        &mut self,
+        packet: FederationPacket,
+        context: &mut FederationContext,
+    ) -> Result<FederationResponse, R::Error> {
+        let packet_id = packet.get_packet_id();
+        // An incoming link has to say who it is first
+        if context.peer_id.is_nil() && !matches!(packet, FederationPacket::Hello { .. }) {
+            return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id)));
+        }
+        match packet {
+            FederationPacket::Hello { realm_id } => {
+                if !context.peer_id.is_nil() || !matches!(context.status, FederationStatus::Entry) {
+                    return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id)));
+                }
+                let manager = match self.realm.get_peer_realm(&realm_id)? {
+                    Some(manager) => manager,
+                    None => {
+                        return Ok(Self::error(ErrorPacket::unknown_device(REALM_TO_REALM, packet_id)));
+                    }
+                };
+                context.peer_id = realm_id;
+                let (random_bytes, key_check) = Self::key_check(&manager)?;
+                context.status = FederationStatus::CheckingKeys {
+                    random_bytes,
+                    verified: false,
+                    answered: false,
+                };
+                Ok(FederationResponse::Messages(vec![key_check]))
+            }
+            FederationPacket::KeyCheck(random_check) => {
+                let manager = match self.realm.get_peer_realm(&context.peer_id)? {
+                    Some(manager) => manager,
+                    None => {
+                        return Ok(Self::error(ErrorPacket::unknown_device(REALM_TO_REALM, packet_id)));
+                    }
+                };
+                let answer = FederationPacket::KeyCheckAnswer(manager.encrypt_message(manager.decrypt_message(random_check)?)?);
+                match &mut context.status {
+                    FederationStatus::Entry => {
+                        // The Realm that opened the link checks the other side as well
+                        let (random_bytes, key_check) = Self::key_check(&manager)?;
+                        context.status = FederationStatus::CheckingKeys {
+                            random_bytes,
+                            verified: false,
+                            answered: true,
+                        };
+                        Ok(FederationResponse::Messages(vec![answer.into(), key_check]))
+                    }
+                    FederationStatus::CheckingKeys { answered, .. } if !*answered => {
+                        *answered = true;
+                        let mut messages = vec![answer.into()];
+                        messages.extend(self.connect_if_checked(context));
+                        Ok(FederationResponse::Messages(messages))
+                    }
+                    _ => Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id))),
+                }
+            }
+            FederationPacket::KeyCheckAnswer(answer) => {
+                let manager = match self.realm.get_peer_realm(&context.peer_id)? {
+                    Some(manager) => manager,
+                    None => {
+                        return Ok(Self::error(ErrorPacket::unknown_device(REALM_TO_REALM, packet_id)));
+                    }
+                };
+                let (random_bytes, verified) = match &mut context.status {
+                    FederationStatus::CheckingKeys { random_bytes, verified, .. } if !*verified => {
+                        (random_bytes, verified)
+                    }
+                    _ => return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id))),
+                };
+                if !manager.decrypt_message(answer)?.eq(random_bytes) {
+                    warn!("Key Check with realm {} failed", context.peer_id);
+                    return Ok(FederationResponse::Messages(vec![
+                        FederationPacket::KeyCheckResponse(false).into(),
+                    ]));
+                }
+                *verified = true;
+                let mut messages = vec![FederationPacket::KeyCheckResponse(true).into()];
+                messages.extend(self.connect_if_checked(context));
+                Ok(FederationResponse::Messages(messages))
+            }
+            FederationPacket::KeyCheckResponse(success) => {
+                // Only tells if the other Realm could read this side. The link is connected by checking the other Realm
+                if !success {
+                    warn!("Realm {} could not check the keys of this realm", context.peer_id);
+                }
+                Ok(FederationResponse::Nothing)
+            }
+            FederationPacket::RouteAnnounce { device_id, hops } => {
+                if !context.is_connected() {
+                    return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id)));
+                }
+                // Devices connected to this Realm are never routed elsewhere
+                if self.realm.is_paired(&device_id) || hops >= MAX_HOPS {
+                    return Ok(FederationResponse::Nothing);
+                }
+                let route = Route {
+                    via: context.peer_id,
+                    hops: hops + 1,
+                };
+                if self.routes.learn(device_id, route) {
+                    debug!("Learnt route to {} via {}", device_id, context.peer_id);
+                    Ok(FederationResponse::Broadcast {
+                        except: Some(context.peer_id),
+                        messages: vec![FederationPacket::RouteAnnounce {
+                            device_id,
+                            hops: route.hops,
+                        }
+                            .into()],
+                    })
+                } else {
+                    Ok(FederationResponse::Nothing)
+                }
+            }
+            FederationPacket::RouteWithdraw { device_id } => {
+                if !context.is_connected() {
+                    return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id)));
+                }
+                if self.routes.withdraw(&device_id, &context.peer_id) {
+                    Ok(FederationResponse::Broadcast {
+                        except: Some(context.peer_id),
+                        messages: vec![FederationPacket::RouteWithdraw { device_id }.into()],
+                    })
+                } else {
+                    Ok(FederationResponse::Nothing)
+                }
+            }
+            FederationPacket::Forward {
+                source,
+                target,
+                hops,
+                payload,
+            } => {
+                if !context.is_connected() {
+                    return Ok(Self::error(ErrorPacket::invalid_state(REALM_TO_REALM, packet_id)));
+                }
+                if let Some(error) = self.check_forward(&source, &target, &payload, packet_id) {
+                    return Ok(Self::error(error));
+                }
+                if self.realm.is_paired(&target) {
+                    return Ok(FederationResponse::Deliver {
+                        target,
+                        message: RealmPacket::DeviceProxy(source, payload).into(),
+                    });
+                }
+                match self.routes.get(&target) {
+                    Some(route) if hops < MAX_HOPS && route.via != context.peer_id => {
+                        Ok(FederationResponse::SendToRealm {
+                            realm_id: route.via,
+                            message: FederationPacket::Forward {
+                                source,
+                                target,
+                                hops: hops + 1,
+                                payload,
+                            }
+                                .into(),
+                        })
+                    }
+                    _ => Ok(Self::error(ErrorPacket::unknown_device(REALM_TO_REALM, packet_id))),
+                }
+            }
+            FederationPacket::Heartbeat => Ok(FederationResponse::Nothing),
+            FederationPacket::Error(error) => {
+                warn!("Error from realm {}: {:?}", context.peer_id, error);
+                Ok(FederationResponse::Nothing)
+            }
+        }
+    }
+
+    /// Encrypts new random bytes for the other Realm
+    fn key_check(manager: &R::EH) -> Result<(Bytes, Protocol), R::Error> {
+        let mut bytes = [0u8; 256];
+        rand::thread_rng().fill(&mut bytes);
+        let random_bytes = Bytes::copy_from_slice(&bytes);
+        let encrypted = manager.encrypt_message(random_bytes.clone())?;
+        Ok((random_bytes, FederationPacket::KeyCheck(encrypted).into()))
+    }
+
+    /// Connects the link once both KeyChecks are done. Returns the route announcements for the other Realm
+    fn connect_if_checked(&self, context: &mut FederationContext) -> Vec<Protocol> {
+        match context.status {
+            FederationStatus::CheckingKeys {
+                verified: true,
+                answered: true,
+                ..
+            } => {
+                context.status = FederationStatus::Connected;
+                self.route_announcements(&context.peer_id)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks a forwarded frame against the access control and the rate limiter
+    fn check_forward(&self, source: &Uuid, target: &Uuid, payload: &Bytes, packet_id: u8) -> Option<ErrorPacket> {
+        if let Some(limiter) = self.rate_limiter {
+            let limited = limiter
+                .check_device(*source)
+                .and_then(|_| limiter.check_target(*target));
+            if let Err(limited) = limited {
+                debug!("Rate limited forward from {} to {}: {:?}", source, target, limited);
+                return Some(ErrorPacket::rate_limited(REALM_TO_REALM, packet_id));
+            }
+        }
+        let (protocol_id, _) = match packet::read_packet_type(&mut payload.as_ref()) {
+            Ok(value) => value,
+            Err(_) => return Some(ErrorPacket::malformed_packet(REALM_TO_REALM, packet_id)),
+        };
+        if let AclAction::Deny = self.access_control.check(source, target, protocol_id) {
+            debug!("Denied forward from {} to {} for protocol {}", source, target, protocol_id);
+            return Some(ErrorPacket::access_denied(REALM_TO_REALM, packet_id));
+        }
+        None
+    }
+
+    /// Every route this Realm knows about that does not go through the peer
+    fn route_announcements(&self, peer_id: &Uuid) -> Vec<Protocol> {
+        self.routes
+            .routes()
+            .into_iter()
+            .filter(|(_, route)| &route.via != peer_id && route.hops < MAX_HOPS)
+            .map(|(device_id, route)| {
+                FederationPacket::RouteAnnounce {
+                    device_id,
+                    hops: route.hops,
+                }
+                    .into()
+            })
+            .collect()
+    }
+
+    fn error(error: ErrorPacket) -> FederationResponse {
+        FederationResponse::Messages(vec![FederationPacket::Error(error).into()])
+    }
+}
@@ -0,0 +1,163 @@
+use crate::device_manager::{AsyncDeviceManager, PairedDevice};
+use crate::encryption::{DynamicEncryptionManager, EncryptionError};
+use crate::packets::capabilities::{Capabilities, DEVICE_TO_DEVICE};
+use crate::packets::dtd::DeviceToDevicePackets;
+use crate::packets::handlers::events::ConnectionObserver;
+use crate::packets::handlers::{ConnectionContext, DefaultProtocolHandler, Response};
+use crate::packets::{ErrorPacket, Protocol};
+use log::warn;
+use packet::packet::Packet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// The local devices hosted by a single service. Every device has its own [`AsyncDeviceManager`].
+/// So paired devices are never shared between them
+pub struct LocalIdentities<DM> {
+    identities: HashMap<Uuid, Arc<DM>>,
+    ports: HashMap<u16, Uuid>,
+    default: Option<Uuid>,
+}
+
+impl<DM> Default for LocalIdentities<DM> {
+    fn default() -> Self {
+        LocalIdentities {
+            identities: HashMap::new(),
+            ports: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+impl<DM: AsyncDeviceManager> LocalIdentities<DM> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a local device. The first device added is the default
+    pub async fn insert(&mut self, device_manager: Arc<DM>) -> Uuid {
+        let device_id = device_manager.get_device_id().await;
+        self.identities.insert(device_id, device_manager);
+        self.default.get_or_insert(device_id);
+        device_id
+    }
+
+    pub fn remove(&mut self, device_id: &Uuid) -> Option<Arc<DM>> {
+        self.ports.retain(|_, bound| bound != device_id);
+        if self.default.as_ref() == Some(device_id) {
+            self.default = None;
+        }
+        self.identities.remove(device_id)
+    }
+
+    /// Connections accepted on the port go to the device unless the Hello targets another one
+    pub fn bind_port(&mut self, port: u16, device_id: Uuid) {
+        self.ports.insert(port, device_id);
+    }
+
+    /// Used when neither the Hello nor the port pick a device
+    pub fn set_default(&mut self, device_id: Uuid) {
+        self.default = Some(device_id);
+    }
+
+    pub fn get(&self, device_id: &Uuid) -> Option<Arc<DM>> {
+        self.identities.get(device_id).cloned()
+    }
+
+    pub fn device_ids(&self) -> Vec<Uuid> {
+        self.identities.keys().copied().collect()
+    }
+
+    /// Picks the device for a new connection.
+    /// The target inside of the Hello wins over the port, the port wins over the default
+    pub fn resolve(&self, target: Option<&Uuid>, local_port: Option<u16>) -> Option<Arc<DM>> {
+        if let Some(target) = target {
+            return self.get(target);
+        }
+        local_port
+            .and_then(|port| self.ports.get(&port))
+            .or(self.default.as_ref())
+            .and_then(|device_id| self.get(device_id))
+    }
+}
+
+/// A Protocol Handler for a service that hosts several local devices.
+///
+/// The device is picked on Hello and kept inside of [`ConnectionContext::local_device_id`].
+/// Everything after that goes to the [`DefaultProtocolHandler`] of that device
+pub struct IdentityHandler<'i, DM> {
+    identities: &'i LocalIdentities<DM>,
+    local_port: Option<u16>,
+    capabilities: Capabilities,
+    observer: Option<&'i dyn ConnectionObserver>,
+}
+
+impl<'i, Error, PD, DM> IdentityHandler<'i, DM>
+    where
+        Error: std::error::Error + From<EncryptionError>,
+        PD: PairedDevice<DynamicEncryptionManager>,
+        DM: AsyncDeviceManager<Error=Error, PD=PD>,
+{
+    pub fn new(identities: &'i LocalIdentities<DM>) -> Self {
+        IdentityHandler {
+            identities,
+            local_port: None,
+            capabilities: Capabilities::default(),
+            observer: None,
+        }
+    }
+
+    /// The port the connection was accepted on. See [`LocalIdentities::bind_port`]
+    pub fn with_local_port(mut self, port: u16) -> Self {
+        self.local_port = Some(port);
+        self
+    }
+
+    /// Shared by every local device. See [`DefaultProtocolHandler::with_capabilities`]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Shared by every local device. See [`DefaultProtocolHandler::with_observer`]
+    pub fn with_observer(mut self, observer: &'i dyn ConnectionObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Handles the packet that is a for a device. See [`DefaultProtocolHandler::handle_packet_direct_communication`]
+    pub async fn handle_packet_direct_communication(
+        &mut self,
+        packet: Protocol,
+        connection_context: Option<&mut ConnectionContext>,
+    ) -> Result<Response, Error> {
+        let device_manager = match (&packet, &connection_context) {
+            (_, Some(context)) => self.identities.get(&context.local_device_id),
+            (Protocol::DeviceToDevice(DeviceToDevicePackets::Hello { target, .. }), None) => {
+                self.identities.resolve(target.as_ref(), self.local_port)
+            }
+            _ => None,
+        };
+        let device_manager = match device_manager {
+            Some(device_manager) => device_manager,
+            None => {
+                warn!("No local device for the connection on port {:?}", self.local_port);
+                let packet_id = match &packet {
+                    Protocol::DeviceToDevice(packet) => packet.get_packet_id(),
+                    _ => 0,
+                };
+                return Ok(Response::Message(
+                    DeviceToDevicePackets::Error(ErrorPacket::unknown_device(DEVICE_TO_DEVICE, packet_id)).into(),
+                ));
+            }
+        };
+        let mut handler =
+            DefaultProtocolHandler::new(device_manager.as_ref()).with_capabilities(self.capabilities.clone());
+        if let Some(observer) = self.observer {
+            handler = handler.with_observer(observer);
+        }
+        handler
+            .handle_packet_direct_communication(packet, connection_context)
+            .await
+    }
+}
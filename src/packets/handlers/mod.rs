@@ -1,18 +1,30 @@
-use crate::device_manager::{DeviceManager, PairedDevice};
+/// Status transitions and pairing outcomes for the app
+pub mod events;
+/// The Handler for links between federated Realms
+pub mod federation;
+/// The Handler for a service that hosts several local devices
+pub mod identities;
+/// The Handler for a Realm Server
+pub mod realm;
+
+use crate::device_manager::{AsyncDeviceManager, DeviceMetadata, PairedDevice};
 use crate::encryption::{
-    DynamicEncryptionManager, EncryptionError, EncryptionManager, EncryptionSet,
+    DynamicEncryptionManager, EncryptionError, EncryptionManager, EncryptionSet, ThemisEncryptionManager,
 };
+use crate::packets::capabilities::{Capabilities, Negotiated, DEVICE_TO_DEVICE, DEVICE_TO_REALM, REALM_TO_REALM};
 use crate::packets::dtd::DeviceToDevicePackets;
+use crate::packets::handlers::events::{ConnectionEvent, ConnectionObserver};
 use crate::packets::{ErrorPacket, Protocol};
 use crate::protocol::{ConnectionStatus, DTDViaRealm, DirectConnection};
 use bytes::{Bytes};
+use uuid::Uuid;
 use rand::Rng;
 use std::io::Cursor;
 use log::{ warn};
+use packet::packet::Packet;
 
 use themis::keygen::gen_ec_key_pair;
-use themis::keys::{EcdsaKeyPair, EcdsaPublicKey};
-use themis::secure_message::SecureMessage;
+use themis::keys::EcdsaPublicKey;
 
 
 /// Responses the Handlers can return
@@ -24,9 +36,42 @@ pub enum Response {
     },
     /// Send this message to the other device to continue the connection
     Message(Protocol),
+    /// Pairing stopped before the device was registered. Send the message if there is one
+    PairingAborted {
+        aborted: PairingAborted,
+        message: Option<Protocol>,
+    },
     Nothing,
 }
 
+/// Why pairing stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingAbortReason {
+    /// The user said no to the pair request
+    Rejected,
+    /// The other side sent a key that could not be read
+    BadKey,
+    /// The test strings did not match or only one side sent one
+    TestMismatch,
+    /// One of the KeyCheck directions failed
+    KeyCheckFailed,
+    /// The connection closed mid-pairing. See [`ConnectionContext::close`]
+    Disconnected,
+}
+
+/// Pairing with a device was aborted. The staged keys are gone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingAborted {
+    pub device_id: Uuid,
+    pub reason: PairingAbortReason,
+}
+
+/// Keys that are registered with the device manager once both KeyCheck directions succeed
+pub struct StagedPairing {
+    pub encryption: EncryptionSet,
+    pub metadata: DeviceMetadata,
+}
+
 /// Connection Type
 pub enum ConnectionType {
     DTDViaRealm(DTDViaRealm),
@@ -39,17 +84,70 @@ pub struct ConnectionContext {
     pub encryption: DynamicEncryptionManager,
     pub status: ConnectionStatus,
     pub connection_type: ConnectionType,
+    /// The local identity that accepted the connection
+    pub local_device_id: Uuid,
+    /// Set while pairing. Dropping the context rolls the pairing back
+    pub staged: Option<StagedPairing>,
+    /// What both sides agreed on inside of Hello
+    pub negotiated: Option<Negotiated>,
+}
+
+impl ConnectionContext {
+    pub fn new(device_id: Uuid, local_device_id: Uuid) -> Self {
+        ConnectionContext {
+            encryption: DynamicEncryptionManager::None,
+            status: ConnectionStatus::PendingEncryption,
+            connection_type: ConnectionType::DirectConnection(DirectConnection { device_id }),
+            local_device_id,
+            staged: None,
+            negotiated: None,
+        }
+    }
+
+    /// Rather or not the connection is in the middle of pairing
+    pub fn is_pairing(&self) -> bool {
+        self.staged.is_some()
+            || matches!(
+                self.status,
+                ConnectionStatus::PendingPairRequest { .. } | ConnectionStatus::Pairing { .. }
+            )
+    }
+
+    /// The other device
+    pub fn device_id(&self) -> Uuid {
+        match &self.connection_type {
+            ConnectionType::DirectConnection(direct) => direct.device_id,
+            ConnectionType::DTDViaRealm(via_realm) => via_realm.device_id,
+        }
+    }
+
+    /// Call when the connection closes. Returns the abort if it closed mid-pairing.
+    /// See [`DefaultProtocolHandler::connection_closed`] to tell the observer as well
+    pub fn close(self) -> Option<PairingAborted> {
+        if !self.is_pairing() {
+            return None;
+        }
+        Some(PairingAborted {
+            device_id: self.device_id(),
+            reason: PairingAbortReason::Disconnected,
+        })
+    }
 }
 
 /// The Default Protocol Handler. For a receiving device.
-/// For a Realm Server please use the RealmHandler(hint: it does not exist yet).
+/// For a Realm Server please use the [`RealmHandler`](realm::RealmHandler).
+///
+/// The device manager is awaited. So `pair_request` can wait on the user without blocking the task.
+/// Any sync [`DeviceManager`](crate::device_manager::DeviceManager) works as well
 pub struct DefaultProtocolHandler<
     'dm,
     Error,
     PD: PairedDevice<DynamicEncryptionManager>,
-    DM: DeviceManager<Error=Error, PD=PD>,
+    DM: AsyncDeviceManager<Error=Error, PD=PD>,
 > {
-    device_manager: &'dm mut DM,
+    device_manager: &'dm DM,
+    capabilities: Capabilities,
+    observer: Option<&'dm dyn ConnectionObserver>,
     phantom: std::marker::PhantomData<Error>,
     phantom_pd: std::marker::PhantomData<PD>,
 }
@@ -58,66 +156,150 @@ impl<
     'dm,
     Error,
     PD: PairedDevice<DynamicEncryptionManager>,
-    DM: DeviceManager<Error=Error, PD=PD>,
+    DM: AsyncDeviceManager<Error=Error, PD=PD>,
 > DefaultProtocolHandler<'dm, Error, PD, DM>
     where
         Error: std::error::Error + std::convert::From<crate::encryption::EncryptionError>,
 {
-    pub fn new(device_manager: &'dm mut DM) -> Self {
+    pub fn new(device_manager: &'dm DM) -> Self {
         DefaultProtocolHandler {
             device_manager,
+            capabilities: Capabilities::default(),
+            observer: None,
             phantom: std::marker::PhantomData,
             phantom_pd: std::marker::PhantomData,
         }
     }
+
+    /// What this device supports. Defaults to [`Capabilities::default`]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+    /// Told about status transitions, pairing outcomes and errors
+    pub fn with_observer(mut self, observer: &'dm dyn ConnectionObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
     /// Handles the packet that is a for a device.
     ///
-    /// # Panics
-    /// Panics if the packet is not a DeviceToDevice Packet.
-    pub fn handle_packet_direct_communication(
+    /// Realm packets are answered with an Invalid State error
+    pub async fn handle_packet_direct_communication(
         &mut self,
         packet: Protocol,
-        connection_context: Option<&mut ConnectionContext>,
+        mut connection_context: Option<&mut ConnectionContext>,
     ) -> Result<Response, Error> {
-        match packet {
+        let before = connection_context.as_deref().map(|context| context.status.kind());
+        let response = match packet {
             Protocol::DeviceToDevice(device_to_device) => self
-                .handle_device_to_device_direct_communication(device_to_device, connection_context),
-            Protocol::DeviceToRealm(_) => {
-                todo!("Currently realms do not exist in the real world. Just  in André's imagination.")
+                .handle_device_to_device_direct_communication(device_to_device, connection_context.as_deref_mut())
+                .await?,
+            // A device only talks DeviceToDevice. Realms are handled by their own handlers
+            Protocol::DeviceToRealm(packet) => Response::Message(
+                DeviceToDevicePackets::Error(ErrorPacket::invalid_state(DEVICE_TO_REALM, packet.get_packet_id())).into(),
+            ),
+            Protocol::RealmToRealm(packet) => Response::Message(
+                DeviceToDevicePackets::Error(ErrorPacket::invalid_state(REALM_TO_REALM, packet.get_packet_id())).into(),
+            ),
+        };
+        let after = match &response {
+            Response::NewContext { new_context, .. } => Some(new_context.as_ref()),
+            _ => connection_context.as_deref(),
+        };
+        if let Some(after) = after {
+            if before != Some(after.status.kind()) {
+                self.emit(ConnectionEvent::StatusChanged {
+                    device_id: after.device_id(),
+                    from: before,
+                    to: after.status.kind(),
+                });
             }
         }
+        if let Response::PairingAborted { aborted, .. } = &response {
+            self.emit(ConnectionEvent::PairingAborted(aborted.clone()));
+        }
+        Ok(response)
     }
-    fn handle_device_to_device_direct_communication(
+
+    /// Call when the connection closes. Tells the observer and returns the abort if it closed mid-pairing
+    pub fn connection_closed(&self, context: ConnectionContext) -> Option<PairingAborted> {
+        let device_id = context.device_id();
+        let aborted = context.close();
+        if let Some(aborted) = &aborted {
+            self.emit(ConnectionEvent::PairingAborted(aborted.clone()));
+        }
+        self.emit(ConnectionEvent::Disconnected { device_id });
+        aborted
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(observer) = self.observer {
+            observer.on_event(event);
+        }
+    }
+    async fn handle_device_to_device_direct_communication(
         &mut self,
         packet: DeviceToDevicePackets,
         connection_context: Option<&mut ConnectionContext>,
     ) -> Result<Response, Error> {
+        let packet_id = packet.get_packet_id();
+        let negotiated = connection_context
+            .as_ref()
+            .and_then(|context| context.negotiated.as_ref());
+        if negotiated.map(|negotiated| !negotiated.supports(DEVICE_TO_DEVICE)).unwrap_or(false) {
+            return Ok(Response::Message(
+                DeviceToDevicePackets::Error(ErrorPacket::incompatible(DEVICE_TO_DEVICE, packet_id)).into(),
+            ));
+        }
         match packet {
-            DeviceToDevicePackets::Hello { device_id, .. } => {
-                if connection_context.is_some() {
-                    let is_paired = self.device_manager.is_paired(&device_id);
-
-                    Ok(Response::Message(Protocol::DeviceToDevice(
-                        DeviceToDevicePackets::Hello {
-                            device_id: self.device_manager.get_device_id(),
-                            paired: is_paired,
-                        },
-                    )))
+            DeviceToDevicePackets::Hello { device_id, target, capabilities, .. } => {
+                let local_device_id = self.device_manager.get_device_id().await;
+                if target.map(|target| target != local_device_id).unwrap_or(false) {
+                    warn!("Hello from {} was meant for another device", device_id);
+                    return Ok(Self::unknown_device(packet_id));
+                }
+                let negotiated = match self.capabilities.negotiate(&capabilities) {
+                    // Both sides have to speak DeviceToDevice. A Realm never does
+                    Ok(negotiated) if negotiated.supports(DEVICE_TO_DEVICE) => negotiated,
+                    Ok(_) => {
+                        warn!("Refused Hello from {}: DeviceToDevice was not negotiated", device_id);
+                        return Ok(Response::Message(
+                            DeviceToDevicePackets::Error(ErrorPacket::incompatible(DEVICE_TO_DEVICE, packet_id)).into(),
+                        ));
+                    }
+                    Err(incompatible) => {
+                        warn!("Refused Hello from {}: {}", device_id, incompatible);
+                        return Ok(Response::Message(
+                            DeviceToDevicePackets::Error(ErrorPacket::incompatible(DEVICE_TO_DEVICE, packet_id)).into(),
+                        ));
+                    }
+                };
+                if let Some(device) = self.device_manager.get_paired_device(&device_id).await? {
+                    if device.get_metadata().is_blocked() {
+                        warn!("Refused Hello from blocked device {}", device_id);
+                        return Ok(Response::Message(
+                            DeviceToDevicePackets::Error(ErrorPacket::access_denied(DEVICE_TO_DEVICE, packet_id)).into(),
+                        ));
+                    }
+                    self.device_manager.touch_last_seen(&device_id).await?;
+                }
+                let is_paired = self.device_manager.is_paired(&device_id).await;
+                // Only what was agreed on is sent back. So both sides end up with the same result
+                let hello = Protocol::DeviceToDevice(DeviceToDevicePackets::Hello {
+                    device_id: local_device_id,
+                    paired: is_paired,
+                    target: Some(device_id),
+                    capabilities: negotiated.to_capabilities(),
+                });
+                if let Some(context) = connection_context {
+                    context.negotiated = Some(negotiated);
+                    Ok(Response::Message(hello))
                 } else {
-                    let is_paired = self.device_manager.is_paired(&device_id);
-
-                    let context = ConnectionContext {
-                        encryption: DynamicEncryptionManager::None,
-                        status: ConnectionStatus::PendingEncryption,
-                        connection_type: ConnectionType::DirectConnection(DirectConnection {
-                            device_id,
-                        }),
-                    };
+                    let mut context = ConnectionContext::new(device_id, local_device_id);
+                    context.negotiated = Some(negotiated);
                     Ok(Response::NewContext {
-                        message: Protocol::DeviceToDevice(DeviceToDevicePackets::Hello {
-                            device_id: self.device_manager.get_device_id(),
-                            paired: is_paired,
-                        }),
+                        message: hello,
                         new_context: Box::new(context),
                     })
                 }
@@ -126,259 +308,312 @@ impl<
                 device_name,
                 details,
             } => {
-                if let Some(context) = connection_context {
-                    if let ConnectionType::DirectConnection(direct) = &context.connection_type {
-                        let details = if let Some(details) = details {
-                            Cursor::new(details)
-                        } else {
-                            Cursor::default()
-                        };
-                        let (request, test) = self.device_manager.pair_request(
-                            &direct.device_id,
-                            &device_name,
-                            details,
-                        )?;
-                        if request {
-                            let pair = gen_ec_key_pair();
-
-                            let encrypted_test = if let Some(test) = &test {
-                                let message = SecureMessage::new(pair.clone());
-                                let bytes = message
-                                    .encrypt(test.as_ref())
-                                    .map_err(EncryptionError::from)?;
-                                Some(Bytes::from(bytes))
-                            } else {
-                                None
-                            };
-                            let (private, public) = pair.split();
-                            let public_key = crate::ToBytes::to_bytes(public.clone());
-                            context.status = ConnectionStatus::Pairing {
-                                public_key: public,
-                                private_key: private,
-                                key_b: None,
-                                test,
-                            };
+                let context = match connection_context {
+                    Some(context) => context,
+                    None => return Ok(Self::invalid_state(packet_id)),
+                };
+                let direct = match &context.connection_type {
+                    ConnectionType::DirectConnection(direct) => direct.clone(),
+                    // Pairing through a Realm is not supported
+                    _ => return Ok(Self::invalid_state(packet_id)),
+                };
+                let details = if let Some(details) = details {
+                    Cursor::new(details)
+                } else {
+                    Cursor::default()
+                };
+                let (request, test) = self.device_manager.pair_request(
+                    &direct.device_id,
+                    &device_name,
+                    details,
+                ).await?;
+                if request {
+                    let (private, public) = gen_ec_key_pair().split();
+                    let public_key = crate::ToBytes::to_bytes(public.clone());
+                    // The test comes back inside of the other SendKey. This side has no key to encrypt it to yet
+                    context.status = ConnectionStatus::Pairing {
+                        public_key: public,
+                        private_key: private,
+                        key_b: None,
+                        test,
+                        device_name: Some(device_name),
+                    };
 
-                            Ok(Response::Message(Protocol::DeviceToDevice(
-                                DeviceToDevicePackets::SendKey {
-                                    public_key,
-                                    test: encrypted_test,
-                                },
-                            )))
-                        } else {
-                            todo!("Say No and Disconnect")
-                        }
-                    } else {
-                        panic!("Direct Connection Only!!!!")
-                    }
+                    Ok(Response::Message(Protocol::DeviceToDevice(
+                        DeviceToDevicePackets::SendKey {
+                            public_key,
+                            test: None,
+                        },
+                    )))
                 } else {
-                    todo!("Implement Error")
+                    Ok(Self::abort_pairing(
+                        context,
+                        direct.device_id,
+                        PairingAbortReason::Rejected,
+                        Some(DeviceToDevicePackets::Error(ErrorPacket::access_denied(DEVICE_TO_DEVICE, packet_id)).into()),
+                    ))
                 }
             }
-            DeviceToDevicePackets::SendKey { public_key, test } => {
+            DeviceToDevicePackets::SendKey { public_key, test: other_test_string } => {
+                let context = match connection_context {
+                    Some(context) => context,
+                    None => return Ok(Self::invalid_state(packet_id)),
+                };
+                let direct = match &context.connection_type {
+                    ConnectionType::DirectConnection(direct) => direct.clone(),
+                    _ => return Ok(Self::invalid_state(packet_id)),
+                };
                 let key_b = if let Ok(key_b) = EcdsaPublicKey::try_from_slice(public_key.as_ref()) {
                     key_b
                 } else {
-                    return Ok(Response::Message(DeviceToDevicePackets::Error(ErrorPacket::from((0, 4, 1, "Bad Key"))).into()));
+                    return Ok(Self::abort_pairing(
+                        context,
+                        direct.device_id,
+                        PairingAbortReason::BadKey,
+                        Some(DeviceToDevicePackets::Error(ErrorPacket::from((0, 4, 1, "Bad Key"))).into()),
+                    ));
                 };
-                let other_test_string = test;
-                if let Some(context) = connection_context {
-                    if let ConnectionType::DirectConnection(direct) = &context.connection_type {
-                        if let ConnectionStatus::PendingPairRequest { test } = &context.status {
-                            let my_key = gen_ec_key_pair();
-                            if other_test_string.is_none() != test.is_none() {
-                                return Ok(Response::Message(DeviceToDevicePackets::Error(ErrorPacket::from((0, 4, 1, "Key Check Failed"))).into()));
-                            }
-                            let (my_private, my_public) = my_key.split();
-                            let encrypted_key_again = if let Some(other_test_string) =
-                            other_test_string
-                            {
-                                let my_test = test.as_ref().unwrap();
-
-                                let mix_message = SecureMessage::new(EcdsaKeyPair::join(
-                                    my_private.clone(),
-                                    key_b.clone(),
-                                ));
-                                let vec = mix_message
-                                    .encrypt(my_test.as_ref())
-                                    .map_err(EncryptionError::from)?;
-                                if !other_test_string.eq(&vec) {
-                                    return Ok(Response::Message(DeviceToDevicePackets::Error(ErrorPacket::from((0, 4, 1, "Key Check Failed"))).into()));
-                                }
-                                let my_message = SecureMessage::new(EcdsaKeyPair::join(
-                                    my_private.clone(),
-                                    my_public.clone(),
-                                ));
-                                let my_test_encrypted = my_message
-                                    .encrypt(my_test.as_ref())
-                                    .map_err(EncryptionError::from)?;
-                                Some(Bytes::from(my_test_encrypted))
-                            } else {
-                                None
-                            };
-                            // As far as this device is concerned, the other device is now paired.
-                            let message =
-                                Protocol::DeviceToDevice(DeviceToDevicePackets::SendKey {
-                                    public_key: crate::ToBytes::to_bytes(my_public.clone()),
-                                    test: encrypted_key_again,
-                                });
-                            self.device_manager.register_device(
-                                &direct.device_id,
-                                EncryptionSet {
-                                    public_key: my_public,
-                                    private_key: my_private,
-                                    key_b,
-                                },
-                            )?;
-                            Ok(Response::NewContext {
-                                message,
-                                new_context: Box::new(ConnectionContext {
-                                    encryption: DynamicEncryptionManager::None,
-                                    status: ConnectionStatus::PendingEncryption,
-                                    connection_type: ConnectionType::DirectConnection(
-                                        direct.clone(),
-                                    ),
-                                }),
-                            })
-                        } else if let ConnectionStatus::Pairing {
-                            test,
+                let key_check_failed: Protocol =
+                    DeviceToDevicePackets::Error(ErrorPacket::from((0, 4, 1, "Key Check Failed"))).into();
+                match context.status.clone() {
+                    ConnectionStatus::PendingPairRequest { test } => {
+                        // The other device did not have our key. So it could not have encrypted a test
+                        if other_test_string.is_some() {
+                            return Ok(Self::abort_pairing(
+                                context,
+                                direct.device_id,
+                                PairingAbortReason::TestMismatch,
+                                Some(key_check_failed),
+                            ));
+                        }
+                        let (my_private, my_public) = gen_ec_key_pair().split();
+                        let encryption = EncryptionSet {
+                            public_key: my_public.clone(),
+                            private_key: my_private,
+                            key_b,
+                        };
+                        let encrypted_test = match &test {
+                            Some(test) => Some(
+                                ThemisEncryptionManager::from(&encryption).encrypt_message(test.clone())?,
+                            ),
+                            None => None,
+                        };
+                        let message = Protocol::DeviceToDevice(DeviceToDevicePackets::SendKey {
+                            public_key: crate::ToBytes::to_bytes(my_public),
+                            test: encrypted_test,
+                        });
+                        // Registered once both key checks pass
+                        context.staged = Some(StagedPairing {
+                            encryption,
+                            // The other device never sent a PairRequest. So there is no name
+                            metadata: DeviceMetadata::new(None),
+                        });
+                        context.status = ConnectionStatus::PendingEncryption;
+                        Ok(Response::Message(message))
+                    }
+                    ConnectionStatus::Pairing {
+                        test,
+                        public_key,
+                        private_key,
+                        device_name,
+                        ..
+                    } => {
+                        let encryption = EncryptionSet {
                             public_key,
                             private_key,
-                            ..
-                        } = &context.status
-                        {
-                            if let Some(other_test_string) = other_test_string {
-                                let my_test = test.as_ref().unwrap();
-
-                                let mix_message = SecureMessage::new(EcdsaKeyPair::join(
-                                    private_key.clone(),
-                                    key_b.clone(),
-                                ));
-                                let vec = mix_message
-                                    .encrypt(my_test.as_ref())
-                                    .map_err(EncryptionError::from)?;
-                                if !other_test_string.eq(&vec) {
-                                    return todo!("Mismatching Test Strings"); // The key has been compromised
-                                }
-                            }
-                            let mix_message = SecureMessage::new(EcdsaKeyPair::join(
-                                private_key.clone(),
-                                key_b.clone(),
+                            key_b,
+                        };
+                        let manager = ThemisEncryptionManager::from(&encryption);
+                        let test_passed = match (&test, other_test_string) {
+                            // Only the other device could have encrypted the test with the shared keys
+                            (Some(my_test), Some(other_test_string)) => manager
+                                .decrypt_message(other_test_string)
+                                .map(|other_test| other_test.eq(my_test))
+                                .unwrap_or(false),
+                            (None, None) => true,
+                            // Only one side wanted a test
+                            _ => false,
+                        };
+                        if !test_passed {
+                            // The key has been compromised
+                            return Ok(Self::abort_pairing(
+                                context,
+                                direct.device_id,
+                                PairingAbortReason::TestMismatch,
+                                Some(key_check_failed),
                             ));
-
-                            let mut bytes = [0u8; 256];
-                            rand::thread_rng().fill(&mut bytes);
-                            let encrypt =
-                                mix_message.encrypt(bytes).map_err(EncryptionError::from)?;
-                            // As far as this device is concerned, the other device is now paired.
-
-                            let message = Protocol::DeviceToDevice(
-                                DeviceToDevicePackets::KeyCheck(Bytes::from(encrypt)),
-                            );
-                            self.device_manager.register_device(
-                                &direct.device_id,
-                                EncryptionSet {
-                                    public_key: public_key.clone(),
-                                    private_key: private_key.clone(),
-                                    key_b,
-                                },
-                            )?;
-
-                            Ok(Response::NewContext {
-                                message,
-                                new_context: Box::new(ConnectionContext {
-                                    encryption: DynamicEncryptionManager::None,
-                                    status: ConnectionStatus::PendingEncryption,
-                                    connection_type: ConnectionType::DirectConnection(
-                                        direct.clone(),
-                                    ),
-                                }),
-                            })
-                        } else {
-                            // This device is not in pairing mode
-                            todo!("Implement Error")
                         }
-                    } else {
-                        panic!("Direct Connection Only!!!!")
+                        let (random_bytes, key_check) = Self::key_check(&manager)?;
+                        context.staged = Some(StagedPairing {
+                            encryption,
+                            metadata: DeviceMetadata::new(device_name),
+                        });
+                        context.status = ConnectionStatus::CheckingKeys {
+                            random_bytes: Some(random_bytes),
+                            verified: false,
+                            answered: false,
+                        };
+                        Ok(Response::Message(key_check))
                     }
-                } else {
-                    todo!("Implement Error")
+                    // This device is not in pairing mode
+                    _ => Ok(Self::invalid_state(packet_id)),
                 }
             }
             DeviceToDevicePackets::KeyCheck(random_check) => {
-                if let Some(context) = connection_context {
-                    if let ConnectionType::DirectConnection(direct) = &context.connection_type {
-                        if let ConnectionStatus::PendingEncryption = &context.status {
-                            let result =
-                                self.device_manager.get_paired_device(&direct.device_id)?;
-                            let manager = result.get_encryption_manager();
-                            let decrypt_message = manager.decrypt_message(random_check)?;
-                            context.status = ConnectionStatus::CheckingKeys {
-                                random_bytes: decrypt_message.clone(),
-                            };
-                            let encrypt_message = manager.encrypt_message(decrypt_message)?;
-
-                            Ok(Response::Message(Protocol::DeviceToDevice(
-                                DeviceToDevicePackets::KeyCheck(encrypt_message),
-                            )))
-                        } else if let ConnectionStatus::CheckingKeys { random_bytes } =
-                        &context.status
+                let context = match connection_context {
+                    Some(context) => context,
+                    None => return Ok(Self::invalid_state(packet_id)),
+                };
+                let direct = match &context.connection_type {
+                    ConnectionType::DirectConnection(direct) => direct.clone(),
+                    _ => return Ok(Self::invalid_state(packet_id)),
+                };
+                let manager = match self.connection_encryption(context, &direct.device_id).await? {
+                    Some(manager) => manager,
+                    None => return Ok(Self::unknown_device(packet_id)),
+                };
+                let key_check_failed: Protocol = DeviceToDevicePackets::KeyCheckResponse(false).into();
+                match context.status.clone() {
+                    // Either the other device checks this side first or it sent its check after ours passed
+                    status @ (ConnectionStatus::PendingEncryption
+                    | ConnectionStatus::CheckingKeys {
+                        verified: true,
+                        answered: false,
+                        ..
+                    }) => {
+                        let answer = match manager
+                            .decrypt_message(random_check)
+                            .and_then(|decrypted| manager.encrypt_message(decrypted))
                         {
-                            let result =
-                                self.device_manager.get_paired_device(&direct.device_id)?;
-                            let manager = result.get_encryption_manager();
-                            let bytes = manager.decrypt_message(random_check)?;
-                            if !bytes.eq(random_bytes) {
-                                Ok(Response::Message(Protocol::DeviceToDevice(
-                                    DeviceToDevicePackets::KeyCheckResponse(false),
-                                )))
-                            } else {
-                                Ok(Response::NewContext {
-                                    message: Protocol::DeviceToDevice(
-                                        DeviceToDevicePackets::KeyCheckResponse(true),
-                                    ),
-                                    new_context: Box::new(ConnectionContext {
-                                        encryption: manager,
-                                        status: ConnectionStatus::Connected,
-                                        connection_type: ConnectionType::DirectConnection(
-                                            direct.clone(),
-                                        ),
-                                    }),
-                                })
+                            Ok(answer) => answer,
+                            Err(_) if context.staged.is_some() => {
+                                return Ok(Self::abort_pairing(
+                                    context,
+                                    direct.device_id,
+                                    PairingAbortReason::KeyCheckFailed,
+                                    Some(key_check_failed),
+                                ));
+                            }
+                            Err(error) => return Err(error.into()),
+                        };
+                        context.status = match status {
+                            ConnectionStatus::CheckingKeys { random_bytes, verified, .. } => {
+                                ConnectionStatus::CheckingKeys {
+                                    random_bytes,
+                                    verified,
+                                    answered: true,
+                                }
+                            }
+                            _ => ConnectionStatus::CheckingKeys {
+                                random_bytes: None,
+                                verified: false,
+                                answered: true,
+                            },
+                        };
+                        Ok(Response::Message(Protocol::DeviceToDevice(
+                            DeviceToDevicePackets::KeyCheck(answer),
+                        )))
+                    }
+                    // The answer to our check
+                    ConnectionStatus::CheckingKeys {
+                        random_bytes: Some(random_bytes),
+                        verified: false,
+                        answered,
+                    } => {
+                        let matches = manager
+                            .decrypt_message(random_check)
+                            .map(|bytes| bytes.eq(&random_bytes))
+                            .unwrap_or(false);
+                        if !matches {
+                            if context.staged.is_some() {
+                                return Ok(Self::abort_pairing(
+                                    context,
+                                    direct.device_id,
+                                    PairingAbortReason::KeyCheckFailed,
+                                    Some(key_check_failed),
+                                ));
                             }
+                            self.emit(ConnectionEvent::KeyCheckFailed {
+                                device_id: direct.device_id,
+                            });
+                            return Ok(Response::Message(key_check_failed));
+                        }
+                        if answered {
+                            // The other device decrypted our check and we decrypted theirs
+                            self.commit_pairing(context, &direct.device_id).await?;
+                            context.encryption = manager;
+                            context.status = ConnectionStatus::Connected;
                         } else {
-                            todo!("Implement Error")
+                            // The other device checks this side next
+                            context.status = ConnectionStatus::CheckingKeys {
+                                random_bytes: Some(random_bytes),
+                                verified: true,
+                                answered: false,
+                            };
                         }
-                    } else {
-                        panic!("Direct Connections only!")
+                        Ok(Response::Message(Protocol::DeviceToDevice(
+                            DeviceToDevicePackets::KeyCheckResponse(true),
+                        )))
                     }
-                } else {
-                    todo!("Implement Error Responses")
+                    _ => Ok(Self::invalid_state(packet_id)),
                 }
             }
             DeviceToDevicePackets::KeyCheckResponse(success) => {
                 if let Some(context) = connection_context {
                     if let ConnectionType::DirectConnection(direct) = &context.connection_type {
-                        if let ConnectionStatus::CheckingKeys { .. } = &context.status {
+                        let direct = direct.clone();
+                        if let ConnectionStatus::CheckingKeys {
+                            random_bytes,
+                            verified,
+                            answered,
+                        } = context.status.clone()
+                        {
                             if success {
-                                let result =
-                                    self.device_manager.get_paired_device(&direct.device_id)?;
-                                let manager = result.get_encryption_manager();
-                                context.encryption = manager;
-                                context.status = ConnectionStatus::Connected;
-                                Ok(Response::Nothing)
+                                let manager =
+                                    match self.connection_encryption(context, &direct.device_id).await? {
+                                        Some(manager) => manager,
+                                        None => return Ok(Self::unknown_device(packet_id)),
+                                    };
+                                if verified && answered {
+                                    self.commit_pairing(context, &direct.device_id).await?;
+                                    context.encryption = manager;
+                                    context.status = ConnectionStatus::Connected;
+                                    Ok(Response::Nothing)
+                                } else if answered && random_bytes.is_none() {
+                                    // The other device read our answer. Now this side checks the other device
+                                    let (random_bytes, key_check) = Self::key_check(&manager)?;
+                                    context.status = ConnectionStatus::CheckingKeys {
+                                        random_bytes: Some(random_bytes),
+                                        verified: false,
+                                        answered: true,
+                                    };
+                                    Ok(Response::Message(key_check))
+                                } else {
+                                    // Nothing is committed until this side has checked the other device
+                                    Ok(Self::invalid_state(packet_id))
+                                }
+                            } else if context.staged.is_some() {
+                                Ok(Self::abort_pairing(
+                                    context,
+                                    direct.device_id,
+                                    PairingAbortReason::KeyCheckFailed,
+                                    None,
+                                ))
                             } else {
                                 warn!("Key Check Failed");
+                                self.emit(ConnectionEvent::KeyCheckFailed {
+                                    device_id: direct.device_id,
+                                });
                                 Ok(Response::Nothing)
                             }
                         } else {
-                            Ok(Response::Message(DeviceToDevicePackets::Error(ErrorPacket::from((0, 5, 0, "Invalid State"))).into()))
+                            Ok(Self::invalid_state(packet_id))
                         }
                     } else {
-                        panic!("Direct Connections only!")
+                        Ok(Self::invalid_state(packet_id))
                     }
                 } else {
-                    Ok(Response::Message(DeviceToDevicePackets::Error(ErrorPacket::from((0, 5, 0, "Invalid State"))).into()))
+                    Ok(Self::invalid_state(packet_id))
                 }
             }
             DeviceToDevicePackets::Heartbeat => {
@@ -387,8 +622,74 @@ impl<
             }
             DeviceToDevicePackets::Error(error) => {
                 warn!("Error: {:?}", error);
+                self.emit(ConnectionEvent::ErrorReceived {
+                    device_id: connection_context.map(|context| context.device_id()),
+                    error,
+                });
                 Ok(Response::Nothing)
             }
         }
     }
+
+    /// The keys for the connection. While pairing the staged keys are used instead of the paired device
+    async fn connection_encryption(
+        &self,
+        context: &ConnectionContext,
+        device_id: &Uuid,
+    ) -> Result<Option<DynamicEncryptionManager>, Error> {
+        if let Some(staged) = &context.staged {
+            return Ok(Some(DynamicEncryptionManager::Themis(ThemisEncryptionManager::from(
+                &staged.encryption,
+            ))));
+        }
+        Ok(self
+            .device_manager
+            .get_paired_device(device_id)
+            .await?
+            .map(|device| device.get_encryption_manager()))
+    }
+
+    /// Both key checks passed. Registers the staged device
+    async fn commit_pairing(&self, context: &mut ConnectionContext, device_id: &Uuid) -> Result<(), Error> {
+        if let Some(staged) = context.staged.take() {
+            self.device_manager
+                .register_device(device_id, staged.encryption, staged.metadata)
+                .await?;
+            self.emit(ConnectionEvent::Paired { device_id: *device_id });
+        }
+        Ok(())
+    }
+
+    /// Drops the staged keys. Nothing was registered so there is nothing else to undo
+    fn abort_pairing(
+        context: &mut ConnectionContext,
+        device_id: Uuid,
+        reason: PairingAbortReason,
+        message: Option<Protocol>,
+    ) -> Response {
+        warn!("Pairing with {} aborted: {:?}", device_id, reason);
+        context.staged = None;
+        context.status = ConnectionStatus::Entry;
+        Response::PairingAborted {
+            aborted: PairingAborted { device_id, reason },
+            message,
+        }
+    }
+
+    /// A KeyCheck with random bytes. Returns the bytes the other device has to send back
+    fn key_check(manager: &impl EncryptionManager<Error = EncryptionError>) -> Result<(Bytes, Protocol), EncryptionError> {
+        let mut bytes = [0u8; 256];
+        rand::thread_rng().fill(&mut bytes);
+        let random_bytes = Bytes::copy_from_slice(&bytes);
+        let encrypted = manager.encrypt_message(random_bytes.clone())?;
+        Ok((random_bytes, DeviceToDevicePackets::KeyCheck(encrypted).into()))
+    }
+
+    fn unknown_device(packet_id: u8) -> Response {
+        Response::Message(DeviceToDevicePackets::Error(ErrorPacket::unknown_device(DEVICE_TO_DEVICE, packet_id)).into())
+    }
+
+    fn invalid_state(packet_id: u8) -> Response {
+        Response::Message(DeviceToDevicePackets::Error(ErrorPacket::invalid_state(DEVICE_TO_DEVICE, packet_id)).into())
+    }
 }
@@ -0,0 +1,343 @@
+use crate::packets::capabilities::{Capabilities, Negotiated, DEVICE_TO_DEVICE, DEVICE_TO_REALM, REALM_TO_REALM};
+use crate::packets::federation::FederationPacket;
+use crate::packets::realm::{LoginDetails, RealmPacket};
+use crate::packets::{ErrorPacket, Protocol};
+use crate::realm::acl::{AccessControl, AclAction};
+use crate::realm::federation::RouteTable;
+use crate::realm::limits::{LoginAttempt, RateLimited, RateLimiter};
+use crate::realm::login::{LoginResult, LoginSession, PasswordCredentials};
+use crate::realm::Realm;
+use bytes::Bytes;
+use log::{debug, warn};
+use packet::packet::Packet;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// Responses the Realm Handler can return
+pub enum RealmResponse {
+    /// The connection now has a context. Nothing has to be sent back
+    NewContext(Box<RealmConnectionContext>),
+    /// Send this message back to the device
+    Message(Protocol),
+    /// Send this message to another device connected to the Realm
+    Forward {
+        target: Uuid,
+        message: Protocol,
+    },
+    /// Send this message over the federation link to another Realm
+    ForwardToRealm {
+        realm_id: Uuid,
+        message: Protocol,
+    },
+    Nothing,
+}
+
+/// Context for a device connected to the Realm
+pub struct RealmConnectionContext {
+    /// The device id sent inside of the Hello
+    pub device_id: Uuid,
+    /// The session. None until the device has logged in
+    pub session: Option<LoginSession>,
+    /// What the device and the Realm agreed on inside of Hello
+    pub negotiated: Option<Negotiated>,
+}
+
+impl RealmConnectionContext {
+    pub fn new(device_id: Uuid) -> Self {
+        RealmConnectionContext {
+            device_id,
+            session: None,
+            negotiated: None,
+        }
+    }
+    /// Rather or not the device has a session that has not expired
+    pub fn is_logged_in(&self) -> bool {
+        self.session
+            .as_ref()
+            .map(|session| !session.is_expired())
+            .unwrap_or(false)
+    }
+}
+
+/// The Protocol Handler for a Realm Server.
+pub struct RealmHandler<'realm, R: Realm> {
+    realm: &'realm R,
+    access_control: AccessControl,
+    routes: Option<&'realm RouteTable>,
+    rate_limiter: Option<&'realm RateLimiter>,
+    capabilities: Capabilities,
+}
+
+impl<'realm, R: Realm> RealmHandler<'realm, R> {
+    pub fn new(realm: &'realm R, access_control: AccessControl) -> Self {
+        RealmHandler {
+            realm,
+            access_control,
+            routes: None,
+            rate_limiter: None,
+            capabilities: Capabilities::realm(),
+        }
+    }
+
+    /// Devices that are not connected to this Realm will be looked up in the route table
+    /// and forwarded over the federation link
+    pub fn with_routes(mut self, routes: &'realm RouteTable) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// Hello, DeviceLogin and DeviceProxy packets will be checked against the rate limiter.
+    /// Failed logins will count towards the lockout
+    pub fn with_rate_limiter(mut self, rate_limiter: &'realm RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// What the Realm supports. Defaults to [`Capabilities::realm`]
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn access_control(&self) -> &AccessControl {
+        &self.access_control
+    }
+
+    pub fn access_control_mut(&mut self) -> &mut AccessControl {
+        &mut self.access_control
+    }
+
+    /// Handles a packet sent from a device to the Realm
+    ///
+    /// `source` is the address the packet came from. It is used for rate limiting
+    pub fn handle_packet(
+        &mut self,
+        packet: Protocol,
+        source: IpAddr,
+        connection_context: Option<&mut RealmConnectionContext>,
+    ) -> Result<RealmResponse, R::Error> {
+        match packet {
+            Protocol::DeviceToRealm(realm_packet) => {
+                let packet_id = realm_packet.get_packet_id();
+                let negotiated = connection_context
+                    .as_ref()
+                    .and_then(|context| context.negotiated.as_ref());
+                if negotiated.map(|negotiated| !negotiated.supports(DEVICE_TO_REALM)).unwrap_or(false) {
+                    return Ok(Self::error(ErrorPacket::incompatible(DEVICE_TO_REALM, packet_id)));
+                }
+                if let Err(limited) = self.check_limits(&realm_packet, source, connection_context.as_deref()) {
+                    debug!("Rate limited {} from {}: {:?}", packet_id, source, limited);
+                    return Ok(Self::error(match limited {
+                        RateLimited::LockedOut { .. } => {
+                            ErrorPacket::locked_out(DEVICE_TO_REALM, packet_id)
+                        }
+                        _ => ErrorPacket::rate_limited(DEVICE_TO_REALM, packet_id),
+                    }));
+                }
+                self.handle_realm_packet(realm_packet, source, connection_context)
+            }
+            Protocol::DeviceToDevice(packet) => Ok(Self::error(ErrorPacket::invalid_state(
+                DEVICE_TO_DEVICE,
+                packet.get_packet_id(),
+            ))),
+            // Federation links are handled by the FederationHandler
+            Protocol::RealmToRealm(packet) => Ok(Self::error(ErrorPacket::invalid_state(
+                REALM_TO_REALM,
+                packet.get_packet_id(),
+            ))),
+        }
+    }
+
+    fn check_limits(
+        &self,
+        packet: &RealmPacket,
+        source: IpAddr,
+        connection_context: Option<&RealmConnectionContext>,
+    ) -> Result<(), RateLimited> {
+        let limiter = if let Some(limiter) = self.rate_limiter {
+            limiter
+        } else {
+            return Ok(());
+        };
+        let device_id = connection_context.map(|context| context.device_id);
+        match packet {
+            RealmPacket::Hello { device_id, .. } => {
+                limiter.check_ip(source)?;
+                limiter.check_device(*device_id)
+            }
+            RealmPacket::DeviceLogin(details) => {
+                limiter.check_ip(source)?;
+                if let Some(device_id) = device_id {
+                    let credentials = PasswordCredentials::from_details(details);
+                    limiter.check_lockout(&Self::login_attempt(device_id, source, credentials.as_ref()))?;
+                    limiter.check_device(device_id)?;
+                }
+                Ok(())
+            }
+            RealmPacket::DeviceProxy(target, _) => {
+                limiter.check_ip(source)?;
+                if let Some(device_id) = device_id {
+                    limiter.check_device(device_id)?;
+                }
+                limiter.check_target(*target)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The device, address and account of a login for the lockout
+    fn login_attempt(device_id: Uuid, source: IpAddr, credentials: Option<&PasswordCredentials>) -> LoginAttempt<'_> {
+        LoginAttempt {
+            device_id,
+            address: source,
+            account: credentials.map(|credentials| credentials.username.as_str()),
+        }
+    }
+
+    fn handle_realm_packet(
+        &mut self,
+        packet: RealmPacket,
+        source: IpAddr,
+        connection_context: Option<&mut RealmConnectionContext>,
+    ) -> Result<RealmResponse, R::Error> {
+        let packet_id = packet.get_packet_id();
+        match packet {
+            RealmPacket::Hello { device_id, capabilities, .. } => {
+                if connection_context.is_some() {
+                    return Ok(Self::error(ErrorPacket::invalid_state(DEVICE_TO_REALM, packet_id)));
+                }
+                match self.capabilities.negotiate(&capabilities) {
+                    Ok(negotiated) if negotiated.supports(DEVICE_TO_REALM) => {
+                        let mut context = RealmConnectionContext::new(device_id);
+                        context.negotiated = Some(negotiated);
+                        Ok(RealmResponse::NewContext(Box::new(context)))
+                    }
+                    Ok(_) => {
+                        warn!("Refused Hello from {}: DeviceToRealm was not negotiated", device_id);
+                        Ok(Self::error(ErrorPacket::incompatible(DEVICE_TO_REALM, packet_id)))
+                    }
+                    Err(incompatible) => {
+                        warn!("Refused Hello from {}: {}", device_id, incompatible);
+                        Ok(Self::error(ErrorPacket::incompatible(DEVICE_TO_REALM, packet_id)))
+                    }
+                }
+            }
+            RealmPacket::DeviceLogin(details) => {
+                let context = if let Some(context) = connection_context {
+                    context
+                } else {
+                    return Ok(Self::error(ErrorPacket::invalid_state(DEVICE_TO_REALM, packet_id)));
+                };
+                let provider_id = match &details {
+                    LoginDetails::None => 0,
+                    LoginDetails::Other { id, .. } => *id,
+                };
+                let credentials = PasswordCredentials::from_details(&details);
+                let attempt = Self::login_attempt(context.device_id, source, credentials.as_ref());
+                let result = self.realm.login(&context.device_id, details);
+                // Details that could not be checked count as a failure as well
+                if let (Some(limiter), Ok(LoginResult::Rejected) | Err(_)) = (self.rate_limiter, &result) {
+                    limiter.login_failed(&attempt);
+                }
+                match result? {
+                    LoginResult::Accepted(session) => {
+                        if let Some(limiter) = self.rate_limiter {
+                            limiter.login_succeeded(&attempt);
+                        }
+                        let message = RealmPacket::LoginAccepted {
+                            session_token: session.token.clone(),
+                            expires_at: session.expires_at_unix(),
+                        };
+                        context.session = Some(session);
+                        Ok(RealmResponse::Message(message.into()))
+                    }
+                    LoginResult::Challenge(challenge) => Ok(RealmResponse::Message(
+                        RealmPacket::LoginChallenge {
+                            provider_id,
+                            challenge,
+                        }
+                            .into(),
+                    )),
+                    LoginResult::Rejected => {
+                        Ok(Self::error(ErrorPacket::login_rejected(DEVICE_TO_REALM, packet_id)))
+                    }
+                }
+            }
+            RealmPacket::DeviceProxy(target, payload) => {
+                let context = match connection_context {
+                    Some(context) if context.is_logged_in() => context,
+                    _ => {
+                        return Ok(Self::error(ErrorPacket::not_logged_in(DEVICE_TO_REALM, packet_id)));
+                    }
+                };
+                self.proxy(context, packet_id, target, payload)
+            }
+            RealmPacket::Heartbeat => Ok(RealmResponse::Nothing),
+            RealmPacket::Error(error) => {
+                warn!("Error: {:?}", error);
+                Ok(RealmResponse::Nothing)
+            }
+            // The Realm key exchange is not supported yet
+            RealmPacket::SendKey { .. }
+            | RealmPacket::KeyCheck(_)
+            | RealmPacket::KeyCheckResponse(_) => {
+                Ok(Self::error(ErrorPacket::invalid_state(DEVICE_TO_REALM, packet_id)))
+            }
+            // These are only sent by the Realm
+            RealmPacket::LoginChallenge { .. } | RealmPacket::LoginAccepted { .. } => {
+                Ok(Self::error(ErrorPacket::invalid_state(DEVICE_TO_REALM, packet_id)))
+            }
+        }
+    }
+
+    /// Checks the frame against the access control list and forwards it to the target.
+    ///
+    /// The payload starts with the protocol id and packet id. The content after that is never read
+    fn proxy(
+        &mut self,
+        context: &RealmConnectionContext,
+        packet_id: u8,
+        target: Uuid,
+        payload: Bytes,
+    ) -> Result<RealmResponse, R::Error> {
+        let (protocol_id, _) = match packet::read_packet_type(&mut payload.as_ref()) {
+            Ok(value) => value,
+            Err(_) => {
+                return Ok(Self::error(ErrorPacket::malformed_packet(DEVICE_TO_REALM, packet_id)));
+            }
+        };
+        if let AclAction::Deny = self
+            .access_control
+            .check(&context.device_id, &target, protocol_id)
+        {
+            debug!(
+                "Denied proxy from {} to {} for protocol {}",
+                context.device_id, target, protocol_id
+            );
+            return Ok(Self::error(ErrorPacket::access_denied(DEVICE_TO_REALM, packet_id)));
+        }
+        if self.realm.is_paired(&target) {
+            return Ok(RealmResponse::Forward {
+                target,
+                message: RealmPacket::DeviceProxy(context.device_id, payload).into(),
+            });
+        }
+        if let Some(route) = self.routes.and_then(|routes| routes.get(&target)) {
+            return Ok(RealmResponse::ForwardToRealm {
+                realm_id: route.via,
+                message: FederationPacket::Forward {
+                    source: context.device_id,
+                    target,
+                    hops: 0,
+                    payload,
+                }
+                    .into(),
+            });
+        }
+        Ok(Self::error(ErrorPacket::unknown_device(DEVICE_TO_REALM, packet_id)))
+    }
+
+    fn error(error: ErrorPacket) -> RealmResponse {
+        RealmResponse::Message(RealmPacket::Error(error).into())
+    }
+}
@@ -1,4 +1,8 @@
+/// Protocol version and capability negotiation. Sent inside of both Hello packets
+pub mod capabilities;
 pub mod dtd;
+/// Realm to Realm packets used for federation
+pub mod federation;
 /// Default Handlers for the packets established here
 pub mod handlers;
 pub mod realm;
@@ -6,11 +10,10 @@ pub mod realm;
 use std::borrow::Cow;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::io::{BufRead, Write};
 use crate::packets::dtd::DeviceToDevicePackets;
+use crate::packets::federation::FederationPacket;
 use crate::packets::realm::RealmPacket;
-use packet::{PacketContent, PacketReadError, PacketWriteError, Protocol};
-use rmp::Marker;
+use packet::{PacketContent, Protocol};
 
 #[derive(Protocol)]
 pub enum Protocol {
@@ -20,6 +23,8 @@ pub enum Protocol {
     //DeviceToPeer (),
     #[protocol(protocol_id = 0x02)]
     DeviceToRealm(RealmPacket),
+    #[protocol(protocol_id = 0x03)]
+    RealmToRealm(FederationPacket),
 }
 
 pub trait PacketType {
@@ -27,9 +32,10 @@ pub trait PacketType {
 }
 
 /// An Error Packet Response
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PacketContent)]
 pub enum ErrorPacket {
     /// Error that contains a reference to the error causing packet
+    #[content(tag = 0)]
     ErrorWithReference {
         reference_protocol: u8,
         reference_packet: u8,
@@ -37,20 +43,73 @@ pub enum ErrorPacket {
         error_message: Option<Cow<'static, str>>,
     },
     /// No Reference to what caused the error
+    #[content(tag = 1)]
     ErrorNoReference {
         error_code: u8,
         error_message: Option<Cow<'static, str>>,
     },
 }
 impl ErrorPacket{
+    /// The packet was not expected in the current state of the connection
+    pub const INVALID_STATE: u8 = 0;
+    /// The key or the key check was not accepted
+    pub const KEY_CHECK_FAILED: u8 = 1;
+    /// The device has to log in before sending this packet
+    pub const NOT_LOGGED_IN: u8 = 2;
+    /// The login details were not accepted
+    pub const LOGIN_REJECTED: u8 = 3;
+    /// The access control list does not allow this
+    pub const ACCESS_DENIED: u8 = 4;
+    /// The device the packet is addressed to is unknown
+    pub const UNKNOWN_DEVICE: u8 = 5;
+    /// The packet could not be read
+    pub const MALFORMED_PACKET: u8 = 6;
+    /// Too many packets were sent. Slow down
+    pub const RATE_LIMITED: u8 = 7;
+    /// Too many failed logins. Wait before trying again
+    pub const LOCKED_OUT: u8 = 8;
+    /// The Hello did not share a version, encryption suite or protocol with this side
+    pub const INCOMPATIBLE: u8 = 9;
+
     pub fn invalid_state(protocol: u8, packet: u8) -> Self {
         ErrorPacket::ErrorWithReference {
             reference_protocol: protocol,
             reference_packet: packet,
-            error_code: 0,
+            error_code: Self::INVALID_STATE,
             error_message: Some("Invalid State".into()),
         }
     }
+    pub fn not_logged_in(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::NOT_LOGGED_IN, "Not Logged In"))
+    }
+    pub fn login_rejected(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::LOGIN_REJECTED, "Login Rejected"))
+    }
+    pub fn access_denied(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::ACCESS_DENIED, "Access Denied"))
+    }
+    pub fn unknown_device(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::UNKNOWN_DEVICE, "Unknown Device"))
+    }
+    pub fn malformed_packet(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::MALFORMED_PACKET, "Malformed Packet"))
+    }
+    pub fn rate_limited(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::RATE_LIMITED, "Rate Limited"))
+    }
+    pub fn locked_out(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::LOCKED_OUT, "Locked Out"))
+    }
+    pub fn incompatible(protocol: u8, packet: u8) -> Self {
+        ErrorPacket::from((protocol, packet, Self::INCOMPATIBLE, "Incompatible"))
+    }
+    /// The error code regardless of the variant
+    pub fn error_code(&self) -> u8 {
+        match self {
+            ErrorPacket::ErrorWithReference { error_code, .. } => *error_code,
+            ErrorPacket::ErrorNoReference { error_code, .. } => *error_code,
+        }
+    }
 }
 impl From<(u8, u8,u8)> for ErrorPacket{
     fn from((protocol, reference, error): (u8, u8, u8)) -> Self {
@@ -121,49 +180,3 @@ impl Error for ErrorPacket {
         None
     }
 }
-
-impl PacketContent for ErrorPacket {
-    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        let buf = reader.fill_buf()?;
-        if let Marker::True = Marker::from_u8(buf[0]) {
-            Ok(Self::ErrorWithReference {
-                reference_protocol: rmp::decode::read_u8(reader)?,
-                reference_packet: rmp::decode::read_u8(reader)?,
-                error_code: rmp::decode::read_u8(reader)?,
-                error_message: PacketContent::read(reader)?,
-            })
-        } else {
-            Ok(Self::ErrorNoReference {
-                error_code: rmp::decode::read_u8(reader)?,
-                error_message: PacketContent::read(reader)?,
-            })
-        }
-    }
-
-    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
-        rmp::encode::write_u8(writer, 1)?;
-        match self {
-            Self::ErrorWithReference {
-                reference_protocol,
-                reference_packet,
-                error_code,
-                error_message,
-            } => {
-                rmp::encode::write_bool(writer, false)?;
-                rmp::encode::write_u8(writer, *reference_protocol)?;
-                rmp::encode::write_u8(writer, *reference_packet)?;
-                rmp::encode::write_u8(writer, *error_code)?;
-                PacketContent::write(error_message, writer)?;
-            }
-            Self::ErrorNoReference {
-                error_code,
-                error_message,
-            } => {
-                rmp::encode::write_bool(writer, false)?;
-                rmp::encode::write_u8(writer, *error_code)?;
-                PacketContent::write(error_message, writer)?;
-            }
-        }
-        Ok(())
-    }
-}
\ No newline at end of file
@@ -1,7 +1,7 @@
-use std::io::{BufRead, Write};
-use bytes::{Bytes, BytesMut};
-use packet::{Packet, PacketReadError, PacketWriteError};
+use bytes::Bytes;
+use packet::Packet;
 use uuid::Uuid;
+use crate::packets::capabilities::Capabilities;
 use crate::packets::ErrorPacket;
 use packet::{PacketContent};
 
@@ -21,6 +21,8 @@ pub enum RealmPacket {
     Hello {
         device_id: Uuid,
         public_key_hash: Option<Bytes>, // Hash of the public key that the realm should have. None if the realm is not paired
+        /// The version, encryption suites and protocols the device supports
+        capabilities: Capabilities,
     },
     /// Sent from the Realm to the client if the login was successful
     #[packet(packet_id = 3)]
@@ -37,49 +39,32 @@ pub enum RealmPacket {
     DeviceLogin(LoginDetails),
     #[packet(packet_id = 7)]
     DeviceProxy(Uuid, Bytes),
+    /// Sent from the Realm when the login provider wants the device to sign a challenge.
+    /// The device answers with a new DeviceLogin using the same provider id
+    #[packet(packet_id = 8)]
+    LoginChallenge {
+        provider_id: u8,
+        challenge: Bytes,
+    },
+    /// Sent from the Realm to the client if the login was successful
+    #[packet(packet_id = 9)]
+    LoginAccepted {
+        session_token: Bytes,
+        /// Seconds since the unix epoch
+        expires_at: u64,
+    },
 }
 
 /// The login details for the Realm
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PacketContent)]
 pub enum LoginDetails {
     /// No Login Details
+    #[content(tag = 0)]
     None,
     /// Login Details up for implementation details
+    #[content(tag = 1)]
     Other {
         id: u8,
         details: Bytes,
     },
 }
-
-impl PacketContent for LoginDetails {
-    fn read<Reader: BufRead>(reader: &mut Reader) -> Result<Self, PacketReadError> where Self: Sized {
-        match rmp::decode::read_u8(reader)? {
-            0 => {
-                Ok(LoginDetails::None)
-            }
-            id => {
-                let details = rmp::decode::read_bin_len(reader).map_err(PacketReadError::from)?;
-                let mut bytes = BytesMut::with_capacity(details as usize);
-                reader.read_exact(&mut bytes).map_err(PacketReadError::from)?;
-                let other = LoginDetails::Other {
-                    id,
-                    details: bytes.freeze(),
-                };
-                Ok(other)
-            }
-        }
-    }
-
-    fn write<Writer: Write>(&self, writer: &mut Writer) -> Result<(), PacketWriteError> where Self: Sized {
-        match self {
-            LoginDetails::None => {
-                rmp::encode::write_u8(writer, 0).map_err(PacketWriteError::from)?;
-            }
-            LoginDetails::Other { id, details } => {
-                rmp::encode::write_u8(writer, *id).map_err(PacketWriteError::from)?;
-                rmp::encode::write_bin(writer, details.as_ref()).map_err(PacketWriteError::from)?;
-            }
-        }
-        Ok(())
-    }
-}
\ No newline at end of file
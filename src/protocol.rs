@@ -20,17 +20,50 @@ pub enum ConnectionStatus {
         key_b: Option<EcdsaPublicKey>,
         /// The Test String. If None do not test. If it is some It needs to be verified
         test: Option<Bytes>,
+        /// The name sent inside of the PairRequest
+        device_name: Option<String>,
     },
     /// The connection is still needing to be encrypted
     PendingEncryption,
 
+    /// Both devices check that the other one has the shared keys. Nothing is registered until both checks pass
     CheckingKeys {
-        random_bytes: Bytes,
+        /// Sent to the other device inside of a KeyCheck. None until this side sends its check
+        random_bytes: Option<Bytes>,
+        /// The other device sent `random_bytes` back
+        verified: bool,
+        /// The KeyCheck of the other device has been answered
+        answered: bool,
     },
     /// The connection is ready to use.
     Connected,
 }
 
+impl ConnectionStatus {
+    /// The status without the keys and test strings
+    pub fn kind(&self) -> ConnectionStatusKind {
+        match self {
+            ConnectionStatus::Entry => ConnectionStatusKind::Entry,
+            ConnectionStatus::PendingPairRequest { .. } => ConnectionStatusKind::PendingPairRequest,
+            ConnectionStatus::Pairing { .. } => ConnectionStatusKind::Pairing,
+            ConnectionStatus::PendingEncryption => ConnectionStatusKind::PendingEncryption,
+            ConnectionStatus::CheckingKeys { .. } => ConnectionStatusKind::CheckingKeys,
+            ConnectionStatus::Connected => ConnectionStatusKind::Connected,
+        }
+    }
+}
+
+/// A [`ConnectionStatus`] that can be handed to the app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatusKind {
+    Entry,
+    PendingPairRequest,
+    Pairing,
+    PendingEncryption,
+    CheckingKeys,
+    Connected,
+}
+
 pub trait ConnectionType {}
 
 #[derive(Clone)]
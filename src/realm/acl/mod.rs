@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// What to do with a proxied frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+/// Who a rule applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclSubject {
+    /// Every device
+    Any,
+    Device(Uuid),
+    /// Every device in the group. See [`AccessControl::add_to_group`]
+    Group(String),
+}
+
+/// A single allow or deny rule between two devices
+#[derive(Debug, Clone)]
+pub struct AclRule {
+    /// The device sending the DeviceProxy
+    pub source: AclSubject,
+    /// The device the DeviceProxy is addressed to
+    pub target: AclSubject,
+    /// The protocol ids this rule applies to. None means all protocols
+    pub protocols: Option<Vec<u8>>,
+    pub action: AclAction,
+}
+
+impl AclRule {
+    pub fn allow(source: AclSubject, target: AclSubject) -> Self {
+        AclRule {
+            source,
+            target,
+            protocols: None,
+            action: AclAction::Allow,
+        }
+    }
+    pub fn deny(source: AclSubject, target: AclSubject) -> Self {
+        AclRule {
+            source,
+            target,
+            protocols: None,
+            action: AclAction::Deny,
+        }
+    }
+    /// Limits the rule to the given protocol ids
+    pub fn for_protocols(mut self, protocols: impl Into<Vec<u8>>) -> Self {
+        self.protocols = Some(protocols.into());
+        self
+    }
+}
+
+/// The access control list used by the Realm for every proxied frame.
+///
+/// Rules are checked in the order they were added, and the first matching rule wins.
+/// If no rule matches, devices in the same group (owned by the same account) are allowed when
+/// `allow_same_group` is set. Otherwise the `default_action` is used.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Vec<AclRule>,
+    groups: HashMap<String, HashSet<Uuid>>,
+    pub allow_same_group: bool,
+    pub default_action: AclAction,
+}
+
+impl Default for AccessControl {
+    /// Allows everything
+    fn default() -> Self {
+        AccessControl::new(AclAction::Allow)
+    }
+}
+
+impl AccessControl {
+    pub fn new(default_action: AclAction) -> Self {
+        AccessControl {
+            rules: Vec::new(),
+            groups: HashMap::new(),
+            allow_same_group: true,
+            default_action,
+        }
+    }
+
+    pub fn add_rule(&mut self, rule: AclRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Adds the device to the group. Returns false if it was already in the group
+    pub fn add_to_group(&mut self, group: impl Into<String>, device_id: Uuid) -> bool {
+        self.groups.entry(group.into()).or_default().insert(device_id)
+    }
+
+    pub fn remove_from_group(&mut self, group: &str, device_id: &Uuid) -> bool {
+        self.groups
+            .get_mut(group)
+            .map(|devices| devices.remove(device_id))
+            .unwrap_or(false)
+    }
+
+    pub fn is_in_group(&self, group: &str, device_id: &Uuid) -> bool {
+        self.groups
+            .get(group)
+            .map(|devices| devices.contains(device_id))
+            .unwrap_or(false)
+    }
+
+    fn matches(&self, subject: &AclSubject, device_id: &Uuid) -> bool {
+        match subject {
+            AclSubject::Any => true,
+            AclSubject::Device(id) => id == device_id,
+            AclSubject::Group(group) => self.is_in_group(group, device_id),
+        }
+    }
+
+    fn share_group(&self, source: &Uuid, target: &Uuid) -> bool {
+        self.groups
+            .values()
+            .any(|devices| devices.contains(source) && devices.contains(target))
+    }
+
+    /// Checks if the source can send a frame of the protocol to the target
+    pub fn check(&self, source: &Uuid, target: &Uuid, protocol_id: u8) -> AclAction {
+        let rule = self.rules.iter().find(|rule| {
+            self.matches(&rule.source, source)
+                && self.matches(&rule.target, target)
+                && rule
+                .protocols
+                .as_ref()
+                .map(|protocols| protocols.contains(&protocol_id))
+                .unwrap_or(true)
+        });
+        if let Some(rule) = rule {
+            rule.action
+        } else if self.allow_same_group && self.share_group(source, target) {
+            AclAction::Allow
+        } else {
+            self.default_action
+        }
+    }
+
+    pub fn is_allowed(&self, source: &Uuid, target: &Uuid, protocol_id: u8) -> bool {
+        self.check(source, target, protocol_id) == AclAction::Allow
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Routes and forwarded frames are dropped after passing through this many realms
+pub const MAX_HOPS: u8 = 8;
+
+/// How to reach a device connected to another realm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    /// The federated realm to send the frame to
+    pub via: Uuid,
+    /// The number of realm hops to the device
+    pub hops: u8,
+}
+
+/// Routes learnt from the federated realms.
+///
+/// This is shared between every connection of the Realm, so it locks internally.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    routes: RwLock<HashMap<Uuid, Route>>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, device_id: &Uuid) -> Option<Route> {
+        self.routes
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .get(device_id)
+            .copied()
+    }
+
+    /// Learns a route. Only replaces the existing route if the new one is shorter or comes from the same realm.
+    ///
+    /// # Returns
+    /// true if the route table changed
+    pub fn learn(&self, device_id: Uuid, route: Route) -> bool {
+        if route.hops > MAX_HOPS {
+            return false;
+        }
+        let mut routes = self.routes.write().unwrap_or_else(|error| error.into_inner());
+        match routes.get(&device_id) {
+            Some(existing) if *existing == route => false,
+            Some(existing) if existing.via != route.via && existing.hops <= route.hops => false,
+            _ => {
+                routes.insert(device_id, route);
+                true
+            }
+        }
+    }
+
+    /// Removes the route if it goes through the realm
+    pub fn withdraw(&self, device_id: &Uuid, via: &Uuid) -> bool {
+        let mut routes = self.routes.write().unwrap_or_else(|error| error.into_inner());
+        if routes.get(device_id).map(|route| &route.via == via).unwrap_or(false) {
+            routes.remove(device_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes every route going through the realm. Returns the devices that are no longer reachable
+    pub fn remove_via(&self, via: &Uuid) -> Vec<Uuid> {
+        let mut routes = self.routes.write().unwrap_or_else(|error| error.into_inner());
+        let removed: Vec<Uuid> = routes
+            .iter()
+            .filter(|(_, route)| &route.via == via)
+            .map(|(device_id, _)| *device_id)
+            .collect();
+        for device_id in &removed {
+            routes.remove(device_id);
+        }
+        removed
+    }
+
+    /// A copy of every route
+    pub fn routes(&self) -> Vec<(Uuid, Route)> {
+        self.routes
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .iter()
+            .map(|(device_id, route)| (*device_id, *route))
+            .collect()
+    }
+}
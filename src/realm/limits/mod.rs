@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Where the [`RateLimiter`] gets the time from
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// [`Instant::now`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        C::now(self)
+    }
+}
+
+/// A token bucket. Holds up to `capacity` tokens and refills `per_second` tokens every second
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub fn new(capacity: u32, per_second: f64) -> Self {
+        RateLimit {
+            capacity,
+            per_second,
+        }
+    }
+}
+
+/// Exponential lockout after failed logins.
+///
+/// Failures are counted for the device, the account and the IP address the login came from
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutSettings {
+    /// The number of failed logins in a row before the device or account is locked out
+    pub failures_before_lockout: u32,
+    /// The number of failed logins in a row before the IP address is locked out.
+    /// Higher because several devices can share one address
+    pub failures_before_ip_lockout: u32,
+    /// The length of the first lockout. Every failure after that doubles it
+    pub base: Duration,
+    /// The longest a lockout can be
+    pub max: Duration,
+    /// Failures are forgotten once there has not been one for this long
+    pub forget_after: Duration,
+}
+
+impl Default for LockoutSettings {
+    fn default() -> Self {
+        LockoutSettings {
+            failures_before_lockout: 5,
+            failures_before_ip_lockout: 25,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60 * 15),
+            forget_after: Duration::from_secs(60 * 15),
+        }
+    }
+}
+
+/// A login. Every part of it can be locked out
+#[derive(Debug, Clone, Copy)]
+pub struct LoginAttempt<'a> {
+    pub device_id: Uuid,
+    pub address: IpAddr,
+    /// The account the login is for. None if the login details do not name one
+    pub account: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LockoutKey {
+    Device(Uuid),
+    Account(String),
+    Ip(IpAddr),
+}
+
+impl LoginAttempt<'_> {
+    fn keys(&self) -> Vec<LockoutKey> {
+        let mut keys = vec![LockoutKey::Device(self.device_id), LockoutKey::Ip(self.address)];
+        if let Some(account) = self.account {
+            keys.push(LockoutKey::Account(account.to_string()));
+        }
+        keys
+    }
+}
+
+/// The limits used by the [`RateLimiter`]. A None limit is not checked
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    /// For every source IP address. Applies to Hello, DeviceLogin and DeviceProxy
+    pub per_ip: Option<RateLimit>,
+    /// For every device id. Applies to DeviceLogin and DeviceProxy
+    pub per_device: Option<RateLimit>,
+    /// For every device a DeviceProxy is addressed to
+    pub per_target: Option<RateLimit>,
+    pub lockout: LockoutSettings,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            per_ip: Some(RateLimit::new(100, 20.0)),
+            per_device: Some(RateLimit::new(100, 20.0)),
+            per_target: Some(RateLimit::new(200, 50.0)),
+            lockout: LockoutSettings::default(),
+        }
+    }
+}
+
+/// Why a packet was limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    Ip,
+    Device,
+    Target,
+    /// Too many failed logins. Try again after `retry_after`
+    LockedOut {
+        retry_after: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.capacity as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.capacity as f64);
+        self.last = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LoginFailures {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Keeps one bucket for every key
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    fn new() -> Self {
+        Buckets {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take(&self, key: K, limit: &RateLimit, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|error| error.into_inner());
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::full(limit, now))
+            .take(limit, now)
+    }
+
+    /// Removes buckets that have refilled. They are the same as a new bucket
+    fn prune(&self, limit: &RateLimit, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|error| error.into_inner());
+        buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.capacity as f64
+        });
+    }
+}
+
+/// Token bucket rate limiting and login lockouts for the Realm.
+///
+/// This is shared between every connection of the Realm, so it locks internally.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    ips: Buckets<IpAddr>,
+    devices: Buckets<Uuid>,
+    targets: Buckets<Uuid>,
+    failures: Mutex<HashMap<LockoutKey, LoginFailures>>,
+    clock: Box<dyn Clock>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(RateLimits::default())
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            ips: Buckets::new(),
+            devices: Buckets::new(),
+            targets: Buckets::new(),
+            failures: Mutex::new(HashMap::new()),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Uses `clock` instead of [`SystemClock`]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    pub fn check_ip(&self, address: IpAddr) -> Result<(), RateLimited> {
+        match &self.limits.per_ip {
+            Some(limit) if !self.ips.take(address, limit, self.clock.now()) => Err(RateLimited::Ip),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_device(&self, device_id: Uuid) -> Result<(), RateLimited> {
+        match &self.limits.per_device {
+            Some(limit) if !self.devices.take(device_id, limit, self.clock.now()) => Err(RateLimited::Device),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_target(&self, target: Uuid) -> Result<(), RateLimited> {
+        match &self.limits.per_target {
+            Some(limit) if !self.targets.take(target, limit, self.clock.now()) => Err(RateLimited::Target),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error if the device, account or IP address of the login is locked out because of failed logins
+    pub fn check_lockout(&self, attempt: &LoginAttempt) -> Result<(), RateLimited> {
+        let now = self.clock.now();
+        let failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        let locked_until = attempt
+            .keys()
+            .iter()
+            .filter_map(|key| failures.get(key).and_then(|value| value.locked_until))
+            .max();
+        match locked_until {
+            Some(until) if until > now => Err(RateLimited::LockedOut {
+                retry_after: until - now,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a failed login for the device, account and IP address.
+    /// Once the failures reach the limit every failure doubles the lockout
+    pub fn login_failed(&self, attempt: &LoginAttempt) {
+        let settings = &self.limits.lockout;
+        let now = self.clock.now();
+        let mut failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        for key in attempt.keys() {
+            let before_lockout = match key {
+                LockoutKey::Ip(_) => settings.failures_before_ip_lockout,
+                _ => settings.failures_before_lockout,
+            };
+            let entry = failures.entry(key).or_insert(LoginFailures {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if now.saturating_duration_since(entry.last_failure) >= settings.forget_after {
+                entry.failures = 0;
+            }
+            entry.failures = entry.failures.saturating_add(1);
+            entry.last_failure = now;
+            if entry.failures >= before_lockout {
+                let exponent = (entry.failures - before_lockout).min(31);
+                let lockout = settings
+                    .base
+                    .checked_mul(1u32 << exponent)
+                    .unwrap_or(settings.max)
+                    .min(settings.max);
+                entry.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    /// Resets the failed logins of the device and account. The IP address keeps its failures
+    pub fn login_succeeded(&self, attempt: &LoginAttempt) {
+        let mut failures = self.failures.lock().unwrap_or_else(|error| error.into_inner());
+        for key in attempt.keys() {
+            if !matches!(key, LockoutKey::Ip(_)) {
+                failures.remove(&key);
+            }
+        }
+    }
+
+    /// The number of devices, accounts and IP addresses with failed logins that are remembered
+    pub fn tracked_failures(&self) -> usize {
+        self.failures.lock().unwrap_or_else(|error| error.into_inner()).len()
+    }
+
+    /// Removes state that no longer limits anything. Call this every now and then to keep memory in check
+    pub fn prune(&self) {
+        let now = self.clock.now();
+        if let Some(limit) = &self.limits.per_ip {
+            self.ips.prune(limit, now);
+        }
+        if let Some(limit) = &self.limits.per_device {
+            self.devices.prune(limit, now);
+        }
+        if let Some(limit) = &self.limits.per_target {
+            self.targets.prune(limit, now);
+        }
+        let forget_after = self.limits.lockout.forget_after;
+        self.failures
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .retain(|_, value| {
+                value.locked_until.map(|until| until > now).unwrap_or(false)
+                    || now.saturating_duration_since(value.last_failure) < forget_after
+            });
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use hmac::Hmac;
+use packet::{PacketContent, PacketReadError};
+use rand::Rng;
+use sha2::Sha256;
+use themis::keys::EcdsaPublicKey;
+use themis::secure_message::SecureVerify;
+use uuid::Uuid;
+
+use crate::encryption::EncryptionError;
+use crate::packets::realm::LoginDetails;
+
+/// Provider id used for [`LoginDetails::None`]
+pub const NO_DETAILS_PROVIDER_ID: u8 = 0;
+/// Provider id of the [`PreSharedTokenProvider`]
+pub const PRE_SHARED_TOKEN_PROVIDER_ID: u8 = 1;
+/// Provider id of the [`PasswordProvider`]
+pub const PASSWORD_PROVIDER_ID: u8 = 2;
+/// Provider id of the [`ChallengeResponseProvider`]
+pub const CHALLENGE_RESPONSE_PROVIDER_ID: u8 = 3;
+
+/// The result of a login attempt
+#[derive(Debug, Clone)]
+pub enum LoginResult {
+    /// The device is now logged in
+    Accepted(LoginSession),
+    /// The provider wants the device to sign this challenge and send a new DeviceLogin
+    Challenge(Bytes),
+    /// The login details were not accepted
+    Rejected,
+}
+
+/// A session handed to a device after a successful login
+#[derive(Debug, Clone)]
+pub struct LoginSession {
+    /// Random token identifying the session
+    pub token: Bytes,
+    /// When the session stops being valid
+    pub expires_at: SystemTime,
+    /// What the session is allowed to do. The meaning is up to the Realm
+    pub scopes: Vec<String>,
+}
+
+impl LoginSession {
+    /// Creates a session with a random 32 byte token
+    pub fn new(ttl: Duration, scopes: Vec<String>) -> Self {
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill(&mut token);
+        LoginSession {
+            token: Bytes::copy_from_slice(&token),
+            expires_at: SystemTime::now() + ttl,
+            scopes,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|value| value == scope)
+    }
+
+    /// Seconds since the unix epoch. This is what is sent inside of `RealmPacket::LoginAccepted`
+    pub fn expires_at_unix(&self) -> u64 {
+        self.expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// How long the sessions created by a provider last and what scopes they get
+#[derive(Debug, Clone)]
+pub struct SessionSettings {
+    pub ttl: Duration,
+    pub scopes: Vec<String>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        SessionSettings {
+            ttl: Duration::from_secs(60 * 60),
+            scopes: Vec::new(),
+        }
+    }
+}
+
+impl SessionSettings {
+    pub fn start(&self) -> LoginSession {
+        LoginSession::new(self.ttl, self.scopes.clone())
+    }
+}
+
+#[derive(Debug)]
+pub enum LoginError {
+    /// No provider is registered for the id
+    UnknownProvider(u8),
+    /// The details could not be parsed by the provider
+    MalformedDetails(PacketReadError),
+    Encryption(EncryptionError),
+}
+
+impl From<PacketReadError> for LoginError {
+    fn from(value: PacketReadError) -> Self {
+        LoginError::MalformedDetails(value)
+    }
+}
+
+impl From<EncryptionError> for LoginError {
+    fn from(value: EncryptionError) -> Self {
+        LoginError::Encryption(value)
+    }
+}
+
+/// A way of logging into the Realm.
+///
+/// The provider is picked by the `id` inside of [`LoginDetails::Other`]
+pub trait LoginProvider: Send + Sync {
+    /// The id this provider is registered under
+    fn id(&self) -> u8;
+    /// Checks the details sent by the device
+    fn login(&self, device_id: &Uuid, details: &Bytes) -> Result<LoginResult, LoginError>;
+}
+
+/// A registry of [`LoginProvider`]s keyed by their id
+#[derive(Default)]
+pub struct LoginProviders {
+    providers: HashMap<u8, Box<dyn LoginProvider>>,
+}
+
+impl LoginProviders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Registers the provider. Returns the provider that was previously registered under the same id
+    pub fn register<P: LoginProvider + 'static>(
+        &mut self,
+        provider: P,
+    ) -> Option<Box<dyn LoginProvider>> {
+        self.providers.insert(provider.id(), Box::new(provider))
+    }
+
+    pub fn get(&self, id: u8) -> Option<&dyn LoginProvider> {
+        self.providers.get(&id).map(|provider| provider.as_ref())
+    }
+
+    /// Finds the provider for the details and passes them along.
+    ///
+    /// [`LoginDetails::None`] goes to the provider registered under [`NO_DETAILS_PROVIDER_ID`] with empty details.
+    pub fn login(&self, device_id: &Uuid, details: LoginDetails) -> Result<LoginResult, LoginError> {
+        let (id, details) = match details {
+            LoginDetails::None => (NO_DETAILS_PROVIDER_ID, Bytes::new()),
+            LoginDetails::Other { id, details } => (id, details),
+        };
+        let provider = self.get(id).ok_or(LoginError::UnknownProvider(id))?;
+        provider.login(device_id, &details)
+    }
+}
+
+/// Compares the two values without returning early
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Each device has a token that is known by both sides.
+///
+/// The details are the raw token
+pub struct PreSharedTokenProvider {
+    tokens: HashMap<Uuid, Bytes>,
+    pub session: SessionSettings,
+}
+
+impl PreSharedTokenProvider {
+    pub fn new(session: SessionSettings) -> Self {
+        PreSharedTokenProvider {
+            tokens: HashMap::new(),
+            session,
+        }
+    }
+    /// Sets the token for the device. Returns the old token
+    pub fn add_token(&mut self, device_id: Uuid, token: Bytes) -> Option<Bytes> {
+        self.tokens.insert(device_id, token)
+    }
+    pub fn remove_token(&mut self, device_id: &Uuid) -> Option<Bytes> {
+        self.tokens.remove(device_id)
+    }
+}
+
+impl LoginProvider for PreSharedTokenProvider {
+    fn id(&self) -> u8 {
+        PRE_SHARED_TOKEN_PROVIDER_ID
+    }
+
+    fn login(&self, device_id: &Uuid, details: &Bytes) -> Result<LoginResult, LoginError> {
+        match self.tokens.get(device_id) {
+            Some(token) if constant_time_eq(token, details) => {
+                Ok(LoginResult::Accepted(self.session.start()))
+            }
+            _ => Ok(LoginResult::Rejected),
+        }
+    }
+}
+
+/// The details sent for the [`PasswordProvider`]
+#[derive(Debug, Clone, PacketContent)]
+pub struct PasswordCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl PasswordCredentials {
+    /// The credentials inside of the details. None if they are not for the [`PasswordProvider`] or can not be read
+    pub fn from_details(details: &LoginDetails) -> Option<Self> {
+        match details {
+            LoginDetails::Other { id, details } if *id == PASSWORD_PROVIDER_ID => {
+                PasswordCredentials::read(&mut details.as_ref()).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A salted PBKDF2-HMAC-SHA256 hash of a password.
+///
+/// The realm only stores this. Never the password
+#[derive(Debug, Clone)]
+pub struct PasswordVerifier {
+    pub salt: Bytes,
+    pub rounds: u32,
+    pub hash: Bytes,
+}
+
+impl PasswordVerifier {
+    pub const DEFAULT_ROUNDS: u32 = 100_000;
+
+    /// Hashes the password with a new random salt
+    pub fn new(password: &str) -> Self {
+        Self::with_rounds(password, Self::DEFAULT_ROUNDS)
+    }
+
+    pub fn with_rounds(password: &str, rounds: u32) -> Self {
+        let salt = Self::random_bytes(16);
+        let hash = Self::hash(password, &salt, rounds);
+        PasswordVerifier { salt, rounds, hash }
+    }
+
+    /// A verifier no password matches. Checked for unknown usernames so they take as long as known ones
+    fn dummy(rounds: u32) -> Self {
+        PasswordVerifier {
+            salt: Self::random_bytes(16),
+            rounds,
+            hash: Self::random_bytes(32),
+        }
+    }
+
+    fn random_bytes(len: usize) -> Bytes {
+        let mut bytes = vec![0u8; len];
+        rand::thread_rng().fill(bytes.as_mut_slice());
+        Bytes::from(bytes)
+    }
+
+    fn hash(password: &str, salt: &[u8], rounds: u32) -> Bytes {
+        let mut hash = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, rounds, &mut hash);
+        Bytes::copy_from_slice(&hash)
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = Self::hash(password, &self.salt, self.rounds);
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+/// An account that can log in with the [`PasswordProvider`]
+#[derive(Debug, Clone)]
+pub struct PasswordAccount {
+    pub verifier: PasswordVerifier,
+    /// The scopes given to the sessions of this account. Added to the scopes of the [`SessionSettings`]
+    pub scopes: Vec<String>,
+    /// The device ids that can log in with this account. Any other device is rejected
+    pub devices: HashSet<Uuid>,
+}
+
+/// Username and Password login.
+///
+/// The details are a [`PasswordCredentials`]
+pub struct PasswordProvider {
+    accounts: HashMap<String, PasswordAccount>,
+    dummy: PasswordVerifier,
+    pub session: SessionSettings,
+}
+
+impl PasswordProvider {
+    pub fn new(session: SessionSettings) -> Self {
+        PasswordProvider {
+            accounts: HashMap::new(),
+            dummy: PasswordVerifier::dummy(PasswordVerifier::DEFAULT_ROUNDS),
+            session,
+        }
+    }
+    /// The dummy verifier for unknown usernames uses the rounds of the last added account
+    pub fn add_account(&mut self, username: impl Into<String>, account: PasswordAccount) -> Option<PasswordAccount> {
+        self.dummy = PasswordVerifier::dummy(account.verifier.rounds);
+        self.accounts.insert(username.into(), account)
+    }
+    pub fn remove_account(&mut self, username: &str) -> Option<PasswordAccount> {
+        self.accounts.remove(username)
+    }
+}
+
+impl LoginProvider for PasswordProvider {
+    fn id(&self) -> u8 {
+        PASSWORD_PROVIDER_ID
+    }
+
+    fn login(&self, device_id: &Uuid, details: &Bytes) -> Result<LoginResult, LoginError> {
+        let credentials = PasswordCredentials::read(&mut details.as_ref())?;
+        let account = match self.accounts.get(&credentials.username) {
+            Some(account) => account,
+            None => {
+                // Same amount of work as a wrong password
+                self.dummy.verify(&credentials.password);
+                return Ok(LoginResult::Rejected);
+            }
+        };
+        // The password is checked first so a wrong device takes as long as a wrong password
+        if !account.verifier.verify(&credentials.password) || !account.devices.contains(device_id) {
+            return Ok(LoginResult::Rejected);
+        }
+        let mut scopes = self.session.scopes.clone();
+        scopes.extend(account.scopes.iter().cloned());
+        Ok(LoginResult::Accepted(LoginSession::new(self.session.ttl, scopes)))
+    }
+}
+
+/// The device proves it has its Themis key by signing a challenge.
+///
+/// 1. The device sends empty details. The realm answers with a challenge
+/// 2. The device sends the challenge signed via `SecureSign` as the details
+pub struct ChallengeResponseProvider {
+    keys: HashMap<Uuid, EcdsaPublicKey>,
+    pending: Mutex<HashMap<Uuid, (Bytes, Instant)>>,
+    /// How long a challenge stays valid
+    pub challenge_ttl: Duration,
+    pub session: SessionSettings,
+}
+
+impl ChallengeResponseProvider {
+    pub fn new(session: SessionSettings) -> Self {
+        ChallengeResponseProvider {
+            keys: HashMap::new(),
+            pending: Mutex::new(HashMap::new()),
+            challenge_ttl: Duration::from_secs(60),
+            session,
+        }
+    }
+    /// Sets the public key of the device. Returns the old key
+    pub fn add_device_key(&mut self, device_id: Uuid, key: EcdsaPublicKey) -> Option<EcdsaPublicKey> {
+        self.keys.insert(device_id, key)
+    }
+    pub fn remove_device_key(&mut self, device_id: &Uuid) -> Option<EcdsaPublicKey> {
+        self.keys.remove(device_id)
+    }
+}
+
+impl LoginProvider for ChallengeResponseProvider {
+    fn id(&self) -> u8 {
+        CHALLENGE_RESPONSE_PROVIDER_ID
+    }
+
+    fn login(&self, device_id: &Uuid, details: &Bytes) -> Result<LoginResult, LoginError> {
+        let key = if let Some(key) = self.keys.get(device_id) {
+            key
+        } else {
+            return Ok(LoginResult::Rejected);
+        };
+        let mut pending = self.pending.lock().unwrap_or_else(|error| error.into_inner());
+        if details.is_empty() {
+            let mut challenge = [0u8; 32];
+            rand::thread_rng().fill(&mut challenge);
+            let challenge = Bytes::copy_from_slice(&challenge);
+            pending.insert(*device_id, (challenge.clone(), Instant::now()));
+            return Ok(LoginResult::Challenge(challenge));
+        }
+        // A challenge can only be answered once
+        let (challenge, issued) = if let Some(value) = pending.remove(device_id) {
+            value
+        } else {
+            return Ok(LoginResult::Rejected);
+        };
+        if issued.elapsed() > self.challenge_ttl {
+            return Ok(LoginResult::Rejected);
+        }
+        let verify = SecureVerify::new(key.clone());
+        let signed = match verify.verify(details) {
+            Ok(value) => value,
+            Err(_) => return Ok(LoginResult::Rejected),
+        };
+        if constant_time_eq(&signed, &challenge) {
+            Ok(LoginResult::Accepted(self.session.start()))
+        } else {
+            Ok(LoginResult::Rejected)
+        }
+    }
+}
@@ -0,0 +1,114 @@
+/// Access control for frames proxied by the Realm
+pub mod acl;
+/// Routing between federated realms
+pub mod federation;
+/// Rate limiting and login lockouts
+pub mod limits;
+/// Pluggable login providers for the Realm
+pub mod login;
+
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::device_manager::PairedDevice;
+use crate::encryption::{EncryptionManager};
+use crate::packets::realm::LoginDetails;
+use crate::realm::login::LoginResult;
+
+
+pub trait Realm {
+    /// Error that can be returned
+    type Error;
+    ///  Encryption Manager
+    type EH: EncryptionManager;
+    type PD: PairedDevice<Self::EH>;
+    /// Called when a device sends a DeviceLogin packet.
+    ///
+    /// Most implementations will pass this along to a [`LoginProviders`](login::LoginProviders) registry
+    fn login(&self, device_id: &Uuid, login: LoginDetails) -> Result<LoginResult, Self::Error>;
+
+    fn is_paired(&self, uuid: &Uuid) -> bool;
+    /// Gets the paired device
+    ///
+    /// # Returns
+    /// None if the device is not paired with the realm
+    fn get_paired_device(&self, uuid: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error>;
+    /// The id this realm uses when talking to other realms
+    fn get_realm_id(&self) -> Uuid;
+    /// The Encryption Manager for a realm this realm is federated with.
+    ///
+    /// # Returns
+    /// None if the realm is not a known peer
+    fn get_peer_realm(&self, realm_id: &Uuid) -> Result<Option<Self::EH>, Self::Error>;
+
+}
+
+/// The async version of [`Realm`]. For realms backed by a database
+///
+/// Every [`Realm`] that is `Send + Sync` implements this as well
+#[async_trait]
+pub trait AsyncRealm: Send + Sync {
+    /// Error that can be returned
+    type Error: Send;
+    ///  Encryption Manager
+    type EH: EncryptionManager + Send;
+    type PD: PairedDevice<Self::EH> + Send + Sync;
+    /// See [`Realm::login`]
+    async fn login(&self, device_id: &Uuid, login: LoginDetails) -> Result<LoginResult, Self::Error>;
+
+    async fn is_paired(&self, uuid: &Uuid) -> bool;
+    /// Gets the paired device
+    ///
+    /// # Returns
+    /// None if the device is not paired with the realm
+    async fn get_paired_device(&self, uuid: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error>;
+    /// The id this realm uses when talking to other realms
+    async fn get_realm_id(&self) -> Uuid;
+    /// See [`Realm::get_peer_realm`]
+    async fn get_peer_realm(&self, realm_id: &Uuid) -> Result<Option<Self::EH>, Self::Error>;
+}
+
+#[async_trait]
+impl<T> AsyncRealm for T
+    where
+        T: Realm + Send + Sync,
+        T::Error: Send,
+        T::EH: Send,
+        T::PD: Send + Sync,
+{
+    type Error = T::Error;
+    type EH = T::EH;
+    type PD = T::PD;
+
+    async fn login(&self, device_id: &Uuid, login: LoginDetails) -> Result<LoginResult, Self::Error> {
+        Realm::login(self, device_id, login)
+    }
+
+    async fn is_paired(&self, uuid: &Uuid) -> bool {
+        Realm::is_paired(self, uuid)
+    }
+
+    async fn get_paired_device(&self, uuid: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        Realm::get_paired_device(self, uuid)
+    }
+
+    async fn get_realm_id(&self) -> Uuid {
+        Realm::get_realm_id(self)
+    }
+
+    async fn get_peer_realm(&self, realm_id: &Uuid) -> Result<Option<Self::EH>, Self::Error> {
+        Realm::get_peer_realm(self, realm_id)
+    }
+}
+
+/// Represents a device that is paired with the realm. On the local side
+pub trait DeviceRealmConnection{
+    type EH: EncryptionManager;
+
+
+    fn get_ip(&self) -> &IpAddr;
+    /// An Encryption Manager.
+    /// This value is owned by the caller
+    fn get_encryption_manager(&self) -> Self::EH;
+}
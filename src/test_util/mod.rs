@@ -0,0 +1,655 @@
+use crate::device_manager::bundle::{BundledDevice, BundledRealm, PairingBundle};
+use crate::device_manager::{DeviceManager, DeviceMetadata, PairedDevice, TrustLevel};
+use crate::encryption::{DynamicEncryptionManager, EncryptionError, EncryptionSet, ThemisEncryptionManager};
+use crate::packets::dtd::DeviceToDevicePackets;
+use crate::packets::federation::FederationPacket;
+use crate::packets::handlers::federation::FederationResponse;
+use crate::packets::handlers::realm::RealmResponse;
+use crate::packets::realm::{LoginDetails, RealmPacket};
+use crate::packets::{ErrorPacket, Protocol};
+use crate::realm::limits::Clock;
+use crate::realm::login::{LoginResult, LoginSession};
+use crate::realm::{DeviceRealmConnection, Realm};
+use bytes::Bytes;
+use packet::IntoPacket;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum InMemoryError {
+    Encryption(EncryptionError),
+    /// Returned when a [`PairAnswer::Fail`] is used
+    PairRequestFailed,
+    /// Returned when [`InMemoryRealm::push_login_error`] is used
+    LoginFailed,
+}
+
+impl Display for InMemoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InMemoryError::Encryption(error) => write!(f, "Encryption error: {:?}", error),
+            InMemoryError::PairRequestFailed => write!(f, "Pair request failed"),
+            InMemoryError::LoginFailed => write!(f, "Login failed"),
+        }
+    }
+}
+
+impl std::error::Error for InMemoryError {}
+
+impl From<EncryptionError> for InMemoryError {
+    fn from(value: EncryptionError) -> Self {
+        InMemoryError::Encryption(value)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// A paired device. Without an encryption manager the device uses [`DynamicEncryptionManager::None`]
+#[derive(Clone)]
+pub struct InMemoryDevice {
+    pub device_id: Uuid,
+    pub encryption: Option<ThemisEncryptionManager>,
+    pub metadata: DeviceMetadata,
+}
+
+impl InMemoryDevice {
+    pub fn new(device_id: Uuid) -> Self {
+        InMemoryDevice {
+            device_id,
+            encryption: None,
+            metadata: DeviceMetadata::new(None),
+        }
+    }
+
+    pub fn with_trust_level(mut self, trust_level: TrustLevel) -> Self {
+        self.metadata.trust_level = trust_level;
+        self
+    }
+}
+
+impl From<(Uuid, &EncryptionSet, DeviceMetadata)> for InMemoryDevice {
+    fn from((device_id, encryption, metadata): (Uuid, &EncryptionSet, DeviceMetadata)) -> Self {
+        InMemoryDevice {
+            device_id,
+            metadata,
+            encryption: Some(ThemisEncryptionManager::from(encryption)),
+        }
+    }
+}
+
+impl PairedDevice<DynamicEncryptionManager> for InMemoryDevice {
+    fn get_device_id(&self) -> &Uuid {
+        &self.device_id
+    }
+
+    fn get_encryption_manager(&self) -> DynamicEncryptionManager {
+        match &self.encryption {
+            Some(encryption) => DynamicEncryptionManager::Themis(encryption.clone()),
+            None => DynamicEncryptionManager::None,
+        }
+    }
+
+    fn get_metadata(&self) -> DeviceMetadata {
+        self.metadata.clone()
+    }
+}
+
+/// A realm the [`InMemoryDeviceManager`] is paired with
+#[derive(Clone)]
+pub struct InMemoryRealmConnection {
+    pub ip: IpAddr,
+    pub encryption: Option<ThemisEncryptionManager>,
+}
+
+impl DeviceRealmConnection for InMemoryRealmConnection {
+    type EH = DynamicEncryptionManager;
+
+    fn get_ip(&self) -> &IpAddr {
+        &self.ip
+    }
+
+    fn get_encryption_manager(&self) -> Self::EH {
+        match &self.encryption {
+            Some(encryption) => DynamicEncryptionManager::Themis(encryption.clone()),
+            None => DynamicEncryptionManager::None,
+        }
+    }
+}
+
+/// How the [`InMemoryDeviceManager`] answers a pair request
+#[derive(Debug, Clone)]
+pub enum PairAnswer {
+    /// Accept with the optional test value
+    Accept(Option<Bytes>),
+    Reject,
+    /// Return [`InMemoryError::PairRequestFailed`]
+    Fail,
+}
+
+/// A call made to the [`InMemoryDeviceManager`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceManagerCall {
+    GetDeviceId,
+    GetDeviceName,
+    IsPaired(Uuid),
+    GetPairedDevices,
+    GetPairedDevice(Uuid),
+    RegisterDevice(Uuid),
+    UpdateMetadata(Uuid),
+    TouchLastSeen(Uuid),
+    DeleteDevice(Uuid),
+    ExportBundle,
+    /// The device id inside of the bundle
+    ImportBundle(Uuid),
+    PairRequest {
+        device_id: Uuid,
+        device_name: String,
+        details: Bytes,
+    },
+    GetConnectedRealms,
+    GetRealmByIp(IpAddr),
+}
+
+/// A [`DeviceManager`] that keeps everything in memory.
+///
+/// Pair requests are answered from a queue. Once the queue is empty the default answer is used.
+pub struct InMemoryDeviceManager {
+    device_id: Mutex<Uuid>,
+    device_name: Mutex<String>,
+    devices: Mutex<HashMap<Uuid, Arc<InMemoryDevice>>>,
+    realms: Mutex<HashMap<IpAddr, Arc<InMemoryRealmConnection>>>,
+    pair_answers: Mutex<VecDeque<PairAnswer>>,
+    default_pair_answer: Mutex<PairAnswer>,
+    calls: Mutex<Vec<DeviceManagerCall>>,
+}
+
+impl InMemoryDeviceManager {
+    /// A device with a random id. Pair requests are rejected by default
+    pub fn new(device_name: impl Into<String>) -> Self {
+        InMemoryDeviceManager {
+            device_id: Mutex::new(Uuid::new_v4()),
+            device_name: Mutex::new(device_name.into()),
+            devices: Mutex::new(HashMap::new()),
+            realms: Mutex::new(HashMap::new()),
+            pair_answers: Mutex::new(VecDeque::new()),
+            default_pair_answer: Mutex::new(PairAnswer::Reject),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_device_id(self, device_id: Uuid) -> Self {
+        *lock(&self.device_id) = device_id;
+        self
+    }
+
+    /// Answers the next pair request with `answer`
+    pub fn push_pair_answer(&self, answer: PairAnswer) {
+        lock(&self.pair_answers).push_back(answer);
+    }
+
+    /// Used once the queued answers run out
+    pub fn set_default_pair_answer(&self, answer: PairAnswer) {
+        *lock(&self.default_pair_answer) = answer;
+    }
+
+    /// Adds a paired device without going through pairing
+    pub fn add_device(&self, device: InMemoryDevice) {
+        lock(&self.devices).insert(device.device_id, Arc::new(device));
+    }
+
+    pub fn add_realm(&self, realm: InMemoryRealmConnection) {
+        lock(&self.realms).insert(realm.ip, Arc::new(realm));
+    }
+
+    /// The device without recording a call
+    pub fn device(&self, device_id: &Uuid) -> Option<Arc<InMemoryDevice>> {
+        lock(&self.devices).get(device_id).cloned()
+    }
+
+    /// Every call in the order they were made
+    pub fn calls(&self) -> Vec<DeviceManagerCall> {
+        lock(&self.calls).clone()
+    }
+
+    pub fn clear_calls(&self) {
+        lock(&self.calls).clear();
+    }
+
+    /// The device ids passed to `register_device`
+    pub fn registered_devices(&self) -> Vec<Uuid> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                DeviceManagerCall::RegisterDevice(device_id) => Some(device_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The device ids and names of every pair request
+    pub fn pair_requests(&self) -> Vec<(Uuid, String)> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                DeviceManagerCall::PairRequest {
+                    device_id,
+                    device_name,
+                    ..
+                } => Some((device_id, device_name)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, call: DeviceManagerCall) {
+        lock(&self.calls).push(call);
+    }
+}
+
+impl DeviceManager for InMemoryDeviceManager {
+    type Error = InMemoryError;
+    type EH = DynamicEncryptionManager;
+    type PD = InMemoryDevice;
+    type RealmConnection = InMemoryRealmConnection;
+
+    fn get_device_id(&self) -> Uuid {
+        self.record(DeviceManagerCall::GetDeviceId);
+        *lock(&self.device_id)
+    }
+
+    fn get_device_name(&self) -> String {
+        self.record(DeviceManagerCall::GetDeviceName);
+        lock(&self.device_name).clone()
+    }
+
+    fn is_paired(&self, uuid: &Uuid) -> bool {
+        self.record(DeviceManagerCall::IsPaired(*uuid));
+        lock(&self.devices).contains_key(uuid)
+    }
+
+    fn get_paired_devices(&self) -> Result<Vec<Arc<Self::PD>>, Self::Error> {
+        self.record(DeviceManagerCall::GetPairedDevices);
+        Ok(lock(&self.devices).values().cloned().collect())
+    }
+
+    fn get_paired_device(&self, device_id: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        self.record(DeviceManagerCall::GetPairedDevice(*device_id));
+        Ok(self.device(device_id))
+    }
+
+    fn register_device(
+        &self,
+        device_id: &Uuid,
+        encryption: EncryptionSet,
+        metadata: DeviceMetadata,
+    ) -> Result<(), Self::Error> {
+        self.record(DeviceManagerCall::RegisterDevice(*device_id));
+        self.add_device(InMemoryDevice::from((*device_id, &encryption, metadata)));
+        Ok(())
+    }
+
+    fn update_metadata(&self, device_id: &Uuid, metadata: DeviceMetadata) -> Result<(), Self::Error> {
+        self.record(DeviceManagerCall::UpdateMetadata(*device_id));
+        let mut devices = lock(&self.devices);
+        if let Some(device) = devices.get_mut(device_id) {
+            let mut updated = device.as_ref().clone();
+            updated.metadata = metadata;
+            *device = Arc::new(updated);
+        }
+        Ok(())
+    }
+
+    fn touch_last_seen(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        self.record(DeviceManagerCall::TouchLastSeen(*device_id));
+        let mut devices = lock(&self.devices);
+        if let Some(device) = devices.get_mut(device_id) {
+            let mut updated = device.as_ref().clone();
+            updated.metadata = updated.metadata.seen();
+            *device = Arc::new(updated);
+        }
+        Ok(())
+    }
+
+    fn delete_device(&self, device_id: &Uuid) -> Result<(), Self::Error> {
+        self.record(DeviceManagerCall::DeleteDevice(*device_id));
+        lock(&self.devices).remove(device_id);
+        Ok(())
+    }
+
+    /// Devices and realms without an encryption manager are left out
+    fn export_bundle(&self) -> Result<PairingBundle, Self::Error> {
+        self.record(DeviceManagerCall::ExportBundle);
+        Ok(PairingBundle {
+            device_id: *lock(&self.device_id),
+            device_name: lock(&self.device_name).clone(),
+            devices: lock(&self.devices)
+                .values()
+                .filter_map(|device| {
+                    Some(BundledDevice {
+                        device_id: device.device_id,
+                        encryption: device.encryption.clone()?,
+                        metadata: device.metadata.clone(),
+                    })
+                })
+                .collect(),
+            realms: lock(&self.realms)
+                .values()
+                .map(|realm| BundledRealm {
+                    ip: realm.ip,
+                    realm_id: None,
+                    encryption: realm.encryption.clone(),
+                })
+                .collect(),
+        })
+    }
+
+    fn import_bundle(&self, bundle: PairingBundle) -> Result<(), Self::Error> {
+        self.record(DeviceManagerCall::ImportBundle(bundle.device_id));
+        *lock(&self.device_id) = bundle.device_id;
+        *lock(&self.device_name) = bundle.device_name;
+        *lock(&self.devices) = bundle
+            .devices
+            .into_iter()
+            .map(|device| {
+                let device = InMemoryDevice {
+                    device_id: device.device_id,
+                    encryption: Some(device.encryption),
+                    metadata: device.metadata,
+                };
+                (device.device_id, Arc::new(device))
+            })
+            .collect();
+        *lock(&self.realms) = bundle
+            .realms
+            .into_iter()
+            .map(|realm| {
+                let realm = InMemoryRealmConnection {
+                    ip: realm.ip,
+                    encryption: realm.encryption,
+                };
+                (realm.ip, Arc::new(realm))
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn pair_request(
+        &self,
+        device_id: &Uuid,
+        device_name: &str,
+        cursor: Cursor<Bytes>,
+    ) -> Result<(bool, Option<Bytes>), Self::Error> {
+        self.record(DeviceManagerCall::PairRequest {
+            device_id: *device_id,
+            device_name: device_name.to_string(),
+            details: cursor.into_inner(),
+        });
+        let answer = lock(&self.pair_answers)
+            .pop_front()
+            .unwrap_or_else(|| lock(&self.default_pair_answer).clone());
+        match answer {
+            PairAnswer::Accept(test) => Ok((true, test)),
+            PairAnswer::Reject => Ok((false, None)),
+            PairAnswer::Fail => Err(InMemoryError::PairRequestFailed),
+        }
+    }
+
+    fn get_connected_realms(&self) -> Result<Vec<Arc<Self::RealmConnection>>, Self::Error> {
+        self.record(DeviceManagerCall::GetConnectedRealms);
+        Ok(lock(&self.realms).values().cloned().collect())
+    }
+
+    fn get_realm_by_ip(&self, realm: IpAddr) -> Result<Option<Arc<Self::RealmConnection>>, Self::Error> {
+        self.record(DeviceManagerCall::GetRealmByIp(realm));
+        Ok(lock(&self.realms).get(&realm).cloned())
+    }
+}
+
+/// A call made to the [`InMemoryRealm`]
+#[derive(Debug, Clone)]
+pub enum RealmCall {
+    Login {
+        device_id: Uuid,
+        details: LoginDetails,
+    },
+    IsPaired(Uuid),
+    GetPairedDevice(Uuid),
+    GetRealmId,
+    GetPeerRealm(Uuid),
+}
+
+/// A [`Realm`] that keeps everything in memory.
+///
+/// Logins are answered from a queue. Once the queue is empty the default answer is used.
+/// The default accepts every login with a one hour session
+pub struct InMemoryRealm {
+    realm_id: Uuid,
+    devices: Mutex<HashMap<Uuid, Arc<InMemoryDevice>>>,
+    peers: Mutex<HashMap<Uuid, Option<ThemisEncryptionManager>>>,
+    /// None fails the login
+    login_answers: Mutex<VecDeque<Option<LoginResult>>>,
+    default_login_answer: Mutex<Option<LoginResult>>,
+    calls: Mutex<Vec<RealmCall>>,
+}
+
+impl Default for InMemoryRealm {
+    fn default() -> Self {
+        InMemoryRealm::new()
+    }
+}
+
+impl InMemoryRealm {
+    /// A realm with a random id
+    pub fn new() -> Self {
+        InMemoryRealm {
+            realm_id: Uuid::new_v4(),
+            devices: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            login_answers: Mutex::new(VecDeque::new()),
+            default_login_answer: Mutex::new(None),
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_realm_id(mut self, realm_id: Uuid) -> Self {
+        self.realm_id = realm_id;
+        self
+    }
+
+    pub fn add_device(&self, device: InMemoryDevice) {
+        lock(&self.devices).insert(device.device_id, Arc::new(device));
+    }
+
+    pub fn remove_device(&self, device_id: &Uuid) {
+        lock(&self.devices).remove(device_id);
+    }
+
+    /// Adds a federated realm. Without an encryption manager the peer gets [`DynamicEncryptionManager::None`]
+    pub fn add_peer(&self, realm_id: Uuid, encryption: Option<ThemisEncryptionManager>) {
+        lock(&self.peers).insert(realm_id, encryption);
+    }
+
+    /// Answers the next login with `answer`
+    pub fn push_login_answer(&self, answer: LoginResult) {
+        lock(&self.login_answers).push_back(Some(answer));
+    }
+
+    /// Fails the next login with [`InMemoryError::LoginFailed`]
+    pub fn push_login_error(&self) {
+        lock(&self.login_answers).push_back(None);
+    }
+
+    /// Used once the queued answers run out
+    pub fn set_default_login_answer(&self, answer: LoginResult) {
+        *lock(&self.default_login_answer) = Some(answer);
+    }
+
+    /// Every call in the order they were made
+    pub fn calls(&self) -> Vec<RealmCall> {
+        lock(&self.calls).clone()
+    }
+
+    pub fn clear_calls(&self) {
+        lock(&self.calls).clear();
+    }
+
+    /// The device ids of every login
+    pub fn logins(&self) -> Vec<Uuid> {
+        self.calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                RealmCall::Login { device_id, .. } => Some(device_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, call: RealmCall) {
+        lock(&self.calls).push(call);
+    }
+}
+
+impl Realm for InMemoryRealm {
+    type Error = InMemoryError;
+    type EH = DynamicEncryptionManager;
+    type PD = InMemoryDevice;
+
+    fn login(&self, device_id: &Uuid, login: LoginDetails) -> Result<LoginResult, Self::Error> {
+        self.record(RealmCall::Login {
+            device_id: *device_id,
+            details: login,
+        });
+        let answer = match lock(&self.login_answers).pop_front() {
+            Some(Some(answer)) => Some(answer),
+            Some(None) => return Err(InMemoryError::LoginFailed),
+            None => None,
+        };
+        Ok(answer.unwrap_or_else(|| {
+            lock(&self.default_login_answer)
+                .clone()
+                .unwrap_or_else(|| LoginResult::Accepted(LoginSession::new(Duration::from_secs(60 * 60), Vec::new())))
+        }))
+    }
+
+    fn is_paired(&self, uuid: &Uuid) -> bool {
+        self.record(RealmCall::IsPaired(*uuid));
+        lock(&self.devices).contains_key(uuid)
+    }
+
+    fn get_paired_device(&self, uuid: &Uuid) -> Result<Option<Arc<Self::PD>>, Self::Error> {
+        self.record(RealmCall::GetPairedDevice(*uuid));
+        Ok(lock(&self.devices).get(uuid).cloned())
+    }
+
+    fn get_realm_id(&self) -> Uuid {
+        self.record(RealmCall::GetRealmId);
+        self.realm_id
+    }
+
+    fn get_peer_realm(&self, realm_id: &Uuid) -> Result<Option<Self::EH>, Self::Error> {
+        self.record(RealmCall::GetPeerRealm(*realm_id));
+        Ok(lock(&self.peers).get(realm_id).map(|encryption| match encryption {
+            Some(encryption) => DynamicEncryptionManager::Themis(encryption.clone()),
+            None => DynamicEncryptionManager::None,
+        }))
+    }
+}
+
+/// A [`Clock`] that only moves when it is told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *lock(&self.now) += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *lock(&self.now)
+    }
+}
+
+/// A frame of the protocol as it is put inside of a DeviceProxy or a Forward
+pub fn frame(protocol_id: u8) -> Bytes {
+    let mut frame = Vec::new();
+    (protocol_id, 1u8, vec![1u8, 2, 3]).into_packet(&mut frame).unwrap();
+    Bytes::from(frame)
+}
+
+/// Anything that can be answered with an [`ErrorPacket`]
+pub trait ErrorResponse {
+    /// The error if it is the only thing that is sent back
+    fn error(&self) -> Option<&ErrorPacket>;
+}
+
+impl ErrorResponse for Protocol {
+    fn error(&self) -> Option<&ErrorPacket> {
+        match self {
+            Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))
+            | Protocol::DeviceToRealm(RealmPacket::Error(error))
+            | Protocol::RealmToRealm(FederationPacket::Error(error)) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorResponse for RealmPacket {
+    fn error(&self) -> Option<&ErrorPacket> {
+        match self {
+            RealmPacket::Error(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorResponse for RealmResponse {
+    fn error(&self) -> Option<&ErrorPacket> {
+        match self {
+            RealmResponse::Message(message) => message.error(),
+            _ => None,
+        }
+    }
+}
+
+impl ErrorResponse for FederationResponse {
+    fn error(&self) -> Option<&ErrorPacket> {
+        match self {
+            FederationResponse::Messages(messages) => match messages.as_slice() {
+                [message] => message.error(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The error code of the response. Panics if the response is not an error
+pub fn error_code(response: impl ErrorResponse) -> u8 {
+    match response.error() {
+        Some(error) => error.error_code(),
+        None => panic!("Expected an error"),
+    }
+}
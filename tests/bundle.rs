@@ -0,0 +1,175 @@
+use abst_rs::device_manager::bundle::{
+    BundleError, BundledDevice, PairingBundle, BUNDLE_KDF_ROUNDS, BUNDLE_MAGIC,
+};
+use abst_rs::device_manager::file::FileDeviceManager;
+use abst_rs::device_manager::{DeviceManager, DeviceMetadata, PairedDevice, TrustLevel};
+use abst_rs::encryption::{EncryptionSet, ThemisEncryptionManager};
+use std::path::PathBuf;
+use themis::keygen::gen_ec_key_pair;
+use uuid::Uuid;
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+fn encryption_set() -> EncryptionSet {
+    let (private_key, public_key) = gen_ec_key_pair().split();
+    let (_, key_b) = gen_ec_key_pair().split();
+    EncryptionSet {
+        public_key,
+        private_key,
+        key_b,
+    }
+}
+
+fn bundle() -> PairingBundle {
+    PairingBundle {
+        device_id: Uuid::new_v4(),
+        device_name: "laptop".to_string(),
+        devices: vec![BundledDevice {
+            device_id: Uuid::new_v4(),
+            encryption: ThemisEncryptionManager::from(&encryption_set()),
+            metadata: DeviceMetadata::new(Some("phone".to_string())),
+        }],
+        realms: Vec::new(),
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("abst-rs-bundle-{}", Uuid::new_v4()))
+}
+
+#[test]
+fn seal_and_open() {
+    let bundle = bundle();
+    let sealed = bundle.seal(PASSPHRASE).unwrap();
+    assert!(sealed.starts_with(BUNDLE_MAGIC));
+
+    let opened = PairingBundle::open(&sealed, PASSPHRASE).unwrap();
+    assert_eq!(opened.device_id, bundle.device_id);
+    assert_eq!(opened.device_name, bundle.device_name);
+    assert_eq!(opened.devices.len(), 1);
+    assert_eq!(opened.devices[0].device_id, bundle.devices[0].device_id);
+    assert_eq!(opened.devices[0].metadata, bundle.devices[0].metadata);
+    assert_eq!(
+        opened.devices[0].encryption.self_private_key,
+        bundle.devices[0].encryption.self_private_key
+    );
+}
+
+#[test]
+fn wrong_passphrase_is_rejected() {
+    let sealed = bundle().seal(PASSPHRASE).unwrap();
+    assert!(matches!(
+        PairingBundle::open(&sealed, "wrong"),
+        Err(BundleError::Rejected)
+    ));
+}
+
+#[test]
+fn tampered_bundles_are_rejected() {
+    let sealed = bundle().seal(PASSPHRASE).unwrap();
+
+    let mut payload = sealed.to_vec();
+    let last = payload.len() - 1;
+    payload[last] ^= 0x01;
+    assert!(matches!(
+        PairingBundle::open(&payload, PASSPHRASE),
+        Err(BundleError::Rejected)
+    ));
+
+    // The salt is part of the header, which is authenticated as well
+    let mut header = sealed.to_vec();
+    header[BUNDLE_MAGIC.len() + 6] ^= 0x01;
+    assert!(matches!(
+        PairingBundle::open(&header, PASSPHRASE),
+        Err(BundleError::Rejected)
+    ));
+
+    // The rounds are fixed. A bundle can not make open spend hours in PBKDF2
+    for rounds in [0, 1, BUNDLE_KDF_ROUNDS + 1, u32::MAX] {
+        let mut cost = sealed.to_vec();
+        cost[BUNDLE_MAGIC.len() + 1..BUNDLE_MAGIC.len() + 5].copy_from_slice(&rounds.to_be_bytes());
+        assert!(matches!(
+            PairingBundle::open(&cost, PASSPHRASE),
+            Err(BundleError::Malformed)
+        ));
+    }
+
+    let mut version = sealed.to_vec();
+    version[BUNDLE_MAGIC.len()] = u8::MAX;
+    assert!(matches!(
+        PairingBundle::open(&version, PASSPHRASE),
+        Err(BundleError::UnsupportedVersion(u8::MAX))
+    ));
+
+    assert!(matches!(
+        PairingBundle::open(&sealed[..20], PASSPHRASE),
+        Err(BundleError::Malformed)
+    ));
+    assert!(matches!(
+        PairingBundle::open(&sealed[..sealed.len() - 8], PASSPHRASE),
+        Err(BundleError::Rejected)
+    ));
+}
+
+#[test]
+fn file_device_manager_migration() {
+    let old_dir = temp_dir();
+    let new_dir = temp_dir();
+    let old = FileDeviceManager::create(&old_dir, "laptop", PASSPHRASE).unwrap();
+    let peer = Uuid::new_v4();
+    old.register_device(&peer, encryption_set(), DeviceMetadata::new(Some("phone".to_string())))
+        .unwrap();
+    let sealed = old.export_bundle().unwrap().seal("transfer").unwrap();
+
+    let new = FileDeviceManager::create(&new_dir, "new laptop", "new passphrase").unwrap();
+    new.import_bundle(PairingBundle::open(&sealed, "transfer").unwrap())
+        .unwrap();
+    assert_eq!(DeviceManager::get_device_id(&new), DeviceManager::get_device_id(&old));
+    assert_eq!(DeviceManager::get_device_name(&new), "laptop");
+    assert!(DeviceManager::is_paired(&new, &peer));
+
+    // The imported keys are sealed under the new passphrase
+    drop(new);
+    let reopened = FileDeviceManager::open(&new_dir, "new passphrase").unwrap();
+    let device = DeviceManager::get_paired_device(&reopened, &peer)
+        .unwrap()
+        .unwrap();
+    let original = DeviceManager::get_paired_device(&old, &peer).unwrap().unwrap();
+    assert_eq!(device.get_metadata(), original.get_metadata());
+    assert_eq!(DeviceManager::get_device_id(&reopened), DeviceManager::get_device_id(&old));
+
+    std::fs::remove_dir_all(old_dir).unwrap();
+    std::fs::remove_dir_all(new_dir).unwrap();
+}
+
+#[test]
+fn file_device_manager_last_seen() {
+    let dir = temp_dir();
+    let manager = FileDeviceManager::create(&dir, "laptop", PASSPHRASE).unwrap();
+    let peer = Uuid::new_v4();
+    let mut metadata = DeviceMetadata::new(Some("phone".to_string()));
+    metadata.last_seen = None;
+    manager.register_device(&peer, encryption_set(), metadata.clone()).unwrap();
+
+    // Blocked after the handler looked at the device but before it was touched
+    metadata.trust_level = TrustLevel::Blocked;
+    manager.update_metadata(&peer, metadata).unwrap();
+    manager.touch_last_seen(&peer).unwrap();
+    let device = manager.get_paired_device(&peer).unwrap().unwrap();
+    assert!(device.get_metadata().is_blocked());
+    assert!(device.get_metadata().last_seen.is_some());
+
+    // Only in memory until the manager is flushed
+    let on_disk = FileDeviceManager::open(&dir, PASSPHRASE).unwrap();
+    let device = on_disk.get_paired_device(&peer).unwrap().unwrap();
+    assert_eq!(device.get_metadata().last_seen, None);
+    drop(on_disk);
+    manager.flush().unwrap();
+    let on_disk = FileDeviceManager::open(&dir, PASSPHRASE).unwrap();
+    let device = on_disk.get_paired_device(&peer).unwrap().unwrap();
+    assert!(device.get_metadata().last_seen.is_some());
+    assert!(device.get_metadata().is_blocked());
+
+    drop((manager, on_disk));
+    std::fs::remove_dir_all(dir).unwrap();
+}
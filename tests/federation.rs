@@ -0,0 +1,243 @@
+use abst_rs::encryption::{EncryptionSet, ThemisEncryptionManager};
+use abst_rs::packets::capabilities::DEVICE_TO_DEVICE;
+use abst_rs::packets::federation::FederationPacket;
+use abst_rs::packets::handlers::federation::{FederationContext, FederationHandler, FederationResponse};
+use abst_rs::packets::realm::RealmPacket;
+use abst_rs::packets::{ErrorPacket, Protocol};
+use abst_rs::realm::acl::{AccessControl, AclAction, AclRule, AclSubject};
+use abst_rs::realm::federation::{Route, RouteTable};
+use abst_rs::realm::limits::{RateLimit, RateLimiter, RateLimits};
+use abst_rs::realm::Realm;
+use abst_rs::test_util::{error_code, frame, InMemoryDevice, InMemoryRealm};
+use bytes::Bytes;
+use themis::keygen::gen_ec_key_pair;
+use uuid::Uuid;
+
+/// Two Realms that know each other's public key
+fn realms() -> (InMemoryRealm, InMemoryRealm) {
+    let (a_private, a_public) = gen_ec_key_pair().split();
+    let (b_private, b_public) = gen_ec_key_pair().split();
+    let a_link = EncryptionSet {
+        public_key: a_public.clone(),
+        private_key: a_private,
+        key_b: b_public.clone(),
+    };
+    let b_link = EncryptionSet {
+        public_key: b_public,
+        private_key: b_private,
+        key_b: a_public,
+    };
+    let a = InMemoryRealm::new();
+    let b = InMemoryRealm::new();
+    a.add_peer(b.get_realm_id(), Some(ThemisEncryptionManager::from(&a_link)));
+    b.add_peer(a.get_realm_id(), Some(ThemisEncryptionManager::from(&b_link)));
+    (a, b)
+}
+
+fn messages(response: FederationResponse) -> Vec<FederationPacket> {
+    match response {
+        FederationResponse::Messages(messages) => messages
+            .into_iter()
+            .map(|message| match message {
+                Protocol::RealmToRealm(packet) => packet,
+                _ => panic!("Expected a federation packet"),
+            })
+            .collect(),
+        // Broadcasts go over the other links
+        FederationResponse::Nothing | FederationResponse::Broadcast { .. } => Vec::new(),
+        _ => panic!("Expected messages for the link"),
+    }
+}
+
+/// Passes messages over the link until both sides are quiet. Returns every packet that was sent
+fn run_link(
+    (a, a_context): (&mut FederationHandler<InMemoryRealm>, &mut FederationContext),
+    (b, b_context): (&mut FederationHandler<InMemoryRealm>, &mut FederationContext),
+    first: FederationPacket,
+) -> Vec<FederationPacket> {
+    let mut sent = vec![first.clone()];
+    let mut to_b = vec![first];
+    let mut to_a = Vec::new();
+    while !to_a.is_empty() || !to_b.is_empty() {
+        for packet in std::mem::take(&mut to_b) {
+            to_a.extend(messages(b.handle_packet(packet, b_context).unwrap()));
+        }
+        sent.extend(to_a.iter().cloned());
+        for packet in std::mem::take(&mut to_a) {
+            to_b.extend(messages(a.handle_packet(packet, a_context).unwrap()));
+        }
+        sent.extend(to_b.iter().cloned());
+    }
+    sent
+}
+
+#[test]
+fn handshake_checks_both_realms() {
+    let (a, b) = realms();
+    let (a_routes, b_routes) = (RouteTable::new(), RouteTable::new());
+    let far_device = Uuid::new_v4();
+    b_routes.learn(far_device, Route { via: Uuid::new_v4(), hops: 1 });
+    let mut a_handler = FederationHandler::new(&a, &a_routes, AccessControl::default());
+    let mut b_handler = FederationHandler::new(&b, &b_routes, AccessControl::default());
+    let mut a_context = FederationContext::outgoing(b.get_realm_id());
+    let mut b_context = FederationContext::incoming();
+
+    let hello = match a_handler.hello() {
+        Protocol::RealmToRealm(packet) => packet,
+        _ => panic!("Expected a federation packet"),
+    };
+    let sent = run_link((&mut a_handler, &mut a_context), (&mut b_handler, &mut b_context), hello);
+
+    assert!(a_context.is_connected());
+    assert!(b_context.is_connected());
+    assert_eq!(b_context.peer_id, a.get_realm_id());
+    let key_checks = sent
+        .iter()
+        .filter(|packet| matches!(packet, FederationPacket::KeyCheck(_)))
+        .count();
+    let answers = sent
+        .iter()
+        .filter(|packet| matches!(packet, FederationPacket::KeyCheckAnswer(_)))
+        .count();
+    assert_eq!((key_checks, answers), (2, 2));
+    // The routes of B were announced once the link was connected
+    let route = a_routes.get(&far_device).unwrap();
+    assert_eq!((route.via, route.hops), (b.get_realm_id(), 2));
+}
+
+#[test]
+fn key_check_response_does_not_connect() {
+    let (a, b) = realms();
+    let routes = RouteTable::new();
+    let mut handler = FederationHandler::new(&b, &routes, AccessControl::default());
+
+    // An incoming link has to send Hello first
+    let mut context = FederationContext::incoming();
+    let response = handler
+        .handle_packet(FederationPacket::KeyCheckResponse(true), &mut context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+    assert!(!context.is_connected());
+    let announce = FederationPacket::RouteAnnounce {
+        device_id: Uuid::new_v4(),
+        hops: 0,
+    };
+    let response = handler.handle_packet(announce.clone(), &mut context).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+
+    // The peer is known but this side has not checked its keys
+    let mut context = FederationContext::outgoing(a.get_realm_id());
+    handler
+        .handle_packet(FederationPacket::KeyCheckResponse(true), &mut context)
+        .unwrap();
+    assert!(!context.is_connected());
+    let response = handler.handle_packet(announce, &mut context).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+    assert!(routes.routes().is_empty());
+}
+
+#[test]
+fn wrong_key_check_answer_is_refused() {
+    let (a, b) = realms();
+    let routes = RouteTable::new();
+    let mut handler = FederationHandler::new(&b, &routes, AccessControl::default());
+    let mut context = FederationContext::incoming();
+
+    let hello = FederationPacket::Hello { realm_id: a.get_realm_id() };
+    let key_check = messages(handler.handle_packet(hello, &mut context).unwrap());
+    assert!(matches!(key_check.as_slice(), [FederationPacket::KeyCheck(_)]));
+
+    let answer = FederationPacket::KeyCheckAnswer(Bytes::from_static(b"not the bytes"));
+    let response = messages(handler.handle_packet(answer, &mut context).unwrap());
+    assert!(matches!(response.as_slice(), [FederationPacket::KeyCheckResponse(false)]));
+    // Answering the KeyCheck of the other side is not enough either
+    let response = messages(
+        handler
+            .handle_packet(FederationPacket::KeyCheck(Bytes::from_static(b"check")), &mut context)
+            .unwrap(),
+    );
+    assert!(matches!(response.as_slice(), [FederationPacket::KeyCheckAnswer(_)]));
+    assert!(!context.is_connected());
+
+    let hello = FederationPacket::Hello { realm_id: Uuid::new_v4() };
+    let response = handler.handle_packet(hello, &mut FederationContext::incoming()).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::UNKNOWN_DEVICE);
+}
+
+#[test]
+fn forwards_are_checked() {
+    let (a, b) = realms();
+    let (a_routes, b_routes) = (RouteTable::new(), RouteTable::new());
+    let source = Uuid::new_v4();
+    let target = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    b.add_device(InMemoryDevice::new(target));
+    b.add_device(InMemoryDevice::new(other));
+    let mut acl = AccessControl::new(AclAction::Allow);
+    acl.add_rule(AclRule::deny(AclSubject::Device(source), AclSubject::Device(target)).for_protocols([5]));
+    let limiter = RateLimiter::new(RateLimits {
+        per_ip: None,
+        per_device: None,
+        per_target: Some(RateLimit::new(1, 0.0)),
+        ..RateLimits::default()
+    });
+    let mut a_handler = FederationHandler::new(&a, &a_routes, AccessControl::default());
+    let mut b_handler = FederationHandler::new(&b, &b_routes, acl).with_rate_limiter(&limiter);
+    let mut a_context = FederationContext::outgoing(b.get_realm_id());
+    let mut b_context = FederationContext::incoming();
+
+    let forward = |target: Uuid, payload: Bytes| FederationPacket::Forward {
+        source,
+        target,
+        hops: 0,
+        payload,
+    };
+    // Nothing is forwarded before the link is connected
+    let response = b_handler
+        .handle_packet(forward(target, frame(DEVICE_TO_DEVICE)), &mut b_context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+
+    let hello = FederationPacket::Hello { realm_id: a.get_realm_id() };
+    run_link((&mut a_handler, &mut a_context), (&mut b_handler, &mut b_context), hello);
+    assert!(b_context.is_connected());
+
+    let response = b_handler
+        .handle_packet(forward(target, frame(5)), &mut b_context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::ACCESS_DENIED);
+
+    let response = b_handler
+        .handle_packet(forward(other, Bytes::from_static(b"x")), &mut b_context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::MALFORMED_PACKET);
+
+    // The target bucket was emptied by the denied frame
+    let response = b_handler
+        .handle_packet(forward(target, frame(DEVICE_TO_DEVICE)), &mut b_context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::RATE_LIMITED);
+
+    let unknown = Uuid::new_v4();
+    let response = b_handler
+        .handle_packet(forward(unknown, frame(DEVICE_TO_DEVICE)), &mut b_context)
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::UNKNOWN_DEVICE);
+
+    let limiter = RateLimiter::default();
+    let mut b_handler = FederationHandler::new(&b, &b_routes, AccessControl::default()).with_rate_limiter(&limiter);
+    match b_handler
+        .handle_packet(forward(target, frame(DEVICE_TO_DEVICE)), &mut b_context)
+        .unwrap()
+    {
+        FederationResponse::Deliver {
+            target: delivered_to,
+            message: Protocol::DeviceToRealm(RealmPacket::DeviceProxy(from, payload)),
+        } => {
+            assert_eq!(delivered_to, target);
+            assert_eq!(from, source);
+            assert_eq!(payload, frame(DEVICE_TO_DEVICE));
+        }
+        _ => panic!("Expected the frame to be delivered"),
+    }
+}
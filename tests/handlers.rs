@@ -0,0 +1,766 @@
+use abst_rs::device_manager::{DeviceManager, TrustLevel};
+use abst_rs::packets::capabilities::{Capabilities, DEVICE_TO_REALM, SUITE_THEMIS_SECURE_MESSAGE};
+use abst_rs::packets::dtd::DeviceToDevicePackets;
+use abst_rs::packets::federation::FederationPacket;
+use abst_rs::packets::handlers::realm::{RealmHandler, RealmResponse};
+use abst_rs::packets::handlers::events::ConnectionEvent;
+use abst_rs::packets::handlers::identities::{IdentityHandler, LocalIdentities};
+use abst_rs::packets::handlers::{
+    ConnectionContext, ConnectionType, DefaultProtocolHandler, PairingAbortReason, Response,
+};
+use abst_rs::packets::realm::{LoginDetails, RealmPacket};
+use abst_rs::packets::{ErrorPacket, Protocol};
+use abst_rs::protocol::{ConnectionStatus, ConnectionStatusKind, DTDViaRealm};
+use abst_rs::realm::acl::AccessControl;
+use abst_rs::realm::login::LoginResult;
+use abst_rs::test_util::{
+    DeviceManagerCall, InMemoryDevice, InMemoryDeviceManager, InMemoryError, InMemoryRealm,
+    PairAnswer,
+};
+use bytes::Bytes;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+#[tokio::test]
+async fn hello_creates_context() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    let other = Uuid::new_v4();
+    device_manager.add_device(InMemoryDevice::new(other));
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+
+    let hello = DeviceToDevicePackets::Hello {
+        device_id: other,
+        paired: true,
+        target: None,
+        capabilities: Capabilities::default(),
+    };
+    let response = handler
+        .handle_packet_direct_communication(hello.into(), None)
+        .await
+        .unwrap();
+    match response {
+        Response::NewContext {
+            message: Protocol::DeviceToDevice(DeviceToDevicePackets::Hello { paired, .. }),
+            ..
+        } => assert!(paired),
+        _ => panic!("Expected a new context"),
+    }
+    assert!(device_manager
+        .calls()
+        .contains(&DeviceManagerCall::IsPaired(other)));
+}
+
+#[tokio::test]
+async fn hello_from_blocked_device_is_refused() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    let other = Uuid::new_v4();
+    device_manager.add_device(InMemoryDevice::new(other).with_trust_level(TrustLevel::Blocked));
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+
+    let hello = DeviceToDevicePackets::Hello {
+        device_id: other,
+        paired: true,
+        target: None,
+        capabilities: Capabilities::default(),
+    };
+    match handler
+        .handle_packet_direct_communication(hello.into(), None)
+        .await
+        .unwrap()
+    {
+        Response::Message(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+            assert_eq!(error.error_code(), ErrorPacket::ACCESS_DENIED)
+        }
+        _ => panic!("Expected an error"),
+    }
+    assert!(!device_manager
+        .calls()
+        .contains(&DeviceManagerCall::TouchLastSeen(other)));
+}
+
+/// Sends Hello and a PairRequest from a device that is not paired yet
+async fn pair_request(
+    handler: &mut Handler<'_>,
+    other: Uuid,
+) -> Result<Response, InMemoryError> {
+    let hello = DeviceToDevicePackets::Hello {
+        device_id: other,
+        paired: false,
+        target: None,
+        capabilities: Capabilities::default(),
+    };
+    let mut context = match handler
+        .handle_packet_direct_communication(hello.into(), None)
+        .await
+        .unwrap()
+    {
+        Response::NewContext { new_context, .. } => new_context,
+        _ => panic!("Expected a new context"),
+    };
+    let pair_request = DeviceToDevicePackets::PairRequest {
+        device_name: "other".to_string(),
+        details: Some(Bytes::from_static(b"details")),
+    };
+    handler
+        .handle_packet_direct_communication(pair_request.into(), Some(&mut context))
+        .await
+}
+
+#[tokio::test]
+async fn rejected_pair_request_is_recorded() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    device_manager.push_pair_answer(PairAnswer::Reject);
+    let other = Uuid::new_v4();
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+
+    match pair_request(&mut handler, other).await.unwrap() {
+        Response::PairingAborted { aborted, message } => {
+            assert_eq!(aborted.device_id, other);
+            assert_eq!(aborted.reason, PairingAbortReason::Rejected);
+            match message {
+                Some(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+                    assert_eq!(error.error_code(), ErrorPacket::ACCESS_DENIED)
+                }
+                _ => panic!("Expected an error for the other side"),
+            }
+        }
+        _ => panic!("Expected pairing to be aborted"),
+    }
+    assert_eq!(
+        device_manager.pair_requests(),
+        vec![(other, "other".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn failed_pair_request_is_an_error() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    device_manager.push_pair_answer(PairAnswer::Fail);
+    let other = Uuid::new_v4();
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+
+    assert!(pair_request(&mut handler, other).await.is_err());
+    assert_eq!(
+        device_manager.pair_requests(),
+        vec![(other, "other".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn hello_picks_the_local_identity() {
+    let work = Arc::new(InMemoryDeviceManager::new("work"));
+    let home = Arc::new(InMemoryDeviceManager::new("home"));
+    let other = Uuid::new_v4();
+    home.add_device(InMemoryDevice::new(other));
+    let mut identities = LocalIdentities::new();
+    let work_id = identities.insert(work.clone()).await;
+    let home_id = identities.insert(home.clone()).await;
+    identities.bind_port(7000, home_id);
+
+    let hello = |target| DeviceToDevicePackets::Hello {
+        device_id: other,
+        paired: true,
+        target,
+        capabilities: Capabilities::default(),
+    };
+    let local_device = |response| match response {
+        Response::NewContext {
+            message: Protocol::DeviceToDevice(DeviceToDevicePackets::Hello { device_id, paired, .. }),
+            new_context,
+        } => {
+            assert_eq!(new_context.local_device_id, device_id);
+            (device_id, paired)
+        }
+        _ => panic!("Expected a new context"),
+    };
+
+    // The default is the first identity
+    let mut handler = IdentityHandler::new(&identities);
+    let response = handler
+        .handle_packet_direct_communication(hello(None).into(), None)
+        .await
+        .unwrap();
+    assert_eq!(local_device(response), (work_id, false));
+
+    let mut handler = IdentityHandler::new(&identities).with_local_port(7000);
+    let response = handler
+        .handle_packet_direct_communication(hello(None).into(), None)
+        .await
+        .unwrap();
+    assert_eq!(local_device(response), (home_id, true));
+
+    // The target wins over the port
+    let response = handler
+        .handle_packet_direct_communication(hello(Some(work_id)).into(), None)
+        .await
+        .unwrap();
+    assert_eq!(local_device(response), (work_id, false));
+    assert!(!work.calls().contains(&DeviceManagerCall::TouchLastSeen(other)));
+    assert!(home.calls().contains(&DeviceManagerCall::TouchLastSeen(other)));
+
+    match handler
+        .handle_packet_direct_communication(hello(Some(Uuid::new_v4())).into(), None)
+        .await
+        .unwrap()
+    {
+        Response::Message(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+            assert_eq!(error.error_code(), ErrorPacket::UNKNOWN_DEVICE)
+        }
+        _ => panic!("Expected an error"),
+    }
+}
+
+fn message(response: Response) -> DeviceToDevicePackets {
+    match response {
+        Response::Message(Protocol::DeviceToDevice(packet)) => packet,
+        _ => panic!("Expected a message"),
+    }
+}
+
+/// Runs `initiator` and `receiver` up to the point where the receiver waits on the echoed KeyCheck
+type Handler<'dm> = DefaultProtocolHandler<'dm, InMemoryError, InMemoryDevice, InMemoryDeviceManager>;
+
+/// Runs the pairing until the initiator has sent its key. Returns that SendKey
+async fn send_keys(
+    initiator: &InMemoryDeviceManager,
+    receiver: &InMemoryDeviceManager,
+    initiator_test: Option<&'static [u8]>,
+    receiver_test: Option<&'static [u8]>,
+) -> (ConnectionContext, ConnectionContext, DeviceToDevicePackets) {
+    let initiator_id = DeviceManager::get_device_id(initiator);
+    let receiver_id = DeviceManager::get_device_id(receiver);
+    receiver.push_pair_answer(PairAnswer::Accept(receiver_test.map(Bytes::from_static)));
+    let mut initiator_handler = DefaultProtocolHandler::new(initiator);
+    let mut receiver_handler = DefaultProtocolHandler::new(receiver);
+
+    let hello = DeviceToDevicePackets::Hello {
+        device_id: initiator_id,
+        paired: false,
+        target: Some(receiver_id),
+        capabilities: Capabilities::default(),
+    };
+    let mut receiver_context = match receiver_handler
+        .handle_packet_direct_communication(hello.into(), None)
+        .await
+        .unwrap()
+    {
+        Response::NewContext { new_context, .. } => *new_context,
+        _ => panic!("Expected a new context"),
+    };
+    let mut initiator_context = ConnectionContext::new(receiver_id, initiator_id);
+    initiator_context.status = ConnectionStatus::PendingPairRequest {
+        test: initiator_test.map(Bytes::from_static),
+    };
+
+    let pair_request = DeviceToDevicePackets::PairRequest {
+        device_name: "initiator".to_string(),
+        details: None,
+    };
+    let send_key = message(
+        receiver_handler
+            .handle_packet_direct_communication(pair_request.into(), Some(&mut receiver_context))
+            .await
+            .unwrap(),
+    );
+    // The receiver has no key to encrypt a test to yet
+    assert!(matches!(send_key, DeviceToDevicePackets::SendKey { test: None, .. }));
+    let send_key = message(
+        initiator_handler
+            .handle_packet_direct_communication(send_key.into(), Some(&mut initiator_context))
+            .await
+            .unwrap(),
+    );
+    (initiator_context, receiver_context, send_key)
+}
+
+/// Returns the answer of the initiator to the first KeyCheck of the receiver
+async fn pair_until_key_check(
+    initiator: &InMemoryDeviceManager,
+    receiver: &InMemoryDeviceManager,
+) -> (ConnectionContext, ConnectionContext, DeviceToDevicePackets) {
+    let (mut initiator_context, mut receiver_context, send_key) = send_keys(initiator, receiver, None, None).await;
+    let key_check = message(
+        DefaultProtocolHandler::new(receiver)
+            .handle_packet_direct_communication(send_key.into(), Some(&mut receiver_context))
+            .await
+            .unwrap(),
+    );
+    let key_check = message(
+        DefaultProtocolHandler::new(initiator)
+            .handle_packet_direct_communication(key_check.into(), Some(&mut initiator_context))
+            .await
+            .unwrap(),
+    );
+    // Nothing is registered until both key checks pass
+    assert!(initiator.registered_devices().is_empty());
+    assert!(receiver.registered_devices().is_empty());
+    (initiator_context, receiver_context, key_check)
+}
+
+/// Passes the messages between both devices until one side has nothing left to send. `packet` goes to the receiver first.
+/// Returns every packet that was sent
+async fn finish_pairing(
+    (initiator, initiator_context): (&mut Handler<'_>, &mut ConnectionContext),
+    (receiver, receiver_context): (&mut Handler<'_>, &mut ConnectionContext),
+    packet: DeviceToDevicePackets,
+) -> Vec<DeviceToDevicePackets> {
+    let mut sent = vec![packet.clone()];
+    let mut next = Some(packet);
+    let mut to_receiver = true;
+    while let Some(packet) = next.take() {
+        let response = if to_receiver {
+            receiver
+                .handle_packet_direct_communication(packet.into(), Some(receiver_context))
+                .await
+        } else {
+            initiator
+                .handle_packet_direct_communication(packet.into(), Some(initiator_context))
+                .await
+        };
+        if let Response::Message(Protocol::DeviceToDevice(packet)) = response.unwrap() {
+            sent.push(packet.clone());
+            next = Some(packet);
+        }
+        to_receiver = !to_receiver;
+    }
+    sent
+}
+
+#[tokio::test]
+async fn pairing_registers_after_both_key_checks() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let (mut initiator_context, mut receiver_context, key_check) =
+        pair_until_key_check(&initiator, &receiver).await;
+    let mut initiator_handler = DefaultProtocolHandler::new(&initiator);
+    let mut receiver_handler = DefaultProtocolHandler::new(&receiver);
+
+    // The receiver checked the initiator. Now the initiator checks the receiver
+    let response = message(
+        receiver_handler
+            .handle_packet_direct_communication(key_check.into(), Some(&mut receiver_context))
+            .await
+            .unwrap(),
+    );
+    assert!(matches!(response, DeviceToDevicePackets::KeyCheckResponse(true)));
+    let key_check = message(
+        initiator_handler
+            .handle_packet_direct_communication(response.into(), Some(&mut initiator_context))
+            .await
+            .unwrap(),
+    );
+    assert!(matches!(
+        initiator_context.status,
+        ConnectionStatus::CheckingKeys { random_bytes: Some(_), .. }
+    ));
+    let answer = message(
+        receiver_handler
+            .handle_packet_direct_communication(key_check.into(), Some(&mut receiver_context))
+            .await
+            .unwrap(),
+    );
+    assert!(initiator.registered_devices().is_empty());
+    assert!(receiver.registered_devices().is_empty());
+
+    let response = message(
+        initiator_handler
+            .handle_packet_direct_communication(answer.into(), Some(&mut initiator_context))
+            .await
+            .unwrap(),
+    );
+    assert!(matches!(response, DeviceToDevicePackets::KeyCheckResponse(true)));
+    assert_eq!(initiator.registered_devices(), vec![DeviceManager::get_device_id(&receiver)]);
+    assert!(matches!(initiator_context.status, ConnectionStatus::Connected));
+    assert!(initiator_context.close().is_none());
+
+    let response = receiver_handler
+        .handle_packet_direct_communication(response.into(), Some(&mut receiver_context))
+        .await
+        .unwrap();
+    assert!(matches!(response, Response::Nothing));
+    assert_eq!(receiver.registered_devices(), vec![DeviceManager::get_device_id(&initiator)]);
+    assert!(matches!(receiver_context.status, ConnectionStatus::Connected));
+}
+
+#[tokio::test]
+async fn initiator_checks_the_receiver() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let (mut initiator_context, mut receiver_context, key_check) =
+        pair_until_key_check(&initiator, &receiver).await;
+    let mut initiator_handler = DefaultProtocolHandler::new(&initiator);
+    let response = message(
+        DefaultProtocolHandler::new(&receiver)
+            .handle_packet_direct_communication(key_check.into(), Some(&mut receiver_context))
+            .await
+            .unwrap(),
+    );
+    message(
+        initiator_handler
+            .handle_packet_direct_communication(response.into(), Some(&mut initiator_context))
+            .await
+            .unwrap(),
+    );
+
+    // Another KeyCheckResponse does not skip the check of the receiver
+    let response = initiator_handler
+        .handle_packet_direct_communication(
+            DeviceToDevicePackets::KeyCheckResponse(true).into(),
+            Some(&mut initiator_context),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        message(response),
+        DeviceToDevicePackets::Error(error) if error.error_code() == ErrorPacket::INVALID_STATE
+    ));
+    let wrong = DeviceToDevicePackets::KeyCheck(Bytes::from_static(b"not the bytes"));
+    match initiator_handler
+        .handle_packet_direct_communication(wrong.into(), Some(&mut initiator_context))
+        .await
+        .unwrap()
+    {
+        Response::PairingAborted { aborted, .. } => {
+            assert_eq!(aborted.reason, PairingAbortReason::KeyCheckFailed)
+        }
+        _ => panic!("Expected the pairing to be aborted"),
+    }
+    assert!(initiator.registered_devices().is_empty());
+    assert!(receiver.registered_devices().is_empty());
+}
+
+#[tokio::test]
+async fn pairing_with_a_test_string() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let (mut initiator_context, mut receiver_context, send_key) =
+        send_keys(&initiator, &receiver, Some(b"1234"), Some(b"1234")).await;
+    assert!(matches!(send_key, DeviceToDevicePackets::SendKey { test: Some(_), .. }));
+    let sent = finish_pairing(
+        (&mut DefaultProtocolHandler::new(&initiator), &mut initiator_context),
+        (&mut DefaultProtocolHandler::new(&receiver), &mut receiver_context),
+        send_key,
+    )
+    .await;
+    let key_checks = sent
+        .iter()
+        .filter(|packet| matches!(packet, DeviceToDevicePackets::KeyCheck(_)))
+        .count();
+    assert_eq!(key_checks, 4);
+    assert!(matches!(initiator_context.status, ConnectionStatus::Connected));
+    assert!(matches!(receiver_context.status, ConnectionStatus::Connected));
+    assert_eq!(initiator.registered_devices(), vec![DeviceManager::get_device_id(&receiver)]);
+    assert_eq!(receiver.registered_devices(), vec![DeviceManager::get_device_id(&initiator)]);
+}
+
+#[tokio::test]
+async fn wrong_test_string_aborts_pairing() {
+    for (initiator_test, receiver_test) in [
+        (Some(&b"1234"[..]), Some(&b"4321"[..])),
+        (Some(&b"1234"[..]), None),
+        (None, Some(&b"1234"[..])),
+    ] {
+        let initiator = InMemoryDeviceManager::new("initiator");
+        let receiver = InMemoryDeviceManager::new("receiver");
+        let (_, mut receiver_context, send_key) = send_keys(&initiator, &receiver, initiator_test, receiver_test).await;
+        match DefaultProtocolHandler::new(&receiver)
+            .handle_packet_direct_communication(send_key.into(), Some(&mut receiver_context))
+            .await
+            .unwrap()
+        {
+            Response::PairingAborted { aborted, .. } => {
+                assert_eq!(aborted.reason, PairingAbortReason::TestMismatch)
+            }
+            _ => panic!("Expected the pairing to be aborted"),
+        }
+        assert!(receiver.registered_devices().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn failed_key_check_rolls_back() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let (mut initiator_context, mut receiver_context, key_check) =
+        pair_until_key_check(&initiator, &receiver).await;
+    let tampered = match key_check {
+        DeviceToDevicePackets::KeyCheck(bytes) => {
+            let mut bytes = bytes.to_vec();
+            bytes[0] ^= 0xFF;
+            DeviceToDevicePackets::KeyCheck(Bytes::from(bytes))
+        }
+        _ => panic!("Expected a KeyCheck"),
+    };
+
+    let response = match DefaultProtocolHandler::new(&receiver)
+        .handle_packet_direct_communication(tampered.into(), Some(&mut receiver_context))
+        .await
+        .unwrap()
+    {
+        Response::PairingAborted { aborted, message } => {
+            assert_eq!(aborted.reason, PairingAbortReason::KeyCheckFailed);
+            message.unwrap()
+        }
+        _ => panic!("Expected the pairing to be aborted"),
+    };
+    assert!(receiver_context.staged.is_none());
+    assert!(matches!(
+        DefaultProtocolHandler::new(&initiator)
+            .handle_packet_direct_communication(response, Some(&mut initiator_context))
+            .await
+            .unwrap(),
+        Response::PairingAborted { .. }
+    ));
+    assert!(initiator.registered_devices().is_empty());
+    assert!(receiver.registered_devices().is_empty());
+}
+
+#[tokio::test]
+async fn disconnect_mid_pairing_is_an_abort() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let (initiator_context, _, _) = pair_until_key_check(&initiator, &receiver).await;
+    let aborted = initiator_context.close().unwrap();
+    assert_eq!(aborted.reason, PairingAbortReason::Disconnected);
+    assert_eq!(aborted.device_id, DeviceManager::get_device_id(&receiver));
+}
+
+#[tokio::test]
+async fn observer_sees_pairing_progress() {
+    let initiator = InMemoryDeviceManager::new("initiator");
+    let receiver = InMemoryDeviceManager::new("receiver");
+    let initiator_id = DeviceManager::get_device_id(&initiator);
+    let events = Mutex::new(Vec::new());
+    let observer = |event| events.lock().unwrap().push(event);
+    let mut handler = DefaultProtocolHandler::new(&receiver).with_observer(&observer);
+
+    let (mut initiator_context, mut receiver_context, send_key) = send_keys(&initiator, &receiver, None, None).await;
+    // The first steps went through a handler without the observer
+    events.lock().unwrap().clear();
+    finish_pairing(
+        (&mut DefaultProtocolHandler::new(&initiator), &mut initiator_context),
+        (&mut handler, &mut receiver_context),
+        send_key,
+    )
+    .await;
+    let error = ErrorPacket::invalid_state(0x00, 4);
+    handler
+        .handle_packet_direct_communication(
+            DeviceToDevicePackets::Error(error.clone()).into(),
+            Some(&mut receiver_context),
+        )
+        .await
+        .unwrap();
+    assert!(handler.connection_closed(receiver_context).is_none());
+
+    let status = |from, to| ConnectionEvent::StatusChanged {
+        device_id: initiator_id,
+        from,
+        to,
+    };
+    assert_eq!(
+        events.into_inner().unwrap(),
+        vec![
+            status(
+                Some(ConnectionStatusKind::Pairing),
+                ConnectionStatusKind::CheckingKeys
+            ),
+            ConnectionEvent::Paired {
+                device_id: initiator_id
+            },
+            status(
+                Some(ConnectionStatusKind::CheckingKeys),
+                ConnectionStatusKind::Connected
+            ),
+            ConnectionEvent::ErrorReceived {
+                device_id: Some(initiator_id),
+                error,
+            },
+            ConnectionEvent::Disconnected {
+                device_id: initiator_id
+            },
+        ]
+    );
+}
+
+#[test]
+fn realm_login_and_proxy() {
+    let realm = InMemoryRealm::new();
+    let device = Uuid::new_v4();
+    realm.add_device(InMemoryDevice::new(device));
+    realm.push_login_answer(LoginResult::Rejected);
+    let mut handler = RealmHandler::new(&realm, AccessControl::default());
+
+    let hello = RealmPacket::Hello {
+        device_id: device,
+        public_key_hash: None,
+        capabilities: Capabilities::default(),
+    };
+    let mut context = match handler.handle_packet(hello.into(), SOURCE, None).unwrap() {
+        RealmResponse::NewContext(context) => context,
+        _ => panic!("Expected a new context"),
+    };
+
+    assert_eq!(context.negotiated.as_ref().unwrap().protocols, vec![DEVICE_TO_REALM]);
+
+    let proxy = RealmPacket::DeviceProxy(device, Bytes::new());
+    match handler
+        .handle_packet(proxy.into(), SOURCE, Some(&mut context))
+        .unwrap()
+    {
+        RealmResponse::Message(Protocol::DeviceToRealm(RealmPacket::Error(error))) => {
+            assert_eq!(error.error_code(), ErrorPacket::NOT_LOGGED_IN)
+        }
+        _ => panic!("Expected an error"),
+    }
+
+    let login = RealmPacket::DeviceLogin(LoginDetails::None);
+    assert!(matches!(
+        handler.handle_packet(login.into(), SOURCE, Some(&mut context)).unwrap(),
+        RealmResponse::Message(Protocol::DeviceToRealm(RealmPacket::Error(_)))
+    ));
+    let login = RealmPacket::DeviceLogin(LoginDetails::None);
+    assert!(matches!(
+        handler.handle_packet(login.into(), SOURCE, Some(&mut context)).unwrap(),
+        RealmResponse::Message(Protocol::DeviceToRealm(RealmPacket::LoginAccepted { .. }))
+    ));
+    assert!(context.is_logged_in());
+    assert_eq!(realm.logins(), vec![device, device]);
+}
+
+#[tokio::test]
+async fn hello_negotiates_capabilities() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+    let capabilities = Capabilities {
+        version: 7,
+        encryption_suites: vec![42, SUITE_THEMIS_SECURE_MESSAGE],
+        ..Capabilities::default()
+    };
+
+    let hello = DeviceToDevicePackets::Hello {
+        device_id: Uuid::new_v4(),
+        paired: false,
+        target: None,
+        capabilities,
+    };
+    match handler
+        .handle_packet_direct_communication(hello.into(), None)
+        .await
+        .unwrap()
+    {
+        Response::NewContext {
+            message: Protocol::DeviceToDevice(DeviceToDevicePackets::Hello { capabilities, .. }),
+            new_context,
+        } => {
+            let negotiated = new_context.negotiated.unwrap();
+            assert_eq!(negotiated.version, Capabilities::default().version);
+            assert_eq!(negotiated.encryption_suite, SUITE_THEMIS_SECURE_MESSAGE);
+            assert_eq!(capabilities, negotiated.to_capabilities());
+        }
+        _ => panic!("Expected a new context"),
+    }
+
+    let no_common_suite = Capabilities {
+        encryption_suites: vec![42],
+        ..Capabilities::default()
+    };
+    // A Realm only shares DeviceToRealm with a device
+    for capabilities in [no_common_suite, Capabilities::realm()] {
+        let hello = DeviceToDevicePackets::Hello {
+            device_id: Uuid::new_v4(),
+            paired: false,
+            target: None,
+            capabilities,
+        };
+        match handler
+            .handle_packet_direct_communication(hello.into(), None)
+            .await
+            .unwrap()
+        {
+            Response::Message(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+                assert_eq!(error.error_code(), ErrorPacket::INCOMPATIBLE)
+            }
+            _ => panic!("Expected an error"),
+        }
+    }
+
+    // Nothing else is handled on a connection that did not agree on DeviceToDevice
+    let mut context = ConnectionContext::new(Uuid::new_v4(), DeviceManager::get_device_id(&device_manager));
+    context.negotiated = Capabilities::default()
+        .negotiate(&Capabilities::new(vec![DEVICE_TO_REALM]))
+        .ok();
+    match handler
+        .handle_packet_direct_communication(DeviceToDevicePackets::Heartbeat.into(), Some(&mut context))
+        .await
+        .unwrap()
+    {
+        Response::Message(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+            assert_eq!(error.error_code(), ErrorPacket::INCOMPATIBLE)
+        }
+        _ => panic!("Expected an error"),
+    }
+}
+
+#[tokio::test]
+async fn realm_packets_are_refused() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+
+    let packets: [Protocol; 2] = [
+        RealmPacket::Heartbeat.into(),
+        FederationPacket::Hello { realm_id: Uuid::new_v4() }.into(),
+    ];
+    for packet in packets {
+        match handler.handle_packet_direct_communication(packet, None).await.unwrap() {
+            Response::Message(Protocol::DeviceToDevice(DeviceToDevicePackets::Error(error))) => {
+                assert_eq!(error.error_code(), ErrorPacket::INVALID_STATE)
+            }
+            _ => panic!("Expected an error"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn pairing_needs_a_direct_connection() {
+    let device_manager = InMemoryDeviceManager::new("test");
+    let mut handler = DefaultProtocolHandler::new(&device_manager);
+    let pair_request = || DeviceToDevicePackets::PairRequest {
+        device_name: "other".to_string(),
+        details: None,
+    };
+
+    let response = handler
+        .handle_packet_direct_communication(pair_request().into(), None)
+        .await
+        .unwrap();
+    assert!(matches!(
+        message(response),
+        DeviceToDevicePackets::Error(error) if error.error_code() == ErrorPacket::INVALID_STATE
+    ));
+
+    let device_id = Uuid::new_v4();
+    let mut context = ConnectionContext::new(device_id, Uuid::new_v4());
+    context.status = ConnectionStatus::Connected;
+    context.connection_type = ConnectionType::DTDViaRealm(DTDViaRealm {
+        device_id,
+        realm_reference: SOURCE,
+    });
+    let packets: [DeviceToDevicePackets; 2] = [
+        pair_request(),
+        DeviceToDevicePackets::KeyCheck(Bytes::from_static(b"check")),
+    ];
+    for packet in packets {
+        let response = handler
+            .handle_packet_direct_communication(packet.into(), Some(&mut context))
+            .await
+            .unwrap();
+        assert!(matches!(
+            message(response),
+            DeviceToDevicePackets::Error(error) if error.error_code() == ErrorPacket::INVALID_STATE
+        ));
+    }
+}
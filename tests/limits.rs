@@ -0,0 +1,180 @@
+use abst_rs::realm::limits::{LockoutSettings, LoginAttempt, RateLimit, RateLimited, RateLimiter, RateLimits};
+use abst_rs::test_util::ManualClock;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn with_clock(limits: RateLimits) -> (RateLimiter, Arc<ManualClock>) {
+    let clock = Arc::new(ManualClock::new());
+    (RateLimiter::new(limits).with_clock(clock.clone()), clock)
+}
+
+fn lockout() -> RateLimits {
+    RateLimits {
+        lockout: LockoutSettings {
+            failures_before_lockout: 3,
+            failures_before_ip_lockout: 5,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+            forget_after: Duration::from_secs(60),
+        },
+        ..RateLimits::default()
+    }
+}
+
+fn retry_after(result: Result<(), RateLimited>) -> Duration {
+    match result {
+        Err(RateLimited::LockedOut { retry_after }) => retry_after,
+        result => panic!("Expected a lockout, got {:?}", result),
+    }
+}
+
+#[test]
+fn token_bucket() {
+    let (limiter, clock) = with_clock(RateLimits {
+        per_ip: Some(RateLimit::new(2, 1.0)),
+        per_device: None,
+        per_target: Some(RateLimit::new(1, 0.5)),
+        ..RateLimits::default()
+    });
+    assert!(limiter.check_ip(ADDRESS).is_ok());
+    assert!(limiter.check_ip(ADDRESS).is_ok());
+    assert_eq!(limiter.check_ip(ADDRESS), Err(RateLimited::Ip));
+    // Every key has its own bucket
+    assert!(limiter.check_ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).is_ok());
+
+    clock.advance(Duration::from_secs(1));
+    assert!(limiter.check_ip(ADDRESS).is_ok());
+    assert_eq!(limiter.check_ip(ADDRESS), Err(RateLimited::Ip));
+    // Never more than the capacity
+    clock.advance(Duration::from_secs(60));
+    for _ in 0..2 {
+        assert!(limiter.check_ip(ADDRESS).is_ok());
+    }
+    assert_eq!(limiter.check_ip(ADDRESS), Err(RateLimited::Ip));
+
+    let target = Uuid::new_v4();
+    assert!(limiter.check_target(target).is_ok());
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(limiter.check_target(target), Err(RateLimited::Target));
+    clock.advance(Duration::from_secs(1));
+    assert!(limiter.check_target(target).is_ok());
+    // No limit is no check
+    for _ in 0..1000 {
+        assert!(limiter.check_device(target).is_ok());
+    }
+}
+
+#[test]
+fn lockout_doubles() {
+    let mut limits = lockout();
+    limits.lockout.failures_before_ip_lockout = 100;
+    let (limiter, clock) = with_clock(limits);
+    let attempt = LoginAttempt {
+        device_id: Uuid::new_v4(),
+        address: ADDRESS,
+        account: Some("alice"),
+    };
+    for _ in 0..2 {
+        limiter.login_failed(&attempt);
+        assert!(limiter.check_lockout(&attempt).is_ok());
+    }
+    limiter.login_failed(&attempt);
+    assert_eq!(retry_after(limiter.check_lockout(&attempt)), Duration::from_secs(1));
+    clock.advance(Duration::from_secs(1));
+    assert!(limiter.check_lockout(&attempt).is_ok());
+
+    limiter.login_failed(&attempt);
+    assert_eq!(retry_after(limiter.check_lockout(&attempt)), Duration::from_secs(2));
+    limiter.login_failed(&attempt);
+    assert_eq!(retry_after(limiter.check_lockout(&attempt)), Duration::from_secs(4));
+    // Capped at the max
+    limiter.login_failed(&attempt);
+    assert_eq!(retry_after(limiter.check_lockout(&attempt)), Duration::from_secs(4));
+
+    clock.advance(Duration::from_secs(4));
+    limiter.login_succeeded(&attempt);
+    assert!(limiter.check_lockout(&attempt).is_ok());
+    limiter.login_failed(&attempt);
+    assert!(limiter.check_lockout(&attempt).is_ok());
+}
+
+#[test]
+fn lockout_covers_the_account_and_address() {
+    let (limiter, _clock) = with_clock(lockout());
+    let alice = |device_id| LoginAttempt {
+        device_id,
+        address: ADDRESS,
+        account: Some("alice"),
+    };
+    // A new device id every time does not get around the account
+    for _ in 0..3 {
+        limiter.login_failed(&alice(Uuid::new_v4()));
+    }
+    assert!(limiter.check_lockout(&alice(Uuid::new_v4())).is_err());
+    let other_address = LoginAttempt {
+        address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        ..alice(Uuid::new_v4())
+    };
+    assert!(limiter.check_lockout(&other_address).is_err());
+
+    // Nor a new account. The address is locked out after more failures
+    let bob = |device_id| LoginAttempt {
+        device_id,
+        address: ADDRESS,
+        account: Some("bob"),
+    };
+    assert!(limiter.check_lockout(&bob(Uuid::new_v4())).is_ok());
+    limiter.login_failed(&bob(Uuid::new_v4()));
+    assert!(limiter.check_lockout(&bob(Uuid::new_v4())).is_ok());
+    limiter.login_failed(&bob(Uuid::new_v4()));
+    assert!(limiter.check_lockout(&bob(Uuid::new_v4())).is_err());
+    let anyone = LoginAttempt {
+        device_id: Uuid::new_v4(),
+        address: ADDRESS,
+        account: None,
+    };
+    assert!(limiter.check_lockout(&anyone).is_err());
+    // Logging in does not clear the address
+    limiter.login_succeeded(&anyone);
+    assert!(limiter.check_lockout(&anyone).is_err());
+}
+
+#[test]
+fn prune_forgets_old_failures() {
+    let (limiter, clock) = with_clock(lockout());
+    let attempt = LoginAttempt {
+        device_id: Uuid::new_v4(),
+        address: ADDRESS,
+        account: None,
+    };
+    limiter.login_failed(&attempt);
+    limiter.login_failed(&attempt);
+    assert_eq!(limiter.tracked_failures(), 2);
+
+    clock.advance(Duration::from_secs(30));
+    limiter.prune();
+    assert_eq!(limiter.tracked_failures(), 2);
+    clock.advance(Duration::from_secs(30));
+    limiter.prune();
+    assert_eq!(limiter.tracked_failures(), 0);
+
+    // A lockout that ends after the failures would be forgotten is kept until it ends
+    let mut limits = lockout();
+    limits.lockout.base = Duration::from_secs(120);
+    limits.lockout.max = Duration::from_secs(120);
+    let (limiter, clock) = with_clock(limits);
+    for _ in 0..3 {
+        limiter.login_failed(&attempt);
+    }
+    clock.advance(Duration::from_secs(90));
+    limiter.prune();
+    assert!(limiter.check_lockout(&attempt).is_err());
+    clock.advance(Duration::from_secs(30));
+    limiter.prune();
+    assert!(limiter.check_lockout(&attempt).is_ok());
+    assert_eq!(limiter.tracked_failures(), 0);
+}
@@ -0,0 +1,120 @@
+use abst_rs::packets::realm::LoginDetails;
+use abst_rs::realm::login::{
+    ChallengeResponseProvider, LoginProvider, LoginProviders, LoginResult, PasswordAccount,
+    PasswordCredentials, PasswordProvider, PasswordVerifier, PreSharedTokenProvider, SessionSettings,
+    PASSWORD_PROVIDER_ID,
+};
+use bytes::Bytes;
+use packet::PacketContent;
+use std::time::Duration;
+use themis::keygen::gen_ec_key_pair;
+use themis::secure_message::SecureSign;
+use uuid::Uuid;
+
+/// Keeps PBKDF2 fast inside of tests
+const ROUNDS: u32 = 1_000;
+
+fn accepted(result: LoginResult) -> bool {
+    matches!(result, LoginResult::Accepted(_))
+}
+
+fn credentials(username: &str, password: &str) -> Bytes {
+    let mut details = Vec::new();
+    PasswordCredentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    }
+    .write(&mut details)
+    .unwrap();
+    Bytes::from(details)
+}
+
+#[test]
+fn pre_shared_token() {
+    let device = Uuid::new_v4();
+    let mut provider = PreSharedTokenProvider::new(SessionSettings::default());
+    provider.add_token(device, Bytes::from_static(b"secret"));
+
+    assert!(accepted(provider.login(&device, &Bytes::from_static(b"secret")).unwrap()));
+    assert!(!accepted(provider.login(&device, &Bytes::from_static(b"secreT")).unwrap()));
+    assert!(!accepted(provider.login(&device, &Bytes::new()).unwrap()));
+    assert!(!accepted(provider.login(&Uuid::new_v4(), &Bytes::from_static(b"secret")).unwrap()));
+}
+
+#[test]
+fn password() {
+    let device = Uuid::new_v4();
+    let mut provider = PasswordProvider::new(SessionSettings {
+        ttl: Duration::from_secs(60),
+        scopes: vec!["proxy".to_string()],
+    });
+    provider.add_account(
+        "alice",
+        PasswordAccount {
+            verifier: PasswordVerifier::with_rounds("hunter2", ROUNDS),
+            scopes: vec!["account:alice".to_string()],
+            devices: [device].into_iter().collect(),
+        },
+    );
+
+    match provider.login(&device, &credentials("alice", "hunter2")).unwrap() {
+        LoginResult::Accepted(session) => {
+            assert!(session.has_scope("proxy"));
+            assert!(session.has_scope("account:alice"));
+            assert!(!session.is_expired());
+        }
+        result => panic!("Expected the login to be accepted, got {:?}", result),
+    }
+    assert!(!accepted(provider.login(&device, &credentials("alice", "hunter3")).unwrap()));
+    assert!(!accepted(provider.login(&device, &credentials("bob", "hunter2")).unwrap()));
+    // The password is right but the account is not bound to this device
+    assert!(!accepted(provider.login(&Uuid::new_v4(), &credentials("alice", "hunter2")).unwrap()));
+    assert!(provider.login(&device, &Bytes::from_static(b"garbage")).is_err());
+}
+
+#[test]
+fn challenge_response() {
+    let device = Uuid::new_v4();
+    let (private, public) = gen_ec_key_pair().split();
+    let mut provider = ChallengeResponseProvider::new(SessionSettings::default());
+    provider.add_device_key(device, public);
+    let sign = SecureSign::new(private);
+
+    let challenge = match provider.login(&device, &Bytes::new()).unwrap() {
+        LoginResult::Challenge(challenge) => challenge,
+        result => panic!("Expected a challenge, got {:?}", result),
+    };
+    let signed = Bytes::from(sign.sign(&challenge).unwrap());
+    assert!(accepted(provider.login(&device, &signed).unwrap()));
+    // A challenge can only be answered once
+    assert!(!accepted(provider.login(&device, &signed).unwrap()));
+
+    provider.login(&device, &Bytes::new()).unwrap();
+    let wrong = Bytes::from(sign.sign(b"not the challenge").unwrap());
+    assert!(!accepted(provider.login(&device, &wrong).unwrap()));
+
+    assert!(!accepted(provider.login(&Uuid::new_v4(), &Bytes::new()).unwrap()));
+}
+
+#[test]
+fn providers_are_picked_by_id() {
+    let device = Uuid::new_v4();
+    let mut providers = LoginProviders::new();
+    let mut password = PasswordProvider::new(SessionSettings::default());
+    password.add_account(
+        "alice",
+        PasswordAccount {
+            verifier: PasswordVerifier::with_rounds("hunter2", ROUNDS),
+            scopes: Vec::new(),
+            devices: [device].into_iter().collect(),
+        },
+    );
+    providers.register(password);
+
+    let details = LoginDetails::Other {
+        id: PASSWORD_PROVIDER_ID,
+        details: credentials("alice", "hunter2"),
+    };
+    assert!(accepted(providers.login(&device, details).unwrap()));
+    assert!(providers.login(&device, LoginDetails::None).is_err());
+}
@@ -0,0 +1,203 @@
+use abst_rs::packets::capabilities::{Capabilities, DEVICE_TO_DEVICE, REALM_TO_REALM};
+use abst_rs::packets::handlers::realm::{RealmConnectionContext, RealmHandler, RealmResponse};
+use abst_rs::packets::realm::{LoginDetails, RealmPacket};
+use abst_rs::packets::{ErrorPacket, Protocol};
+use abst_rs::realm::acl::{AccessControl, AclAction, AclRule, AclSubject};
+use abst_rs::realm::limits::{LockoutSettings, RateLimiter, RateLimits};
+use abst_rs::realm::login::{LoginResult, PasswordCredentials, PASSWORD_PROVIDER_ID};
+use abst_rs::test_util::{error_code, frame, InMemoryDevice, InMemoryRealm};
+use bytes::Bytes;
+use packet::PacketContent;
+use std::net::{IpAddr, Ipv4Addr};
+use uuid::Uuid;
+
+const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Sends Hello and logs in
+fn logged_in(handler: &mut RealmHandler<InMemoryRealm>, device_id: Uuid) -> RealmConnectionContext {
+    let hello = RealmPacket::Hello {
+        device_id,
+        public_key_hash: None,
+        capabilities: Capabilities::default(),
+    };
+    let mut context = match handler.handle_packet(hello.into(), SOURCE, None).unwrap() {
+        RealmResponse::NewContext(context) => *context,
+        _ => panic!("Expected a new context"),
+    };
+    let login = RealmPacket::DeviceLogin(LoginDetails::None);
+    handler.handle_packet(login.into(), SOURCE, Some(&mut context)).unwrap();
+    assert!(context.is_logged_in());
+    context
+}
+
+#[test]
+fn acl_rules() {
+    let alice = Uuid::new_v4();
+    let alice_phone = Uuid::new_v4();
+    let bob = Uuid::new_v4();
+    let mut acl = AccessControl::new(AclAction::Deny);
+    acl.add_to_group("alice", alice);
+    acl.add_to_group("alice", alice_phone);
+
+    // Nothing matches. Devices of the same account can talk, everyone else gets the default
+    assert!(acl.is_allowed(&alice, &alice_phone, DEVICE_TO_DEVICE));
+    assert!(!acl.is_allowed(&alice, &bob, DEVICE_TO_DEVICE));
+    acl.allow_same_group = false;
+    assert!(!acl.is_allowed(&alice, &alice_phone, DEVICE_TO_DEVICE));
+    acl.allow_same_group = true;
+
+    // The first matching rule wins
+    acl.add_rule(AclRule::deny(AclSubject::Device(bob), AclSubject::Group("alice".to_string())).for_protocols([5]));
+    acl.add_rule(AclRule::allow(AclSubject::Device(bob), AclSubject::Any));
+    assert!(acl.is_allowed(&bob, &alice, DEVICE_TO_DEVICE));
+    assert!(!acl.is_allowed(&bob, &alice, 5));
+    assert!(!acl.is_allowed(&bob, &alice_phone, 5));
+    // Rules are one way
+    assert!(!acl.is_allowed(&alice, &bob, DEVICE_TO_DEVICE));
+
+    // A rule beats the same group
+    acl.add_rule(AclRule::deny(AclSubject::Any, AclSubject::Device(alice_phone)));
+    assert!(!acl.is_allowed(&alice, &alice_phone, DEVICE_TO_DEVICE));
+
+    assert!(acl.remove_from_group("alice", &alice));
+    assert!(!acl.is_in_group("alice", &alice));
+    acl.clear_rules();
+    assert!(acl.rules().is_empty());
+    assert!(!acl.is_allowed(&bob, &alice, DEVICE_TO_DEVICE));
+}
+
+#[test]
+fn proxy_is_checked_against_the_acl() {
+    let realm = InMemoryRealm::new();
+    let sender = Uuid::new_v4();
+    let target = Uuid::new_v4();
+    realm.add_device(InMemoryDevice::new(sender));
+    realm.add_device(InMemoryDevice::new(target));
+    let mut acl = AccessControl::new(AclAction::Allow);
+    acl.add_rule(AclRule::deny(AclSubject::Device(sender), AclSubject::Device(target)).for_protocols([5]));
+    let mut handler = RealmHandler::new(&realm, acl);
+    let mut context = logged_in(&mut handler, sender);
+
+    let proxy = RealmPacket::DeviceProxy(target, frame(5));
+    let response = handler.handle_packet(proxy.into(), SOURCE, Some(&mut context)).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::ACCESS_DENIED);
+
+    let proxy = RealmPacket::DeviceProxy(target, frame(DEVICE_TO_DEVICE));
+    match handler.handle_packet(proxy.into(), SOURCE, Some(&mut context)).unwrap() {
+        RealmResponse::Forward {
+            target: forwarded_to,
+            message: Protocol::DeviceToRealm(RealmPacket::DeviceProxy(source, payload)),
+        } => {
+            assert_eq!(forwarded_to, target);
+            // The target sees who sent it, not who it was sent to
+            assert_eq!(source, sender);
+            assert_eq!(payload, frame(DEVICE_TO_DEVICE));
+        }
+        _ => panic!("Expected the frame to be forwarded"),
+    }
+
+    // A frame without a protocol id can not be checked
+    let proxy = RealmPacket::DeviceProxy(target, Bytes::from_static(b"x"));
+    let response = handler.handle_packet(proxy.into(), SOURCE, Some(&mut context)).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::MALFORMED_PACKET);
+}
+
+#[test]
+fn hello_without_device_to_realm_is_refused() {
+    let realm = InMemoryRealm::new();
+    let mut handler = RealmHandler::new(&realm, AccessControl::default());
+    // Another Realm shares REALM_TO_REALM but that is not handled here
+    let hello = RealmPacket::Hello {
+        device_id: Uuid::new_v4(),
+        public_key_hash: None,
+        capabilities: Capabilities::new(vec![REALM_TO_REALM]),
+    };
+    let response = handler.handle_packet(hello.into(), SOURCE, None).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INCOMPATIBLE);
+
+    let mut context = logged_in(&mut handler, Uuid::new_v4());
+    context.negotiated = Capabilities::realm().negotiate(&Capabilities::new(vec![REALM_TO_REALM])).ok();
+    let response = handler
+        .handle_packet(RealmPacket::Heartbeat.into(), SOURCE, Some(&mut context))
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INCOMPATIBLE);
+}
+
+#[test]
+fn realm_key_exchange_is_refused() {
+    let realm = InMemoryRealm::new();
+    let device = Uuid::new_v4();
+    let mut handler = RealmHandler::new(&realm, AccessControl::default());
+    let mut context = logged_in(&mut handler, device);
+
+    for packet in [
+        RealmPacket::SendKey { public_key: Bytes::from_static(b"key") },
+        RealmPacket::KeyCheck(Bytes::from_static(b"check")),
+        RealmPacket::KeyCheckResponse(true),
+    ] {
+        let response = handler.handle_packet(packet.into(), SOURCE, None).unwrap();
+        assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+    }
+    let response = handler
+        .handle_packet(RealmPacket::KeyCheckResponse(true).into(), SOURCE, Some(&mut context))
+        .unwrap();
+    assert_eq!(error_code(response), ErrorPacket::INVALID_STATE);
+}
+
+#[test]
+fn failed_logins_lock_out_the_account() {
+    let realm = InMemoryRealm::new();
+    let limiter = RateLimiter::new(RateLimits {
+        lockout: LockoutSettings {
+            failures_before_lockout: 2,
+            ..LockoutSettings::default()
+        },
+        ..RateLimits::default()
+    });
+    let mut handler = RealmHandler::new(&realm, AccessControl::default()).with_rate_limiter(&limiter);
+    let login = |username: &str| {
+        let mut details = Vec::new();
+        PasswordCredentials {
+            username: username.to_string(),
+            password: "hunter2".to_string(),
+        }
+        .write(&mut details)
+        .unwrap();
+        RealmPacket::DeviceLogin(LoginDetails::Other {
+            id: PASSWORD_PROVIDER_ID,
+            details: Bytes::from(details),
+        })
+    };
+    let hello = |handler: &mut RealmHandler<InMemoryRealm>| {
+        let hello = RealmPacket::Hello {
+            device_id: Uuid::new_v4(),
+            public_key_hash: None,
+            capabilities: Capabilities::default(),
+        };
+        match handler.handle_packet(hello.into(), SOURCE, None).unwrap() {
+            RealmResponse::NewContext(context) => *context,
+            _ => panic!("Expected a new context"),
+        }
+    };
+
+    let mut context = hello(&mut handler);
+    realm.push_login_answer(LoginResult::Rejected);
+    let response = handler.handle_packet(login("alice").into(), SOURCE, Some(&mut context)).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::LOGIN_REJECTED);
+    // A login that fails with an error counts as well
+    realm.push_login_error();
+    assert!(handler.handle_packet(login("alice").into(), SOURCE, Some(&mut context)).is_err());
+
+    // Another device can not log in to the account
+    let mut context = hello(&mut handler);
+    let logins = realm.logins().len();
+    let response = handler.handle_packet(login("alice").into(), SOURCE, Some(&mut context)).unwrap();
+    assert_eq!(error_code(response), ErrorPacket::LOCKED_OUT);
+    assert_eq!(realm.logins().len(), logins);
+
+    let response = handler.handle_packet(login("bob").into(), SOURCE, Some(&mut context)).unwrap();
+    assert!(matches!(
+        response,
+        RealmResponse::Message(Protocol::DeviceToRealm(RealmPacket::LoginAccepted { .. }))
+    ));
+}
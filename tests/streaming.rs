@@ -0,0 +1,62 @@
+use abst_rs::a_sync::tokio_abst::{read_packet_streaming, send_packet};
+use abst_rs::encryption::{EncryptionError, EncryptionManager};
+use bytes::Bytes;
+use packet::PacketContent;
+
+/// Streaming reads are only for links without encryption
+struct Plaintext;
+
+impl EncryptionManager for Plaintext {
+    type Error = EncryptionError;
+
+    fn decrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        Ok(message)
+    }
+
+    fn encrypt_message(&self, message: Bytes) -> Result<Bytes, Self::Error> {
+        Ok(message)
+    }
+}
+
+/// A whole frame the way `send_packet` writes it
+async fn frame(protocol: u8, packet: u8, payload: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::new();
+    send_packet(&mut frame, &Plaintext, None, (protocol, packet, payload))
+        .await
+        .unwrap();
+    frame
+}
+
+#[tokio::test]
+async fn unread_payload_is_skipped() {
+    let mut first = Vec::new();
+    7u32.write(&mut first).unwrap();
+    String::from("not read").write(&mut first).unwrap();
+    let mut second = Vec::new();
+    9u32.write(&mut second).unwrap();
+    let mut stream = frame(1, 2, first).await;
+    stream.extend(frame(1, 3, second).await);
+    let mut reader = stream.as_slice();
+
+    // The string after the u32 is skipped
+    let read = read_packet_streaming::<_, u32>(&mut reader).await.unwrap();
+    assert_eq!(read, (1, 2, 7));
+    let read = read_packet_streaming::<_, u32>(&mut reader).await.unwrap();
+    assert_eq!(read, (1, 3, 9));
+    assert!(reader.is_empty());
+}
+
+#[tokio::test]
+async fn content_can_not_read_past_the_payload() {
+    // Says 10 bytes, has 2. The next frame must not be read as the rest of the string
+    let mut short = Vec::new();
+    rmp::encode::write_str_len(&mut short, 10).unwrap();
+    short.extend_from_slice(b"ab");
+    let mut next = Vec::new();
+    String::from("next frame").write(&mut next).unwrap();
+    let mut stream = frame(1, 2, short).await;
+    stream.extend(frame(1, 3, next).await);
+
+    let result = read_packet_streaming::<_, String>(&mut stream.as_slice()).await;
+    assert!(result.is_err(), "{:?}", result.map(|(_, _, content)| content));
+}